@@ -1,7 +1,44 @@
-const { app, BrowserWindow, nativeImage, Tray, Menu, Notification } = require('electron');
+const { app, BrowserWindow, ipcMain, nativeImage, Tray, Menu, Notification } = require('electron');
+const crypto = require('crypto');
+const dgram = require('dgram');
 const path = require('path');
 
 let tray;
+// UDP sockets bound to media relays, by call id. The relay latches onto the
+// address the HELLO came from, so RTP must leave through the same socket.
+const relaySockets = new Map();
+
+// HELLO <side> <unix time> <hex HMAC-SHA256(secret, "<session id>:<side>:<unix time>")>
+function relayHello({ sessionId, side, secret }) {
+  const timestamp = Math.floor(Date.now() / 1000);
+  const mac = crypto
+    .createHmac('sha256', Buffer.from(secret, 'hex'))
+    .update(`${sessionId}:${side}:${timestamp}`)
+    .digest('hex');
+  return Buffer.from(`HELLO ${side} ${timestamp} ${mac}`);
+}
+
+ipcMain.handle('relay:bind', (_event, relay) => {
+  let socket = relaySockets.get(relay.callId);
+  if (!socket) {
+    socket = dgram.createSocket(relay.host.includes(':') ? 'udp6' : 'udp4');
+    relaySockets.set(relay.callId, socket);
+  }
+  return new Promise((resolve, reject) => {
+    socket.send(relayHello(relay), relay.port, relay.host, (error) => {
+      if (error) {
+        reject(error);
+      } else {
+        resolve(socket.address().port);
+      }
+    });
+  });
+});
+
+ipcMain.handle('relay:close', (_event, callId) => {
+  relaySockets.get(callId)?.close();
+  relaySockets.delete(callId);
+});
 
 function createWindow() {
   const win = new BrowserWindow({
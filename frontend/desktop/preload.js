@@ -1,9 +1,16 @@
-const { contextBridge } = require('electron');
+const { contextBridge, ipcRenderer } = require('electron');
 
 contextBridge.exposeInMainWorld('voipDesktop', {
   notify: (title, body) => {
     new Notification({ title, body }).show();
-  }
+  },
+  // Only offered when a native RTP stack sends through the relay socket;
+  // an idle relay is reclaimed and takes the call down with it.
+  ...(process.env.DESKTOP_RELAY_MEDIA === '1' && {
+    // Bind our side of a call's relay; resolves with the local UDP port.
+    bindRelay: (relay) => ipcRenderer.invoke('relay:bind', relay),
+    closeRelay: (callId) => ipcRenderer.invoke('relay:close', callId)
+  })
 });
 
 
@@ -13,6 +13,7 @@ type AuthPayload = {
   iat: number;
   exp: number;
   nonce: string;
+  role: 'agent' | 'supervisor';
 };
 
 function deriveUserId(email: string): string {
@@ -23,6 +24,14 @@ function deriveUserId(email: string): string {
     .replace(/(.{8})(.{4})(.{4})(.{4})(.{12})/, '$1-$2-$3-$4-$5');
 }
 
+// Demo accounts allowed to monitor, whisper to and barge into agents' calls.
+function demoSupervisors(): string[] {
+  return (process.env.DEMO_SUPERVISOR_EMAILS ?? '')
+    .split(',')
+    .map((entry) => entry.trim().toLowerCase())
+    .filter(Boolean);
+}
+
 export async function POST(request: NextRequest): Promise<NextResponse> {
   const body = (await request.json()) as LoginBody;
   const email = body.email?.trim();
@@ -55,6 +64,7 @@ export async function POST(request: NextRequest): Promise<NextResponse> {
     iat: now,
     exp: now + 60 * 60 * 8,
     nonce: randomUUID(),
+    role: demoSupervisors().includes(email.toLowerCase()) ? 'supervisor' : 'agent',
   };
 
   const jwt = await new SignJWT(payload)
@@ -8,7 +8,7 @@ export type SignalingEvent = {
 type SignalingCallbacks = {
   onEvent?: (event: SignalingEvent) => void;
   onOpen?: () => void;
-  onClose?: () => void;
+  onClose?: (event: CloseEvent) => void;
   onError?: (error: Event) => void;
 };
 
@@ -17,7 +17,15 @@ const DEFAULT_SIGNALING_URL =
     ? 'ws://localhost:8080/ws'
     : 'wss://signaling.voip.example.com/ws';
 
-function buildUrl(token: string): string {
+/** Close code for a resume whose missed frames the server no longer holds. */
+export const CLOSE_CANNOT_RESUME = 4003;
+
+type ResumeState = {
+  resumeToken: string;
+  lastSeq: number;
+};
+
+function buildUrl(token: string, resume: ResumeState | null): string {
   if (typeof window === 'undefined') {
     return DEFAULT_SIGNALING_URL;
   }
@@ -25,6 +33,11 @@ function buildUrl(token: string): string {
   const base = process.env.NEXT_PUBLIC_SIGNALING_URL ?? DEFAULT_SIGNALING_URL;
   const url = new URL(base, window.location.href);
   url.searchParams.set('token', token);
+  if (resume) {
+    // Lets the server reattach our previous session and replay what we missed.
+    url.searchParams.set('resume', resume.resumeToken);
+    url.searchParams.set('lastSeq', String(resume.lastSeq));
+  }
   return url.toString();
 }
 
@@ -33,12 +46,15 @@ function buildUrl(token: string): string {
  *
  * The softphone store holds a single instance of this class which is
  * responsible for establishing the WS channel, parsing JSON messages emitted
- * by the Rust signalling service, and surfacing lifecycle callbacks.
+ * by the Rust signalling service, and surfacing lifecycle callbacks. It also
+ * remembers the session's resume token and the last sequenced frame seen, so a
+ * reconnect after a dropped socket resumes the same server-side session.
  */
 export class SignalingClient {
   #callbacks: SignalingCallbacks;
   #socket: WebSocket | null = null;
   #connectionPromise: Promise<void> | null = null;
+  #resume: ResumeState | null = null;
 
   constructor(callbacks: SignalingCallbacks = {}) {
     this.#callbacks = callbacks;
@@ -60,7 +76,7 @@ export class SignalingClient {
 
     this.#connectionPromise = new Promise((resolve, reject) => {
       try {
-        const url = buildUrl(token);
+        const url = buildUrl(token, this.#resume);
         const socket = new WebSocket(url);
         this.#socket = socket;
 
@@ -72,6 +88,13 @@ export class SignalingClient {
         socket.onmessage = (event) => {
           try {
             const payload = JSON.parse(event.data) as SignalingEvent;
+            if (payload.type === 'session') {
+              // A fresh session numbers its frames from the start again.
+              const lastSeq = payload.resumed ? this.#resume?.lastSeq ?? 0 : 0;
+              this.#resume = { resumeToken: payload.resumeToken as string, lastSeq };
+            } else if (typeof payload.seq === 'number' && this.#resume) {
+              this.#resume.lastSeq = payload.seq;
+            }
             this.#callbacks.onEvent?.(payload);
           } catch (error) {
             // Bad payloads shouldn't tear down the whole connection.
@@ -87,8 +110,12 @@ export class SignalingClient {
           }
         };
 
-        socket.onclose = () => {
-          this.#callbacks.onClose?.();
+        socket.onclose = (event) => {
+          if (event.code === CLOSE_CANNOT_RESUME) {
+            // The old session is gone; the next connect starts a fresh one.
+            this.#resume = null;
+          }
+          this.#callbacks.onClose?.(event);
           this.#socket = null;
           this.#connectionPromise = null;
         };
@@ -111,6 +138,8 @@ export class SignalingClient {
   }
 
   disconnect(): void {
+    // A deliberate close ends the session; there is nothing left to resume.
+    this.#resume = null;
     this.#socket?.close();
     this.#socket = null;
     this.#connectionPromise = null;
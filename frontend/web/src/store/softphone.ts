@@ -13,10 +13,70 @@
 import create from 'zustand';
 import SimplePeer, { Instance as PeerInstance, SignalData } from 'simple-peer';
 import { fetchAuthToken } from '@/lib/auth/client';
-import { SignalingClient, type SignalingEvent } from '@/lib/signaling/client';
+import { CLOSE_CANNOT_RESUME, SignalingClient, type SignalingEvent } from '@/lib/signaling/client';
 
 type CallState = 'idle' | 'connecting' | 'ringing' | 'in-call' | 'ended' | 'error';
 type SignalingStatus = 'disconnected' | 'connecting' | 'connected';
+type PresenceStatus =
+  | 'available'
+  | 'away'
+  | 'busy'
+  | 'do_not_disturb'
+  | 'on_call'
+  | 'wrap_up'
+  | 'offline';
+
+type AgentPresence = {
+  userId: string;
+  status: PresenceStatus;
+  note?: string;
+  updatedAt: number;
+};
+
+type ConferenceParticipant = {
+  callId: string;
+  userId: string;
+  moderator: boolean;
+  muted: boolean;
+};
+
+type ConferenceRoom = {
+  conferenceId: string;
+  locked: boolean;
+  participants: ConferenceParticipant[];
+};
+
+type SuperviseMode = 'monitor' | 'whisper' | 'barge';
+
+type Supervision = {
+  // The agent's call we are supervising; our own leg is `callId`.
+  callId: string;
+  agentId: string;
+  mode: SuperviseMode;
+};
+
+type ParkedCall = {
+  callId: string;
+  // The party waiting in the slot.
+  party: string;
+  parkedBy: string;
+};
+
+// A call's media relay and our side of it, from `call.relay`.
+type RelayBinding = {
+  callId: string;
+  sessionId: string;
+  host: string;
+  port: number;
+  side: 'a' | 'b';
+  secret: string;
+};
+
+type RelayBridge = {
+  // Sends the signed HELLO binding our side; resolves with the local UDP port.
+  bindRelay: (relay: RelayBinding) => Promise<number>;
+  closeRelay: (callId: string) => Promise<void>;
+};
 
 type LogEntry = {
   id: string;
@@ -33,6 +93,16 @@ type SoftphoneStore = {
   statusMessage: string | null;
   error: string | null;
   eventLog: LogEntry[];
+  // Live presence of colleagues in the same tenant, keyed by user id.
+  presence: Record<string, AgentPresence>;
+  // Direction our media takes while the call is on hold (either side), else null.
+  holdDirection: string | null;
+  // The room our current call is a leg of, when it is a conference.
+  conference: ConferenceRoom | null;
+  // Set while our current call is a supervisor leg on someone else's call.
+  supervision: Supervision | null;
+  // Occupied park slots of the tenant, keyed by slot number.
+  parkSlots: Record<number, ParkedCall>;
   localStream: MediaStream | null;
   remoteStream: MediaStream | null;
   connectSignaling: () => Promise<void>;
@@ -40,7 +110,20 @@ type SoftphoneStore = {
   startOutgoingCall: () => Promise<void>;
   acceptIncomingCall: () => Promise<void>;
   hangup: () => void;
+  transferCall: (to: string) => void;
+  holdCall: () => void;
+  resumeCall: () => void;
+  parkCall: (slot?: number) => void;
+  retrieveParked: (slot: number) => Promise<void>;
+  pickupCall: (userId?: string) => Promise<void>;
+  joinConference: (conferenceId: string, pin?: string) => Promise<void>;
+  inviteToConference: (to: string) => void;
+  muteParticipant: (callId: string, muted: boolean) => void;
+  kickParticipant: (callId: string) => void;
+  lockConference: (locked: boolean) => void;
+  superviseCall: (callId: string, agentId: string, mode: SuperviseMode) => Promise<void>;
   setDialNumber: (value: string) => void;
+  setPresence: (status: PresenceStatus, note?: string) => void;
   handleSignalingEvent: (event: SignalingEvent) => void;
 };
 
@@ -49,6 +132,8 @@ let peer: PeerInstance | null = null;
 // If the peer connection is not ready when remote SDP arrives we temporarily
 // buffer it here. Once Simple-Peer initialises we flush in insertion order.
 let queuedSignals: SignalData[] = [];
+// The server keeps a dropped session around briefly; reconnect well within it.
+const RESUME_DELAY_MS = 1000;
 
 type SetState = (fn: (state: SoftphoneStore) => Partial<SoftphoneStore>) => void;
 type GetState = () => SoftphoneStore;
@@ -66,6 +151,16 @@ function appendLog(set: SetState, message: string) {
   }));
 }
 
+// Offered by the desktop shell when a native RTP stack carries our media
+// through a relay; browsers cannot send UDP and stay on WebRTC.
+function relayBridge(): RelayBridge | null {
+  if (typeof window === 'undefined') {
+    return null;
+  }
+  const desktop = (window as Window & { voipDesktop?: Partial<RelayBridge> }).voipDesktop;
+  return desktop?.bindRelay && desktop.closeRelay ? (desktop as RelayBridge) : null;
+}
+
 function stopStream(stream: MediaStream | null) {
   stream?.getTracks().forEach((track) => track.stop());
 }
@@ -127,6 +222,39 @@ async function createPeer(
   });
 }
 
+// Drop the peer connection but keep the microphone, for a call that carries
+// on with someone else after a transfer.
+function replacePeer() {
+  if (peer) {
+    peer.removeAllListeners();
+    peer.destroy();
+    peer = null;
+  }
+  queuedSignals = [];
+}
+
+// Carry on with a parked or ringing call under a new call id of our own; the
+// waiting party sends the offer once the server moves it over.
+async function takeOverCall(set: SetState, get: GetState, frame: Record<string, unknown>, status: string) {
+  if (!signalingClient || signalingClient.readyState !== WebSocket.OPEN) {
+    appendLog(set, 'Cannot take a call without signaling connection');
+    return;
+  }
+  const callId = crypto.randomUUID();
+  set(() => ({ callState: 'connecting', statusMessage: status, callId, incomingNumber: null }));
+  try {
+    const stream = await ensureLocalStream(set, get);
+    peer = await createPeer(false, callId, stream, set);
+    queuedSignals.forEach((signal) => peer?.signal(signal));
+    queuedSignals = [];
+    signalingClient.send({ ...frame, callId });
+  } catch (error) {
+    const message = error instanceof Error ? error.message : 'Unable to take the call';
+    set(() => ({ callState: 'error', error: message, statusMessage: message }));
+    appendLog(set, `Call error: ${message}`);
+  }
+}
+
 function cleanupPeer(set: SetState, get: GetState) {
   if (peer) {
     peer.removeAllListeners();
@@ -150,11 +278,25 @@ export const useSoftphoneStore = create<SoftphoneStore>((set, get) => ({
   statusMessage: null,
   error: null,
   eventLog: [],
+  presence: {},
+  holdDirection: null,
+  conference: null,
+  supervision: null,
+  parkSlots: {},
   localStream: null,
   remoteStream: null,
 
   setDialNumber: (value: string) => set(() => ({ dialNumber: value })),
 
+  setPresence: (status: PresenceStatus, note?: string) => {
+    // on_call/offline are derived server-side; the server rejects them here.
+    if (!signalingClient || signalingClient.readyState !== WebSocket.OPEN) {
+      appendLog(set, 'Cannot change presence without signaling connection');
+      return;
+    }
+    signalingClient.send({ type: 'presence.set', status, note });
+  },
+
   connectSignaling: async () => {
     if (typeof window === 'undefined') {
       return;
@@ -175,15 +317,28 @@ export const useSoftphoneStore = create<SoftphoneStore>((set, get) => ({
       }
 
       // The signalling client relays events between this browser and the Rust service.
-      signalingClient = new SignalingClient({
+      const client = new SignalingClient({
         onOpen: () => {
           set(() => ({ signalingStatus: 'connected', statusMessage: 'Signaling connected' }));
           appendLog(set, 'Connected to signaling service');
         },
         onEvent: (event) => get().handleSignalingEvent(event),
-        onClose: () => {
+        onClose: (event) => {
           set(() => ({ signalingStatus: 'disconnected', statusMessage: 'Signaling disconnected' }));
           appendLog(set, 'Signaling disconnected');
+          const reconnect = !event.wasClean || event.code === CLOSE_CANNOT_RESUME;
+          if (reconnect && signalingClient === client) {
+            // Network blip: resume the session so ringing calls and SDP survive,
+            // or start over when it could not be resumed.
+            setTimeout(() => {
+              void fetchAuthToken().then((fresh) => {
+                if (fresh && signalingClient === client) {
+                  set(() => ({ signalingStatus: 'connecting', statusMessage: 'Reconnecting to signaling...' }));
+                  client.connect(fresh).catch(() => appendLog(set, 'Signaling reconnect failed'));
+                }
+              });
+            }, RESUME_DELAY_MS);
+          }
         },
         onError: (error) => {
           console.error('signaling error', error);
@@ -191,7 +346,8 @@ export const useSoftphoneStore = create<SoftphoneStore>((set, get) => ({
         },
       });
 
-      await signalingClient.connect(token);
+      signalingClient = client;
+      await client.connect(token);
     } catch (error) {
       const message = error instanceof Error ? error.message : 'Unable to connect to signaling';
       set(() => ({ signalingStatus: 'disconnected', statusMessage: message, error: message }));
@@ -272,10 +428,147 @@ export const useSoftphoneStore = create<SoftphoneStore>((set, get) => ({
     }
     // Ensure both media and signalling state transitions to "idle".
     cleanupPeer(set, get);
-    set(() => ({ callState: 'ended', statusMessage: 'Call ended', callId: null, incomingNumber: null }));
+    set(() => ({ callState: 'ended', statusMessage: 'Call ended', callId: null, incomingNumber: null, holdDirection: null, conference: null, supervision: null }));
     appendLog(set, 'Call ended');
   },
 
+  transferCall: (to: string) => {
+    const state = get();
+    if (!state.callId || state.callState !== 'in-call' || !to.trim()) {
+      return;
+    }
+    // Blind transfer: the other party is moved on and this side hangs up.
+    signalingClient?.send({ type: 'call.transfer', callId: state.callId, to });
+    set(() => ({ statusMessage: 'Transferring...' }));
+    appendLog(set, `Transferring call to ${to}`);
+  },
+
+  holdCall: () => {
+    const state = get();
+    if (!state.callId || state.callState !== 'in-call') {
+      return;
+    }
+    signalingClient?.send({ type: 'call.hold', callId: state.callId });
+  },
+
+  resumeCall: () => {
+    const state = get();
+    if (!state.callId) {
+      return;
+    }
+    signalingClient?.send({ type: 'call.resume', callId: state.callId });
+  },
+
+  parkCall: (slot?: number) => {
+    const state = get();
+    if (!state.callId || state.callState !== 'in-call') {
+      return;
+    }
+    // Our part ends with `call.ended` (reason `parked`) once the slot is taken.
+    signalingClient?.send({ type: 'call.park', callId: state.callId, slot });
+    set(() => ({ statusMessage: 'Parking...' }));
+    appendLog(set, slot ? `Parking call in slot ${slot}` : 'Parking call');
+  },
+
+  retrieveParked: async (slot: number) => {
+    appendLog(set, `Retrieving call parked in slot ${slot}`);
+    await takeOverCall(set, get, { type: 'call.retrieve', slot }, 'Retrieving call...');
+  },
+
+  pickupCall: async (userId?: string) => {
+    appendLog(set, userId ? `Picking up call ringing ${userId}` : 'Picking up a call in the group');
+    await takeOverCall(set, get, { type: 'call.pickup', userId }, 'Picking up call...');
+  },
+
+  joinConference: async (conferenceId: string, pin?: string) => {
+    if (!signalingClient || signalingClient.readyState !== WebSocket.OPEN) {
+      appendLog(set, 'Cannot join a conference without signaling connection');
+      return;
+    }
+
+    // Each participant is a call leg of its own, answered by the media mixer.
+    const callId = crypto.randomUUID();
+    set(() => ({ callState: 'connecting', statusMessage: 'Joining conference...', callId, incomingNumber: null }));
+    appendLog(set, `Joining conference ${conferenceId}`);
+
+    try {
+      const stream = await ensureLocalStream(set, get);
+      peer = await createPeer(true, callId, stream, set);
+      queuedSignals.forEach((signal) => peer?.signal(signal));
+      queuedSignals = [];
+
+      signalingClient.send({ type: 'conference.join', conferenceId, callId, pin: pin || undefined });
+    } catch (error) {
+      const message = error instanceof Error ? error.message : 'Unable to join conference';
+      set(() => ({ callState: 'error', error: message, statusMessage: message }));
+      appendLog(set, `Conference error: ${message}`);
+    }
+  },
+
+  inviteToConference: (to: string) => {
+    const { conference } = get();
+    if (!conference || !to.trim()) {
+      return;
+    }
+    signalingClient?.send({ type: 'conference.invite', conferenceId: conference.conferenceId, to });
+    appendLog(set, `Invited ${to} to the conference`);
+  },
+
+  muteParticipant: (callId: string, muted: boolean) => {
+    const { conference } = get();
+    if (!conference) {
+      return;
+    }
+    signalingClient?.send({ type: 'conference.mute', conferenceId: conference.conferenceId, callId, muted });
+  },
+
+  kickParticipant: (callId: string) => {
+    const { conference } = get();
+    if (!conference) {
+      return;
+    }
+    signalingClient?.send({ type: 'conference.kick', conferenceId: conference.conferenceId, callId });
+  },
+
+  lockConference: (locked: boolean) => {
+    const { conference } = get();
+    if (!conference) {
+      return;
+    }
+    signalingClient?.send({ type: 'conference.lock', conferenceId: conference.conferenceId, locked });
+  },
+
+  superviseCall: async (callId: string, agentId: string, mode: SuperviseMode) => {
+    if (!signalingClient || signalingClient.readyState !== WebSocket.OPEN) {
+      appendLog(set, 'Cannot supervise without signaling connection');
+      return;
+    }
+
+    // Switching mode reuses the leg we are already listening on.
+    const state = get();
+    if (state.supervision?.callId === callId && state.callId) {
+      signalingClient.send({ type: 'call.supervise', callId, legId: state.callId, agentId, mode });
+      return;
+    }
+
+    const legId = crypto.randomUUID();
+    set(() => ({ callState: 'connecting', statusMessage: 'Joining call...', callId: legId, incomingNumber: null, supervision: { callId, agentId, mode } }));
+    appendLog(set, `Supervising ${agentId} (${mode})`);
+
+    try {
+      const stream = await ensureLocalStream(set, get);
+      peer = await createPeer(true, legId, stream, set);
+      queuedSignals.forEach((signal) => peer?.signal(signal));
+      queuedSignals = [];
+
+      signalingClient.send({ type: 'call.supervise', callId, legId, agentId, mode });
+    } catch (error) {
+      const message = error instanceof Error ? error.message : 'Unable to supervise call';
+      set(() => ({ callState: 'error', error: message, statusMessage: message, supervision: null }));
+      appendLog(set, `Supervision error: ${message}`);
+    }
+  },
+
   handleSignalingEvent: (event: SignalingEvent) => {
     // All signalling messages funnel through this reducer so we have a single
     // place to reason about call state transitions.
@@ -285,7 +578,7 @@ export const useSoftphoneStore = create<SoftphoneStore>((set, get) => ({
         const callId = (event.callId as string) ?? crypto.randomUUID();
         const from = (event.from as string) ?? 'Unknown';
         set(() => ({ callId, callState: 'ringing', incomingNumber: from, statusMessage: 'Incoming call' }));
-        appendLog(set, `Incoming call from ${from}`);
+        appendLog(set, event.transferredBy ? `Call from ${from} transferred by ${event.transferredBy as string}` : `Incoming call from ${from}`);
         break;
       }
       case 'call.ringing': {
@@ -297,20 +590,199 @@ export const useSoftphoneStore = create<SoftphoneStore>((set, get) => ({
       case 'call.connected': {
         set(() => ({ callState: 'in-call', statusMessage: 'Call connected' }));
         appendLog(set, 'Call connected');
+        if (relayBridge() && state.callId) {
+          signalingClient?.send({ type: 'call.relay', callId: state.callId });
+        }
+        break;
+      }
+      case 'call.relay': {
+        const bridge = relayBridge();
+        if (!bridge || event.callId !== state.callId) {
+          break;
+        }
+        const relay = event as unknown as RelayBinding;
+        bridge
+          .bindRelay(relay)
+          .then((port) => appendLog(set, `Media relayed via ${relay.host}:${relay.port} from local port ${port}`))
+          .catch((error) => appendLog(set, `Relay binding failed: ${error instanceof Error ? error.message : String(error)}`));
         break;
       }
       case 'call.ended': {
-        // Either party hung up – mirror the termination and release resources.
-        appendLog(set, 'Remote ended the call');
+        // A call we were transferred away from ends after we moved on.
+        if (event.callId && event.callId !== state.callId) {
+          break;
+        }
+        // Either party hung up (or the server gave up on the call) – mirror the
+        // termination and release resources.
+        const reason = (event.reason as string | undefined) ?? 'hangup';
+        appendLog(set, `Call ended (${reason})`);
+        if (state.callId) {
+          void relayBridge()?.closeRelay(state.callId);
+        }
         cleanupPeer(set, get);
-        set(() => ({ callState: 'ended', statusMessage: 'Call ended by remote', callId: null, incomingNumber: null }));
+        set(() => ({ callState: 'ended', statusMessage: `Call ended: ${reason.replace(/_/g, ' ')}`, callId: null, incomingNumber: null, holdDirection: null, conference: null, supervision: null }));
+        break;
+      }
+      case 'call.transferred': {
+        // The call continues under a new id with a new peer; renegotiate media
+        // from scratch, offering if we are the caller of the new call.
+        const callId = event.newCallId as string;
+        const stream = state.localStream;
+        replacePeer();
+        set(() => ({ callId, incomingNumber: (event.peer as string) ?? null, statusMessage: 'Call transferred', holdDirection: null }));
+        appendLog(set, `Call transferred to ${(event.peer as string) ?? 'Unknown'}`);
+        if (stream) {
+          void createPeer(Boolean(event.caller), callId, stream, set).then((instance) => {
+            peer = instance;
+            queuedSignals.forEach((signal) => peer?.signal(signal));
+            queuedSignals = [];
+          });
+        }
+        break;
+      }
+      case 'call.held':
+      case 'call.resumed': {
+        if (event.callId !== state.callId) {
+          break;
+        }
+        // The server tells us which way media may flow now; the microphone
+        // stays muted unless we may send, the held side hears music on hold.
+        const direction = (event.direction as string) ?? 'sendrecv';
+        const onHold = direction !== 'sendrecv';
+        state.localStream?.getAudioTracks().forEach((track) => {
+          track.enabled = !onHold;
+        });
+        set(() => ({ holdDirection: onHold ? direction : null, statusMessage: onHold ? 'On hold' : 'Call connected' }));
+        appendLog(set, event.type === 'call.held' ? 'Call put on hold' : 'Call resumed');
+        break;
+      }
+      case 'call.transfer.failed': {
+        // The transfer did not happen; the original call is still up.
+        const message = (event.message as string) ?? 'Transfer failed';
+        set(() => ({ statusMessage: message }));
+        appendLog(set, `Transfer failed: ${message}`);
+        break;
+      }
+      case 'park.slot': {
+        const slot = event.slot as number;
+        const parked = event.parked as ParkedCall | null;
+        set((current) => {
+          const parkSlots = { ...current.parkSlots };
+          if (parked) {
+            parkSlots[slot] = parked;
+          } else {
+            delete parkSlots[slot];
+          }
+          return { parkSlots };
+        });
+        appendLog(set, parked ? `Call from ${parked.party} parked in slot ${slot}` : `Park slot ${slot} is free`);
+        break;
+      }
+      case 'conference.state': {
+        // Sent once we are in: the room as it stands.
+        const conference: ConferenceRoom = {
+          conferenceId: event.conferenceId as string,
+          locked: Boolean(event.locked),
+          participants: (event.participants as ConferenceParticipant[] | undefined) ?? [],
+        };
+        set(() => ({ conference, statusMessage: 'In conference' }));
+        appendLog(set, `Joined conference with ${conference.participants.length - 1} other(s)`);
+        break;
+      }
+      case 'conference.joined': {
+        const participant = event.participant as ConferenceParticipant;
+        set((current) => current.conference
+          ? { conference: { ...current.conference, participants: [...current.conference.participants, participant] } }
+          : {});
+        appendLog(set, `${participant.userId} joined the conference`);
+        break;
+      }
+      case 'conference.left': {
+        if (event.callId === state.callId) {
+          set(() => ({ conference: null }));
+          appendLog(set, event.kicked ? 'Removed from the conference' : 'Left the conference');
+          break;
+        }
+        set((current) => current.conference
+          ? { conference: { ...current.conference, participants: current.conference.participants.filter((p) => p.callId !== event.callId) } }
+          : {});
+        appendLog(set, `${event.userId as string} ${event.kicked ? 'was removed from' : 'left'} the conference`);
+        break;
+      }
+      case 'conference.muted': {
+        const muted = Boolean(event.muted);
+        if (event.callId === state.callId) {
+          // A moderator may mute us; follow along with the microphone.
+          state.localStream?.getAudioTracks().forEach((track) => {
+            track.enabled = !muted;
+          });
+        }
+        set((current) => current.conference
+          ? { conference: { ...current.conference, participants: current.conference.participants.map((p) => (p.callId === event.callId ? { ...p, muted } : p)) } }
+          : {});
+        break;
+      }
+      case 'conference.locked': {
+        const locked = Boolean(event.locked);
+        set((current) => current.conference ? { conference: { ...current.conference, locked } } : {});
+        appendLog(set, locked ? 'Conference locked' : 'Conference unlocked');
+        break;
+      }
+      case 'conference.invited': {
+        appendLog(set, `${event.by as string} invited you to conference ${event.conferenceId as string}`);
+        break;
+      }
+      case 'call.supervised': {
+        const mode = (event.mode as SuperviseMode | null) ?? null;
+        if (state.supervision?.callId === event.callId) {
+          // Our own leg: confirm the mode the relay now mixes us in with.
+          if (mode) {
+            set((current) => (current.supervision ? { supervision: { ...current.supervision, mode }, statusMessage: `Supervising (${mode})` } : {}));
+          }
+          break;
+        }
+        // We are the agent: a supervisor started or stopped talking to us.
+        appendLog(set, mode ? `Supervisor ${event.supervisor as string} is on the call (${mode})` : 'Supervisor left the call');
+        break;
+      }
+      case 'session': {
+        appendLog(set, event.resumed ? 'Signaling session resumed' : 'Signaling session started');
+        break;
+      }
+      case 'auth.expiring': {
+        // The server closes the socket once the token lapses; swap in a fresh one.
+        appendLog(set, 'Signaling token expiring, refreshing');
+        void fetchAuthToken().then((token) => {
+          if (token && signalingClient?.readyState === WebSocket.OPEN) {
+            signalingClient.send({ type: 'auth.refresh', token });
+          }
+        });
+        break;
+      }
+      case 'auth.refreshed': {
+        appendLog(set, 'Signaling token refreshed');
         break;
       }
       case 'call.error': {
+        if (event.code === 'invalid_token') {
+          // A rejected refresh does not affect the current call.
+          appendLog(set, `Token refresh failed: ${event.message as string}`);
+          break;
+        }
+        if (event.code === 'rate_limited') {
+          // The frame was shed; the call itself is unaffected.
+          appendLog(set, 'Signaling is rate limited, slow down');
+          break;
+        }
+        if (!event.callId && state.conference) {
+          // A refused conference command; we are still in the room.
+          appendLog(set, (event.message as string) ?? 'Conference error');
+          break;
+        }
         // Transport/PBX errors surface as a final terminal state.
         const message = (event.message as string) ?? 'Call error';
         appendLog(set, message);
-        set(() => ({ callState: 'error', statusMessage: message, error: message }));
+        set(() => ({ callState: 'error', statusMessage: message, error: message, conference: null, supervision: null }));
         cleanupPeer(set, get);
         break;
       }
@@ -328,10 +800,24 @@ export const useSoftphoneStore = create<SoftphoneStore>((set, get) => ({
         }
         break;
       }
+      case 'presence.snapshot': {
+        // Sent once per connection; replaces whatever we knew before.
+        const agents = (event.agents as AgentPresence[] | undefined) ?? [];
+        const presence: Record<string, AgentPresence> = {};
+        agents.forEach((agent) => {
+          presence[agent.userId] = agent;
+        });
+        set(() => ({ presence }));
+        appendLog(set, `${agents.length} colleague(s) online`);
+        break;
+      }
       case 'presence': {
-        // Presence events currently just surface in the on-screen log.
-        const message = (event.message as string) ?? 'Presence update';
-        appendLog(set, message);
+        const agent = event as unknown as AgentPresence;
+        if (!agent.userId || !agent.status) {
+          break;
+        }
+        set((current) => ({ presence: { ...current.presence, [agent.userId]: agent } }));
+        appendLog(set, `${agent.userId} is now ${agent.status}`);
         break;
       }
       default: {
@@ -0,0 +1,285 @@
+//! Authenticated binding of endpoints to relay sides.
+//!
+//! `/alloc` hands out a secret per side. Before sending media an endpoint
+//! announces itself from the address it sends from with
+//!
+//! ```text
+//! HELLO <side> <unix time> <hex HMAC-SHA256(secret, "<session id>:<side>:<unix time>")>
+//! ```
+//!
+//! and the relay binds that side to the address only if the MAC checks out
+//! with the side's secret and the time is within [`BIND_WINDOW`] of ours. A
+//! side may be moved to a new address the same way (after a NAT rebinding,
+//! say), but only with a handshake newer than the one it was last bound with,
+//! so a captured handshake cannot be replayed to take over the leg.
+//!
+//! Mixer legs (conference participants and supervisors) have a port, and a
+//! secret, of their own. They bind the same way, as side `a` of a session
+//! named by the leg's id.
+
+use crate::Side;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// How far a handshake's time may be from ours.
+pub const BIND_WINDOW: Duration = Duration::from_secs(30);
+
+const SECRET_LEN: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BindError {
+    #[error("malformed handshake")]
+    Malformed,
+    #[error("handshake time outside the window")]
+    Stale,
+    #[error("handshake not signed with the side's secret")]
+    Mismatch,
+    #[error("handshake not newer than the side's current binding")]
+    Replay,
+}
+
+/// A parsed `HELLO`.
+pub struct Hello {
+    pub side: Side,
+    timestamp: u64,
+    mac: Vec<u8>,
+}
+
+impl Hello {
+    /// Parse a handshake; `None` when the packet is not one.
+    pub fn parse(packet: &[u8]) -> Option<Result<Hello, BindError>> {
+        let rest = packet.strip_prefix(b"HELLO ")?;
+        Some(Self::fields(rest).ok_or(BindError::Malformed))
+    }
+
+    fn fields(rest: &[u8]) -> Option<Hello> {
+        let text = std::str::from_utf8(rest).ok()?.trim_end();
+        let mut fields = text.split(' ');
+        let side = match fields.next()? {
+            "a" => Side::A,
+            "b" => Side::B,
+            _ => return None,
+        };
+        let timestamp = fields.next()?;
+        let mac = hex::decode(fields.next()?).ok()?;
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Hello {
+            side,
+            timestamp: timestamp.parse().ok()?,
+            mac,
+        })
+    }
+}
+
+/// The secret of one relay side or mixer leg, and the newest handshake it
+/// was bound with.
+pub struct Key {
+    secret: [u8; SECRET_LEN],
+    bound_at: AtomicU64,
+}
+
+impl Key {
+    pub fn generate() -> Self {
+        let mut secret = [0u8; SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Key {
+            secret,
+            bound_at: AtomicU64::new(0),
+        }
+    }
+
+    /// The secret, as handed to the endpoint.
+    pub fn secret(&self) -> String {
+        hex::encode(self.secret)
+    }
+
+    /// Check a handshake for session `session_id`. `rebind` is set when it
+    /// would move the side to another address, which only a handshake newer
+    /// than the current binding may.
+    pub fn verify(&self, session_id: Uuid, hello: &Hello, rebind: bool) -> Result<(), BindError> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac accepts any key length");
+        let side = match hello.side {
+            Side::A => "a",
+            Side::B => "b",
+        };
+        mac.update(format!("{session_id}:{side}:{}", hello.timestamp).as_bytes());
+        mac.verify_slice(&hello.mac)
+            .map_err(|_| BindError::Mismatch)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now.abs_diff(hello.timestamp) > BIND_WINDOW.as_secs() {
+            return Err(BindError::Stale);
+        }
+        if rebind {
+            self.bound_at
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                    (hello.timestamp > last).then_some(hello.timestamp)
+                })
+                .map_err(|_| BindError::Replay)?;
+        } else {
+            self.bound_at.fetch_max(hello.timestamp, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// The keys of a relay's two sides.
+pub struct Credentials {
+    keys: [Key; 2],
+}
+
+impl Credentials {
+    pub fn generate() -> Self {
+        Credentials {
+            keys: [Key::generate(), Key::generate()],
+        }
+    }
+
+    /// The secret of `side`, as handed to its endpoint.
+    pub fn secret(&self, side: Side) -> String {
+        self.keys[side.index()].secret()
+    }
+
+    /// Check a handshake for relay `session_id`; see [`Key::verify`].
+    pub fn verify(&self, session_id: Uuid, hello: &Hello, rebind: bool) -> Result<(), BindError> {
+        self.keys[hello.side.index()].verify(session_id, hello, rebind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// A `HELLO` for `side` of relay `session_id`, as an endpoint builds it.
+    fn hello(credentials: &Credentials, session_id: Uuid, side: &str, timestamp: u64) -> Vec<u8> {
+        let side_of = if side == "a" { Side::A } else { Side::B };
+        let secret = hex::decode(credentials.secret(side_of)).unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&secret).unwrap();
+        mac.update(format!("{session_id}:{side}:{timestamp}").as_bytes());
+        let mac = hex::encode(mac.finalize().into_bytes());
+        format!("HELLO {side} {timestamp} {mac}").into_bytes()
+    }
+
+    fn verify(
+        credentials: &Credentials,
+        session_id: Uuid,
+        packet: &[u8],
+        rebind: bool,
+    ) -> Result<(), BindError> {
+        let hello = Hello::parse(packet).expect("a handshake")?;
+        credentials.verify(session_id, &hello, rebind)
+    }
+
+    #[test]
+    fn valid_mac_binds() {
+        let credentials = Credentials::generate();
+        let session_id = Uuid::new_v4();
+        for side in ["a", "b"] {
+            let packet = hello(&credentials, session_id, side, now());
+            assert!(verify(&credentials, session_id, &packet, false).is_ok());
+        }
+        // A trailing newline from a line-oriented client is tolerated.
+        let mut packet = hello(&credentials, session_id, "a", now());
+        packet.push(b'\n');
+        assert!(verify(&credentials, session_id, &packet, false).is_ok());
+    }
+
+    #[test]
+    fn wrong_secret_is_refused() {
+        let credentials = Credentials::generate();
+        let session_id = Uuid::new_v4();
+        let packet = hello(&Credentials::generate(), session_id, "a", now());
+        assert!(matches!(
+            verify(&credentials, session_id, &packet, false),
+            Err(BindError::Mismatch)
+        ));
+        // Signed for another relay, or claiming the other side.
+        let packet = hello(&credentials, Uuid::new_v4(), "a", now());
+        assert!(matches!(
+            verify(&credentials, session_id, &packet, false),
+            Err(BindError::Mismatch)
+        ));
+        let signed_for_a = String::from_utf8(hello(&credentials, session_id, "a", now())).unwrap();
+        let packet = signed_for_a.replacen("HELLO a", "HELLO b", 1);
+        assert!(matches!(
+            verify(&credentials, session_id, packet.as_bytes(), false),
+            Err(BindError::Mismatch)
+        ));
+    }
+
+    #[test]
+    fn stale_time_is_refused() {
+        let credentials = Credentials::generate();
+        let session_id = Uuid::new_v4();
+        let window = BIND_WINDOW.as_secs();
+        for timestamp in [now() - window - 5, now() + window + 5] {
+            let packet = hello(&credentials, session_id, "a", timestamp);
+            assert!(matches!(
+                verify(&credentials, session_id, &packet, false),
+                Err(BindError::Stale)
+            ));
+        }
+    }
+
+    #[test]
+    fn rebind_needs_a_newer_handshake() {
+        let credentials = Credentials::generate();
+        let session_id = Uuid::new_v4();
+        let now = now();
+        let first = hello(&credentials, session_id, "a", now);
+        assert!(verify(&credentials, session_id, &first, false).is_ok());
+        // The same handshake replayed from elsewhere, or an older one.
+        assert!(matches!(
+            verify(&credentials, session_id, &first, true),
+            Err(BindError::Replay)
+        ));
+        let older = hello(&credentials, session_id, "a", now - 1);
+        assert!(matches!(
+            verify(&credentials, session_id, &older, true),
+            Err(BindError::Replay)
+        ));
+        let newer = hello(&credentials, session_id, "a", now + 1);
+        assert!(verify(&credentials, session_id, &newer, true).is_ok());
+        assert!(matches!(
+            verify(&credentials, session_id, &newer, true),
+            Err(BindError::Replay)
+        ));
+        // Each side keeps its own record.
+        let other = hello(&credentials, session_id, "b", now);
+        assert!(verify(&credentials, session_id, &other, true).is_ok());
+    }
+
+    #[test]
+    fn malformed_handshakes() {
+        assert!(Hello::parse(b"\x80\x00rtp").is_none());
+        for packet in [
+            &b"HELLO c 1700000000 00"[..],
+            b"HELLO a soon 00",
+            b"HELLO a 1700000000 xyz",
+            b"HELLO a 1700000000",
+            b"HELLO a 1700000000 00 extra",
+        ] {
+            assert!(matches!(
+                Hello::parse(packet),
+                Some(Err(BindError::Malformed))
+            ));
+        }
+    }
+}
@@ -0,0 +1,597 @@
+//! Conference bridges: a server-side audio mixer for any number of parties.
+//!
+//! Each participant gets a UDP port of its own from the node's port pool and
+//! exchanges plain G.711 RTP with it. Every 20 ms the mixer takes one frame from each participant's
+//! queue, sums them, and sends everyone the sum minus their own voice in
+//! their own codec. Muted participants still hear the room but are left out
+//! of the mix. Like music on hold, the mixer cannot take part in DTLS-SRTP
+//! sessions, so only endpoints that negotiate plain RTP can join.
+//!
+//! A participant binds its address with a `HELLO` signed with the secret
+//! handed out on joining (see [`crate::binding`]). Its RTP then latches the
+//! port to wherever it comes from, like a relay side's (see
+//! [`crate::latch`]). Endpoints that cannot send a handshake, such as SIP
+//! phones, are taken as bound to the address in their offer until one
+//! arrives, so only RTP from the host their SDP names can latch.
+
+use crate::binding::{self, BindError, Hello};
+use crate::latch::Latch;
+use crate::ports::{Lease, LeaseError, PortPool};
+use crate::{g711, rtp, Side};
+use sdp::{Codec, Connection, Direction, LocalMedia, Origin, SessionDescription};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tokio::task::AbortHandle;
+use uuid::Uuid;
+
+const MIX_INTERVAL: Duration = Duration::from_millis(20);
+/// Frames a participant may run ahead of the mixer before the oldest are
+/// dropped; bounds the latency a fast sender can build up.
+const MAX_QUEUED_FRAMES: usize = 10;
+
+pub(crate) type Frame = [i16; g711::FRAME_SAMPLES];
+/// Frames summed without clipping yet.
+pub(crate) type Mix = [i32; g711::FRAME_SAMPLES];
+
+#[derive(Debug, thiserror::Error)]
+pub enum JoinError {
+    #[error("the offer has no audio in a codec the mixer supports")]
+    NoCodec,
+    #[error(transparent)]
+    Ports(#[from] LeaseError),
+}
+
+/// A leg's answer, and what its endpoint binds its address with.
+pub struct Joined {
+    pub answer: SessionDescription,
+    /// The leg's `HELLO` secret, for its endpoint only.
+    pub secret: String,
+    /// The leg's port.
+    pub port: u16,
+}
+
+pub struct Conference {
+    pub id: Uuid,
+    ports: Arc<PortPool>,
+    participants: RwLock<HashMap<Uuid, Arc<Participant>>>,
+    mixer: Mutex<Option<AbortHandle>>,
+}
+
+/// One endpoint's media with a mixer: its own port, bound and latched like a
+/// relay side, and the decoded audio it sent that the mixer has yet to take.
+pub(crate) struct Participant {
+    /// The leg's id, which its handshakes are signed for.
+    id: Uuid,
+    lease: Lease,
+    key: binding::Key,
+    /// G.711 flavour negotiated with the participant.
+    payload: AtomicU8,
+    /// Where the participant's audio goes: the address its `HELLO` came
+    /// from, or else its offer's, then wherever its RTP latched to.
+    remote: RwLock<Option<SocketAddr>>,
+    latch: Mutex<Latch>,
+    muted: AtomicBool,
+    /// Decoded audio waiting for the mixer.
+    frames: Mutex<VecDeque<Frame>>,
+    sender: Mutex<rtp::Sender>,
+    receiver: Mutex<Option<AbortHandle>>,
+}
+
+impl Conference {
+    pub fn new(id: Uuid, ports: Arc<PortPool>) -> Arc<Conference> {
+        let conference = Arc::new(Conference {
+            id,
+            ports,
+            participants: RwLock::new(HashMap::new()),
+            mixer: Mutex::new(None),
+        });
+        let weak = Arc::downgrade(&conference);
+        let mixer = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(MIX_INTERVAL);
+            loop {
+                ticks.tick().await;
+                let Some(conference) = weak.upgrade() else {
+                    break;
+                };
+                conference.mix().await;
+            }
+        });
+        *conference.mixer.lock().expect("mixer lock") = Some(mixer.abort_handle());
+        conference
+    }
+
+    /// Add a participant, or renegotiate with one already present, and
+    /// return the answer to its offer. The answer points at `public_ip`.
+    pub async fn join(
+        &self,
+        participant_id: Uuid,
+        offer: &SessionDescription,
+        public_ip: IpAddr,
+    ) -> Result<Joined, JoinError> {
+        let existing = self.participants.read().await.get(&participant_id).cloned();
+        let participant = match existing {
+            Some(participant) => participant,
+            None => {
+                let participant = Participant::bind(&self.ports, participant_id).await?;
+                self.participants
+                    .write()
+                    .await
+                    .insert(participant_id, participant.clone());
+                participant
+            }
+        };
+        let joined = participant.negotiate(offer, public_ip).await;
+        if joined.is_err() {
+            self.leave(participant_id).await;
+        }
+        joined
+    }
+
+    /// Mute or unmute a participant; `false` when it is not in the room.
+    pub async fn set_muted(&self, participant_id: Uuid, muted: bool) -> bool {
+        match self.participants.read().await.get(&participant_id) {
+            Some(participant) => {
+                participant.muted.store(muted, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop a participant and release its port; `false` when it is not in
+    /// the room.
+    pub async fn leave(&self, participant_id: Uuid) -> bool {
+        match self.participants.write().await.remove(&participant_id) {
+            Some(participant) => {
+                participant.close();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.participants.read().await.is_empty()
+    }
+
+    /// Stop mixing and release every participant's port.
+    pub async fn close(&self) {
+        if let Some(mixer) = self.mixer.lock().expect("mixer lock").take() {
+            mixer.abort();
+        }
+        for (_, participant) in self.participants.write().await.drain() {
+            participant.close();
+        }
+    }
+
+    /// One mixing round: everyone hears the sum of everybody else.
+    async fn mix(&self) {
+        let participants: Vec<Arc<Participant>> =
+            self.participants.read().await.values().cloned().collect();
+        if participants.is_empty() {
+            return;
+        }
+        let frames: Vec<Option<Frame>> = participants.iter().map(|p| p.contribution()).collect();
+        for (participant, others) in participants.iter().zip(mix_minus(&frames)) {
+            participant.send(&others).await;
+        }
+    }
+}
+
+/// What the sender of each of `frames` hears: the sum of all the others.
+pub(crate) fn mix_minus(frames: &[Option<Frame>]) -> Vec<Mix> {
+    let mut total = [0i32; g711::FRAME_SAMPLES];
+    for frame in frames.iter().flatten() {
+        add(&mut total, frame, 1);
+    }
+    frames
+        .iter()
+        .map(|own| {
+            let mut others = total;
+            if let Some(own) = own {
+                add(&mut others, own, -1);
+            }
+            others
+        })
+        .collect()
+}
+
+impl Participant {
+    pub(crate) async fn bind(
+        ports: &Arc<PortPool>,
+        id: Uuid,
+    ) -> Result<Arc<Participant>, LeaseError> {
+        // The mixer has no use for RTCP, so only the RTP port is bound.
+        let participant = Arc::new(Participant {
+            id,
+            lease: ports.lease(false).await?,
+            key: binding::Key::generate(),
+            payload: AtomicU8::new(g711::PCMU),
+            remote: RwLock::new(None),
+            latch: Mutex::new(Latch::default()),
+            muted: AtomicBool::new(false),
+            frames: Mutex::new(VecDeque::new()),
+            sender: Mutex::new(rtp::Sender::new(
+                g711::PCMU,
+                Uuid::new_v4().as_u128() as u32,
+            )),
+            receiver: Mutex::new(None),
+        });
+        let weak = Arc::downgrade(&participant);
+        let socket = participant.lease.rtp.clone();
+        let receiver = tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            loop {
+                let (n, from) = match socket.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(err) => {
+                        tracing::warn!(error = %err, "conference recv failed");
+                        break;
+                    }
+                };
+                let Some(participant) = weak.upgrade() else {
+                    break;
+                };
+                participant.receive(&buf[..n], from).await;
+            }
+        });
+        *participant.receiver.lock().expect("receiver lock") = Some(receiver.abort_handle());
+        Ok(participant)
+    }
+
+    /// Answer the participant's `offer` with this port and a G.711 codec.
+    pub(crate) async fn negotiate(
+        &self,
+        offer: &SessionDescription,
+        public_ip: IpAddr,
+    ) -> Result<Joined, JoinError> {
+        let local = LocalMedia {
+            origin: Origin::new(self.id.as_u64_pair().0 >> 1, public_ip),
+            connection: Connection::from(public_ip),
+            port: self.lease.port,
+            codecs: [g711::PCMU, g711::PCMA]
+                .into_iter()
+                .filter_map(Codec::static_payload)
+                .collect(),
+            passthrough: Vec::new(),
+            direction: Direction::SendRecv,
+        };
+        let answer = sdp::answer(offer, &local);
+        let audio = answer
+            .media
+            .iter()
+            .position(|m| m.kind == "audio" && !m.is_rejected());
+        let payload = audio
+            .and_then(|index| answer.media[index].formats.first())
+            .and_then(|format| format.parse().ok());
+        let (Some(index), Some(payload)) = (audio, payload) else {
+            return Err(JoinError::NoCodec);
+        };
+        self.payload.store(payload, Ordering::Relaxed);
+        self.sender
+            .lock()
+            .expect("sender lock")
+            .set_payload_type(payload);
+        self.latch
+            .lock()
+            .expect("latch lock")
+            .expect(offer.media[index].ssrc());
+        let offered = offer.connection_of(index).and_then(|connection| {
+            let ip = connection.address.split('/').next()?.parse().ok()?;
+            Some(SocketAddr::new(ip, offer.media[index].port))
+        });
+        let mut remote = self.remote.write().await;
+        if remote.is_none() {
+            *remote = offered;
+        }
+        Ok(Joined {
+            answer,
+            secret: self.key.secret(),
+            port: self.lease.port,
+        })
+    }
+
+    async fn receive(&self, packet: &[u8], from: SocketAddr) {
+        if let Some(hello) = Hello::parse(packet) {
+            self.bind_address(hello, from).await;
+            return;
+        }
+        let Some((header, offset)) = rtp::Header::parse(packet) else {
+            return;
+        };
+        {
+            let mut remote = self.remote.write().await;
+            // Nothing is taken before the handshake or an offer with an address.
+            let Some(bound) = *remote else {
+                return;
+            };
+            let mut latch = self.latch.lock().expect("latch lock");
+            if from == bound {
+                latch.heard(&header, Instant::now());
+            } else if latch.claims(bound, from, &header, false, Instant::now()) {
+                *remote = Some(from);
+                tracing::info!(leg_id = %self.id, %from, previous = %bound, ssrc = header.ssrc, "mixer leg latched");
+            } else {
+                return;
+            }
+        }
+        queue_frames(&self.frames, header.payload_type, &packet[offset..]);
+    }
+
+    /// Bind the leg to `from` if the handshake is genuine. A leg binds as
+    /// side `a` of the session named by its id.
+    async fn bind_address(&self, hello: Result<Hello, BindError>, from: SocketAddr) {
+        let bound = match hello {
+            Ok(hello) if hello.side != Side::A => Err(BindError::Mismatch),
+            Ok(hello) => {
+                let mut remote = self.remote.write().await;
+                let rebind = remote.is_some_and(|current| current != from);
+                self.key
+                    .verify(self.id, &hello, rebind)
+                    .map(|()| remote.replace(from))
+            }
+            Err(err) => Err(err),
+        };
+        match bound {
+            Ok(previous) if previous == Some(from) => {}
+            Ok(previous) => {
+                self.latch.lock().expect("latch lock").reset();
+                tracing::info!(leg_id = %self.id, %from, ?previous, "mixer leg bound");
+            }
+            Err(err) => {
+                tracing::warn!(leg_id = %self.id, %from, error = %err, "mixer leg handshake rejected");
+            }
+        }
+    }
+
+    /// What the participant adds to this round's mix: its oldest frame,
+    /// which is used up even while it is muted.
+    fn contribution(&self) -> Option<Frame> {
+        self.next_frame()
+            .filter(|_| !self.muted.load(Ordering::Relaxed))
+    }
+
+    /// The oldest frame the participant sent that was not mixed yet.
+    pub(crate) fn next_frame(&self) -> Option<Frame> {
+        self.frames.lock().expect("frame lock").pop_front()
+    }
+
+    /// Send the participant 20 ms of summed audio in its own codec.
+    pub(crate) async fn send(&self, mix: &Mix) {
+        let Some(to) = *self.remote.read().await else {
+            return;
+        };
+        let audio = encode_mix(self.payload.load(Ordering::Relaxed), mix);
+        let packet = self
+            .sender
+            .lock()
+            .expect("sender lock")
+            .packet(&audio, g711::FRAME_SAMPLES as u32);
+        // A lost packet is replaced by the next round's.
+        let _ = self.lease.rtp.send_to(&packet, to).await;
+    }
+
+    pub(crate) fn close(&self) {
+        if let Some(receiver) = self.receiver.lock().expect("receiver lock").take() {
+            receiver.abort();
+        }
+    }
+}
+
+/// Add (`sign` 1) or take out (`sign` -1) one voice of a mix.
+pub(crate) fn add(mix: &mut Mix, frame: &Frame, sign: i32) {
+    for (sum, sample) in mix.iter_mut().zip(frame) {
+        *sum += sign * i32::from(*sample);
+    }
+}
+
+/// Decode G.711 `payload` into frames at the back of `frames`, dropping the
+/// oldest once a sender has run too far ahead of the mixer.
+pub(crate) fn queue_frames(frames: &Mutex<VecDeque<Frame>>, payload_type: u8, payload: &[u8]) {
+    if !matches!(payload_type, g711::PCMU | g711::PCMA) {
+        // Comfort noise, DTMF events and the like are not mixed.
+        return;
+    }
+    let mut frames = frames.lock().expect("frame lock");
+    for chunk in payload.chunks(g711::FRAME_SAMPLES) {
+        let mut frame = [0i16; g711::FRAME_SAMPLES];
+        for (sample, byte) in frame.iter_mut().zip(chunk) {
+            *sample = g711::decode(payload_type, *byte);
+        }
+        if frames.len() == MAX_QUEUED_FRAMES {
+            frames.pop_front();
+        }
+        frames.push_back(frame);
+    }
+}
+
+/// Encode a mix, clipping where voices add up past full scale.
+pub(crate) fn encode_mix(payload: u8, mix: &Mix) -> [u8; g711::FRAME_SAMPLES] {
+    let mut audio = [0u8; g711::FRAME_SAMPLES];
+    for (byte, sum) in audio.iter_mut().zip(mix) {
+        *byte = g711::encode(
+            payload,
+            (*sum).clamp(i16::MIN.into(), i16::MAX.into()) as i16,
+        );
+    }
+    audio
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use std::net::Ipv4Addr;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SSRC: u32 = 0x1234_5678;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    /// A participant on a port of its own, joined with `offer`.
+    async fn participant(ports: std::ops::RangeInclusive<u16>, offer: &str) -> Arc<Participant> {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let pool = PortPool::new(localhost, ports, None, Duration::ZERO).unwrap();
+        let participant = Participant::bind(&pool, Uuid::new_v4()).await.unwrap();
+        let offer = offer.parse::<SessionDescription>().unwrap();
+        participant.negotiate(&offer, localhost).await.unwrap();
+        participant
+    }
+
+    fn offer(address: SocketAddr, extra: &str) -> String {
+        let ip = address.ip();
+        format!(
+            "v=0\r\no=- 1 1 IN IP4 {ip}\r\ns=-\r\nc=IN IP4 {ip}\r\nt=0 0\r\n\
+             m=audio {} RTP/AVP 0\r\n{extra}",
+            address.port()
+        )
+    }
+
+    /// A `HELLO` for leg `leg_id` signed with `secret`, as an endpoint
+    /// builds it.
+    fn hello(secret: &str, leg_id: Uuid) -> Vec<u8> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&hex::decode(secret).unwrap()).unwrap();
+        mac.update(format!("{leg_id}:a:{timestamp}").as_bytes());
+        let mac = hex::encode(mac.finalize().into_bytes());
+        format!("HELLO a {timestamp} {mac}").into_bytes()
+    }
+
+    fn rtp(ssrc: u32, sequence: u16) -> Vec<u8> {
+        let header = rtp::Header {
+            marker: false,
+            payload_type: g711::PCMU,
+            sequence,
+            timestamp: u32::from(sequence) * 160,
+            ssrc,
+        };
+        header.packet(&[0xff; g711::FRAME_SAMPLES])
+    }
+
+    /// A frame of `sample` throughout.
+    fn frame(sample: i16) -> Frame {
+        [sample; g711::FRAME_SAMPLES]
+    }
+
+    #[test]
+    fn everyone_hears_the_others_but_not_themselves() {
+        let mixes = mix_minus(&[Some(frame(100)), Some(frame(-30)), None, Some(frame(7))]);
+        let heard: Vec<i32> = mixes.iter().map(|mix| mix[0]).collect();
+        assert_eq!(heard, [-23, 107, 77, 70]);
+        assert!(mixes.iter().all(|mix| mix.iter().all(|sum| *sum == mix[0])));
+        assert_eq!(mix_minus(&[Some(frame(100))])[0], [0; g711::FRAME_SAMPLES]);
+    }
+
+    #[tokio::test]
+    async fn muted_participants_are_left_out_without_falling_behind() {
+        let participant = participant(48020..=48029, &offer(addr("198.51.100.7:4000"), "")).await;
+        let voice = [g711::encode(g711::PCMU, 1000); g711::FRAME_SAMPLES];
+        queue_frames(&participant.frames, g711::PCMU, &voice);
+        queue_frames(&participant.frames, g711::PCMU, &voice);
+        assert!(participant.contribution().is_some());
+        participant.muted.store(true, Ordering::Relaxed);
+        assert!(participant.contribution().is_none());
+        // The frame sent while muted is gone, not saved for later.
+        participant.muted.store(false, Ordering::Relaxed);
+        assert!(participant.contribution().is_none());
+    }
+
+    #[test]
+    fn fast_senders_lose_their_oldest_frames() {
+        let frames = Mutex::new(VecDeque::new());
+        for sample in 0..MAX_QUEUED_FRAMES as i16 + 2 {
+            let payload = [g711::encode(g711::PCMA, sample * 1000); g711::FRAME_SAMPLES];
+            queue_frames(&frames, g711::PCMA, &payload);
+        }
+        let frames = frames.into_inner().unwrap();
+        assert_eq!(frames.len(), MAX_QUEUED_FRAMES);
+        assert_eq!(
+            frames[0][0],
+            g711::decode(g711::PCMA, g711::encode(g711::PCMA, 2000))
+        );
+    }
+
+    #[test]
+    fn packets_split_into_frames_and_only_g711_is_queued() {
+        let frames = Mutex::new(VecDeque::new());
+        // 40 ms in one packet.
+        queue_frames(&frames, g711::PCMU, &[0xFF; 2 * g711::FRAME_SAMPLES]);
+        assert_eq!(frames.lock().unwrap().len(), 2);
+        // Telephone events and comfort noise.
+        queue_frames(&frames, 101, &[0; 4]);
+        queue_frames(&frames, 13, &[0; 1]);
+        assert_eq!(frames.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn loud_mixes_clip_at_full_scale() {
+        let mut mix: Mix = [0; g711::FRAME_SAMPLES];
+        add(&mut mix, &frame(30_000), 1);
+        add(&mut mix, &frame(30_000), 1);
+        mix[1] = -60_000;
+        for payload in [g711::PCMU, g711::PCMA] {
+            let audio = encode_mix(payload, &mix);
+            assert_eq!(audio[0], g711::encode(payload, i16::MAX));
+            assert_eq!(audio[1], g711::encode(payload, i16::MIN));
+        }
+    }
+
+    #[tokio::test]
+    async fn participants_bind_with_their_secret() {
+        // Behind a NAT: the offer names an address nothing comes from.
+        let phone = addr("198.51.100.7:4000");
+        let participant = participant(48000..=48009, &offer(addr("10.0.0.2:5000"), "")).await;
+        participant.receive(&rtp(SSRC, 1), phone).await;
+        assert!(participant.next_frame().is_none());
+
+        let forged = hello(&binding::Key::generate().secret(), participant.id);
+        participant.receive(&forged, phone).await;
+        assert_eq!(
+            *participant.remote.read().await,
+            Some(addr("10.0.0.2:5000"))
+        );
+
+        let genuine = hello(&participant.key.secret(), participant.id);
+        participant.receive(&genuine, phone).await;
+        assert_eq!(*participant.remote.read().await, Some(phone));
+        participant.receive(&rtp(SSRC, 2), phone).await;
+        assert!(participant.next_frame().is_some());
+        // The handshake cannot be replayed from elsewhere.
+        participant
+            .receive(&genuine, addr("203.0.113.9:4000"))
+            .await;
+        assert_eq!(*participant.remote.read().await, Some(phone));
+    }
+
+    #[tokio::test]
+    async fn without_a_handshake_only_the_offered_host_latches() {
+        let phone = addr("198.51.100.7:4000");
+        let offer = offer(phone, &format!("a=ssrc:{SSRC} cname:phone\r\n"));
+        let participant = participant(48010..=48019, &offer).await;
+        // Another host, or the phone's host with another stream.
+        participant
+            .receive(&rtp(SSRC, 1), addr("203.0.113.9:4000"))
+            .await;
+        participant
+            .receive(&rtp(SSRC + 1, 1), addr("198.51.100.7:4002"))
+            .await;
+        assert!(participant.next_frame().is_none());
+        assert_eq!(*participant.remote.read().await, Some(phone));
+
+        // Its announced stream from another port of its host latches.
+        let from = addr("198.51.100.7:4002");
+        participant.receive(&rtp(SSRC, 1), from).await;
+        assert_eq!(*participant.remote.read().await, Some(from));
+        assert!(participant.next_frame().is_some());
+    }
+}
@@ -0,0 +1,73 @@
+//! Relay tear-down events for signaling.
+//!
+//! Whenever a relay closes, signaling is told why. For a relay that still
+//! carried a signaling call this lets it end the call, rather than leave the
+//! parties listening to silence. The event carries the final statistics of
+//! the relay's RTP streams as well. Events are best effort: one that cannot be
+//! delivered is logged and dropped. Each carries `MEDIA_EVENTS_SECRET` as a
+//! bearer token, which signaling requires.
+
+use serde::Serialize;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Why a relay was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Released through `DELETE /alloc/:session_id`.
+    Released,
+    /// Carried no packets for the idle timeout.
+    Idle,
+    /// Reached the maximum session lifetime.
+    MaxLifetime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayClosed {
+    pub session_id: Uuid,
+    /// The signaling call the relay was carrying, unless another relay has
+    /// taken over the call since.
+    pub call_id: Option<Uuid>,
+    pub reason: CloseReason,
+    /// Final quality statistics of the relay's RTP streams.
+    pub streams: Vec<crate::stats::StreamStats>,
+}
+
+pub struct Events {
+    http: reqwest::Client,
+    base_url: String,
+    secret: Option<String>,
+}
+
+impl Events {
+    pub fn from_env() -> Self {
+        let base_url =
+            std::env::var("SIGNALING_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
+        Events {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(3))
+                .build()
+                .expect("static http client config"),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            secret: std::env::var("MEDIA_EVENTS_SECRET").ok(),
+        }
+    }
+
+    /// Report a closed relay, off the caller's path.
+    pub fn relay_closed(&self, event: RelayClosed) {
+        let mut request = self
+            .http
+            .post(format!("{}/media/relay-closed", self.base_url))
+            .json(&event);
+        if let Some(secret) = &self.secret {
+            request = request.bearer_auth(secret);
+        }
+        tokio::spawn(async move {
+            let sent = request.send().await.and_then(|res| res.error_for_status());
+            if let Err(err) = sent {
+                tracing::warn!(session_id = %event.session_id, error = %err, "failed to report closed relay");
+            }
+        });
+    }
+}
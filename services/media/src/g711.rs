@@ -0,0 +1,132 @@
+//! G.711 companding (ITU-T G.711), the only codecs the media service
+//! decodes or generates itself.
+
+/// Payload types of G.711 (RFC 3551).
+pub const PCMU: u8 = 0;
+pub const PCMA: u8 = 8;
+
+/// Samples in 20 ms at 8 kHz.
+pub const FRAME_SAMPLES: usize = 160;
+
+pub fn encode(payload: u8, sample: i16) -> u8 {
+    match payload {
+        PCMA => alaw(sample),
+        _ => ulaw(sample),
+    }
+}
+
+pub fn decode(payload: u8, byte: u8) -> i16 {
+    match payload {
+        PCMA => alaw_decode(byte),
+        _ => ulaw_decode(byte),
+    }
+}
+
+/// Linear PCM to mu-law.
+fn ulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+    let mut value = i32::from(sample);
+    let sign = if value < 0 {
+        value = -value;
+        0x80
+    } else {
+        0
+    };
+    value = value.min(CLIP) + BIAS;
+    let exponent = (7 - (value >> 7).leading_zeros().saturating_sub(24)).min(7) as i32;
+    let mantissa = (value >> (exponent + 3)) & 0x0F;
+    !(sign | (exponent << 4) as u8 | mantissa as u8)
+}
+
+fn ulaw_decode(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = i32::from(byte & 0x0F);
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    if byte & 0x80 != 0 {
+        -magnitude as i16
+    } else {
+        magnitude as i16
+    }
+}
+
+/// Linear PCM to A-law.
+fn alaw(sample: i16) -> u8 {
+    let value = i32::from(sample) >> 3;
+    let (sign, value) = if value >= 0 {
+        (0x80, value)
+    } else {
+        (0, -value - 1)
+    };
+    let encoded = if value < 32 {
+        (value >> 1) as u8
+    } else {
+        let exponent = (31 - (value as u32).leading_zeros())
+            .saturating_sub(4)
+            .min(7);
+        let mantissa = (value >> exponent) & 0x0F;
+        ((exponent << 4) as u8) | mantissa as u8
+    };
+    (sign | encoded) ^ 0x55
+}
+
+fn alaw_decode(byte: u8) -> i16 {
+    let byte = byte ^ 0x55;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = i32::from(byte & 0x0F);
+    let magnitude = match exponent {
+        0 => (mantissa << 4) + 8,
+        _ => ((mantissa << 4) + 0x108) << (exponent - 1),
+    };
+    if byte & 0x80 != 0 {
+        magnitude as i16
+    } else {
+        -magnitude as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_code_survives_a_round_trip() {
+        for payload in [PCMU, PCMA] {
+            for byte in 0..=u8::MAX {
+                // Mu-law's negative zero comes back as the positive one.
+                if payload == PCMU && byte == 0x7F {
+                    continue;
+                }
+                assert_eq!(
+                    encode(payload, decode(payload, byte)),
+                    byte,
+                    "{payload} {byte:#04x}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn samples_come_back_within_a_quantization_step() {
+        for payload in [PCMU, PCMA] {
+            for sample in (i16::MIN..=i16::MAX).step_by(7) {
+                let back = decode(payload, encode(payload, sample));
+                let error = (i32::from(back) - i32::from(sample)).abs();
+                // Steps grow with the magnitude: 4 bits of mantissa per segment.
+                let step = (i32::from(sample).abs() >> 4).max(16);
+                assert!(error <= step, "{payload} {sample} -> {back}");
+                assert!(
+                    back == 0 || (back < 0) == (sample < 0),
+                    "{payload} {sample} -> {back}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn silence_encodes_to_the_idle_codes() {
+        assert_eq!(encode(PCMU, 0), 0xFF);
+        assert_eq!(encode(PCMA, 0), 0xD5);
+    }
+}
@@ -0,0 +1,263 @@
+//! Symmetric RTP latching for relay sides.
+//!
+//! A side is bound by its `HELLO` (see [`crate::binding`]), but what counts
+//! is where its RTP comes from. The first valid RTP after binding latches the
+//! side there and pins the SSRC it sends. When the side's SDP announced its
+//! SSRC (`a=ssrc`), only RTP with that SSRC latches; otherwise the first one
+//! seen is taken. It must come from the bound address, or from another port on the same host as long as the other side
+//! is not waiting to latch on that host too: when both parties sit behind
+//! one NAT, neither may be told apart by host, so each must send from the
+//! address it bound. Media is then sent back to wherever the side sends
+//! from, which is what gets through NATs.
+//!
+//! When a mobile client's NAT mapping changes mid-call its RTP suddenly
+//! arrives from a new address. The side moves there once [`RELATCH_PACKETS`]
+//! in-sequence packets with the pinned SSRC have come from it while nothing
+//! came from the old address for [`RELATCH_QUIET`]. Packets that would have
+//! to guess the SSRC and sequence number, or that arrive while the side is
+//! still talking from its address, never move it.
+
+use crate::rtp::Header;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// In-sequence packets needed from a new address before moving a side.
+pub const RELATCH_PACKETS: u32 = 3;
+/// How long the old address must have been silent before moving a side.
+pub const RELATCH_QUIET: Duration = Duration::from_millis(250);
+/// How far ahead of the last sequence number a packet may be and still
+/// continue the stream (RFC 3550 appendix A.1).
+const MAX_DROPOUT: u16 = 3000;
+
+#[derive(Debug, Default)]
+pub struct Latch {
+    /// The SSRC the side's SDP announced, if it did.
+    expected: Option<u32>,
+    /// The SSRC the side sends, once latched.
+    ssrc: Option<u32>,
+    /// Sequence number and arrival of the side's last RTP from its address.
+    last: Option<(u16, Instant)>,
+    /// A new address the side seems to have moved to.
+    candidate: Option<Candidate>,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    from: SocketAddr,
+    sequence: u16,
+    packets: u32,
+}
+
+/// Whether `sequence` continues a stream last at `last`.
+fn continues(last: u16, sequence: u16) -> bool {
+    (1..=MAX_DROPOUT).contains(&sequence.wrapping_sub(last))
+}
+
+impl Latch {
+    /// The side was (re)bound by a handshake; latch afresh.
+    pub fn reset(&mut self) {
+        *self = Latch {
+            expected: self.expected,
+            ..Latch::default()
+        };
+    }
+
+    /// The side's SDP announced `ssrc`, or (re)negotiated without one.
+    pub fn expect(&mut self, ssrc: Option<u32>) {
+        self.expected = ssrc;
+    }
+
+    /// RTP from the side's current address, arrived at `now`.
+    pub fn heard(&mut self, header: &Header, now: Instant) {
+        // The address is the side's; a new SSRC there is a restarted stream.
+        self.ssrc = Some(header.ssrc);
+        self.last = Some((header.sequence, now));
+        self.candidate = None;
+    }
+
+    /// Whether RTP with `ssrc` from `from` could latch the side, bound at
+    /// `bound` but not latched yet.
+    pub fn could_latch(&self, bound: SocketAddr, from: SocketAddr, ssrc: u32) -> bool {
+        self.ssrc.is_none()
+            && from.ip() == bound.ip()
+            && self.expected.is_none_or(|expected| expected == ssrc)
+    }
+
+    /// RTP from `from`, which no side is on, while the side is at `bound`,
+    /// arrived at `now`. `contested` is set when the other side could latch
+    /// to `from` as well. `true` when the side should move to `from`.
+    pub fn claims(
+        &mut self,
+        bound: SocketAddr,
+        from: SocketAddr,
+        header: &Header,
+        contested: bool,
+        now: Instant,
+    ) -> bool {
+        let (Some(ssrc), Some((last, heard_at))) = (self.ssrc, self.last) else {
+            // Not latched yet: RTP from the bound host latches, unless it
+            // could as well be the other side's.
+            if !contested && self.could_latch(bound, from, header.ssrc) {
+                self.heard(header, now);
+                return true;
+            }
+            return false;
+        };
+        if header.ssrc != ssrc {
+            return false;
+        }
+        let (previous, packets) = match &self.candidate {
+            Some(candidate) if candidate.from == from => {
+                (candidate.sequence, candidate.packets + 1)
+            }
+            _ => (last, 1),
+        };
+        if !continues(previous, header.sequence) {
+            // The stream from there broke off; it has to start over.
+            if packets > 1 {
+                self.candidate = None;
+            }
+            return false;
+        }
+        self.candidate = Some(Candidate {
+            from,
+            sequence: header.sequence,
+            packets,
+        });
+        if packets < RELATCH_PACKETS || now.duration_since(heard_at) < RELATCH_QUIET {
+            return false;
+        }
+        self.heard(header, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SSRC: u32 = 0x1234_5678;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn rtp(ssrc: u32, sequence: u16) -> Header {
+        Header {
+            marker: false,
+            payload_type: 0,
+            sequence,
+            timestamp: u32::from(sequence) * 160,
+            ssrc,
+        }
+    }
+
+    /// A side bound at `bound` and latched there at `at` with `SSRC` at
+    /// sequence 10.
+    fn latched(bound: SocketAddr, at: Instant) -> Latch {
+        let mut latch = Latch::default();
+        assert!(latch.claims(bound, bound, &rtp(SSRC, 10), false, at));
+        latch
+    }
+
+    #[test]
+    fn first_rtp_from_the_bound_host_latches() {
+        let bound = addr("198.51.100.7:4000");
+        let now = Instant::now();
+        let mut latch = Latch::default();
+        assert!(!latch.claims(bound, addr("203.0.113.9:4000"), &rtp(SSRC, 1), false, now));
+        assert!(latch.claims(bound, addr("198.51.100.7:4002"), &rtp(SSRC, 1), false, now));
+        assert!(!latch.could_latch(bound, addr("198.51.100.7:4004"), SSRC));
+    }
+
+    #[test]
+    fn shared_host_only_latches_the_exact_address() {
+        // Both parties behind one NAT: the other side may be on that port.
+        let bound = addr("198.51.100.7:4000");
+        let now = Instant::now();
+        let mut latch = Latch::default();
+        assert!(!latch.claims(bound, addr("198.51.100.7:4002"), &rtp(SSRC, 1), true, now));
+        assert!(latch.could_latch(bound, addr("198.51.100.7:4002"), SSRC));
+    }
+
+    #[test]
+    fn announced_ssrc_is_required_to_latch() {
+        let bound = addr("198.51.100.7:4000");
+        let from = addr("198.51.100.7:4002");
+        let now = Instant::now();
+        let mut latch = Latch::default();
+        latch.expect(Some(SSRC));
+        assert!(!latch.could_latch(bound, from, SSRC + 1));
+        assert!(!latch.claims(bound, from, &rtp(SSRC + 1, 1), false, now));
+        // The announcement outlives a rebinding.
+        latch.reset();
+        assert!(!latch.claims(bound, from, &rtp(SSRC + 1, 2), false, now));
+        assert!(latch.claims(bound, from, &rtp(SSRC, 1), false, now));
+        // Renegotiated without one: any SSRC latches again.
+        latch.reset();
+        latch.expect(None);
+        assert!(latch.claims(bound, from, &rtp(SSRC + 1, 1), false, now));
+    }
+
+    #[test]
+    fn relatches_after_quiet_period() {
+        let bound = addr("198.51.100.7:4000");
+        let moved = addr("198.51.100.7:5000");
+        let start = Instant::now();
+        let mut latch = latched(bound, start);
+        // Still talking from its address: the new one never wins.
+        for sequence in 11..11 + RELATCH_PACKETS as u16 {
+            assert!(!latch.claims(bound, moved, &rtp(SSRC, sequence), false, start));
+        }
+        let later = start + RELATCH_QUIET;
+        assert!(latch.claims(bound, moved, &rtp(SSRC, 14), false, later));
+    }
+
+    #[test]
+    fn relatch_needs_enough_packets() {
+        let bound = addr("198.51.100.7:4000");
+        let moved = addr("203.0.113.9:6000");
+        let start = Instant::now();
+        let mut latch = latched(bound, start);
+        let later = start + RELATCH_QUIET;
+        for sequence in 11..10 + RELATCH_PACKETS as u16 {
+            assert!(!latch.claims(bound, moved, &rtp(SSRC, sequence), false, later));
+        }
+        let last = rtp(SSRC, 10 + RELATCH_PACKETS as u16);
+        assert!(latch.claims(bound, moved, &last, false, later));
+    }
+
+    #[test]
+    fn wrong_ssrc_never_moves_the_side() {
+        let bound = addr("198.51.100.7:4000");
+        let moved = addr("203.0.113.9:6000");
+        let start = Instant::now();
+        let mut latch = latched(bound, start);
+        let later = start + RELATCH_QUIET;
+        for sequence in 11..20 {
+            assert!(!latch.claims(bound, moved, &rtp(SSRC + 1, sequence), false, later));
+        }
+    }
+
+    #[test]
+    fn out_of_sequence_packets_start_over() {
+        let bound = addr("198.51.100.7:4000");
+        let moved = addr("203.0.113.9:6000");
+        let start = Instant::now();
+        let mut latch = latched(bound, start);
+        let later = start + RELATCH_QUIET;
+        let mut claims = |sequence| latch.claims(bound, moved, &rtp(SSRC, sequence), false, later);
+        // Behind the side's own stream, or too far ahead of it.
+        assert!(!claims(5));
+        assert!(!claims(10 + MAX_DROPOUT + 1));
+        // A run broken by a packet that does not continue it is discarded.
+        assert!(!claims(11));
+        assert!(!claims(12));
+        assert!(!claims(12));
+        assert!(latch.candidate.is_none());
+        let mut claims = |sequence| latch.claims(bound, moved, &rtp(SSRC, sequence), false, later);
+        assert!(!claims(13));
+        assert!(!claims(14));
+        assert!(claims(15));
+    }
+}
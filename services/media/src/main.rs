@@ -1,99 +1,202 @@
+mod binding;
+mod conference;
+mod events;
+mod g711;
+mod latch;
+mod moh;
+mod ports;
+mod rtp;
+mod stats;
+mod supervision;
+
 use axum::http::StatusCode;
 use axum::{
-    extract::State,
-    routing::{get, post},
+    extract::{Path, State},
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
-use serde::Serialize;
+use sdp::SessionDescription;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    net::{Ipv4Addr, SocketAddr},
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
 };
-use tokio::{net::UdpSocket, sync::RwLock};
+use tokio::{net::UdpSocket, sync::RwLock, task::AbortHandle};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid;
 
 /// Shared state for the media relay HTTP API.
 ///
-/// Each allocation creates a [`Relay`] (backed by a UDP socket) which is stored
-/// so subsequent HTTP calls can look it up for tear-down/inspection.
+/// Each allocation creates a [`Relay`] (backed by a port leased from the
+/// node's [`ports::PortPool`]) which is stored
+/// so subsequent HTTP calls can look it up for tear-down/inspection. Relays are
+/// closed when released, once idle for too long, or when they reach the
+/// maximum session lifetime; signaling hears about each through [`events`].
 #[derive(Clone)]
 struct AppState {
     relays: Arc<RwLock<HashMap<Uuid, Arc<Relay>>>>,
+    /// Relays allocated for a signaling call, by call id. Side A carries the
+    /// caller's media and side B the callee's.
+    calls: Arc<RwLock<HashMap<Uuid, Uuid>>>,
+    /// Conference mixers, by the conference id signaling chose.
+    conferences: Arc<RwLock<HashMap<Uuid, Arc<conference::Conference>>>>,
+    /// Address endpoints reach the relays on, written into rewritten SDP.
+    public_ip: IpAddr,
+    /// Ports relays, conference participants and supervisors are bound to.
+    ports: Arc<ports::PortPool>,
+    events: Arc<events::Events>,
 }
 
+/// How often relays are checked for idleness and age.
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(5);
+
 struct Relay {
     id: Uuid,
-    socket: Arc<UdpSocket>,
+    /// The relay's RTP port, and its RTCP port for endpoints that do not
+    /// multiplex RTCP, given back to the pool once the relay is dropped.
+    lease: ports::Lease,
+    /// Secrets endpoints prove they own side A or B with.
+    credentials: binding::Credentials,
+    /// Where side A's and B's media is sent: the address it latched to.
     side_a: Arc<RwLock<Option<SocketAddr>>>,
     side_b: Arc<RwLock<Option<SocketAddr>>>,
+    /// What sides A and B latched to, and whether they are moving.
+    latches: [Mutex<latch::Latch>; 2],
+    /// Music on hold playing to side A or B while that side is held.
+    hold_a: RwLock<Option<AbortHandle>>,
+    hold_b: RwLock<Option<AbortHandle>>,
+    /// G.711 flavour music on hold is encoded in.
+    moh_payload: u8,
+    /// A supervisor listening in, or talking, on the call.
+    supervision: RwLock<Option<Arc<supervision::Supervision>>>,
+    /// Signaling call the relay was allocated for.
+    call_id: Option<Uuid>,
+    /// Quality of the RTP streams each side sends.
+    stats: stats::Stats,
+    created: Instant,
+    /// Milliseconds after `created` the last packet arrived.
+    last_packet: AtomicU64,
+    /// Forwarding loops for the RTP port and, when bound, the RTCP port.
+    receivers: Mutex<Vec<AbortHandle>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Side {
+    A,
+    B,
+}
+
+impl Side {
+    fn index(self) -> usize {
+        match self {
+            Side::A => 0,
+            Side::B => 1,
+        }
+    }
+
+    fn other(self) -> Side {
+        match self {
+            Side::A => Side::B,
+            Side::B => Side::A,
+        }
+    }
 }
 
 impl Relay {
-    /// Allocate a brand new UDP relay and spawn the forwarding loop.
+    /// Start a UDP relay on a leased port pair and spawn the forwarding loops.
     ///
-    /// We bind an ephemeral port, keep track of which endpoint is "side A" or
-    /// "side B" based on a lightweight `HELLO` handshake, then mirror RTP/SRTP
+    /// We keep track of which endpoint is "side A" or
+    /// "side B" based on an authenticated `HELLO` handshake (see [`binding`]),
+    /// then mirror RTP/SRTP
     /// datagrams between both sides.  The `tokio::spawn` keeps the hot packet
     /// loop off the HTTP executor.
-    async fn new() -> anyhow::Result<(Arc<Relay>, u16)> {
-        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
-        let local_port = socket.local_addr()?.port();
-        let socket = Arc::new(socket);
+    fn new(
+        lease: ports::Lease,
+        moh_payload: u8,
+        stats: stats::Stats,
+        call_id: Option<Uuid>,
+    ) -> Arc<Relay> {
+        let socket = lease.rtp.clone();
+        let rtcp = lease.rtcp.clone();
         let relay = Arc::new(Relay {
             id: Uuid::new_v4(),
-            socket: socket.clone(),
+            lease,
+            credentials: binding::Credentials::generate(),
             side_a: Arc::new(RwLock::new(None)),
             side_b: Arc::new(RwLock::new(None)),
+            latches: Default::default(),
+            hold_a: RwLock::new(None),
+            hold_b: RwLock::new(None),
+            moh_payload,
+            supervision: RwLock::new(None),
+            call_id,
+            stats,
+            created: Instant::now(),
+            last_packet: AtomicU64::new(0),
+            receivers: Mutex::new(Vec::new()),
         });
 
         // receive loop
-        let relay_clone = relay.clone();
-        tokio::spawn(async move {
+        let weak = Arc::downgrade(&relay);
+        let receiver = tokio::spawn(async move {
             let mut buf = vec![0u8; 2048];
             loop {
-                match relay_clone.socket.recv_from(&mut buf).await {
+                match socket.recv_from(&mut buf).await {
                     Ok((n, from)) => {
                         if n == 0 {
                             continue;
                         }
+                        let Some(relay_clone) = weak.upgrade() else {
+                            break;
+                        };
+                        relay_clone.last_packet.store(
+                            relay_clone.created.elapsed().as_millis() as u64,
+                            Ordering::Relaxed,
+                        );
 
-                        // On first packets from each side expect a small handshake: b"HELLO " + side ("a" or "b")
-                        // This avoids mis-routing stray RTP noise and mirrors the ICE nominated pair.
-                        if buf[..n].starts_with(b"HELLO ") {
-                            let side = &buf[6..n];
-                            if side == b"a" {
-                                let mut a = relay_clone.side_a.write().await;
-                                *a = Some(from);
-                                tracing::info!(%from, "relay side A bound");
-                            } else if side == b"b" {
-                                let mut b = relay_clone.side_b.write().await;
-                                *b = Some(from);
-                                tracing::info!(%from, "relay side B bound");
-                            }
+                        // On first packets from each side expect a signed handshake binding
+                        // the side to the sender. This avoids mis-routing stray RTP noise and
+                        // mirrors the ICE nominated pair.
+                        if let Some(hello) = binding::Hello::parse(&buf[..n]) {
+                            relay_clone.bind(hello, from).await;
                             continue;
                         }
 
-                        let is_a = {
-                            let a = relay_clone.side_a.read().await;
-                            a.map(|addr| addr == from).unwrap_or(false)
-                        };
+                        let header = rtp::Header::parse(&buf[..n]).map(|(header, _)| header);
+                        let side = relay_clone.source(header.as_ref(), from).await;
+                        if let (Some(side), Some(header)) = (side, &header) {
+                            relay_clone.stats.record(side, header, n);
+                        }
+                        let is_a = side == Some(Side::A);
+                        let is_b = side == Some(Side::B);
 
-                        let is_b = {
-                            let b = relay_clone.side_b.read().await;
-                            b.map(|addr| addr == from).unwrap_or(false)
-                        };
+                        // A held call carries music on hold only.
+                        if side.is_some() && relay_clone.on_hold().await {
+                            continue;
+                        }
+
+                        let supervision = relay_clone.supervision.read().await.clone();
+                        if let (Some(supervision), Some(side)) = (supervision, side) {
+                            supervision.hear(side, &buf[..n]);
+                            // The mixer talks to the other side instead.
+                            if supervision.mixes_to(side.other()) {
+                                continue;
+                            }
+                        }
 
                         // Forward traffic toward the opposite negotiated leg.
                         if is_a {
                             if let Some(to) = *relay_clone.side_b.read().await {
                                 // We ignore send errors here; the next inbound packet will retry.
-                                let _ = relay_clone.socket.send_to(&buf[..n], to).await;
+                                let _ = relay_clone.lease.rtp.send_to(&buf[..n], to).await;
                             }
                         } else if is_b {
                             if let Some(to) = *relay_clone.side_a.read().await {
-                                let _ = relay_clone.socket.send_to(&buf[..n], to).await;
+                                let _ = relay_clone.lease.rtp.send_to(&buf[..n], to).await;
                             }
                         } else {
                             // unknown sender; ignore until handshake is received
@@ -106,33 +209,722 @@ impl Relay {
                 }
             }
         });
+        let mut receivers = vec![receiver.abort_handle()];
+        if let Some(rtcp) = rtcp {
+            receivers.push(tokio::spawn(forward_rtcp(Arc::downgrade(&relay), rtcp)).abort_handle());
+        }
+        *relay.receivers.lock().expect("receiver lock") = receivers;
+
+        relay
+    }
+
+    /// Bind a side to `from` if the handshake is genuine.
+    async fn bind(&self, hello: Result<binding::Hello, binding::BindError>, from: SocketAddr) {
+        let bound = match hello {
+            Ok(hello) => {
+                let (peer, _) = self.side(hello.side);
+                let mut peer = peer.write().await;
+                let rebind = peer.is_some_and(|current| current != from);
+                self.credentials
+                    .verify(self.id, &hello, rebind)
+                    .map(|()| (hello.side, peer.replace(from)))
+            }
+            Err(err) => Err(err),
+        };
+        match bound {
+            Ok((_, previous)) if previous == Some(from) => {}
+            Ok((side, previous)) => {
+                self.latches[side.index()]
+                    .lock()
+                    .expect("latch lock")
+                    .reset();
+                tracing::info!(session_id = %self.id, ?side, %from, ?previous, "relay side bound");
+            }
+            Err(err) => {
+                tracing::warn!(session_id = %self.id, %from, error = %err, "relay handshake rejected");
+            }
+        }
+    }
+
+    /// The side a packet from `from` came from, latching or moving a side to
+    /// `from` when its RTP (`header`) shows up there.
+    async fn source(&self, header: Option<&rtp::Header>, from: SocketAddr) -> Option<Side> {
+        for side in [Side::A, Side::B] {
+            let (peer, _) = self.side(side);
+            if *peer.read().await == Some(from) {
+                if let Some(header) = header {
+                    let mut latch = self.latches[side.index()].lock().expect("latch lock");
+                    latch.heard(header, Instant::now());
+                }
+                return Some(side);
+            }
+        }
+        // Only RTP can move a side; anything else from elsewhere is noise.
+        let header = header?;
+        let mut could_latch = [false; 2];
+        for side in [Side::A, Side::B] {
+            let (peer, _) = self.side(side);
+            if let Some(bound) = *peer.read().await {
+                let latch = self.latches[side.index()].lock().expect("latch lock");
+                could_latch[side.index()] = latch.could_latch(bound, from, header.ssrc);
+            }
+        }
+        for side in [Side::A, Side::B] {
+            let (peer, _) = self.side(side);
+            let mut peer = peer.write().await;
+            let Some(bound) = *peer else {
+                continue;
+            };
+            let moved = self.latches[side.index()]
+                .lock()
+                .expect("latch lock")
+                .claims(
+                    bound,
+                    from,
+                    header,
+                    could_latch[side.other().index()],
+                    Instant::now(),
+                );
+            if moved {
+                *peer = Some(from);
+                tracing::info!(session_id = %self.id, ?side, %from, previous = %bound, ssrc = header.ssrc, "relay side latched");
+                return Some(side);
+            }
+        }
+        None
+    }
+
+    /// How long the relay has gone without receiving a packet, as of `now`.
+    fn idle_for(&self, now: Instant) -> Duration {
+        let last_packet = Duration::from_millis(self.last_packet.load(Ordering::Relaxed));
+        now.saturating_duration_since(self.created)
+            .saturating_sub(last_packet)
+    }
+
+    /// Stop forwarding, music on hold and supervision. The port is released
+    /// once the last handle on the relay is dropped.
+    async fn close(&self) {
+        for receiver in self.receivers.lock().expect("receiver lock").drain(..) {
+            receiver.abort();
+        }
+        for hold in [&self.hold_a, &self.hold_b] {
+            if let Some(task) = hold.write().await.take() {
+                task.abort();
+            }
+        }
+        if let Some(supervision) = self.supervision.write().await.take() {
+            supervision.close();
+        }
+    }
+
+    fn side(
+        &self,
+        side: Side,
+    ) -> (
+        &Arc<RwLock<Option<SocketAddr>>>,
+        &RwLock<Option<AbortHandle>>,
+    ) {
+        match side {
+            Side::A => (&self.side_a, &self.hold_a),
+            Side::B => (&self.side_b, &self.hold_b),
+        }
+    }
+
+    async fn on_hold(&self) -> bool {
+        self.hold_a.read().await.is_some() || self.hold_b.read().await.is_some()
+    }
+
+    /// Start or stop music on hold towards `side`. While either side is
+    /// held nothing is forwarded between them.
+    async fn set_hold(self: &Arc<Self>, side: Side, held: bool) {
+        let (peer, hold) = self.side(side);
+        let mut hold = hold.write().await;
+        if !held {
+            if let Some(task) = hold.take() {
+                task.abort();
+            }
+            return;
+        }
+        if hold.is_some() {
+            return;
+        }
+        let peer = peer.clone();
+        let socket = self.lease.rtp.clone();
+        let mut tone = moh::Tone::new(self.moh_payload, Uuid::new_v4().as_u128() as u32);
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(moh::PACKET_INTERVAL);
+            loop {
+                ticks.tick().await;
+                let packet = tone.next_packet();
+                // Until the held side has sent its HELLO there is nowhere to play to.
+                if let Some(to) = *peer.read().await {
+                    let _ = socket.send_to(&packet, to).await;
+                }
+            }
+        });
+        *hold = Some(task.abort_handle());
+    }
+}
+
+impl Relay {
+    /// Add a supervisor leg in `mode`, or renegotiate the one present, and
+    /// return the answer to the supervisor's offer with the leg's secret.
+    async fn supervise(
+        self: &Arc<Self>,
+        supervisor_id: Uuid,
+        agent: Side,
+        mode: supervision::Mode,
+        offer: &SessionDescription,
+        public_ip: IpAddr,
+        ports: &Arc<ports::PortPool>,
+    ) -> Result<conference::Joined, supervision::SuperviseError> {
+        let mut current = self.supervision.write().await;
+        if let Some(supervision) = current.as_ref() {
+            if supervision.supervisor_id != supervisor_id {
+                return Err(supervision::SuperviseError::Busy);
+            }
+            supervision.set_mode(mode);
+            return Ok(supervision.negotiate(offer, public_ip).await?);
+        }
+        let supervision =
+            supervision::Supervision::start(self, ports, supervisor_id, agent, mode).await?;
+        match supervision.negotiate(offer, public_ip).await {
+            Ok(joined) => {
+                *current = Some(supervision);
+                Ok(joined)
+            }
+            Err(err) => {
+                supervision.close();
+                Err(err.into())
+            }
+        }
+    }
+}
 
-        Ok((relay, local_port))
+/// Forward RTCP between the sides over the relay's RTCP port. An endpoint
+/// that does not multiplex RTCP sends it from, and expects it on, the port
+/// above its RTP port.
+async fn forward_rtcp(relay: Weak<Relay>, socket: Arc<UdpSocket>) {
+    let rtcp_of = |rtp: Option<SocketAddr>| {
+        rtp.and_then(|rtp| Some(SocketAddr::new(rtp.ip(), rtp.port().checked_add(1)?)))
+    };
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                tracing::warn!(error = %err, "rtcp recv failed");
+                break;
+            }
+        };
+        let Some(relay) = relay.upgrade() else {
+            break;
+        };
+        let a = rtcp_of(*relay.side_a.read().await);
+        let b = rtcp_of(*relay.side_b.read().await);
+        let to = if a == Some(from) {
+            b
+        } else if b == Some(from) {
+            a
+        } else {
+            None
+        };
+        if let Some(to) = to {
+            let _ = socket.send_to(&buf[..n], to).await;
+        }
     }
 }
 
+/// The G.711 codec to play music on hold in: the first the endpoint offers.
+fn moh_payload(sdp: Option<&SessionDescription>) -> u8 {
+    sdp.and_then(|sdp| sdp.media.iter().find(|m| m.kind == "audio"))
+        .and_then(|audio| {
+            audio
+                .codecs()
+                .into_iter()
+                .map(|codec| codec.payload)
+                .find(|payload| matches!(*payload, g711::PCMU | g711::PCMA))
+        })
+        .unwrap_or(g711::PCMU)
+}
+
+#[derive(Deserialize)]
+struct AllocRequest {
+    /// An endpoint's offer or answer to route through the relay.
+    #[serde(default)]
+    sdp: Option<String>,
+    /// Signaling call the relay carries, for hold control.
+    #[serde(default)]
+    call_id: Option<Uuid>,
+}
+
 #[derive(Serialize)]
 struct AllocResponse {
     session_id: Uuid,
+    /// Where endpoints send their media: the node's public address.
+    relay_ip: IpAddr,
     relay_port: u16,
+    /// Secret for each side's `HELLO` handshake. Each goes to the endpoint of
+    /// that side only.
+    secrets: SideSecrets,
+    /// The request's SDP with its media pointed at the relay.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sdp: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SideSecrets {
+    a: String,
+    b: String,
 }
 
 async fn alloc(
     State(state): State<AppState>,
-    Json(_): Json<serde_json::Value>,
-) -> Result<Json<AllocResponse>, StatusCode> {
+    Json(req): Json<AllocRequest>,
+) -> Result<Json<AllocResponse>, (StatusCode, &'static str)> {
+    let sdp = req
+        .sdp
+        .map(|sdp| sdp.parse::<SessionDescription>())
+        .transpose()
+        .map_err(|err| {
+            tracing::debug!(error = %err, "rejecting allocation with invalid sdp");
+            (StatusCode::UNPROCESSABLE_ENTITY, "invalid sdp")
+        })?;
+    // Endpoints that do not multiplex RTCP need the port above the RTP port.
+    let rtcp = sdp.as_ref().is_some_and(|sdp| {
+        sdp.media
+            .iter()
+            .any(|m| !m.is_rejected() && m.attribute("rtcp-mux").is_none())
+    });
+    let lease = state.ports.lease(rtcp).await.map_err(|err| {
+        tracing::warn!(error = %err, "relay allocation failed");
+        match err {
+            ports::LeaseError::Exhausted => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "relay capacity reached on this node",
+            ),
+            ports::LeaseError::Bind(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to bind a relay port",
+            ),
+        }
+    })?;
+    let port = lease.port;
     // RTP allocations are short lived, so we keep them in memory behind an RwLock.
-    let (relay, port) = Relay::new()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let relay = Relay::new(
+        lease,
+        moh_payload(sdp.as_ref()),
+        stats::Stats::new(sdp.as_ref()),
+        req.call_id,
+    );
     let id = relay.id;
+    let secrets = SideSecrets {
+        a: relay.credentials.secret(Side::A),
+        b: relay.credentials.secret(Side::B),
+    };
     state.relays.write().await.insert(id, relay);
+    if let Some(call_id) = req.call_id {
+        state.calls.write().await.insert(call_id, id);
+    }
+    let sdp = sdp.map(|mut sdp| {
+        sdp.rewrite_connection(state.public_ip, port);
+        sdp.to_string()
+    });
     Ok(Json(AllocResponse {
         session_id: id,
+        relay_ip: state.public_ip,
         relay_port: port,
+        secrets,
+        sdp,
     }))
 }
 
+/// Take a relay out of service, telling signaling why. `false` when there is
+/// no such relay (any more).
+async fn release(state: &AppState, session_id: Uuid, reason: events::CloseReason) -> bool {
+    let Some(relay) = state.relays.write().await.remove(&session_id) else {
+        return false;
+    };
+    // The call may have moved on to a relay allocated after this one.
+    let call_id = match relay.call_id {
+        Some(call_id) => {
+            let mut calls = state.calls.write().await;
+            let current = calls.get(&call_id) == Some(&session_id);
+            if current {
+                calls.remove(&call_id);
+            }
+            current.then_some(call_id)
+        }
+        None => None,
+    };
+    relay.close().await;
+    tracing::info!(%session_id, ?call_id, ?reason, "relay closed");
+    state.events.relay_closed(events::RelayClosed {
+        session_id,
+        call_id,
+        reason,
+        streams: relay.stats.snapshot(),
+    });
+    true
+}
+
+#[derive(Serialize)]
+struct RelayStatsResponse {
+    session_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    call_id: Option<Uuid>,
+    /// Seconds since the relay was allocated.
+    age_secs: u64,
+    streams: Vec<stats::StreamStats>,
+}
+
+/// Quality statistics of a relay's RTP streams so far.
+async fn relay_stats(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<RelayStatsResponse>, StatusCode> {
+    let relay = state
+        .relays
+        .read()
+        .await
+        .get(&session_id)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(RelayStatsResponse {
+        session_id,
+        call_id: relay.call_id,
+        age_secs: relay.created.elapsed().as_secs(),
+        streams: relay.stats.snapshot(),
+    }))
+}
+
+async fn release_alloc(State(state): State<AppState>, Path(session_id): Path<Uuid>) -> StatusCode {
+    if release(&state, session_id, events::CloseReason::Released).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Release the relay of a signaling call that ended.
+async fn release_call_relay(
+    State(state): State<AppState>,
+    Path(call_id): Path<Uuid>,
+) -> StatusCode {
+    let Some(session_id) = state.calls.read().await.get(&call_id).copied() else {
+        return StatusCode::NOT_FOUND;
+    };
+    if release(&state, session_id, events::CloseReason::Released).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Every [`RECLAIM_INTERVAL`], close the relays [`reclaim_expired`] finds.
+async fn reclaim(state: AppState, idle_timeout: Duration, max_lifetime: Duration) {
+    let mut ticks = tokio::time::interval(RECLAIM_INTERVAL);
+    loop {
+        ticks.tick().await;
+        reclaim_expired(&state, idle_timeout, max_lifetime, Instant::now()).await;
+    }
+}
+
+/// Close relays that received nothing for `idle_timeout`, unless playing
+/// music on hold, and any older than `max_lifetime`, as of `now`.
+async fn reclaim_expired(
+    state: &AppState,
+    idle_timeout: Duration,
+    max_lifetime: Duration,
+    now: Instant,
+) {
+    let relays: Vec<Arc<Relay>> = state.relays.read().await.values().cloned().collect();
+    for relay in relays {
+        let reason = if now.saturating_duration_since(relay.created) >= max_lifetime {
+            events::CloseReason::MaxLifetime
+        } else if relay.idle_for(now) >= idle_timeout && !relay.on_hold().await {
+            events::CloseReason::Idle
+        } else {
+            continue;
+        };
+        release(state, relay.id, reason).await;
+    }
+}
+
+#[derive(Deserialize)]
+struct HoldRequest {
+    /// The side being held, which hears music on hold.
+    side: Side,
+    held: bool,
+}
+
+/// Start or stop music on hold on the relay of a signaling call.
+async fn hold(
+    State(state): State<AppState>,
+    Path(call_id): Path<Uuid>,
+    Json(req): Json<HoldRequest>,
+) -> StatusCode {
+    let Some(relay) = call_relay(&state, call_id).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    tracing::info!(%call_id, side = ?req.side, held = req.held, "relay hold");
+    relay.set_hold(req.side, req.held).await;
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Deserialize)]
+struct SsrcRequest {
+    side: Side,
+    /// The SSRC the side's SDP announced; `None` when it announced none.
+    ssrc: Option<u32>,
+}
+
+/// Tell the relay of a signaling call which SSRC a side announced, so only
+/// RTP with that SSRC latches the side (see [`latch`]).
+async fn expect_ssrc(
+    State(state): State<AppState>,
+    Path(call_id): Path<Uuid>,
+    Json(req): Json<SsrcRequest>,
+) -> StatusCode {
+    let Some(relay) = call_relay(&state, call_id).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    tracing::debug!(%call_id, side = ?req.side, ssrc = ?req.ssrc, "relay side ssrc");
+    relay.latches[req.side.index()]
+        .lock()
+        .expect("latch lock")
+        .expect(req.ssrc);
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Deserialize)]
+struct SuperviseRequest {
+    supervisor_id: Uuid,
+    /// The supervisor's offer.
+    sdp: String,
+    mode: supervision::Mode,
+    /// The side the supervised agent is on.
+    agent: Side,
+}
+
+/// The answer to a mixer leg's offer, and how the leg binds its address:
+/// with a `HELLO` for side `a` of a session named by the leg's id (see
+/// [`binding`]).
+#[derive(Serialize)]
+struct LegResponse {
+    sdp: String,
+    relay_ip: IpAddr,
+    relay_port: u16,
+    /// The leg's `HELLO` secret, for its endpoint only.
+    secret: String,
+}
+
+impl LegResponse {
+    fn new(joined: conference::Joined, public_ip: IpAddr) -> Self {
+        LegResponse {
+            sdp: joined.answer.to_string(),
+            relay_ip: public_ip,
+            relay_port: joined.port,
+            secret: joined.secret,
+        }
+    }
+}
+
+async fn call_relay(state: &AppState, call_id: Uuid) -> Option<Arc<Relay>> {
+    let id = *state.calls.read().await.get(&call_id)?;
+    state.relays.read().await.get(&id).cloned()
+}
+
+/// Add a supervisor to the relay of a signaling call. Posting again for the
+/// same supervisor renegotiates its media and switches mode.
+async fn supervise(
+    State(state): State<AppState>,
+    Path(call_id): Path<Uuid>,
+    Json(req): Json<SuperviseRequest>,
+) -> Result<Json<LegResponse>, StatusCode> {
+    let relay = call_relay(&state, call_id)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let offer = req.sdp.parse::<SessionDescription>().map_err(|err| {
+        tracing::debug!(error = %err, "rejecting supervisor offer with invalid sdp");
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+    let joined = relay
+        .supervise(
+            req.supervisor_id,
+            req.agent,
+            req.mode,
+            &offer,
+            state.public_ip,
+            &state.ports,
+        )
+        .await
+        .map_err(|err| {
+            tracing::warn!(%call_id, error = %err, "supervisor join failed");
+            match err {
+                supervision::SuperviseError::Busy => StatusCode::CONFLICT,
+                supervision::SuperviseError::Join(conference::JoinError::NoCodec) => {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                }
+                supervision::SuperviseError::Join(conference::JoinError::Ports(
+                    ports::LeaseError::Exhausted,
+                )) => StatusCode::SERVICE_UNAVAILABLE,
+                supervision::SuperviseError::Join(conference::JoinError::Ports(
+                    ports::LeaseError::Bind(_),
+                )) => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        })?;
+    tracing::info!(%call_id, supervisor_id = %req.supervisor_id, mode = ?req.mode, "relay supervised");
+    Ok(Json(LegResponse::new(joined, state.public_ip)))
+}
+
+#[derive(Deserialize)]
+struct SupervisorModeRequest {
+    mode: supervision::Mode,
+}
+
+async fn set_supervisor_mode(
+    State(state): State<AppState>,
+    Path(call_id): Path<Uuid>,
+    Json(req): Json<SupervisorModeRequest>,
+) -> StatusCode {
+    let Some(relay) = call_relay(&state, call_id).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    let supervision = relay.supervision.read().await.clone();
+    match supervision {
+        Some(supervision) => {
+            supervision.set_mode(req.mode);
+            tracing::info!(%call_id, mode = ?req.mode, "supervisor mode");
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Drop the supervisor leg; the call goes back to plain forwarding.
+async fn end_supervision(State(state): State<AppState>, Path(call_id): Path<Uuid>) -> StatusCode {
+    let Some(relay) = call_relay(&state, call_id).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    let supervision = relay.supervision.write().await.take();
+    match supervision {
+        Some(supervision) => {
+            supervision.close();
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+#[derive(Deserialize)]
+struct JoinConferenceRequest {
+    participant_id: Uuid,
+    /// The participant's offer.
+    sdp: String,
+}
+
+/// Add a participant to a conference, opening the conference on first use.
+/// Posting again for the same participant renegotiates its media.
+async fn join_conference(
+    State(state): State<AppState>,
+    Path(conference_id): Path<Uuid>,
+    Json(req): Json<JoinConferenceRequest>,
+) -> Result<Json<LegResponse>, StatusCode> {
+    let offer = req.sdp.parse::<SessionDescription>().map_err(|err| {
+        tracing::debug!(error = %err, "rejecting conference offer with invalid sdp");
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+    let conference = state
+        .conferences
+        .write()
+        .await
+        .entry(conference_id)
+        .or_insert_with(|| conference::Conference::new(conference_id, state.ports.clone()))
+        .clone();
+    match conference
+        .join(req.participant_id, &offer, state.public_ip)
+        .await
+    {
+        Ok(joined) => {
+            tracing::info!(%conference_id, participant_id = %req.participant_id, "conference join");
+            Ok(Json(LegResponse::new(joined, state.public_ip)))
+        }
+        Err(err) => {
+            tracing::warn!(%conference_id, error = %err, "conference join failed");
+            close_if_empty(&state, &conference).await;
+            Err(match err {
+                conference::JoinError::NoCodec => StatusCode::UNPROCESSABLE_ENTITY,
+                conference::JoinError::Ports(ports::LeaseError::Exhausted) => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                conference::JoinError::Ports(ports::LeaseError::Bind(_)) => {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UpdateParticipantRequest {
+    muted: bool,
+}
+
+async fn update_participant(
+    State(state): State<AppState>,
+    Path((conference_id, participant_id)): Path<(Uuid, Uuid)>,
+    Json(req): Json<UpdateParticipantRequest>,
+) -> StatusCode {
+    let conference = state.conferences.read().await.get(&conference_id).cloned();
+    match conference {
+        Some(conference) if conference.set_muted(participant_id, req.muted).await => {
+            StatusCode::NO_CONTENT
+        }
+        _ => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Remove a participant; the conference closes with its last participant.
+async fn remove_participant(
+    State(state): State<AppState>,
+    Path((conference_id, participant_id)): Path<(Uuid, Uuid)>,
+) -> StatusCode {
+    let conference = state.conferences.read().await.get(&conference_id).cloned();
+    let Some(conference) = conference else {
+        return StatusCode::NOT_FOUND;
+    };
+    let removed = conference.leave(participant_id).await;
+    close_if_empty(&state, &conference).await;
+    if removed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn close_conference(
+    State(state): State<AppState>,
+    Path(conference_id): Path<Uuid>,
+) -> StatusCode {
+    match state.conferences.write().await.remove(&conference_id) {
+        Some(conference) => {
+            conference.close().await;
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn close_if_empty(state: &AppState, conference: &Arc<conference::Conference>) {
+    let mut conferences = state.conferences.write().await;
+    if conference.is_empty().await {
+        conferences.remove(&conference.id);
+        conference.close().await;
+        tracing::info!(conference_id = %conference.id, "conference closed");
+    }
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 struct IceServersResponse {
@@ -162,7 +954,9 @@ async fn ice_servers() -> Json<IceServersResponse> {
             "credential": credential
         }));
     }
-    Json(IceServersResponse { ice_servers: servers })
+    Json(IceServersResponse {
+        ice_servers: servers,
+    })
 }
 
 #[tokio::main]
@@ -177,20 +971,200 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // Relay ports come from RTP_PORT_MIN..=RTP_PORT_MAX on MEDIA_BIND_IP, at
+    // most MEDIA_CAPACITY at a time (see the `ports` module).
+    let ports = match ports::PortPool::from_env() {
+        Ok(ports) => ports,
+        Err(err) => {
+            tracing::error!(error = %err, "invalid RTP_PORT_MIN/RTP_PORT_MAX");
+            std::process::exit(1);
+        }
+    };
+    let relay_capacity = ports.available();
+    // Endpoints are pointed at MEDIA_PUBLIC_IP, or else the bind address.
+    let public_ip = std::env::var("MEDIA_PUBLIC_IP")
+        .ok()
+        .and_then(|ip| ip.parse().ok())
+        .or(Some(ports.bind_ip).filter(|ip| !ip.is_unspecified()))
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
     let state = AppState {
         relays: Arc::new(RwLock::new(HashMap::new())),
+        calls: Arc::new(RwLock::new(HashMap::new())),
+        conferences: Arc::new(RwLock::new(HashMap::new())),
+        public_ip,
+        ports,
+        events: Arc::new(events::Events::from_env()),
     };
 
+    // Relays nobody releases are reclaimed after RELAY_IDLE_TIMEOUT_SECS
+    // without a packet, and after RELAY_MAX_LIFETIME_SECS regardless.
+    let idle_timeout = Duration::from_secs(
+        std::env::var("RELAY_IDLE_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(60),
+    );
+    let max_lifetime = Duration::from_secs(
+        std::env::var("RELAY_MAX_LIFETIME_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(4 * 60 * 60),
+    );
+    tokio::spawn(reclaim(state.clone(), idle_timeout, max_lifetime));
+
     let app = Router::new()
         // REST endpoints consumed by the WebRTC layer for allocation + ICE details.
         .route("/health", get(|| async { "ok" }))
         .route("/alloc", post(alloc))
+        .route("/alloc/:session_id", get(relay_stats).delete(release_alloc))
+        .route("/calls/:call_id/hold", post(hold))
+        .route("/calls/:call_id/relay", delete(release_call_relay))
+        .route("/calls/:call_id/ssrc", put(expect_ssrc))
+        .route(
+            "/calls/:call_id/supervisor",
+            put(supervise)
+                .patch(set_supervisor_mode)
+                .delete(end_supervision),
+        )
+        // Conference mixers, driven by signaling.
+        .route("/conferences/:conference_id", delete(close_conference))
+        .route(
+            "/conferences/:conference_id/participants",
+            post(join_conference),
+        )
+        .route(
+            "/conferences/:conference_id/participants/:participant_id",
+            patch(update_participant).delete(remove_participant),
+        )
         .route("/ice", get(ice_servers))
         .with_state(state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8083));
-    tracing::info!(%addr, "media service starting (UDP relay + ICE config)");
+    tracing::info!(%addr, relay_capacity, "media service starting (UDP relay + ICE config)");
     axum::serve(tokio::net::TcpListener::bind(addr).await.unwrap(), app)
         .await
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::RangeInclusive;
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+    const MAX_LIFETIME: Duration = Duration::from_secs(4 * 60 * 60);
+    const QUARANTINE: Duration = Duration::from_millis(50);
+
+    fn state(ports: RangeInclusive<u16>) -> AppState {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        AppState {
+            relays: Arc::new(RwLock::new(HashMap::new())),
+            calls: Arc::new(RwLock::new(HashMap::new())),
+            conferences: Arc::new(RwLock::new(HashMap::new())),
+            public_ip: localhost,
+            ports: ports::PortPool::new(localhost, ports, None, QUARANTINE).unwrap(),
+            events: Arc::new(events::Events::from_env()),
+        }
+    }
+
+    /// Allocate a relay for `call_id`, returning its session id and when it
+    /// was created.
+    async fn allocate(state: &AppState, call_id: Uuid) -> (Uuid, Instant) {
+        let request = AllocRequest {
+            sdp: None,
+            call_id: Some(call_id),
+        };
+        let Json(res) = alloc(State(state.clone()), Json(request)).await.unwrap();
+        let created = state.relays.read().await[&res.session_id].created;
+        (res.session_id, created)
+    }
+
+    /// Lease a port pair once the quarantine of those given back is over.
+    async fn lease_after_quarantine(state: &AppState) -> Result<ports::Lease, ports::LeaseError> {
+        tokio::time::sleep(QUARANTINE + Duration::from_millis(20)).await;
+        state.ports.lease(false).await
+    }
+
+    #[tokio::test]
+    async fn a_released_relay_gives_its_ports_back_after_quarantine() {
+        let state = state(49000..=49001);
+        let call_id = Uuid::new_v4();
+        allocate(&state, call_id).await;
+        assert_eq!(state.ports.available(), 0);
+
+        let released = release_call_relay(State(state.clone()), Path(call_id)).await;
+        assert_eq!(released, StatusCode::NO_CONTENT);
+        assert!(state.relays.read().await.is_empty());
+        assert!(state.calls.read().await.is_empty());
+        assert_eq!(state.ports.available(), 1);
+        // Quarantined ports are not handed out again straight away.
+        assert!(matches!(
+            state.ports.lease(false).await,
+            Err(ports::LeaseError::Exhausted)
+        ));
+        assert!(lease_after_quarantine(&state).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn releasing_an_unknown_or_released_relay_changes_nothing() {
+        let state = state(49010..=49011);
+        let unknown = Uuid::new_v4();
+        let status = release_alloc(State(state.clone()), Path(unknown)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let status = release_call_relay(State(state.clone()), Path(unknown)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(state.ports.available(), 1);
+
+        let call_id = Uuid::new_v4();
+        let (session_id, _) = allocate(&state, call_id).await;
+        let status = release_alloc(State(state.clone()), Path(session_id)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let status = release_alloc(State(state.clone()), Path(session_id)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let status = release_call_relay(State(state.clone()), Path(call_id)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // The pair went back once: it is leased again, and only once.
+        assert_eq!(state.ports.available(), 1);
+        let _lease = lease_after_quarantine(&state).await.unwrap();
+        assert_eq!(state.ports.available(), 0);
+        assert!(matches!(
+            state.ports.lease(false).await,
+            Err(ports::LeaseError::Exhausted)
+        ));
+    }
+
+    #[tokio::test]
+    async fn idle_relays_are_reclaimed() {
+        let state = state(49020..=49021);
+        let (session_id, created) = allocate(&state, Uuid::new_v4()).await;
+
+        let almost = created + IDLE_TIMEOUT - Duration::from_secs(1);
+        reclaim_expired(&state, IDLE_TIMEOUT, MAX_LIFETIME, almost).await;
+        assert!(state.relays.read().await.contains_key(&session_id));
+
+        reclaim_expired(&state, IDLE_TIMEOUT, MAX_LIFETIME, created + IDLE_TIMEOUT).await;
+        assert!(state.relays.read().await.is_empty());
+        assert!(state.calls.read().await.is_empty());
+        assert!(lease_after_quarantine(&state).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn busy_relays_are_reclaimed_at_their_maximum_lifetime() {
+        let state = state(49030..=49031);
+        let (session_id, created) = allocate(&state, Uuid::new_v4()).await;
+        // The relay carries packets right up to the end.
+        let last_packet = MAX_LIFETIME - Duration::from_secs(1);
+        state.relays.read().await[&session_id]
+            .last_packet
+            .store(last_packet.as_millis() as u64, Ordering::Relaxed);
+
+        let almost = created + last_packet;
+        reclaim_expired(&state, IDLE_TIMEOUT, MAX_LIFETIME, almost).await;
+        assert!(state.relays.read().await.contains_key(&session_id));
+
+        reclaim_expired(&state, IDLE_TIMEOUT, MAX_LIFETIME, created + MAX_LIFETIME).await;
+        assert!(state.relays.read().await.is_empty());
+        assert!(lease_after_quarantine(&state).await.is_ok());
+    }
+}
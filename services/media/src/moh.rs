@@ -0,0 +1,53 @@
+//! Music on hold, generated as a G.711 RTP stream.
+//!
+//! There are no audio assets to play yet, so the "music" is a slow chime
+//! alternating between two notes. It is sent as plain RTP, which only
+//! endpoints negotiating unencrypted media (SIP phones) can play; a DTLS-SRTP
+//! leg drops it and just hears silence while held.
+
+use crate::{g711, rtp};
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+/// One packet every 20 ms.
+pub const PACKET_INTERVAL: Duration = Duration::from_millis(20);
+const SAMPLE_RATE: f32 = 8000.0;
+/// Each note rings for a second, fading out.
+const NOTE_SAMPLES: u32 = 8000;
+const NOTES: [f32; 2] = [523.25, 392.0];
+const AMPLITUDE: f32 = 6000.0;
+
+/// An endless RTP stream of the chime.
+pub struct Tone {
+    payload: u8,
+    rtp: rtp::Sender,
+    sample: u32,
+}
+
+impl Tone {
+    pub fn new(payload: u8, ssrc: u32) -> Self {
+        Tone {
+            payload,
+            rtp: rtp::Sender::new(payload, ssrc),
+            sample: 0,
+        }
+    }
+
+    /// The next 20 ms as an RTP packet.
+    pub fn next_packet(&mut self) -> Vec<u8> {
+        let mut audio = [0u8; g711::FRAME_SAMPLES];
+        for byte in &mut audio {
+            *byte = g711::encode(self.payload, self.sample());
+            self.sample = self.sample.wrapping_add(1);
+        }
+        self.rtp.packet(&audio, g711::FRAME_SAMPLES as u32)
+    }
+
+    fn sample(&self) -> i16 {
+        let note = NOTES[(self.sample / NOTE_SAMPLES) as usize % NOTES.len()];
+        let offset = self.sample % NOTE_SAMPLES;
+        let t = offset as f32 / SAMPLE_RATE;
+        let envelope = 1.0 - offset as f32 / NOTE_SAMPLES as f32;
+        (AMPLITUDE * envelope * envelope * (TAU * note * t).sin()) as i16
+    }
+}
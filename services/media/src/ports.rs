@@ -0,0 +1,286 @@
+//! The UDP ports relays, conference participants and supervisor legs use.
+//!
+//! Ports come from a configured range, so they can be opened in a firewall or
+//! exposed as a hostPort range. Every lease takes an even port for RTP and
+//! keeps the odd port above it for RTCP, which is only bound for endpoints
+//! that do not multiplex RTCP onto the RTP port, so the range must hold at
+//! least one even port with the next one also inside. Ports given back sit in
+//! quarantine before they are handed out again, so stray packets of a
+//! finished call never reach the next one. A node hands out at most its
+//! capacity of leases at a time.
+
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LeaseError {
+    #[error("every relay port is in use")]
+    Exhausted,
+    #[error("failed to bind a relay port: {0}")]
+    Bind(#[from] std::io::Error),
+}
+
+/// A port range the pool cannot work with.
+#[derive(Debug, thiserror::Error)]
+pub enum RangeError {
+    #[error("the port range {min}-{max} is empty: its minimum is above its maximum")]
+    Reversed { min: u16, max: u16 },
+    #[error("the port range {min}-{max} holds no even port above 0 with an odd one after it")]
+    NoPair { min: u16, max: u16 },
+}
+
+pub struct PortPool {
+    /// Address the sockets are bound to.
+    pub bind_ip: IpAddr,
+    capacity: usize,
+    quarantine: Duration,
+    ports: Mutex<Ports>,
+}
+
+struct Ports {
+    /// Even ports ready to hand out, least recently used first.
+    free: VecDeque<u16>,
+    /// Ports given back, oldest first, with when they were.
+    quarantined: VecDeque<(u16, Instant)>,
+    leased: usize,
+}
+
+/// An RTP/RTCP port pair, given back to the pool when dropped.
+pub struct Lease {
+    pub port: u16,
+    pub rtp: Arc<UdpSocket>,
+    pub rtcp: Option<Arc<UdpSocket>>,
+    pool: Arc<PortPool>,
+}
+
+impl PortPool {
+    pub fn new(
+        bind_ip: IpAddr,
+        range: RangeInclusive<u16>,
+        capacity: Option<usize>,
+        quarantine: Duration,
+    ) -> Result<Arc<PortPool>, RangeError> {
+        let (min, max) = (*range.start(), *range.end());
+        if min > max {
+            return Err(RangeError::Reversed { min, max });
+        }
+        // Binding port 0 would pick an arbitrary port instead.
+        let first = min.max(1).saturating_add(min.max(1) % 2);
+        let free: VecDeque<u16> = (first..=max)
+            .step_by(2)
+            .filter(|&port| port < max)
+            .collect();
+        if free.is_empty() {
+            return Err(RangeError::NoPair { min, max });
+        }
+        let capacity = capacity.map_or(free.len(), |capacity| capacity.min(free.len()));
+        Ok(Arc::new(PortPool {
+            bind_ip,
+            capacity,
+            quarantine,
+            ports: Mutex::new(Ports {
+                free,
+                quarantined: VecDeque::new(),
+                leased: 0,
+            }),
+        }))
+    }
+
+    /// Configure the pool from `MEDIA_BIND_IP`, `RTP_PORT_MIN`,
+    /// `RTP_PORT_MAX`, `MEDIA_CAPACITY` (leases at a time, by default one per
+    /// port pair) and `RTP_PORT_QUARANTINE_SECS`.
+    pub fn from_env() -> Result<Arc<PortPool>, RangeError> {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        }
+        let bind_ip = var("MEDIA_BIND_IP").unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let min = var("RTP_PORT_MIN").unwrap_or(10000);
+        let max = var("RTP_PORT_MAX").unwrap_or(20000);
+        let quarantine = Duration::from_secs(var("RTP_PORT_QUARANTINE_SECS").unwrap_or(10));
+        PortPool::new(bind_ip, min..=max, var("MEDIA_CAPACITY"), quarantine)
+    }
+
+    /// Lease a port pair, binding its RTCP port as well when `rtcp` is set.
+    /// Ports held outside the pool are skipped, each at most once.
+    pub async fn lease(self: &Arc<Self>, rtcp: bool) -> Result<Lease, LeaseError> {
+        let unleased = {
+            let ports = self.ports.lock().expect("port pool lock");
+            ports.free.len() + ports.quarantined.len()
+        };
+        for _ in 0..unleased {
+            let port = self.take()?;
+            match self.bind(port, rtcp).await {
+                Ok((rtp, rtcp)) => {
+                    return Ok(Lease {
+                        port,
+                        rtp: Arc::new(rtp),
+                        rtcp: rtcp.map(Arc::new),
+                        pool: self.clone(),
+                    });
+                }
+                // Something outside the pool holds the port; skip it for now.
+                Err(err) if err.kind() == ErrorKind::AddrInUse => {
+                    tracing::warn!(port, "relay port taken outside the pool");
+                    self.give_back(port);
+                }
+                Err(err) => {
+                    self.give_back(port);
+                    return Err(err.into());
+                }
+            }
+        }
+        Err(LeaseError::Exhausted)
+    }
+
+    /// Leases that can still be handed out.
+    pub fn available(&self) -> usize {
+        let ports = self.ports.lock().expect("port pool lock");
+        self.capacity.saturating_sub(ports.leased)
+    }
+
+    fn take(&self) -> Result<u16, LeaseError> {
+        let mut ports = self.ports.lock().expect("port pool lock");
+        while let Some(&(port, since)) = ports.quarantined.front() {
+            if since.elapsed() < self.quarantine {
+                break;
+            }
+            ports.quarantined.pop_front();
+            ports.free.push_back(port);
+        }
+        if ports.leased >= self.capacity {
+            return Err(LeaseError::Exhausted);
+        }
+        let port = ports.free.pop_front().ok_or(LeaseError::Exhausted)?;
+        ports.leased += 1;
+        Ok(port)
+    }
+
+    async fn bind(&self, port: u16, rtcp: bool) -> std::io::Result<(UdpSocket, Option<UdpSocket>)> {
+        let rtp = UdpSocket::bind((self.bind_ip, port)).await?;
+        let rtcp = match rtcp {
+            true => Some(UdpSocket::bind((self.bind_ip, port + 1)).await?),
+            false => None,
+        };
+        Ok((rtp, rtcp))
+    }
+
+    fn give_back(&self, port: u16) {
+        let mut ports = self.ports.lock().expect("port pool lock");
+        ports.leased -= 1;
+        ports.quarantined.push_back((port, Instant::now()));
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pool.give_back(self.port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn pool(range: RangeInclusive<u16>, capacity: Option<usize>) -> Arc<PortPool> {
+        PortPool::new(LOCALHOST, range, capacity, Duration::ZERO).unwrap()
+    }
+
+    #[test]
+    fn hands_out_even_ports_in_order() {
+        // An odd minimum is rounded up, and the maximum's pair must fit.
+        let pool = pool(40001..=40008, None);
+        assert_eq!(pool.available(), 3);
+        let taken: Vec<u16> = (0..3).map(|_| pool.take().unwrap()).collect();
+        assert_eq!(taken, [40002, 40004, 40006]);
+        assert!(matches!(pool.take(), Err(LeaseError::Exhausted)));
+    }
+
+    #[test]
+    fn given_back_ports_go_last() {
+        let pool = pool(40000..=40005, None);
+        let first = pool.take().unwrap();
+        pool.give_back(first);
+        assert_eq!(pool.take().unwrap(), 40002);
+        assert_eq!(pool.take().unwrap(), 40004);
+        assert_eq!(pool.take().unwrap(), first);
+    }
+
+    #[test]
+    fn quarantined_ports_wait_before_reuse() {
+        let pool =
+            PortPool::new(LOCALHOST, 40000..=40001, None, Duration::from_millis(50)).unwrap();
+        let port = pool.take().unwrap();
+        pool.give_back(port);
+        assert_eq!(pool.available(), 1);
+        assert!(matches!(pool.take(), Err(LeaseError::Exhausted)));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(pool.take().unwrap(), port);
+    }
+
+    #[test]
+    fn capacity_caps_leases_below_the_range() {
+        let pool = pool(40000..=40009, Some(2));
+        assert_eq!(pool.available(), 2);
+        pool.take().unwrap();
+        pool.take().unwrap();
+        assert_eq!(pool.available(), 0);
+        assert!(matches!(pool.take(), Err(LeaseError::Exhausted)));
+    }
+
+    #[test]
+    fn unusable_ranges_are_refused() {
+        let new = |range| PortPool::new(LOCALHOST, range, None, Duration::ZERO);
+        assert!(matches!(
+            new(RangeInclusive::new(20000, 10000)),
+            Err(RangeError::Reversed { .. })
+        ));
+        assert!(matches!(new(40000..=40000), Err(RangeError::NoPair { .. })));
+        assert!(matches!(new(40001..=40002), Err(RangeError::NoPair { .. })));
+        assert!(matches!(new(65535..=65535), Err(RangeError::NoPair { .. })));
+        assert!(matches!(new(0..=1), Err(RangeError::NoPair { .. })));
+        assert!(new(40001..=40003).is_ok());
+    }
+
+    #[tokio::test]
+    async fn leases_bind_their_pair_and_return_on_drop() {
+        let pool = PortPool::new(LOCALHOST, 47000..=47999, Some(1), Duration::ZERO).unwrap();
+        let lease = pool.lease(true).await.unwrap();
+        assert_eq!(lease.port % 2, 0);
+        assert_eq!(lease.rtp.local_addr().unwrap().port(), lease.port);
+        let rtcp = lease.rtcp.as_ref().unwrap().local_addr().unwrap();
+        assert_eq!(rtcp.port(), lease.port + 1);
+        assert!(matches!(
+            pool.lease(false).await,
+            Err(LeaseError::Exhausted)
+        ));
+        drop(lease);
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn ports_held_outside_the_pool_exhaust_it() {
+        // Hold an even port, so the pool's only pair is taken by someone else.
+        let foreign = loop {
+            let socket = std::net::UdpSocket::bind((LOCALHOST, 0)).unwrap();
+            if socket.local_addr().unwrap().port().is_multiple_of(2) {
+                break socket;
+            }
+        };
+        let port = foreign.local_addr().unwrap().port();
+        let pool = pool(port..=port + 1, None);
+        assert!(matches!(
+            pool.lease(false).await,
+            Err(LeaseError::Exhausted)
+        ));
+        assert_eq!(pool.available(), 1);
+    }
+}
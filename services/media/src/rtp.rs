@@ -0,0 +1,194 @@
+//! The RTP fixed header (RFC 3550 section 5.1).
+
+/// Length of the fixed header, without CSRCs or extensions.
+pub const HEADER_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl Header {
+    /// Parse the header of `packet`, returning it with the offset of the
+    /// payload. `None` for anything that is not RTP version 2, including
+    /// RTCP (payload types 72-76 with the marker bit folded in).
+    pub fn parse(packet: &[u8]) -> Option<(Header, usize)> {
+        if packet.len() < HEADER_LEN || packet[0] >> 6 != 2 {
+            return None;
+        }
+        let payload_type = packet[1] & 0x7F;
+        if (72..=76).contains(&payload_type) {
+            return None;
+        }
+        let csrcs = usize::from(packet[0] & 0x0F);
+        let mut offset = HEADER_LEN + 4 * csrcs;
+        if packet[0] & 0x10 != 0 {
+            let length = packet.get(offset + 2..offset + 4)?;
+            offset += 4 + 4 * usize::from(u16::from_be_bytes([length[0], length[1]]));
+        }
+        let mut end = packet.len();
+        if packet[0] & 0x20 != 0 {
+            end = end.checked_sub(usize::from(*packet.last()?))?;
+        }
+        if offset > end {
+            return None;
+        }
+        let header = Header {
+            marker: packet[1] & 0x80 != 0,
+            payload_type,
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        };
+        Some((header, offset))
+    }
+
+    /// Serialize the header followed by `payload`.
+    pub fn packet(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+        packet.push(0x80);
+        packet.push(u8::from(self.marker) << 7 | self.payload_type);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+/// An outgoing RTP stream: numbers packets and advances the timestamp.
+#[derive(Debug)]
+pub struct Sender {
+    header: Header,
+    started: bool,
+}
+
+impl Sender {
+    pub fn new(payload_type: u8, ssrc: u32) -> Self {
+        Sender {
+            header: Header {
+                marker: true,
+                payload_type,
+                sequence: 0,
+                timestamp: 0,
+                ssrc,
+            },
+            started: false,
+        }
+    }
+
+    /// Switch codec, e.g. after renegotiation; the stream carries on.
+    pub fn set_payload_type(&mut self, payload_type: u8) {
+        self.header.payload_type = payload_type;
+    }
+
+    /// The next packet carrying `samples` samples of `payload`.
+    pub fn packet(&mut self, payload: &[u8], samples: u32) -> Vec<u8> {
+        if self.started {
+            self.header.marker = false;
+            self.header.sequence = self.header.sequence.wrapping_add(1);
+            self.header.timestamp = self.header.timestamp.wrapping_add(samples);
+        }
+        self.started = true;
+        self.header.packet(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            marker: true,
+            payload_type: 0,
+            sequence: 7,
+            timestamp: 160,
+            ssrc: 0x1234_5678,
+        }
+    }
+
+    #[test]
+    fn round_trips_the_fixed_header() {
+        let packet = header().packet(&[1, 2, 3]);
+        assert_eq!(Header::parse(&packet), Some((header(), HEADER_LEN)));
+        assert_eq!(&packet[HEADER_LEN..], [1, 2, 3]);
+    }
+
+    #[test]
+    fn skips_csrcs() {
+        let mut packet = header().packet(&[0; 8 + 2]);
+        packet[0] |= 2;
+        assert_eq!(Header::parse(&packet).unwrap().1, HEADER_LEN + 8);
+        // The CSRCs must fit in the packet.
+        packet[0] |= 0x0F;
+        assert_eq!(Header::parse(&packet), None);
+    }
+
+    #[test]
+    fn skips_the_extension_by_its_length() {
+        // Profile 0xBEDE with two words of extension data, then two payload
+        // bytes.
+        let mut packet = header().packet(&[0xBE, 0xDE, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 9, 9]);
+        packet[0] |= 0x10;
+        assert_eq!(Header::parse(&packet).unwrap().1, HEADER_LEN + 12);
+        assert_eq!(Header::parse(&packet[..HEADER_LEN + 3]), None);
+        assert_eq!(Header::parse(&packet[..HEADER_LEN + 8]), None);
+    }
+
+    #[test]
+    fn padding_must_fit_after_the_headers() {
+        let mut packet = header().packet(&[5, 5, 0, 3]);
+        packet[0] |= 0x20;
+        assert_eq!(Header::parse(&packet).unwrap().1, HEADER_LEN);
+        // Padding taking up the whole payload leaves an empty one.
+        *packet.last_mut().unwrap() = 4;
+        assert_eq!(Header::parse(&packet).unwrap().1, HEADER_LEN);
+        *packet.last_mut().unwrap() = 5;
+        assert_eq!(Header::parse(&packet), None);
+        *packet.last_mut().unwrap() = 255;
+        assert_eq!(Header::parse(&packet), None);
+    }
+
+    #[test]
+    fn refuses_rtcp_and_other_versions() {
+        for payload_type in 72..=76 {
+            let packet = Header {
+                payload_type,
+                ..header()
+            }
+            .packet(&[]);
+            assert_eq!(Header::parse(&packet), None);
+        }
+        let packet = Header {
+            payload_type: 77,
+            ..header()
+        }
+        .packet(&[]);
+        assert!(Header::parse(&packet).is_some());
+        let mut packet = header().packet(&[]);
+        packet[0] = 0x40;
+        assert_eq!(Header::parse(&packet), None);
+        assert_eq!(Header::parse(&header().packet(&[])[..HEADER_LEN - 1]), None);
+    }
+
+    #[test]
+    fn sender_numbers_packets_and_advances_the_timestamp() {
+        let mut sender = Sender::new(8, 42);
+        let (first, _) = Header::parse(&sender.packet(&[0; 160], 160)).unwrap();
+        assert!(first.marker);
+        assert_eq!((first.payload_type, first.ssrc), (8, 42));
+        assert_eq!((first.sequence, first.timestamp), (0, 0));
+
+        sender.set_payload_type(0);
+        let (second, offset) = Header::parse(&sender.packet(&[0; 160], 160)).unwrap();
+        assert!(!second.marker);
+        assert_eq!(second.payload_type, 0);
+        assert_eq!((second.sequence, second.timestamp), (1, 160));
+        assert_eq!(offset, HEADER_LEN);
+    }
+}
@@ -0,0 +1,417 @@
+//! Per-stream RTP quality statistics for a relay.
+//!
+//! Every RTP packet a relay receives from one of its sides is counted
+//! against its stream: the SSRC and the direction it travels in. Loss,
+//! reordering and duplicates are read off the sequence numbers, and
+//! interarrival jitter is estimated as in RFC 3550 appendix A.8, in the
+//! units of the payload's RTP clock. Only the fixed header is looked at, so
+//! SRTP streams are measured just as well as plain RTP.
+
+use crate::rtp::Header;
+use crate::Side;
+use sdp::{Codec, SessionDescription};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Streams tracked per relay; an endpoint cycling through SSRCs does not
+/// get to grow the table without bound.
+const MAX_STREAMS: usize = 16;
+/// Sequence numbers behind the highest one remembered for spotting
+/// duplicates.
+const HISTORY: u16 = 128;
+/// How far the sequence number may jump ahead, or fall behind, before the
+/// packet is taken for a stray one, or for a restart of the stream when the
+/// next packet follows on from it (RFC 3550 appendix A.1).
+const MAX_DROPOUT: u16 = 3000;
+const MAX_MISORDER: u16 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    AToB,
+    BToA,
+}
+
+impl From<Side> for Direction {
+    /// The direction media sent by `side` travels in.
+    fn from(side: Side) -> Self {
+        match side {
+            Side::A => Direction::AToB,
+            Side::B => Direction::BToA,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamStats {
+    pub ssrc: u32,
+    pub direction: Direction,
+    pub packets: u64,
+    /// Datagram bytes, headers included.
+    pub bytes: u64,
+    /// Packets never received, by the sequence numbers.
+    pub lost: u64,
+    /// Packets that arrived after a later one.
+    pub reordered: u64,
+    pub duplicates: u64,
+    /// Interarrival jitter in milliseconds; absent while the payload's
+    /// clock rate is unknown.
+    pub jitter_ms: Option<f64>,
+}
+
+pub struct Stats {
+    /// RTP clock rate by payload type, from the SDP the relay was
+    /// allocated with.
+    clock_rates: HashMap<u8, u32>,
+    started: Instant,
+    streams: Mutex<HashMap<(Direction, u32), Stream>>,
+}
+
+#[derive(Debug)]
+struct Stream {
+    packets: u64,
+    bytes: u64,
+    duplicates: u64,
+    reordered: u64,
+    /// Distinct packets received, for loss.
+    received: u64,
+    /// Packets expected before the stream last restarted.
+    expected_before: u64,
+    received_before: u64,
+    /// First and highest sequence number since the last restart, extended
+    /// with the wrap-around count (starting from one wrap, so that late
+    /// packets before the first never go below zero).
+    base: u64,
+    highest: u64,
+    /// Bit `n` set when sequence number `highest - n` arrived.
+    seen: u128,
+    /// The sequence number following a packet that jumped too far; if it
+    /// comes next, the stream restarted there.
+    bad_seq: Option<u16>,
+    /// RFC 3550 jitter state, in RTP clock units.
+    transit: Option<f64>,
+    jitter: f64,
+    clock_rate: Option<u32>,
+}
+
+impl Stats {
+    pub fn new(sdp: Option<&SessionDescription>) -> Self {
+        let clock_rates = sdp
+            .map(|sdp| {
+                sdp.media
+                    .iter()
+                    .flat_map(|media| media.codecs())
+                    .map(|codec| (codec.payload, codec.clock_rate))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Stats {
+            clock_rates,
+            started: Instant::now(),
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a packet of `len` bytes that `side` sent.
+    pub fn record(&self, side: Side, header: &Header, len: usize) {
+        let clock_rate = self
+            .clock_rates
+            .get(&header.payload_type)
+            .copied()
+            .or_else(|| Codec::static_payload(header.payload_type).map(|codec| codec.clock_rate));
+        let arrival = self.started.elapsed().as_secs_f64();
+        let mut streams = self.streams.lock().expect("stats lock");
+        let key = (Direction::from(side), header.ssrc);
+        if !streams.contains_key(&key) && streams.len() >= MAX_STREAMS {
+            return;
+        }
+        streams
+            .entry(key)
+            .or_insert_with(|| Stream::new(header.sequence))
+            .record(header, len, arrival, clock_rate);
+    }
+
+    /// Statistics of every stream so far.
+    pub fn snapshot(&self) -> Vec<StreamStats> {
+        let streams = self.streams.lock().expect("stats lock");
+        let mut snapshot: Vec<StreamStats> = streams
+            .iter()
+            .map(|(&(direction, ssrc), stream)| stream.stats(direction, ssrc))
+            .collect();
+        snapshot.sort_by_key(|stats| (stats.direction == Direction::BToA, stats.ssrc));
+        snapshot
+    }
+}
+
+impl Stream {
+    fn new(sequence: u16) -> Self {
+        let sequence = u64::from(sequence) + (1 << 16);
+        Stream {
+            packets: 0,
+            bytes: 0,
+            duplicates: 0,
+            reordered: 0,
+            received: 0,
+            expected_before: 0,
+            received_before: 0,
+            base: sequence,
+            highest: sequence,
+            seen: 0,
+            bad_seq: None,
+            transit: None,
+            jitter: 0.0,
+            clock_rate: None,
+        }
+    }
+
+    fn record(&mut self, header: &Header, len: usize, arrival: f64, clock_rate: Option<u32>) {
+        self.packets += 1;
+        self.bytes += len as u64;
+
+        let delta = header.sequence.wrapping_sub(self.highest as u16);
+        let behind = delta.wrapping_neg();
+        if self.received == 0 {
+            // The first packet of the stream.
+            self.seen = 1;
+        } else if delta == 0 {
+            self.duplicates += 1;
+            return;
+        } else if delta <= MAX_DROPOUT {
+            // In order, possibly after a gap.
+            self.highest += u64::from(delta);
+            self.seen = self.seen.checked_shl(delta.into()).unwrap_or(0) | 1;
+        } else if behind <= MAX_MISORDER {
+            if behind < HISTORY {
+                let bit = 1u128 << behind;
+                if self.seen & bit != 0 {
+                    self.duplicates += 1;
+                    return;
+                }
+                self.seen |= bit;
+            }
+            self.reordered += 1;
+            self.base = self.base.min(self.highest - u64::from(behind));
+        } else if self.bad_seq == Some(header.sequence) {
+            // Two packets in a row after a jump: the sender restarted the
+            // sequence with the previous one.
+            self.expected_before += self.expected();
+            self.received_before += self.received;
+            *self = Stream {
+                packets: self.packets,
+                bytes: self.bytes,
+                duplicates: self.duplicates,
+                reordered: self.reordered,
+                received: 1,
+                expected_before: self.expected_before,
+                received_before: self.received_before,
+                jitter: self.jitter,
+                clock_rate: self.clock_rate,
+                ..Stream::new(header.sequence.wrapping_sub(1))
+            };
+            self.highest += 1;
+            self.seen = 0b11;
+        } else {
+            // A jump too far either way: a stray packet, unless the next one
+            // follows on from it.
+            self.bad_seq = Some(header.sequence.wrapping_add(1));
+            return;
+        }
+        self.received += 1;
+
+        self.jitter(header, arrival, clock_rate);
+    }
+
+    /// RFC 3550 appendix A.8.
+    fn jitter(&mut self, header: &Header, arrival: f64, clock_rate: Option<u32>) {
+        let Some(clock_rate) = clock_rate else {
+            return;
+        };
+        if self.clock_rate != Some(clock_rate) {
+            // A codec switch changes the clock; start the estimate afresh.
+            self.clock_rate = Some(clock_rate);
+            self.transit = None;
+            self.jitter = 0.0;
+        }
+        let transit = arrival * f64::from(clock_rate) - f64::from(header.timestamp);
+        if let Some(previous) = self.transit {
+            let mut d = (transit - previous).abs();
+            // RTP timestamps wrap at 2^32.
+            if d > f64::from(u32::MAX) / 2.0 {
+                d = (d - f64::from(u32::MAX) - 1.0).abs();
+            }
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+    }
+
+    fn expected(&self) -> u64 {
+        match self.received {
+            0 => 0,
+            _ => self.highest - self.base + 1,
+        }
+    }
+
+    fn stats(&self, direction: Direction, ssrc: u32) -> StreamStats {
+        let expected = self.expected_before + self.expected();
+        let received = self.received_before + self.received;
+        StreamStats {
+            ssrc,
+            direction,
+            packets: self.packets,
+            bytes: self.bytes,
+            lost: expected.saturating_sub(received),
+            reordered: self.reordered,
+            duplicates: self.duplicates,
+            jitter_ms: self
+                .clock_rate
+                .map(|clock_rate| self.jitter * 1000.0 / f64::from(clock_rate)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK_RATE: u32 = 8000;
+
+    fn packet(sequence: u16, timestamp: u32) -> Header {
+        Header {
+            marker: false,
+            payload_type: 0,
+            sequence,
+            timestamp,
+            ssrc: 1,
+        }
+    }
+
+    /// Feed packets with these sequence numbers, all arriving at once.
+    fn stream(sequences: &[u16]) -> Stream {
+        let mut stream = Stream::new(sequences[0]);
+        for &sequence in sequences {
+            stream.record(&packet(sequence, 0), 100, 0.0, None);
+        }
+        stream
+    }
+
+    fn summary(stream: &Stream) -> StreamStats {
+        stream.stats(Direction::AToB, 1)
+    }
+
+    #[test]
+    fn gaps_count_as_lost() {
+        let stats = summary(&stream(&[1, 2, 3, 6, 7]));
+        assert_eq!(stats.packets, 5);
+        assert_eq!(stats.bytes, 500);
+        assert_eq!(stats.lost, 2);
+        assert_eq!(stats.reordered, 0);
+        assert_eq!(stats.duplicates, 0);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let stats = summary(&stream(&[65534, 65535, 0, 1]));
+        assert_eq!(stats.lost, 0);
+        let stats = summary(&stream(&[65534, 1]));
+        assert_eq!(stats.lost, 2);
+    }
+
+    #[test]
+    fn late_packets_fill_their_gap() {
+        let stats = summary(&stream(&[1, 2, 4, 5, 3]));
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.reordered, 1);
+
+        // Even one from before the first packet, and across the wrap.
+        let stats = summary(&stream(&[0, 1, 65535]));
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.reordered, 1);
+
+        // As late as the reordering window allows.
+        let mut sequences: Vec<u16> = (1..=MAX_MISORDER + 1).collect();
+        sequences.remove(0);
+        sequences.push(1);
+        let stats = summary(&stream(&sequences));
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.reordered, 1);
+    }
+
+    #[test]
+    fn packets_beyond_the_history_are_ignored() {
+        // Too late to be told from a duplicate: neither a restart of the
+        // stream nor a fresh loss.
+        let mut stream = stream(&(1..=HISTORY + 100).collect::<Vec<_>>());
+        stream.record(&packet(2, 0), 100, 0.0, None);
+        stream.record(&packet(HISTORY + 101, 0), 100, 0.0, None);
+        let stats = summary(&stream);
+        assert_eq!(stats.packets, u64::from(HISTORY) + 102);
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.reordered, 0);
+        assert_eq!(stats.duplicates, 0);
+    }
+
+    #[test]
+    fn duplicates_are_not_received_twice() {
+        let stats = summary(&stream(&[1, 2, 3, 3]));
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.lost, 0);
+
+        let stats = summary(&stream(&[1, 2, 3, 4, 2]));
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.reordered, 0);
+        assert_eq!(stats.lost, 0);
+
+        // A late packet arriving twice is reordered once.
+        let stats = summary(&stream(&[1, 3, 2, 2]));
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.reordered, 1);
+        assert_eq!(stats.lost, 0);
+    }
+
+    #[test]
+    fn a_restart_keeps_earlier_losses() {
+        // Two lost before the sender jumps to a new sequence, one after.
+        let stats = summary(&stream(&[1, 2, 5, 40000, 40001, 40003]));
+        assert_eq!(stats.packets, 6);
+        assert_eq!(stats.lost, 3);
+
+        // A single stray jump does not restart the stream.
+        let stats = summary(&stream(&[1, 2, 40000, 3, 4]));
+        assert_eq!(stats.lost, 0);
+    }
+
+    #[test]
+    fn jitter_is_zero_for_constant_transit() {
+        let mut stream = Stream::new(0);
+        // 20 ms packets, with the RTP timestamp wrapping on the way.
+        let start = u32::MAX - 800;
+        for i in 0..50u16 {
+            let timestamp = start.wrapping_add(u32::from(i) * 160);
+            let arrival = 1.0 + f64::from(i) * 0.02;
+            stream.record(&packet(i, timestamp), 100, arrival, Some(CLOCK_RATE));
+        }
+        let jitter = summary(&stream).jitter_ms.unwrap();
+        assert!(jitter.abs() < 1e-6, "jitter {jitter}");
+    }
+
+    #[test]
+    fn jitter_converges_on_the_transit_variation() {
+        let mut stream = Stream::new(0);
+        // Every other packet 10 ms late.
+        for i in 0..300u16 {
+            let late = if i % 2 == 1 { 0.01 } else { 0.0 };
+            let arrival = f64::from(i) * 0.02 + late;
+            let timestamp = u32::from(i) * 160;
+            stream.record(&packet(i, timestamp), 100, arrival, Some(CLOCK_RATE));
+        }
+        let jitter = summary(&stream).jitter_ms.unwrap();
+        assert!((jitter - 10.0).abs() < 0.01, "jitter {jitter}");
+    }
+
+    #[test]
+    fn jitter_needs_a_clock_rate() {
+        assert_eq!(summary(&stream(&[1, 2, 3])).jitter_ms, None);
+    }
+}
@@ -0,0 +1,237 @@
+//! Supervisor legs on a call's relay: monitor, whisper and barge.
+//!
+//! A supervisor joins a relayed call over a port of its own, negotiated and
+//! bound like a conference participant's. While one is present the relay also decodes
+//! both sides' G.711, and every 20 ms a mixer sends the supervisor the two of
+//! them. What the call's own parties hear depends on the mode:
+//!
+//! | mode    | agent hears           | customer hears       |
+//! |---------|-----------------------|----------------------|
+//! | monitor | customer              | agent                |
+//! | whisper | customer + supervisor | agent                |
+//! | barge   | customer + supervisor | agent + supervisor   |
+//!
+//! Directions that carry the supervisor's voice are mixed and re-encoded in
+//! the codec the receiving side sends; every other direction is still
+//! forwarded packet for packet.
+
+use crate::conference::{self, Frame, JoinError, Joined, Mix, Participant};
+use crate::ports::PortPool;
+use crate::{g711, rtp, Relay, Side};
+use sdp::SessionDescription;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use uuid::Uuid;
+
+const MIX_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Listen only.
+    Monitor,
+    /// Talk to the agent, unheard by the customer.
+    Whisper,
+    /// Talk to both.
+    Barge,
+}
+
+impl Mode {
+    /// Whether `side` hears the supervisor in this mode, with the agent on
+    /// side `agent`.
+    fn mixes_to(self, side: Side, agent: Side) -> bool {
+        match self {
+            Mode::Monitor => false,
+            Mode::Whisper => side == agent,
+            Mode::Barge => true,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SuperviseError {
+    #[error("another supervisor is already on the call")]
+    Busy,
+    #[error(transparent)]
+    Join(#[from] JoinError),
+}
+
+pub struct Supervision {
+    pub supervisor_id: Uuid,
+    /// The side the supervised agent is on; whispers reach only it.
+    agent: Side,
+    mode: Mutex<Mode>,
+    leg: Arc<Participant>,
+    /// Decoded audio from sides A and B waiting for the mixer.
+    heard: [Mutex<VecDeque<Frame>>; 2],
+    /// G.711 flavour each side sends, which the mixer answers it in.
+    payloads: [AtomicU8; 2],
+    /// Streams the mixer sends to sides A and B in place of forwarding.
+    senders: [Mutex<rtp::Sender>; 2],
+    mixer: Mutex<Option<AbortHandle>>,
+}
+
+impl Supervision {
+    /// Open the supervisor's leg on a port from `ports` and start mixing
+    /// for `relay`.
+    pub async fn start(
+        relay: &Arc<Relay>,
+        ports: &Arc<PortPool>,
+        supervisor_id: Uuid,
+        agent: Side,
+        mode: Mode,
+    ) -> Result<Arc<Supervision>, JoinError> {
+        let supervision = Arc::new(Supervision {
+            supervisor_id,
+            agent,
+            mode: Mutex::new(mode),
+            leg: Participant::bind(ports, supervisor_id).await?,
+            heard: Default::default(),
+            payloads: [
+                AtomicU8::new(relay.moh_payload),
+                AtomicU8::new(relay.moh_payload),
+            ],
+            senders: [side_sender(relay), side_sender(relay)],
+            mixer: Mutex::new(None),
+        });
+        let weak = Arc::downgrade(&supervision);
+        let relay: Weak<Relay> = Arc::downgrade(relay);
+        let mixer = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(MIX_INTERVAL);
+            loop {
+                ticks.tick().await;
+                let (Some(supervision), Some(relay)) = (weak.upgrade(), relay.upgrade()) else {
+                    break;
+                };
+                supervision.mix(&relay).await;
+            }
+        });
+        *supervision.mixer.lock().expect("mixer lock") = Some(mixer.abort_handle());
+        Ok(supervision)
+    }
+
+    /// Answer the supervisor's `offer`.
+    pub async fn negotiate(
+        &self,
+        offer: &SessionDescription,
+        public_ip: IpAddr,
+    ) -> Result<Joined, JoinError> {
+        self.leg.negotiate(offer, public_ip).await
+    }
+
+    pub fn set_mode(&self, mode: Mode) {
+        *self.mode.lock().expect("mode lock") = mode;
+    }
+
+    fn mode(&self) -> Mode {
+        *self.mode.lock().expect("mode lock")
+    }
+
+    /// Whether `side` hears the mixer rather than the other side's packets.
+    pub fn mixes_to(&self, side: Side) -> bool {
+        self.mode().mixes_to(side, self.agent)
+    }
+
+    /// Take in a packet `side` sent.
+    pub fn hear(&self, side: Side, packet: &[u8]) {
+        let Some((header, offset)) = rtp::Header::parse(packet) else {
+            return;
+        };
+        if matches!(header.payload_type, g711::PCMU | g711::PCMA) {
+            self.payloads[side.index()].store(header.payload_type, Ordering::Relaxed);
+        }
+        conference::queue_frames(
+            &self.heard[side.index()],
+            header.payload_type,
+            &packet[offset..],
+        );
+    }
+
+    /// One mixing round: the supervisor hears both sides, and the sides the
+    /// mode lets it talk to hear it over the other side.
+    async fn mix(&self, relay: &Relay) {
+        let [a, b] = [Side::A, Side::B].map(|side| {
+            self.heard[side.index()]
+                .lock()
+                .expect("frame lock")
+                .pop_front()
+        });
+        let supervisor = self.leg.next_frame();
+
+        let mut both: Mix = [0; g711::FRAME_SAMPLES];
+        for frame in [a, b].iter().flatten() {
+            conference::add(&mut both, frame, 1);
+        }
+        self.leg.send(&both).await;
+
+        // A held call carries music on hold only.
+        if relay.on_hold().await {
+            return;
+        }
+        for (side, other) in [(Side::A, b), (Side::B, a)] {
+            if !self.mixes_to(side) {
+                continue;
+            }
+            let Some(to) = *relay.side(side).0.read().await else {
+                continue;
+            };
+            let mut mix: Mix = [0; g711::FRAME_SAMPLES];
+            for frame in [other, supervisor].iter().flatten() {
+                conference::add(&mut mix, frame, 1);
+            }
+            let payload = self.payloads[side.index()].load(Ordering::Relaxed);
+            let audio = conference::encode_mix(payload, &mix);
+            let packet = {
+                let mut sender = self.senders[side.index()].lock().expect("sender lock");
+                sender.set_payload_type(payload);
+                sender.packet(&audio, g711::FRAME_SAMPLES as u32)
+            };
+            let _ = relay.lease.rtp.send_to(&packet, to).await;
+        }
+    }
+
+    /// Stop mixing and release the supervisor's port.
+    pub fn close(&self) {
+        if let Some(mixer) = self.mixer.lock().expect("mixer lock").take() {
+            mixer.abort();
+        }
+        self.leg.close();
+    }
+}
+
+fn side_sender(relay: &Relay) -> Mutex<rtp::Sender> {
+    Mutex::new(rtp::Sender::new(
+        relay.moh_payload,
+        Uuid::new_v4().as_u128() as u32,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn who_hears_the_supervisor_follows_the_module_table() {
+        // (mode, agent hears the supervisor, customer hears the supervisor)
+        let table = [
+            (Mode::Monitor, false, false),
+            (Mode::Whisper, true, false),
+            (Mode::Barge, true, true),
+        ];
+        for (mode, agent_hears, customer_hears) in table {
+            for agent in [Side::A, Side::B] {
+                assert_eq!(mode.mixes_to(agent, agent), agent_hears, "{mode:?}");
+                assert_eq!(
+                    mode.mixes_to(agent.other(), agent),
+                    customer_hears,
+                    "{mode:?}"
+                );
+            }
+        }
+    }
+}
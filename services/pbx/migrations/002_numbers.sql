@@ -0,0 +1,9 @@
+-- Dialable numbers (DIDs and internal extensions) and the call-flow that
+-- answers each of them. Numbers are globally unique across tenants.
+CREATE TABLE IF NOT EXISTS phone_numbers (
+  number TEXT PRIMARY KEY,
+  tenant_id UUID NOT NULL,
+  flow_id UUID NOT NULL REFERENCES call_flows(id) ON DELETE CASCADE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS idx_phone_numbers_tenant ON phone_numbers(tenant_id);
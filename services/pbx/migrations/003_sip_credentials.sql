@@ -0,0 +1,15 @@
+-- SIP digest credentials, one set per user. Only the HA1 hashes for each
+-- supported algorithm are stored (RFC 8760), never the password itself.
+-- The digest username is the user id, which is also the user part of the
+-- phone's address-of-record.
+CREATE TABLE IF NOT EXISTS sip_credentials (
+  tenant_id UUID NOT NULL,
+  user_id UUID NOT NULL,
+  realm TEXT NOT NULL,
+  ha1_md5 TEXT NOT NULL,
+  ha1_sha256 TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (tenant_id, user_id)
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_sip_credentials_user ON sip_credentials(user_id);
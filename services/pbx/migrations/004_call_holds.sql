@@ -0,0 +1,13 @@
+-- Hold intervals of calls, reported by signaling when a hold is released
+-- (or the call ends while on hold). Kept for reporting on hold times.
+CREATE TABLE IF NOT EXISTS call_holds (
+  id BIGSERIAL PRIMARY KEY,
+  call_id UUID NOT NULL,
+  tenant_id UUID NOT NULL,
+  held_by UUID NOT NULL,
+  started_at TIMESTAMPTZ NOT NULL,
+  ended_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  duration_ms BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_call_holds_call ON call_holds(call_id);
+CREATE INDEX IF NOT EXISTS idx_call_holds_tenant ON call_holds(tenant_id, ended_at);
@@ -0,0 +1,14 @@
+-- Conference rooms. PINs are stored as SHA-256 of `<room id>:<pin>`; a room
+-- without a PIN is open to anyone in the tenant, and only those joining with
+-- the moderator PIN may mute, remove or lock.
+CREATE TABLE IF NOT EXISTS conference_rooms (
+  id UUID PRIMARY KEY,
+  tenant_id UUID NOT NULL,
+  name TEXT NOT NULL,
+  pin_hash TEXT,
+  moderator_pin_hash TEXT,
+  max_participants INT NOT NULL DEFAULT 50,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS idx_conference_rooms_tenant ON conference_rooms(tenant_id);
@@ -0,0 +1,16 @@
+-- Call pickup groups. Members of a group may answer calls ringing any other
+-- member; a user belongs to at most one group.
+CREATE TABLE IF NOT EXISTS pickup_groups (
+  id UUID PRIMARY KEY,
+  tenant_id UUID NOT NULL,
+  name TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS idx_pickup_groups_tenant ON pickup_groups(tenant_id);
+
+CREATE TABLE IF NOT EXISTS pickup_group_members (
+  user_id UUID PRIMARY KEY,
+  group_id UUID NOT NULL REFERENCES pickup_groups(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS idx_pickup_group_members_group ON pickup_group_members(group_id);
@@ -0,0 +1,8 @@
+-- Members carry the tenant of their group, so saving a group can never take
+-- in, or move away, a user of another tenant.
+ALTER TABLE pickup_group_members ADD COLUMN IF NOT EXISTS tenant_id UUID;
+UPDATE pickup_group_members m
+SET tenant_id = g.tenant_id
+FROM pickup_groups g
+WHERE g.id = m.group_id AND m.tenant_id IS NULL;
+ALTER TABLE pickup_group_members ALTER COLUMN tenant_id SET NOT NULL;
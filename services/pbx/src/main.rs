@@ -1,9 +1,12 @@
 use axum::{
     extract::State,
-    routing::{get, put},
+    http::HeaderMap,
+    routing::{get, post, put},
     Json, Router,
 };
+use md5::Md5;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::{Pool, Postgres, Row};
 use std::net::SocketAddr;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -16,6 +19,35 @@ use uuid::Uuid;
 #[derive(Clone)]
 struct AppState {
     db: Pool<Postgres>,
+    /// Digest realm SIP credentials are hashed for; must match signaling's
+    /// `SIP_DOMAIN`.
+    sip_realm: String,
+    /// Bearer token signaling presents on the routes that hand out secrets or
+    /// check PINs; without one configured they refuse everyone.
+    service_token: Option<String>,
+}
+
+impl AppState {
+    /// Let the request through only if it carries the service token.
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), axum::http::StatusCode> {
+        let token = headers
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match (&self.service_token, token) {
+            (Some(expected), Some(token))
+                if constant_time_eq(expected.as_bytes(), token.as_bytes()) =>
+            {
+                Ok(())
+            }
+            _ => Err(axum::http::StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+/// Compared in constant time, so a secret cannot be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -152,11 +184,542 @@ async fn update_flow(
     }))
 }
 
+#[derive(Debug, Deserialize)]
+struct AssignNumberRequest {
+    tenant_id: Uuid,
+    flow_id: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+struct NumberAssignment {
+    number: String,
+    tenant_id: Uuid,
+    flow_id: Uuid,
+}
+
+/// Point a dialable number at one of the tenant's call-flows.
+///
+/// The flow must belong to the same tenant; re-assigning a number moves it to
+/// the new flow, but never across tenants.
+async fn assign_number(
+    State(state): State<AppState>,
+    axum::extract::Path(number): axum::extract::Path<String>,
+    Json(req): Json<AssignNumberRequest>,
+) -> Result<Json<NumberAssignment>, axum::http::StatusCode> {
+    let result = sqlx::query(
+        r#"INSERT INTO phone_numbers (number, tenant_id, flow_id)
+           SELECT $1, tenant_id, id FROM call_flows WHERE tenant_id = $2 AND id = $3
+           ON CONFLICT (number) DO UPDATE SET flow_id = EXCLUDED.flow_id
+           WHERE phone_numbers.tenant_id = EXCLUDED.tenant_id"#,
+    )
+    .bind(&number)
+    .bind(req.tenant_id)
+    .bind(req.flow_id)
+    .execute(&state.db)
+    .await
+    .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    if result.rows_affected() == 0 {
+        return Err(axum::http::StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(NumberAssignment {
+        number,
+        tenant_id: req.tenant_id,
+        flow_id: req.flow_id,
+    }))
+}
+
+/// Where a call to a number should go, as decided by its call-flow.
+#[derive(Debug, Serialize)]
+struct Route {
+    tenant_id: Uuid,
+    flow_id: Uuid,
+    /// `None` when the flow starts with a node nothing can execute yet
+    /// (menus, queues, voicemail).
+    destination: Option<Destination>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Destination {
+    User { user_id: Uuid },
+    Conference { room_id: Uuid },
+}
+
+/// Resolve a dialed number to its tenant, flow and first destination.
+///
+/// Signaling calls this for every destination that is not a user id, so it is
+/// a single indexed lookup. Only the flow's entry node is evaluated: a `user`
+/// node rings that user directly and a `conference` node joins its room.
+async fn route_number(
+    State(state): State<AppState>,
+    axum::extract::Path(number): axum::extract::Path<String>,
+) -> Result<Json<Route>, axum::http::StatusCode> {
+    let row = sqlx::query(
+        r#"SELECT n.tenant_id, n.flow_id, f.config
+           FROM phone_numbers n JOIN call_flows f ON f.id = n.flow_id
+           WHERE n.number = $1"#,
+    )
+    .bind(&number)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(axum::http::StatusCode::NOT_FOUND)?;
+
+    let tenant_id: Uuid = row
+        .try_get("tenant_id")
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    let flow_id: Uuid = row
+        .try_get("flow_id")
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    let config: serde_json::Value = row
+        .try_get("config")
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let entry = config.get("nodes").and_then(|nodes| nodes.get(0));
+    let target = entry
+        .and_then(|node| node.get("target"))
+        .and_then(|target| target.as_str())
+        .and_then(|target| target.parse().ok());
+    let destination = match (entry.and_then(|node| node.get("type")?.as_str()), target) {
+        (Some("user"), Some(user_id)) => Some(Destination::User { user_id }),
+        (Some("conference"), Some(room_id)) => Some(Destination::Conference { room_id }),
+        _ => None,
+    };
+
+    Ok(Json(Route {
+        tenant_id,
+        flow_id,
+        destination,
+    }))
+}
+
+/// Shortest SIP password accepted. Registrars on the public internet are
+/// brute-forced around the clock, so short secrets do not last.
+const MIN_SIP_PASSWORD_LEN: usize = 12;
+
+#[derive(Debug, Deserialize)]
+struct SetSipPasswordRequest {
+    password: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SipCredentials {
+    tenant_id: Uuid,
+    user_id: Uuid,
+    realm: String,
+    ha1_md5: String,
+    ha1_sha256: String,
+}
+
+/// Set or replace a user's SIP password.
+///
+/// Only the HA1 hashes (`H(username:realm:password)`) are stored, one per
+/// digest algorithm signaling offers. A user id belongs to exactly one tenant,
+/// so an existing row is only ever updated within the same tenant. Whoever
+/// sets a password can register as the user, so this takes the service token.
+async fn set_sip_password(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    axum::extract::Path((tenant_id, user_id)): axum::extract::Path<(Uuid, Uuid)>,
+    Json(req): Json<SetSipPasswordRequest>,
+) -> Result<axum::http::StatusCode, axum::http::StatusCode> {
+    state.authorize(&headers)?;
+    if req.password.chars().count() < MIN_SIP_PASSWORD_LEN {
+        return Err(axum::http::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let a1 = format!("{user_id}:{}:{}", state.sip_realm, req.password);
+    let ha1_md5 = hex::encode(Md5::digest(a1.as_bytes()));
+    let ha1_sha256 = hex::encode(Sha256::digest(a1.as_bytes()));
+
+    let result = sqlx::query(
+        r#"INSERT INTO sip_credentials (tenant_id, user_id, realm, ha1_md5, ha1_sha256)
+           VALUES ($1, $2, $3, $4, $5)
+           ON CONFLICT (user_id) DO UPDATE
+           SET realm = EXCLUDED.realm, ha1_md5 = EXCLUDED.ha1_md5,
+               ha1_sha256 = EXCLUDED.ha1_sha256, updated_at = NOW()
+           WHERE sip_credentials.tenant_id = EXCLUDED.tenant_id"#,
+    )
+    .bind(tenant_id)
+    .bind(user_id)
+    .bind(&state.sip_realm)
+    .bind(&ha1_md5)
+    .bind(&ha1_sha256)
+    .execute(&state.db)
+    .await
+    .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    if result.rows_affected() == 0 {
+        return Err(axum::http::StatusCode::NOT_FOUND);
+    }
+
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+/// Revoke a user's SIP credentials; registered phones fail their next refresh.
+async fn delete_sip_credentials(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    axum::extract::Path((tenant_id, user_id)): axum::extract::Path<(Uuid, Uuid)>,
+) -> Result<axum::http::StatusCode, axum::http::StatusCode> {
+    state.authorize(&headers)?;
+    let result =
+        sqlx::query(r#"DELETE FROM sip_credentials WHERE tenant_id = $1 AND user_id = $2"#)
+            .bind(tenant_id)
+            .bind(user_id)
+            .execute(&state.db)
+            .await
+            .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    if result.rows_affected() == 0 {
+        return Err(axum::http::StatusCode::NOT_FOUND);
+    }
+
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+/// Hashes signaling needs to check a digest response for `user_id`. They are
+/// as good as the password, so only signaling may fetch them.
+async fn sip_credentials(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    axum::extract::Path(user_id): axum::extract::Path<Uuid>,
+) -> Result<Json<SipCredentials>, axum::http::StatusCode> {
+    state.authorize(&headers)?;
+    let row = sqlx::query(
+        r#"SELECT tenant_id, realm, ha1_md5, ha1_sha256 FROM sip_credentials WHERE user_id = $1"#,
+    )
+    .bind(user_id)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(axum::http::StatusCode::NOT_FOUND)?;
+
+    let column = |name: &str| -> Result<String, axum::http::StatusCode> {
+        row.try_get(name)
+            .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+    };
+    Ok(Json(SipCredentials {
+        tenant_id: row
+            .try_get("tenant_id")
+            .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?,
+        user_id,
+        realm: column("realm")?,
+        ha1_md5: column("ha1_md5")?,
+        ha1_sha256: column("ha1_sha256")?,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ConferenceRoomRequest {
+    name: String,
+    #[serde(default)]
+    pin: Option<String>,
+    #[serde(default)]
+    moderator_pin: Option<String>,
+    #[serde(default)]
+    max_participants: Option<i32>,
+}
+
+/// Shortest conference PIN accepted; PINs are typed on a keypad.
+const MIN_CONFERENCE_PIN_LEN: usize = 4;
+
+fn pin_hash(room_id: Uuid, pin: &str) -> String {
+    hex::encode(Sha256::digest(format!("{room_id}:{pin}").as_bytes()))
+}
+
+/// Create or replace a conference room. Leaving out a PIN removes it.
+async fn set_conference_room(
+    State(state): State<AppState>,
+    axum::extract::Path((tenant_id, room_id)): axum::extract::Path<(Uuid, Uuid)>,
+    Json(req): Json<ConferenceRoomRequest>,
+) -> Result<axum::http::StatusCode, axum::http::StatusCode> {
+    let pins = [&req.pin, &req.moderator_pin];
+    if pins
+        .iter()
+        .flat_map(|pin| pin.as_deref())
+        .any(|pin| pin.len() < MIN_CONFERENCE_PIN_LEN || !pin.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return Err(axum::http::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let max_participants = req.max_participants.unwrap_or(50);
+    if max_participants < 2 {
+        return Err(axum::http::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let result = sqlx::query(
+        r#"INSERT INTO conference_rooms (id, tenant_id, name, pin_hash, moderator_pin_hash, max_participants)
+           VALUES ($1, $2, $3, $4, $5, $6)
+           ON CONFLICT (id) DO UPDATE
+           SET name = EXCLUDED.name, pin_hash = EXCLUDED.pin_hash,
+               moderator_pin_hash = EXCLUDED.moderator_pin_hash,
+               max_participants = EXCLUDED.max_participants, updated_at = NOW()
+           WHERE conference_rooms.tenant_id = EXCLUDED.tenant_id"#,
+    )
+    .bind(room_id)
+    .bind(tenant_id)
+    .bind(&req.name)
+    .bind(req.pin.as_deref().map(|pin| pin_hash(room_id, pin)))
+    .bind(req.moderator_pin.as_deref().map(|pin| pin_hash(room_id, pin)))
+    .bind(max_participants)
+    .execute(&state.db)
+    .await
+    .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    if result.rows_affected() == 0 {
+        return Err(axum::http::StatusCode::NOT_FOUND);
+    }
+
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinConferenceRequest {
+    /// The tenant of whoever joins; rooms of other tenants are not found.
+    tenant_id: Uuid,
+    #[serde(default)]
+    pin: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConferenceAccess {
+    tenant_id: Uuid,
+    room_id: Uuid,
+    name: String,
+    moderator: bool,
+    max_participants: i32,
+}
+
+/// Check a PIN for a room of the caller's tenant. The moderator PIN grants
+/// moderation as well as entry; a room without a participant PIN admits
+/// anyone. Only signaling, which limits wrong guesses, may ask.
+async fn join_conference(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    axum::extract::Path(room_id): axum::extract::Path<Uuid>,
+    Json(req): Json<JoinConferenceRequest>,
+) -> Result<Json<ConferenceAccess>, axum::http::StatusCode> {
+    state.authorize(&headers)?;
+    let row = sqlx::query(
+        r#"SELECT tenant_id, name, pin_hash, moderator_pin_hash, max_participants
+           FROM conference_rooms WHERE id = $1 AND tenant_id = $2"#,
+    )
+    .bind(room_id)
+    .bind(req.tenant_id)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(axum::http::StatusCode::NOT_FOUND)?;
+
+    let internal = |_| axum::http::StatusCode::INTERNAL_SERVER_ERROR;
+    let pin_hash_column: Option<String> = row.try_get("pin_hash").map_err(internal)?;
+    let moderator_hash: Option<String> = row.try_get("moderator_pin_hash").map_err(internal)?;
+    let given = req.pin.as_deref().map(|pin| pin_hash(room_id, pin));
+    let matches = |stored: &Option<String>| match (&given, stored) {
+        (Some(given), Some(stored)) => constant_time_eq(given.as_bytes(), stored.as_bytes()),
+        _ => false,
+    };
+    let moderator = matches(&moderator_hash);
+    let admitted = moderator || pin_hash_column.is_none() || matches(&pin_hash_column);
+    if !admitted {
+        return Err(axum::http::StatusCode::FORBIDDEN);
+    }
+
+    Ok(Json(ConferenceAccess {
+        tenant_id: row.try_get("tenant_id").map_err(internal)?,
+        room_id,
+        name: row.try_get("name").map_err(internal)?,
+        moderator,
+        max_participants: row.try_get("max_participants").map_err(internal)?,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordHoldRequest {
+    tenant_id: Uuid,
+    held_by: Uuid,
+    duration_ms: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct CallHold {
+    held_by: Uuid,
+    /// Unix milliseconds.
+    started_at: i64,
+    duration_ms: i64,
+}
+
+/// Store one finished hold interval of a call; it ended just now.
+async fn record_hold(
+    State(state): State<AppState>,
+    axum::extract::Path(call_id): axum::extract::Path<Uuid>,
+    Json(req): Json<RecordHoldRequest>,
+) -> Result<axum::http::StatusCode, axum::http::StatusCode> {
+    if req.duration_ms < 0 {
+        return Err(axum::http::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    sqlx::query(
+        r#"INSERT INTO call_holds (call_id, tenant_id, held_by, started_at, duration_ms)
+           VALUES ($1, $2, $3, NOW() - $4 * INTERVAL '1 millisecond', $4)"#,
+    )
+    .bind(call_id)
+    .bind(req.tenant_id)
+    .bind(req.held_by)
+    .bind(req.duration_ms)
+    .execute(&state.db)
+    .await
+    .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+/// Hold intervals of a call, oldest first.
+async fn call_holds(
+    State(state): State<AppState>,
+    axum::extract::Path(call_id): axum::extract::Path<Uuid>,
+) -> Result<Json<Vec<CallHold>>, axum::http::StatusCode> {
+    let rows = sqlx::query(
+        r#"SELECT held_by, (EXTRACT(EPOCH FROM started_at) * 1000)::BIGINT AS started_at, duration_ms
+           FROM call_holds WHERE call_id = $1 ORDER BY started_at"#,
+    )
+    .bind(call_id)
+    .fetch_all(&state.db)
+    .await
+    .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let holds = rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(CallHold {
+                held_by: row.try_get("held_by").ok()?,
+                started_at: row.try_get("started_at").ok()?,
+                duration_ms: row.try_get("duration_ms").ok()?,
+            })
+        })
+        .collect();
+
+    Ok(Json(holds))
+}
+
+#[derive(Debug, Deserialize)]
+struct PickupGroupRequest {
+    name: String,
+    members: Vec<Uuid>,
+}
+
+/// Create or replace a pickup group. Members are moved out of any group they
+/// were in before. Users known to belong to another tenant, through their
+/// SIP credentials or pickup group, are refused; there is no user directory
+/// here to check the rest against.
+async fn set_pickup_group(
+    State(state): State<AppState>,
+    axum::extract::Path((tenant_id, group_id)): axum::extract::Path<(Uuid, Uuid)>,
+    Json(mut req): Json<PickupGroupRequest>,
+) -> Result<axum::http::StatusCode, axum::http::StatusCode> {
+    let internal = |_| axum::http::StatusCode::INTERNAL_SERVER_ERROR;
+    req.members.sort_unstable();
+    req.members.dedup();
+    let mut tx = state.db.begin().await.map_err(internal)?;
+    let foreign: bool = sqlx::query_scalar(
+        r#"SELECT EXISTS (
+             SELECT 1 FROM sip_credentials WHERE user_id = ANY($1) AND tenant_id <> $2
+             UNION ALL
+             SELECT 1 FROM pickup_group_members WHERE user_id = ANY($1) AND tenant_id <> $2
+           )"#,
+    )
+    .bind(&req.members)
+    .bind(tenant_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(internal)?;
+    if foreign {
+        return Err(axum::http::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let result = sqlx::query(
+        r#"INSERT INTO pickup_groups (id, tenant_id, name)
+           VALUES ($1, $2, $3)
+           ON CONFLICT (id) DO UPDATE
+           SET name = EXCLUDED.name, updated_at = NOW()
+           WHERE pickup_groups.tenant_id = EXCLUDED.tenant_id"#,
+    )
+    .bind(group_id)
+    .bind(tenant_id)
+    .bind(&req.name)
+    .execute(&mut *tx)
+    .await
+    .map_err(internal)?;
+    if result.rows_affected() == 0 {
+        return Err(axum::http::StatusCode::NOT_FOUND);
+    }
+
+    sqlx::query("DELETE FROM pickup_group_members WHERE group_id = $1")
+        .bind(group_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal)?;
+    let added = sqlx::query(
+        r#"INSERT INTO pickup_group_members (user_id, group_id, tenant_id)
+           SELECT UNNEST($1::UUID[]), $2, $3
+           ON CONFLICT (user_id) DO UPDATE SET group_id = EXCLUDED.group_id
+           WHERE pickup_group_members.tenant_id = EXCLUDED.tenant_id"#,
+    )
+    .bind(&req.members)
+    .bind(group_id)
+    .bind(tenant_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(internal)?;
+    // Another tenant claimed one of them since the check above.
+    if added.rows_affected() != req.members.len() as u64 {
+        return Err(axum::http::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    tx.commit().await.map_err(internal)?;
+
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+struct PickupGroup {
+    group_id: Uuid,
+    tenant_id: Uuid,
+    members: Vec<Uuid>,
+}
+
+/// The pickup group `user_id` belongs to, with every member.
+async fn user_pickup_group(
+    State(state): State<AppState>,
+    axum::extract::Path(user_id): axum::extract::Path<Uuid>,
+) -> Result<Json<PickupGroup>, axum::http::StatusCode> {
+    let internal = |_| axum::http::StatusCode::INTERNAL_SERVER_ERROR;
+    let row = sqlx::query(
+        r#"SELECT g.id, g.tenant_id
+           FROM pickup_group_members m JOIN pickup_groups g ON g.id = m.group_id
+           WHERE m.user_id = $1"#,
+    )
+    .bind(user_id)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(internal)?
+    .ok_or(axum::http::StatusCode::NOT_FOUND)?;
+    let group_id: Uuid = row.try_get("id").map_err(internal)?;
+
+    let members =
+        sqlx::query_scalar("SELECT user_id FROM pickup_group_members WHERE group_id = $1")
+            .bind(group_id)
+            .fetch_all(&state.db)
+            .await
+            .map_err(internal)?;
+
+    Ok(Json(PickupGroup {
+        group_id,
+        tenant_id: row.try_get("tenant_id").map_err(internal)?,
+        members,
+    }))
+}
+
 #[tokio::main]
 async fn main() {
     // PBX acts as the source of truth for routing logic. On boot we set up
     // tracing, create a small connection pool, and expose the REST surface that
-    // the builder (and eventually SIP ingress) use to fetch call-flow graphs.
+    // the builder and the signaling SIP ingress use to fetch call-flow graphs.
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
             std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
@@ -173,13 +736,36 @@ async fn main() {
         .await
         .expect("failed to connect db");
 
-    let state = AppState { db: pool };
+    let state = AppState {
+        db: pool,
+        sip_realm: std::env::var("SIP_DOMAIN").unwrap_or_else(|_| "voip.local".to_string()),
+        service_token: std::env::var("PBX_SERVICE_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty()),
+    };
 
     let app = Router::new()
         // CRUD surface consumed by the call-flow builder.
         .route("/health", get(health))
         .route("/flows", get(list_flows).post(create_flow))
         .route("/flows/:tenant_id/:id", put(update_flow))
+        // Number plan: assignment by the builder, lookup by signaling.
+        .route("/numbers/:number", put(assign_number))
+        .route("/numbers/:number/route", get(route_number))
+        // SIP credentials: managed by the admin UI, checked by signaling.
+        .route(
+            "/sip-credentials/:tenant_id/:user_id",
+            put(set_sip_password).delete(delete_sip_credentials),
+        )
+        .route("/sip-credentials/:user_id", get(sip_credentials))
+        // Conference rooms: configured by the admin UI, joined via signaling.
+        .route("/conferences/:tenant_id/:room_id", put(set_conference_room))
+        .route("/conferences/:room_id/join", post(join_conference))
+        // Pickup groups: configured by the admin UI, looked up by signaling.
+        .route("/pickup-groups/:tenant_id/:group_id", put(set_pickup_group))
+        .route("/users/:user_id/pickup-group", get(user_pickup_group))
+        // Call reporting: hold intervals recorded by signaling.
+        .route("/calls/:call_id/holds", post(record_hold).get(call_holds))
         .with_state(state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8081));
@@ -0,0 +1,2536 @@
+//! Call-control routing between connected agents.
+//!
+//! Each call is tracked by the `callId` chosen by the caller's softphone. The
+//! record remembers who is on either end, and which device of each user is
+//! actually taking part, so `signal` frames and hang-ups can be relayed to the
+//! other party without trusting the client to address them.
+//!
+//! The record also owns the call's lifecycle: `initiated → ringing →
+//! connected → ended`, with `failed` for calls that never reached anyone.
+//! Illegal transitions are refused, unanswered calls are ended after the
+//! configured ring timeout, and a socket that drops mid-call ends the call for
+//! the other party instead of leaving it dangling.
+//!
+//! A call lives on the node that received `call.initiate`; commands for it that
+//! arrive on another replica are forwarded there through the [`Cluster`].
+//!
+//! Transfers never move a party between records. A blind transfer rings the
+//! target on a new call placed by the transferee and ends the old call once
+//! the target rings; if nobody can be rung the old call carries on. An
+//! attended transfer joins the transferee and the consulted party on a new,
+//! already connected call. Either way the parties that carry on are told
+//! their new call id through `call.transferred` before the old call ends.
+//!
+//! Either party of a connected call may put it on hold. Both are told the SDP
+//! direction their media should now take, SDP relayed while the call is held
+//! is narrowed to it, and the call's relay (if any) plays music on hold to
+//! the held party. Each hold interval is reported to the PBX once it is over.
+//!
+//! Endpoints that send their RTP through a media relay ask for one with
+//! `call.relay` once the call is connected. The relay is allocated on the
+//! first request and every party is told about it, each with the secret of
+//! its own side only; it is released when the call ends. The SSRC each
+//! party announces in its SDP is passed on to the relay, which then latches
+//! that party's side to that stream only.
+//!
+//! Dialing a number that leads to a conference room joins the room instead
+//! of ringing anyone; rooms are kept in [`conference`].
+//!
+//! A parked call keeps its record, with the parking agent dropped from it
+//! until the call is retrieved or the park timeout rings that agent back.
+//! Retrieving a parked call and picking up one ringing someone else both
+//! take the call over: the waiting party carries on with the agent on a new
+//! call, told through `call.transferred`. Slots and pickup targets are found
+//! in [`park`].
+//!
+//! [`Cluster`]: crate::cluster::Cluster
+
+use crate::conference;
+use crate::media;
+use crate::park;
+use crate::pbx;
+use crate::presence;
+use crate::protocol::{ClientMessage, EndReason, ErrorCode, ParkedCall, ServerMessage};
+use crate::supervise;
+use crate::AppState;
+use sdp::{Direction, SessionDescription};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::RwLock;
+use tokio::task::AbortHandle;
+use uuid::Uuid;
+
+/// Identity of a single websocket: the verified JWT subject plus the registry
+/// id of the device it is connected from and the node holding the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Endpoint {
+    pub tenant_id: Uuid,
+    pub user_id: Uuid,
+    pub connection_id: Uuid,
+    pub node_id: Uuid,
+}
+
+/// Lifecycle of a call. `Ended` and `Failed` are terminal: a call reaching
+/// either is removed from the table straight away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallState {
+    Initiated,
+    Ringing,
+    Connected,
+    Ended,
+    Failed,
+}
+
+impl CallState {
+    fn can_become(self, next: CallState) -> bool {
+        use CallState::*;
+        matches!(
+            (self, next),
+            (Initiated, Ringing | Ended | Failed)
+                | (Ringing, Connected | Ended | Failed)
+                | (Connected, Ended)
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("illegal call transition from {from:?} to {to:?}")]
+pub struct InvalidTransition {
+    pub from: CallState,
+    pub to: CallState,
+}
+
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub tenant_id: Uuid,
+    pub caller: Endpoint,
+    pub callee: Uuid,
+    /// Device that answered; `None` while every callee device is still ringing.
+    pub answered_by: Option<Endpoint>,
+    pub state: CallState,
+    /// No-answer timer, armed while the call is ringing.
+    ring_timer: Option<AbortHandle>,
+    /// Consult call of an attended transfer of this call, placed by the
+    /// same device.
+    pub consult: Option<Uuid>,
+    /// For a consult call, the call it would transfer.
+    pub consult_for: Option<Uuid>,
+    /// Since when the caller has held the call, while it does.
+    caller_hold: Option<Instant>,
+    /// Since when the callee has held the call, while it does.
+    callee_hold: Option<Instant>,
+    /// When the call started ringing, to pick up the longest ringing first.
+    rang_at: Option<Instant>,
+    /// Set while the call waits in a park slot.
+    parked: Option<Parked>,
+    /// The call's media relay, once a party asked for one.
+    relay: Option<Relay>,
+    /// SSRCs the caller and callee announced in their latest SDP.
+    caller_ssrc: Option<u32>,
+    callee_ssrc: Option<u32>,
+    /// Set on the new call of a blind transfer. Its caller was already on a
+    /// call and stays engaged while this one rings.
+    transferred: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Relay {
+    /// Requested from the media service; the parties are told once it is up.
+    Allocating,
+    Allocated(media::Allocation),
+}
+
+#[derive(Debug, Clone)]
+struct Parked {
+    slot: u16,
+    /// The side that parked the call and dropped out of it.
+    by: Side,
+    /// Ring-back timer.
+    timer: Option<AbortHandle>,
+}
+
+/// Which side of a call a frame came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Caller,
+    Callee,
+}
+
+impl Side {
+    fn other(self) -> Side {
+        match self {
+            Side::Caller => Side::Callee,
+            Side::Callee => Side::Caller,
+        }
+    }
+
+    /// The side of the call's media relay carrying this party.
+    fn relay_side(self) -> &'static str {
+        match self {
+            Side::Caller => "a",
+            Side::Callee => "b",
+        }
+    }
+}
+
+impl Call {
+    fn new(caller: Endpoint, callee: Uuid) -> Self {
+        Call {
+            tenant_id: caller.tenant_id,
+            caller,
+            callee,
+            answered_by: None,
+            state: CallState::Initiated,
+            ring_timer: None,
+            consult: None,
+            consult_for: None,
+            caller_hold: None,
+            callee_hold: None,
+            rang_at: None,
+            parked: None,
+            relay: None,
+            caller_ssrc: None,
+            callee_ssrc: None,
+            transferred: false,
+        }
+    }
+
+    pub fn transition(&mut self, next: CallState) -> Result<(), InvalidTransition> {
+        if !self.state.can_become(next) {
+            return Err(InvalidTransition {
+                from: self.state,
+                to: next,
+            });
+        }
+        self.state = next;
+        if next != CallState::Ringing {
+            if let Some(timer) = self.ring_timer.take() {
+                timer.abort();
+            }
+        }
+        Ok(())
+    }
+
+    /// Work out which side `from` is on. Once a call is answered only the
+    /// answering device may act for the callee; before that any of the callee's
+    /// devices may decline it. Whoever parked the call is no longer on it.
+    fn side_of(&self, from: &Endpoint) -> Option<Side> {
+        if from.tenant_id != self.tenant_id {
+            return None;
+        }
+        let side = if *from == self.caller {
+            Side::Caller
+        } else if from.user_id == self.callee {
+            match self.answered_by {
+                Some(answered_by) if answered_by != *from => return None,
+                _ => Side::Callee,
+            }
+        } else {
+            return None;
+        };
+        match &self.parked {
+            Some(parked) if parked.by == side => None,
+            _ => Some(side),
+        }
+    }
+
+    fn hold_mut(&mut self, side: Side) -> &mut Option<Instant> {
+        match side {
+            Side::Caller => &mut self.caller_hold,
+            Side::Callee => &mut self.callee_hold,
+        }
+    }
+
+    fn ssrc_mut(&mut self, side: Side) -> &mut Option<u32> {
+        match side {
+            Side::Caller => &mut self.caller_ssrc,
+            Side::Callee => &mut self.callee_ssrc,
+        }
+    }
+
+    fn holds(&self, side: Side) -> bool {
+        match side {
+            Side::Caller => self.caller_hold.is_some(),
+            Side::Callee => self.callee_hold.is_some(),
+        }
+    }
+
+    /// The media direction `side` may use given who holds the call: the
+    /// holder stops receiving, the held party stops sending.
+    fn media_direction(&self, side: Side) -> Direction {
+        Direction::from_flags(!self.holds(side.other()), !self.holds(side))
+    }
+
+    /// Whether `user_id` takes part in the call: it is connected and they
+    /// did not park it, or they were transferred onto it and it still rings.
+    fn engages(&self, user_id: Uuid) -> bool {
+        match self.state {
+            CallState::Connected => [Side::Caller, Side::Callee].into_iter().any(|side| {
+                let user = match side {
+                    Side::Caller => self.caller.user_id,
+                    Side::Callee => self.callee,
+                };
+                user == user_id && !matches!(&self.parked, Some(parked) if parked.by == side)
+            }),
+            CallState::Initiated | CallState::Ringing => {
+                self.transferred && self.caller.user_id == user_id
+            }
+            CallState::Ended | CallState::Failed => false,
+        }
+    }
+
+    /// The device taking part for `side`, once there is a single one.
+    fn endpoint(&self, side: Side) -> Option<Endpoint> {
+        match side {
+            Side::Caller => Some(self.caller),
+            Side::Callee => self.answered_by,
+        }
+    }
+}
+
+pub type Calls = Arc<RwLock<HashMap<Uuid, Call>>>;
+
+/// Work carried out on behalf of an endpoint against a call. Commands travel
+/// between replicas so they are always applied on the node owning the call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    Client {
+        msg: ClientMessage,
+    },
+    /// The endpoint's socket closed while it was party to `call_id`.
+    Disconnected {
+        call_id: Uuid,
+    },
+    /// The endpoint retrieves or picks up `call_id`, carrying on with it as
+    /// `new_call_id`.
+    TakeOver {
+        call_id: Uuid,
+        new_call_id: Uuid,
+    },
+}
+
+impl Command {
+    pub fn call_id(&self) -> Option<Uuid> {
+        match self {
+            Command::Client { msg } => msg.existing_call_id(),
+            Command::Disconnected { call_id } | Command::TakeOver { call_id, .. } => Some(*call_id),
+        }
+    }
+}
+
+/// Keep a socket's view of the calls it takes part in up to date, based on
+/// the frames it is sent. Used to clean up after the socket drops.
+pub fn track_outbound(active: &mut HashSet<Uuid>, msg: &ServerMessage) {
+    match msg {
+        ServerMessage::Incoming { call_id, .. }
+        | ServerMessage::Ringing { call_id }
+        | ServerMessage::Connected { call_id }
+        | ServerMessage::Transferred {
+            new_call_id: call_id,
+            ..
+        } => {
+            active.insert(*call_id);
+        }
+        ServerMessage::Ended { call_id, .. }
+        | ServerMessage::Error {
+            call_id: Some(call_id),
+            ..
+        } => {
+            active.remove(call_id);
+        }
+        _ => {}
+    }
+}
+
+/// Deliver a frame to whoever represents `side` in the call: the bound device
+/// if there is one, otherwise every device of that user.
+async fn send_to_side(state: &AppState, call: &Call, side: Side, msg: ServerMessage) {
+    let cluster = &state.cluster;
+    match side {
+        Side::Caller => {
+            cluster.send_to(call.caller, msg).await;
+        }
+        Side::Callee => match call.answered_by {
+            Some(endpoint) => {
+                cluster.send_to(endpoint, msg).await;
+            }
+            None => {
+                cluster
+                    .send_to_user(call.tenant_id, call.callee, None, &msg)
+                    .await;
+            }
+        },
+    }
+}
+
+/// Entry point for frames read from a local socket.
+pub async fn handle_client_message(state: &AppState, from: Endpoint, msg: ClientMessage) {
+    dispatch(state, from, Command::Client { msg }).await;
+}
+
+/// Entry point for a local socket that closed while party to `call_ids`.
+pub async fn handle_disconnect(state: &AppState, from: Endpoint, call_ids: HashSet<Uuid>) {
+    for call_id in call_ids {
+        dispatch(state, from, Command::Disconnected { call_id }).await;
+    }
+}
+
+/// Forward commands for calls owned by another replica; apply everything else
+/// here.
+async fn dispatch(state: &AppState, from: Endpoint, command: Command) {
+    if let Some(call_id) = command.call_id() {
+        if !is_local(state, call_id).await {
+            if let Some(owner) = state.cluster.call_owner(call_id).await {
+                if owner != state.cluster.node_id
+                    && state.cluster.forward(owner, from, command.clone()).await
+                {
+                    return;
+                }
+            }
+        }
+    }
+    apply(state, from, command).await;
+}
+
+/// Whether `call_id` is a call, conference or supervision held on this node.
+pub async fn is_local(state: &AppState, call_id: Uuid) -> bool {
+    state.calls.read().await.contains_key(&call_id)
+        || state.conferences.read().await.contains(call_id)
+        || state.supervisions.read().await.contains(call_id)
+}
+
+/// Apply a command to the local call table.
+pub async fn apply(state: &AppState, from: Endpoint, command: Command) {
+    match command {
+        Command::Client { msg } => apply_client_message(state, from, msg).await,
+        Command::Disconnected { call_id } => disconnected(state, from, call_id).await,
+        Command::TakeOver {
+            call_id,
+            new_call_id,
+        } => apply_take_over(state, from, call_id, new_call_id).await,
+    }
+}
+
+/// Apply a single client frame to the local call table and fan out the
+/// resulting events. Errors are reported back to the originating device as
+/// `call.error` frames.
+async fn apply_client_message(state: &AppState, from: Endpoint, msg: ClientMessage) {
+    match msg {
+        ClientMessage::Initiate { call_id, to } => initiate(state, from, call_id, &to).await,
+        ClientMessage::Answer { call_id } => answer(state, from, call_id).await,
+        ClientMessage::Ended { call_id } => {
+            if conference::hang_up(state, from, call_id).await
+                || supervise::hang_up(state, from, call_id).await
+            {
+                return;
+            }
+            let call = state.calls.read().await.get(&call_id).cloned();
+            // Hanging up an unknown call is harmless (both sides may race to end it).
+            let Some((call, side)) = call.and_then(|call| call.side_of(&from).map(|s| (call, s)))
+            else {
+                return;
+            };
+            let reason = match (call.state, side) {
+                (CallState::Connected, _) => EndReason::Hangup,
+                (_, Side::Caller) => EndReason::Cancelled,
+                (_, Side::Callee) => EndReason::Declined,
+            };
+            finish(state, call_id, reason, Some(from)).await;
+        }
+        ClientMessage::Signal { call_id, data } => {
+            let routed = state
+                .calls
+                .read()
+                .await
+                .get(&call_id)
+                .and_then(|call| call.side_of(&from).map(|side| (call.clone(), side)));
+            if routed.is_none()
+                && (conference::signal(state, from, call_id, &data).await
+                    || supervise::signal(state, from, call_id, &data).await)
+            {
+                return;
+            }
+            let mut data = data;
+            if let Some((call, side)) = &routed {
+                // Nobody is listening on the parking agent's side.
+                if call.parked.is_some() {
+                    return;
+                }
+                if call.caller_hold.is_some() || call.callee_hold.is_some() {
+                    restrict_direction(&mut data, call.media_direction(*side));
+                }
+            }
+            let announced = announced_ssrc(&data);
+            let msg = ServerMessage::Signal { call_id, data };
+            match routed {
+                Some((call, side)) => {
+                    send_to_side(state, &call, side.other(), msg).await;
+                    if let Some(ssrc) = announced {
+                        announce_ssrc(state, call_id, side, ssrc).await;
+                    }
+                }
+                None => {
+                    let err =
+                        ServerMessage::error(Some(call_id), ErrorCode::UnknownCall, "unknown call");
+                    reply(state, from, err).await;
+                }
+            }
+        }
+        ClientMessage::Transfer { call_id, to } => transfer(state, from, call_id, &to).await,
+        ClientMessage::Consult {
+            call_id,
+            consult_call_id,
+            to,
+        } => consult(state, from, call_id, consult_call_id, &to).await,
+        ClientMessage::CompleteTransfer {
+            call_id,
+            consult_call_id,
+        } => complete_transfer(state, from, call_id, consult_call_id).await,
+        ClientMessage::CancelTransfer { call_id } => cancel_transfer(state, from, call_id).await,
+        ClientMessage::Hold { call_id } => hold(state, from, call_id, true).await,
+        ClientMessage::Resume { call_id } => hold(state, from, call_id, false).await,
+        ClientMessage::Relay { call_id } => relay(state, from, call_id).await,
+        ClientMessage::Park { call_id, slot } => park(state, from, call_id, slot).await,
+        ClientMessage::Retrieve { slot, call_id } => {
+            park::retrieve(state, from, slot, call_id).await
+        }
+        ClientMessage::Pickup { call_id, user_id } => {
+            park::pickup(state, from, call_id, user_id).await;
+        }
+        ClientMessage::ConferenceJoin {
+            conference_id,
+            call_id,
+            pin,
+        } => conference::join(state, from, conference_id, call_id, pin).await,
+        ClientMessage::ConferenceInvite { conference_id, to } => {
+            conference::invite(state, from, conference_id, &to).await;
+        }
+        ClientMessage::ConferenceMute {
+            conference_id,
+            call_id,
+            muted,
+        } => conference::mute(state, from, conference_id, call_id, muted).await,
+        ClientMessage::ConferenceKick {
+            conference_id,
+            call_id,
+        } => conference::kick(state, from, conference_id, call_id).await,
+        ClientMessage::ConferenceLock {
+            conference_id,
+            locked,
+        } => conference::lock(state, from, conference_id, locked).await,
+        ClientMessage::Supervise {
+            call_id,
+            leg_id,
+            agent_id,
+            mode,
+        } => supervise::start(state, from, call_id, leg_id, agent_id, mode).await,
+        ClientMessage::SetPresence { status, note } => {
+            presence::set_from_client(state, from, status, note).await;
+        }
+        // Credentials belong to the socket and are handled in its own loop.
+        ClientMessage::RefreshToken { .. } => {}
+    }
+}
+
+/// Where a dialed destination leads.
+enum Destination {
+    User(Uuid),
+    Conference { room_id: Uuid, pin: Option<String> },
+}
+
+/// Turn a dialed destination into the user to ring or conference to join:
+/// user ids are used as-is, anything else is looked up in the PBX number
+/// plan. Keypads dial a conference PIN after a pause, as `<number>,<pin>`.
+async fn resolve_callee(
+    state: &AppState,
+    from: Endpoint,
+    to: &str,
+) -> Result<Destination, ErrorCode> {
+    let to = to.trim();
+    if let Ok(user_id) = to.parse::<Uuid>() {
+        return Ok(Destination::User(user_id));
+    }
+    let (number, pin) = match to.split_once(',') {
+        Some((number, pin)) => {
+            let pin = pin.trim_matches(|c| c == ',' || c == '#');
+            (number, (!pin.is_empty()).then(|| pin.to_string()))
+        }
+        None => (to, None),
+    };
+    let route = match state.pbx.resolve(number).await {
+        Ok(route) => route,
+        Err(err) => {
+            tracing::warn!(error = %err, "pbx number lookup failed");
+            return Err(ErrorCode::Unavailable);
+        }
+    };
+    match route {
+        // Numbers of other tenants are indistinguishable from unknown ones.
+        Some(route) if route.tenant_id == from.tenant_id => match route.destination {
+            Some(pbx::Destination::User { user_id }) => Ok(Destination::User(user_id)),
+            Some(pbx::Destination::Conference { room_id }) => {
+                Ok(Destination::Conference { room_id, pin })
+            }
+            None => {
+                tracing::info!(flow_id = %route.flow_id, "call-flow has no routable entry node");
+                Err(ErrorCode::Unavailable)
+            }
+        },
+        _ => Err(ErrorCode::InvalidDestination),
+    }
+}
+
+/// Resolve the destination of a call `from` wants to place, with the error to
+/// report when it cannot be reached.
+async fn resolve_destination(
+    state: &AppState,
+    from: Endpoint,
+    to: &str,
+) -> Result<Destination, (ErrorCode, &'static str)> {
+    let destination = resolve_callee(state, from, to).await.map_err(|code| {
+        let message = match code {
+            ErrorCode::InvalidDestination => "unknown destination",
+            _ => "destination unavailable",
+        };
+        (code, message)
+    })?;
+    match destination {
+        Destination::User(callee) if callee == from.user_id => {
+            Err((ErrorCode::InvalidDestination, "cannot call yourself"))
+        }
+        destination => Ok(destination),
+    }
+}
+
+/// Resolve a destination that has to be a person, such as a transfer target.
+pub(crate) async fn resolve_user(
+    state: &AppState,
+    from: Endpoint,
+    to: &str,
+) -> Result<Uuid, (ErrorCode, &'static str)> {
+    match resolve_destination(state, from, to).await? {
+        Destination::User(user_id) => Ok(user_id),
+        Destination::Conference { .. } => {
+            Err((ErrorCode::InvalidDestination, "destination is a conference"))
+        }
+    }
+}
+
+async fn initiate(state: &AppState, from: Endpoint, call_id: Uuid, to: &str) {
+    let placed = match resolve_destination(state, from, to).await {
+        Ok(Destination::User(callee)) => ring(state, call_id, Call::new(from, callee), None).await,
+        // Joined on whichever replica the room lives on.
+        Ok(Destination::Conference { room_id, pin }) => {
+            let msg = ClientMessage::ConferenceJoin {
+                conference_id: room_id,
+                call_id,
+                pin,
+            };
+            Box::pin(dispatch(state, from, Command::Client { msg })).await;
+            Ok(())
+        }
+        Err(err) => Err(err),
+    };
+    if let Err((code, message)) = placed {
+        let err = ServerMessage::error(Some(call_id), code, message);
+        reply(state, from, err).await;
+    }
+}
+
+/// The call a party carries on from when [`ring`] moves them onto a new one.
+#[derive(Debug, Clone, Copy)]
+struct Moved {
+    call_id: Uuid,
+    /// Who transferred the party, for the callee's `call.incoming`.
+    by: Option<Uuid>,
+}
+
+/// Record a new call and ring every device of its callee. A caller `moved`
+/// onto the call is told of it through `call.transferred` once it rings.
+///
+/// Returns why the call could not be placed; the caller has not been told,
+/// and nothing is left of the call.
+async fn ring(
+    state: &AppState,
+    call_id: Uuid,
+    mut call: Call,
+    moved: Option<Moved>,
+) -> Result<(), (ErrorCode, &'static str)> {
+    let (from, callee) = (call.caller, call.callee);
+    call.transferred = moved.is_some();
+    {
+        let mut calls = state.calls.write().await;
+        if calls.contains_key(&call_id) {
+            return Err((ErrorCode::DuplicateCall, "duplicate call id"));
+        }
+        calls.insert(call_id, call);
+    }
+    // Ids are only checked against this node's table above; another node may
+    // hold a call by the same id.
+    if !state.cluster.claim_call(call_id).await {
+        state.calls.write().await.remove(&call_id);
+        return Err((ErrorCode::DuplicateCall, "duplicate call id"));
+    }
+
+    // Agents in do-not-disturb are never offered calls.
+    let callee_presence = state.presence.get(from.tenant_id, callee).await;
+    if callee_presence.is_some_and(|p| !p.status.accepts_calls()) {
+        fail(state, call_id).await;
+        return Err((ErrorCode::DoNotDisturb, "user does not accept calls"));
+    }
+
+    // Move to ringing (and arm the no-answer timer) before anyone can see the
+    // call, so an answer racing the rest of this function is always legal.
+    {
+        let mut calls = state.calls.write().await;
+        // Only a hang-up already racing this function gets here first.
+        let Some(call) = calls.get_mut(&call_id) else {
+            return Ok(());
+        };
+        if let Err(err) = call.transition(CallState::Ringing) {
+            tracing::warn!(%call_id, error = %err, "cannot ring call");
+            return Ok(());
+        }
+        let timer_state = state.clone();
+        let ring_timeout = state.ring_timeout;
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(ring_timeout).await;
+            ring_timeout_elapsed(&timer_state, call_id).await;
+        });
+        call.ring_timer = Some(timer.abort_handle());
+        call.rang_at = Some(Instant::now());
+    }
+    state
+        .cluster
+        .set_ringing(from.tenant_id, callee, call_id, true)
+        .await;
+
+    // Lookups are always scoped to the caller's tenant so agents can never
+    // reach users of another customer, even with a guessed id. Every device
+    // the callee is signed in on rings at once, on any node.
+    let incoming = ServerMessage::Incoming {
+        call_id,
+        from: from.user_id,
+        transferred_by: moved.and_then(|moved| moved.by),
+    };
+    let rung = state
+        .cluster
+        .send_to_user(from.tenant_id, callee, None, &incoming)
+        .await;
+    if rung == 0 {
+        fail(state, call_id).await;
+        return Err((ErrorCode::Unavailable, "user unavailable"));
+    }
+    if let Some(moved) = moved {
+        let msg = ServerMessage::Transferred {
+            call_id: moved.call_id,
+            new_call_id: call_id,
+            peer: callee,
+            caller: true,
+        };
+        reply(state, from, msg).await;
+    }
+    reply(state, from, ServerMessage::Ringing { call_id }).await;
+    Ok(())
+}
+
+async fn answer(state: &AppState, from: Endpoint, call_id: Uuid) {
+    let answered = {
+        let mut calls = state.calls.write().await;
+        match calls.get_mut(&call_id) {
+            Some(call) if call.side_of(&from) == Some(Side::Callee) => {
+                match call.transition(CallState::Connected) {
+                    Ok(()) => {
+                        call.answered_by = Some(from);
+                        Ok(call.clone())
+                    }
+                    Err(err) => Err((ErrorCode::InvalidState, err.to_string())),
+                }
+            }
+            _ => Err((ErrorCode::UnknownCall, "unknown call".to_string())),
+        }
+    };
+    let call = match answered {
+        Ok(call) => call,
+        Err((code, message)) => {
+            reply(
+                state,
+                from,
+                ServerMessage::error(Some(call_id), code, message),
+            )
+            .await;
+            return;
+        }
+    };
+    state
+        .cluster
+        .set_ringing(call.tenant_id, call.callee, call_id, false)
+        .await;
+
+    // Stop the phone ringing on every other device of the callee.
+    let elsewhere = ServerMessage::Ended {
+        call_id,
+        reason: EndReason::AnsweredElsewhere,
+    };
+    state
+        .cluster
+        .send_to_user(call.tenant_id, call.callee, Some(from), &elsewhere)
+        .await;
+    send_to_side(
+        state,
+        &call,
+        Side::Caller,
+        ServerMessage::Connected { call_id },
+    )
+    .await;
+    reply(state, from, ServerMessage::Connected { call_id }).await;
+    presence::set_on_call(
+        state,
+        call.tenant_id,
+        &[call.caller.user_id, call.callee],
+        true,
+    )
+    .await;
+}
+
+/// Tell `to` that a transfer of `call_id` did not happen.
+async fn transfer_failed(
+    state: &AppState,
+    to: Endpoint,
+    call_id: Uuid,
+    code: ErrorCode,
+    message: &str,
+) {
+    let msg = ServerMessage::TransferFailed {
+        call_id,
+        code,
+        message: message.to_string(),
+    };
+    reply(state, to, msg).await;
+}
+
+/// The connected call `from` may transfer or park, and the side `from` is on.
+async fn transferable(state: &AppState, from: Endpoint, call_id: Uuid) -> Option<(Call, Side)> {
+    let call = state.calls.read().await.get(&call_id).cloned();
+    let Some((call, side)) = call.and_then(|call| call.side_of(&from).map(|side| (call, side)))
+    else {
+        transfer_failed(state, from, call_id, ErrorCode::UnknownCall, "unknown call").await;
+        return None;
+    };
+    if call.state != CallState::Connected {
+        let message = "only connected calls can be transferred";
+        transfer_failed(state, from, call_id, ErrorCode::InvalidState, message).await;
+        return None;
+    }
+    if call.parked.is_some() {
+        let message = "the call is parked";
+        transfer_failed(state, from, call_id, ErrorCode::InvalidState, message).await;
+        return None;
+    }
+    Some((call, side))
+}
+
+async fn transfer(state: &AppState, from: Endpoint, call_id: Uuid, to: &str) {
+    let Some((call, side)) = transferable(state, from, call_id).await else {
+        return;
+    };
+    let transferee = call
+        .endpoint(side.other())
+        .expect("connected calls have both ends");
+    let target = match resolve_user(state, from, to).await {
+        Ok(target) if target == transferee.user_id => {
+            let message = "cannot transfer a party to themselves";
+            transfer_failed(state, from, call_id, ErrorCode::InvalidDestination, message).await;
+            return;
+        }
+        Ok(target) => target,
+        Err((code, message)) => {
+            transfer_failed(state, from, call_id, code, message).await;
+            return;
+        }
+    };
+
+    let new_call_id = Uuid::new_v4();
+    tracing::info!(%call_id, %new_call_id, "blind transfer");
+    // Ring the new call before ending the old one, so the transferee never
+    // looks free to take another call in between, and keeps the old one if
+    // the target cannot be rung.
+    let moved = Moved {
+        call_id,
+        by: Some(from.user_id),
+    };
+    let call = Call::new(transferee, target);
+    match ring(state, new_call_id, call, Some(moved)).await {
+        Ok(()) => finish(state, call_id, EndReason::Transferred, None).await,
+        Err((code, message)) => transfer_failed(state, from, call_id, code, message).await,
+    }
+}
+
+async fn consult(state: &AppState, from: Endpoint, call_id: Uuid, consult_call_id: Uuid, to: &str) {
+    let Some((call, _)) = transferable(state, from, call_id).await else {
+        return;
+    };
+    if call.consult.is_some() {
+        let message = "a consultation is already in progress";
+        transfer_failed(state, from, call_id, ErrorCode::InvalidState, message).await;
+        return;
+    }
+    let target = match resolve_user(state, from, to).await {
+        Ok(target) => target,
+        Err((code, message)) => {
+            let err = ServerMessage::error(Some(consult_call_id), code, message);
+            reply(state, from, err).await;
+            return;
+        }
+    };
+
+    let mut consult = Call::new(from, target);
+    consult.consult_for = Some(call_id);
+    if let Err((code, message)) = ring(state, consult_call_id, consult, None).await {
+        let err = ServerMessage::error(Some(consult_call_id), code, message);
+        reply(state, from, err).await;
+        return;
+    }
+    // Link the calls unless the consultation already ended.
+    let mut calls = state.calls.write().await;
+    let placed = calls
+        .get(&consult_call_id)
+        .is_some_and(|c| c.consult_for == Some(call_id));
+    if let (true, Some(call)) = (placed, calls.get_mut(&call_id)) {
+        call.consult = Some(consult_call_id);
+    }
+}
+
+/// Join the other party of `call_id` with the other party of the consult
+/// call on a new call, and end both originals. Both calls must live on this
+/// node, which is always true for consultations started with `call.consult`.
+async fn complete_transfer(
+    state: &AppState,
+    from: Endpoint,
+    call_id: Uuid,
+    consult_call_id: Option<Uuid>,
+) {
+    let Some((call, side)) = transferable(state, from, call_id).await else {
+        return;
+    };
+    let Some(consult_call_id) = consult_call_id.or(call.consult) else {
+        let message = "no consultation to complete";
+        transfer_failed(state, from, call_id, ErrorCode::InvalidState, message).await;
+        return;
+    };
+    let consult = state
+        .calls
+        .read()
+        .await
+        .get(&consult_call_id)
+        .and_then(|c| c.side_of(&from).map(|side| (c.clone(), side)));
+    let target = match consult {
+        Some((consult, consult_side)) if consult.state == CallState::Connected => consult
+            .endpoint(consult_side.other())
+            .expect("connected calls have both ends"),
+        Some(_) => {
+            let message = "the consult call is not connected";
+            transfer_failed(state, from, call_id, ErrorCode::InvalidState, message).await;
+            return;
+        }
+        None => {
+            let message = "unknown consult call";
+            transfer_failed(state, from, call_id, ErrorCode::UnknownCall, message).await;
+            return;
+        }
+    };
+    let transferee = call
+        .endpoint(side.other())
+        .expect("connected calls have both ends");
+    if target.user_id == transferee.user_id {
+        let message = "cannot transfer a party to themselves";
+        transfer_failed(state, from, call_id, ErrorCode::InvalidDestination, message).await;
+        return;
+    }
+
+    let new_call_id = Uuid::new_v4();
+    tracing::info!(%call_id, %consult_call_id, %new_call_id, "attended transfer");
+    let mut joined = Call::new(transferee, target.user_id);
+    joined.answered_by = Some(target);
+    joined.state = CallState::Connected;
+    state.calls.write().await.insert(new_call_id, joined);
+    state.cluster.claim_call(new_call_id).await;
+
+    let to_transferee = ServerMessage::Transferred {
+        call_id,
+        new_call_id,
+        peer: target.user_id,
+        caller: true,
+    };
+    let to_target = ServerMessage::Transferred {
+        call_id: consult_call_id,
+        new_call_id,
+        peer: transferee.user_id,
+        caller: false,
+    };
+    reply(state, transferee, to_transferee).await;
+    reply(state, target, to_target).await;
+    finish(state, call_id, EndReason::Transferred, None).await;
+    finish(state, consult_call_id, EndReason::Transferred, None).await;
+}
+
+/// Hang up the consult call of `call_id`, telling every party of it.
+async fn cancel_transfer(state: &AppState, from: Endpoint, call_id: Uuid) {
+    let consult = state
+        .calls
+        .read()
+        .await
+        .get(&call_id)
+        .filter(|call| call.side_of(&from).is_some())
+        .map(|call| call.consult);
+    let consult_call_id = match consult {
+        Some(Some(consult_call_id)) => consult_call_id,
+        Some(None) => {
+            let message = "no consultation in progress";
+            transfer_failed(state, from, call_id, ErrorCode::InvalidState, message).await;
+            return;
+        }
+        None => {
+            transfer_failed(state, from, call_id, ErrorCode::UnknownCall, "unknown call").await;
+            return;
+        }
+    };
+    let connected = state
+        .calls
+        .read()
+        .await
+        .get(&consult_call_id)
+        .map(|c| c.state == CallState::Connected);
+    let reason = match connected {
+        Some(true) => EndReason::Hangup,
+        Some(false) => EndReason::Cancelled,
+        None => return,
+    };
+    finish(state, consult_call_id, reason, None).await;
+}
+
+/// Park `call_id` for `from` in `slot`, or the lowest free slot.
+async fn park(state: &AppState, from: Endpoint, call_id: Uuid, slot: Option<u16>) {
+    let Some((call, side)) = transferable(state, from, call_id).await else {
+        return;
+    };
+    if call.consult.is_some() || call.consult_for.is_some() {
+        let message = "cannot park a call during a consultation";
+        transfer_failed(state, from, call_id, ErrorCode::InvalidState, message).await;
+        return;
+    }
+    let slot = match park::take_slot(state, from.tenant_id, slot, call_id).await {
+        Ok(slot) => slot,
+        Err((code, message)) => {
+            transfer_failed(state, from, call_id, code, message).await;
+            return;
+        }
+    };
+
+    let parked = {
+        let mut calls = state.calls.write().await;
+        match calls.get_mut(&call_id) {
+            Some(call) if call.side_of(&from) == Some(side) => {
+                let timer_state = state.clone();
+                let park_timeout = state.park_timeout;
+                let timer = tokio::spawn(async move {
+                    tokio::time::sleep(park_timeout).await;
+                    park_timeout_elapsed(&timer_state, call_id).await;
+                });
+                call.parked = Some(Parked {
+                    slot,
+                    by: side,
+                    timer: Some(timer.abort_handle()),
+                });
+                // The parked party is held by whoever parked it until the
+                // call is taken over.
+                let newly_held = call.hold_mut(side).is_none();
+                if newly_held {
+                    *call.hold_mut(side) = Some(Instant::now());
+                }
+                Some((call.clone(), newly_held))
+            }
+            _ => None,
+        }
+    };
+    let Some((call, newly_held)) = parked else {
+        // The call ended meanwhile.
+        park::vacate(state, from.tenant_id, slot).await;
+        return;
+    };
+    let party = call
+        .endpoint(side.other())
+        .expect("connected calls have both ends");
+    tracing::info!(%call_id, slot, "call parked");
+
+    if let Err(err) = state
+        .media
+        .hold(call_id, side.other().relay_side(), true)
+        .await
+    {
+        tracing::warn!(%call_id, error = %err, "media relay did not take the hold");
+    }
+    if newly_held {
+        let held = ServerMessage::Held {
+            call_id,
+            by: from.user_id,
+            direction: call.media_direction(side.other()).as_str().to_string(),
+        };
+        reply(state, party, held).await;
+    }
+    let ended = ServerMessage::Ended {
+        call_id,
+        reason: EndReason::Parked,
+    };
+    reply(state, from, ended).await;
+    leave_call(state, from.tenant_id, &[from.user_id]).await;
+
+    let parked = ParkedCall {
+        call_id,
+        party: party.user_id,
+        parked_by: from.user_id,
+    };
+    park::announce(state, from.tenant_id, slot, Some(parked)).await;
+}
+
+/// Nobody retrieved a parked call in time: ring whoever parked it, on a new
+/// call placed by the parked party. With nobody to ring, the call ends.
+async fn park_timeout_elapsed(state: &AppState, call_id: Uuid) {
+    let parked = {
+        let mut calls = state.calls.write().await;
+        calls.get_mut(&call_id).and_then(|call| {
+            let parked = call.parked.as_mut()?;
+            // We are the timer task; make sure finishing does not abort us.
+            parked.timer = None;
+            let by = parked.by;
+            Some((call.clone(), by))
+        })
+    };
+    let Some((call, by)) = parked else {
+        return;
+    };
+    let [parker, party] =
+        [by, by.other()].map(|side| call.endpoint(side).expect("parked calls are connected"));
+    let parker = parker.user_id;
+
+    let new_call_id = Uuid::new_v4();
+    tracing::info!(%call_id, %new_call_id, "parked call not retrieved in time");
+    // As after a blind transfer, the parked party stays on call while the
+    // ring-back rings.
+    let moved = Moved { call_id, by: None };
+    let reason = match ring(state, new_call_id, Call::new(party, parker), Some(moved)).await {
+        Ok(()) => EndReason::Transferred,
+        Err(_) => EndReason::NoAnswer,
+    };
+    finish(state, call_id, reason, None).await;
+}
+
+/// Take over `call_id`, parked or ringing someone else, as `new_call_id`
+/// on whichever replica owns it.
+pub(crate) async fn take_over(state: &AppState, from: Endpoint, call_id: Uuid, new_call_id: Uuid) {
+    let command = Command::TakeOver {
+        call_id,
+        new_call_id,
+    };
+    Box::pin(dispatch(state, from, command)).await;
+}
+
+/// The call that has been ringing one of `users` longest on this node.
+pub(crate) async fn longest_ringing(
+    state: &AppState,
+    tenant_id: Uuid,
+    users: &[Uuid],
+) -> Option<Uuid> {
+    state
+        .calls
+        .read()
+        .await
+        .iter()
+        .filter(|(_, call)| {
+            call.tenant_id == tenant_id
+                && call.state == CallState::Ringing
+                && users.contains(&call.callee)
+        })
+        .min_by_key(|(_, call)| call.rang_at)
+        .map(|(call_id, _)| *call_id)
+}
+
+/// Who `from` carries on with when taking over `call`, and why the call then
+/// ends for everyone else.
+fn take_over_party(
+    call: &Call,
+    from: &Endpoint,
+) -> Result<(Endpoint, EndReason), (ErrorCode, &'static str)> {
+    if call.tenant_id != from.tenant_id {
+        return Err((ErrorCode::UnknownCall, "unknown call"));
+    }
+    match &call.parked {
+        Some(parked) => {
+            let party = call
+                .endpoint(parked.by.other())
+                .expect("parked calls are connected");
+            if party.user_id == from.user_id {
+                return Err((
+                    ErrorCode::InvalidDestination,
+                    "cannot retrieve your own call",
+                ));
+            }
+            Ok((party, EndReason::Transferred))
+        }
+        None if call.state == CallState::Ringing => {
+            if [call.caller.user_id, call.callee].contains(&from.user_id) {
+                return Err((
+                    ErrorCode::InvalidDestination,
+                    "cannot pick up your own call",
+                ));
+            }
+            Ok((call.caller, EndReason::AnsweredElsewhere))
+        }
+        None => Err((ErrorCode::InvalidState, "the call is no longer waiting")),
+    }
+}
+
+/// Join `from` with the party waiting on `call_id` on a new, already
+/// connected call, `new_call_id`, and end the original.
+async fn apply_take_over(state: &AppState, from: Endpoint, call_id: Uuid, new_call_id: Uuid) {
+    if !state.cluster.claim_call(new_call_id).await {
+        let err = ServerMessage::error(
+            Some(new_call_id),
+            ErrorCode::DuplicateCall,
+            "duplicate call id",
+        );
+        reply(state, from, err).await;
+        return;
+    }
+    // Settled under one lock, so a racing answer or a second pickup loses.
+    let taken = {
+        let mut calls = state.calls.write().await;
+        if calls.contains_key(&new_call_id) {
+            Err((ErrorCode::DuplicateCall, "duplicate call id"))
+        } else {
+            match calls.get(&call_id) {
+                Some(call) => take_over_party(call, &from),
+                None => Err((ErrorCode::UnknownCall, "unknown call")),
+            }
+            .map(|(party, reason)| {
+                let original = unlink(&mut calls, call_id).expect("call looked up above");
+                let mut joined = Call::new(party, from.user_id);
+                joined.answered_by = Some(from);
+                joined.state = CallState::Connected;
+                calls.insert(new_call_id, joined);
+                (original, party, reason)
+            })
+        }
+    };
+    let (original, party, reason) = match taken {
+        Ok(taken) => taken,
+        Err((code, message)) => {
+            state.cluster.release_call(new_call_id).await;
+            let err = ServerMessage::error(Some(new_call_id), code, message);
+            reply(state, from, err).await;
+            return;
+        }
+    };
+    released(state, call_id, &original).await;
+    tracing::info!(%call_id, %new_call_id, parked = original.parked.is_some(), "call taken over");
+
+    let moved = ServerMessage::Transferred {
+        call_id,
+        new_call_id,
+        peer: from.user_id,
+        caller: true,
+    };
+    reply(state, party, moved).await;
+    reply(
+        state,
+        from,
+        ServerMessage::Connected {
+            call_id: new_call_id,
+        },
+    )
+    .await;
+    end(state, call_id, original, reason, None).await;
+    presence::set_on_call(state, from.tenant_id, &[party.user_id, from.user_id], true).await;
+}
+
+/// The media service closed the relay of `call_id`: end the call, on
+/// whichever replica owns it.
+pub async fn relay_closed(state: &AppState, call_id: Uuid) {
+    if !state.calls.read().await.contains_key(&call_id) {
+        if let Some(owner) = state.cluster.call_owner(call_id).await {
+            if owner != state.cluster.node_id
+                && state.cluster.forward_relay_closed(owner, call_id).await
+            {
+                return;
+            }
+        }
+    }
+    media_lost(state, call_id).await;
+}
+
+/// End a call of this node whose relay is gone.
+pub async fn media_lost(state: &AppState, call_id: Uuid) {
+    if state.calls.read().await.contains_key(&call_id) {
+        tracing::info!(%call_id, "call lost its media relay");
+        finish(state, call_id, EndReason::MediaLost, None).await;
+    }
+}
+
+/// A party's socket went away. Losing the caller or the answering device ends
+/// the call; losing one of several ringing devices does not (the others keep
+/// ringing until answered or timed out).
+async fn disconnected(state: &AppState, from: Endpoint, call_id: Uuid) {
+    if conference::hang_up(state, from, call_id).await
+        || supervise::hang_up(state, from, call_id).await
+    {
+        return;
+    }
+    let side = state.calls.read().await.get(&call_id).and_then(|call| {
+        call.side_of(&from)
+            .map(|side| (side, call.answered_by.is_some()))
+    });
+    match side {
+        Some((Side::Caller, _)) | Some((Side::Callee, true)) => {
+            finish(state, call_id, EndReason::ConnectionLost, Some(from)).await;
+        }
+        _ => {}
+    }
+}
+
+async fn ring_timeout_elapsed(state: &AppState, call_id: Uuid) {
+    let still_ringing = {
+        let mut calls = state.calls.write().await;
+        match calls.get_mut(&call_id) {
+            Some(call) if call.state == CallState::Ringing => {
+                // We are the timer task; make sure finishing does not abort us.
+                call.ring_timer = None;
+                true
+            }
+            _ => false,
+        }
+    };
+    if still_ringing {
+        tracing::info!(%call_id, "call not answered in time");
+        finish(state, call_id, EndReason::NoAnswer, None).await;
+    }
+}
+
+/// Put `call_id` on hold for `from`, or take it off hold.
+async fn hold(state: &AppState, from: Endpoint, call_id: Uuid, held: bool) {
+    let (call, side, since) = {
+        let mut calls = state.calls.write().await;
+        let Some((call, side)) = calls
+            .get_mut(&call_id)
+            .and_then(|call| call.side_of(&from).map(|side| (call, side)))
+        else {
+            drop(calls);
+            let err = ServerMessage::error(Some(call_id), ErrorCode::UnknownCall, "unknown call");
+            reply(state, from, err).await;
+            return;
+        };
+        if call.state != CallState::Connected || call.parked.is_some() {
+            drop(calls);
+            let message = "only connected calls can be held";
+            let err = ServerMessage::error(Some(call_id), ErrorCode::InvalidState, message);
+            reply(state, from, err).await;
+            return;
+        }
+        if call.hold_mut(side).is_some() == held {
+            // Already in the requested state: confirm it to the requester
+            // alone.
+            let direction = call.media_direction(side);
+            drop(calls);
+            reply(state, from, hold_event(call_id, from, direction, held)).await;
+            return;
+        }
+        let slot = call.hold_mut(side);
+        let since = if held {
+            *slot = Some(Instant::now());
+            None
+        } else {
+            slot.take()
+        };
+        (call.clone(), side, since)
+    };
+    tracing::info!(%call_id, held, "hold changed");
+    if let Some(since) = since {
+        record_hold(state, &call, call_id, from.user_id, since);
+    }
+
+    // The relay plays music on hold to whoever is held.
+    if let Err(err) = state
+        .media
+        .hold(call_id, side.other().relay_side(), held)
+        .await
+    {
+        tracing::warn!(%call_id, error = %err, "media relay did not take the hold");
+    }
+
+    for party in [Side::Caller, Side::Callee] {
+        let msg = hold_event(call_id, from, call.media_direction(party), held);
+        send_to_side(state, &call, party, msg).await;
+    }
+}
+
+/// `call.held` or `call.resumed` by `from`, for a party whose media now
+/// takes `direction`.
+fn hold_event(call_id: Uuid, from: Endpoint, direction: Direction, held: bool) -> ServerMessage {
+    let (by, direction) = (from.user_id, direction.as_str().to_string());
+    if held {
+        ServerMessage::Held {
+            call_id,
+            by,
+            direction,
+        }
+    } else {
+        ServerMessage::Resumed {
+            call_id,
+            by,
+            direction,
+        }
+    }
+}
+
+/// Hand `from` the media relay of its connected call, allocating it first if
+/// nobody asked for it before.
+async fn relay(state: &AppState, from: Endpoint, call_id: Uuid) {
+    let allocated = {
+        let mut calls = state.calls.write().await;
+        let Some((call, side)) = calls
+            .get_mut(&call_id)
+            .and_then(|call| call.side_of(&from).map(|side| (call, side)))
+        else {
+            drop(calls);
+            let err = ServerMessage::error(Some(call_id), ErrorCode::UnknownCall, "unknown call");
+            reply(state, from, err).await;
+            return;
+        };
+        if call.state != CallState::Connected || call.parked.is_some() {
+            drop(calls);
+            let message = "only connected calls have a relay";
+            let err = ServerMessage::error(Some(call_id), ErrorCode::InvalidState, message);
+            reply(state, from, err).await;
+            return;
+        }
+        match &call.relay {
+            Some(Relay::Allocated(allocation)) => Some((allocation.clone(), side)),
+            // Everyone is told once the allocation in flight completes.
+            Some(Relay::Allocating) => return,
+            None => {
+                call.relay = Some(Relay::Allocating);
+                None
+            }
+        }
+    };
+    if let Some((allocation, side)) = allocated {
+        reply(state, from, relay_message(call_id, &allocation, side)).await;
+        return;
+    }
+
+    let allocation = match state.media.allocate(call_id).await {
+        Ok(allocation) => allocation,
+        Err(err) => {
+            tracing::warn!(%call_id, error = %err, "failed to allocate a media relay");
+            if let Some(call) = state.calls.write().await.get_mut(&call_id) {
+                call.relay = None;
+            }
+            let message = "no media relay available";
+            let err = ServerMessage::error(Some(call_id), ErrorCode::Unavailable, message);
+            reply(state, from, err).await;
+            return;
+        }
+    };
+    let call = state.calls.write().await.get_mut(&call_id).map(|call| {
+        call.relay = Some(Relay::Allocated(allocation.clone()));
+        call.clone()
+    });
+    let Some(call) = call else {
+        // The call ended while the relay was allocated; `end` left it to us.
+        if let Err(err) = state.media.release(call_id).await {
+            tracing::warn!(%call_id, error = %err, "failed to release media relay");
+        }
+        return;
+    };
+    tracing::info!(%call_id, session_id = %allocation.session_id, "media relay allocated");
+    // Before anyone hears of the relay, so no stray stream latches first.
+    for (party, ssrc) in [
+        (Side::Caller, call.caller_ssrc),
+        (Side::Callee, call.callee_ssrc),
+    ] {
+        if ssrc.is_some() {
+            expect_ssrc(state, call_id, party, ssrc).await;
+        }
+    }
+    for party in [Side::Caller, Side::Callee] {
+        let msg = relay_message(call_id, &allocation, party);
+        send_to_side(state, &call, party, msg).await;
+    }
+}
+
+/// The SSRC announced by SDP in a `signal` payload: `None` when the payload
+/// carries no SDP, `Some(None)` when its audio announces none.
+fn announced_ssrc(data: &serde_json::Value) -> Option<Option<u32>> {
+    let desc = data
+        .get("sdp")
+        .and_then(|sdp| sdp.as_str())
+        .and_then(|sdp| sdp.parse::<SessionDescription>().ok())?;
+    Some(
+        desc.media
+            .iter()
+            .find(|m| m.kind == "audio" && !m.is_rejected())
+            .and_then(|audio| audio.ssrc()),
+    )
+}
+
+/// Note the SSRC `side` announced, passing it on to the call's relay if it
+/// has one and the SSRC changed.
+async fn announce_ssrc(state: &AppState, call_id: Uuid, side: Side, ssrc: Option<u32>) {
+    let relayed = {
+        let mut calls = state.calls.write().await;
+        let Some(call) = calls.get_mut(&call_id) else {
+            return;
+        };
+        if std::mem::replace(call.ssrc_mut(side), ssrc) == ssrc {
+            return;
+        }
+        matches!(call.relay, Some(Relay::Allocated(_)))
+    };
+    // Otherwise the relay is told once allocated.
+    if relayed {
+        expect_ssrc(state, call_id, side, ssrc).await;
+    }
+}
+
+async fn expect_ssrc(state: &AppState, call_id: Uuid, side: Side, ssrc: Option<u32>) {
+    if let Err(err) = state
+        .media
+        .expect_ssrc(call_id, side.relay_side(), ssrc)
+        .await
+    {
+        tracing::warn!(%call_id, error = %err, "media relay did not take the ssrc");
+    }
+}
+
+fn relay_message(call_id: Uuid, allocation: &media::Allocation, side: Side) -> ServerMessage {
+    ServerMessage::Relay {
+        call_id,
+        session_id: allocation.session_id,
+        host: allocation.relay_ip.to_string(),
+        port: allocation.relay_port,
+        side: side.relay_side().to_string(),
+        secret: allocation.secret(side.relay_side()).to_string(),
+    }
+}
+
+/// The device of `user_id` on the connected call `call_id`, with its relay
+/// side, for `supervisor` to join the call next to.
+pub(crate) async fn supervised_agent(
+    state: &AppState,
+    supervisor: Endpoint,
+    call_id: Uuid,
+    user_id: Uuid,
+) -> Result<(Endpoint, &'static str), (ErrorCode, &'static str)> {
+    let calls = state.calls.read().await;
+    let call = calls
+        .get(&call_id)
+        .filter(|call| call.tenant_id == supervisor.tenant_id);
+    let Some(call) = call else {
+        return Err((ErrorCode::UnknownCall, "unknown call"));
+    };
+    if call.state != CallState::Connected {
+        return Err((
+            ErrorCode::InvalidState,
+            "only connected calls can be supervised",
+        ));
+    }
+    if [call.caller.user_id, call.callee].contains(&supervisor.user_id) {
+        return Err((ErrorCode::NotAllowed, "cannot supervise your own call"));
+    }
+    match call.answered_by {
+        _ if call.caller.user_id == user_id => Ok((call.caller, Side::Caller.relay_side())),
+        Some(callee) if callee.user_id == user_id => Ok((callee, Side::Callee.relay_side())),
+        _ => Err((
+            ErrorCode::InvalidDestination,
+            "the agent is not on this call",
+        )),
+    }
+}
+
+/// Report a finished hold interval to the PBX, off the routing path.
+fn record_hold(state: &AppState, call: &Call, call_id: Uuid, held_by: Uuid, since: Instant) {
+    let pbx = state.pbx.clone();
+    let tenant_id = call.tenant_id;
+    let duration = since.elapsed();
+    tokio::spawn(async move {
+        if let Err(err) = pbx.record_hold(tenant_id, call_id, held_by, duration).await {
+            tracing::warn!(%call_id, error = %err, "failed to record hold");
+        }
+    });
+}
+
+/// Narrow the media directions of an SDP payload to `allowed`. Payloads
+/// without SDP, such as trickled candidates, are left alone.
+fn restrict_direction(data: &mut serde_json::Value, allowed: Direction) {
+    let Some(mut desc) = data
+        .get("sdp")
+        .and_then(|sdp| sdp.as_str())
+        .and_then(|sdp| sdp.parse::<SessionDescription>().ok())
+    else {
+        return;
+    };
+    for index in 0..desc.media.len() {
+        let current = desc.direction_of(index);
+        desc.media[index].set_direction(Direction::from_flags(
+            current.sends() && allowed.sends(),
+            current.receives() && allowed.receives(),
+        ));
+    }
+    data["sdp"] = serde_json::Value::String(desc.to_string());
+}
+
+/// Move a call to `ended` and tell every party except `initiator` why.
+async fn finish(state: &AppState, call_id: Uuid, reason: EndReason, initiator: Option<Endpoint>) {
+    if let Some(call) = remove_call(state, call_id).await {
+        end(state, call_id, call, reason, initiator).await;
+    }
+}
+
+/// Wind down a call already taken out of the table.
+async fn end(
+    state: &AppState,
+    call_id: Uuid,
+    mut call: Call,
+    reason: EndReason,
+    initiator: Option<Endpoint>,
+) {
+    let was_connected = call.state == CallState::Connected;
+    // Holds still running end with the call.
+    if let Some(since) = call.caller_hold {
+        record_hold(state, &call, call_id, call.caller.user_id, since);
+    }
+    if let Some(since) = call.callee_hold {
+        record_hold(state, &call, call_id, call.callee, since);
+    }
+    if let Err(err) = call.transition(CallState::Ended) {
+        tracing::warn!(%call_id, error = %err, "ending call from unexpected state");
+    }
+
+    // Whoever parked the call was told when it did.
+    let mut parker = None;
+    if let Some(parked) = call.parked.take() {
+        if let Some(timer) = parked.timer {
+            timer.abort();
+        }
+        parker = call.endpoint(parked.by);
+        if let Err(err) = state
+            .media
+            .hold(call_id, parked.by.other().relay_side(), false)
+            .await
+        {
+            tracing::warn!(%call_id, error = %err, "media relay did not stop music on hold");
+        }
+        park::vacate(state, call.tenant_id, parked.slot).await;
+    }
+
+    let told = |endpoint: Endpoint| initiator == Some(endpoint) || parker == Some(endpoint);
+    let msg = ServerMessage::Ended { call_id, reason };
+    if !told(call.caller) {
+        state.cluster.send_to(call.caller, msg.clone()).await;
+    }
+    match call.answered_by {
+        Some(endpoint) if !told(endpoint) => {
+            state.cluster.send_to(endpoint, msg).await;
+        }
+        Some(_) => {}
+        // Still ringing: silence every device, bar the one that declined.
+        None => {
+            state
+                .cluster
+                .send_to_user(call.tenant_id, call.callee, initiator, &msg)
+                .await;
+        }
+    }
+
+    supervise::call_ended(state, call_id).await;
+    // Nothing is left to carry; don't wait for the relay to idle out. One
+    // still being allocated is released once the allocation completes.
+    if let Some(Relay::Allocated(_)) = call.relay {
+        if let Err(err) = state.media.release(call_id).await {
+            tracing::warn!(%call_id, error = %err, "failed to release media relay");
+        }
+    }
+    if was_connected {
+        leave_call(state, call.tenant_id, &[call.caller.user_id, call.callee]).await;
+    } else if call.transferred {
+        leave_call(state, call.tenant_id, &[call.caller.user_id]).await;
+    }
+}
+
+/// `users` left a call or conference: take those of them with no other call
+/// or conference leg out of `on_call`. A consult call, like any call carrying
+/// on from another, lives on the node of the call it came from, so a user's
+/// other calls are found here.
+pub async fn leave_call(state: &AppState, tenant_id: Uuid, users: &[Uuid]) {
+    let off_call: Vec<Uuid> = {
+        let calls = state.calls.read().await;
+        users
+            .iter()
+            .copied()
+            .filter(|user_id| !calls.values().any(|call| call.engages(*user_id)))
+            .collect()
+    };
+    let off_call: Vec<Uuid> = {
+        let conferences = state.conferences.read().await;
+        off_call
+            .into_iter()
+            .filter(|user_id| !conferences.engages(*user_id))
+            .collect()
+    };
+    presence::set_on_call(state, tenant_id, &off_call, false).await;
+}
+
+/// Move a call that never rang anyone to `failed`. Whoever placed it is
+/// told why by [`ring`]'s caller.
+async fn fail(state: &AppState, call_id: Uuid) {
+    let Some(mut call) = remove_call(state, call_id).await else {
+        return;
+    };
+    if let Err(err) = call.transition(CallState::Failed) {
+        tracing::warn!(%call_id, error = %err, "failing call from unexpected state");
+    }
+    if call.transferred {
+        leave_call(state, call.tenant_id, &[call.caller.user_id]).await;
+    }
+}
+
+async fn remove_call(state: &AppState, call_id: Uuid) -> Option<Call> {
+    let call = unlink(&mut *state.calls.write().await, call_id)?;
+    released(state, call_id, &call).await;
+    Some(call)
+}
+
+fn unlink(calls: &mut HashMap<Uuid, Call>, call_id: Uuid) -> Option<Call> {
+    let call = calls.remove(&call_id)?;
+    // A consultation and the call it was for outlive each other.
+    if let Some(parent) = call.consult_for.and_then(|id| calls.get_mut(&id)) {
+        parent.consult = None;
+    }
+    if let Some(consult) = call.consult.and_then(|id| calls.get_mut(&id)) {
+        consult.consult_for = None;
+    }
+    Some(call)
+}
+
+/// Drop the cluster's records of a call taken out of the table.
+async fn released(state: &AppState, call_id: Uuid, call: &Call) {
+    state.cluster.release_call(call_id).await;
+    if call.state == CallState::Ringing {
+        state
+            .cluster
+            .set_ringing(call.tenant_id, call.callee, call_id, false)
+            .await;
+    }
+}
+
+async fn reply(state: &AppState, to: Endpoint, msg: ServerMessage) {
+    state.cluster.send_to(to, msg).await;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::presence::PresenceStatus;
+    use crate::registry::DeviceKind;
+    use crate::session::Session;
+    use std::time::Duration;
+
+    fn endpoint(tenant_id: Uuid) -> Endpoint {
+        Endpoint {
+            tenant_id,
+            user_id: Uuid::new_v4(),
+            connection_id: Uuid::new_v4(),
+            node_id: Uuid::new_v4(),
+        }
+    }
+
+    fn call() -> Call {
+        Call::new(endpoint(Uuid::new_v4()), Uuid::new_v4())
+    }
+
+    fn connected() -> Call {
+        let mut call = call();
+        call.transition(CallState::Ringing).unwrap();
+        call.transition(CallState::Connected).unwrap();
+        call
+    }
+
+    /// A signed-in device of a new user, online and reachable.
+    pub(crate) async fn online(state: &AppState, tenant_id: Uuid) -> Session {
+        Session::open(state, tenant_id, Uuid::new_v4(), DeviceKind::default()).await
+    }
+
+    /// Put a call from `caller` answered by `callee` into the table, with
+    /// both parties on a call.
+    pub(crate) async fn connect(state: &AppState, caller: Endpoint, callee: Endpoint) -> Uuid {
+        let call_id = Uuid::new_v4();
+        let mut call = Call::new(caller, callee.user_id);
+        call.transition(CallState::Ringing).unwrap();
+        call.transition(CallState::Connected).unwrap();
+        call.answered_by = Some(callee);
+        state.calls.write().await.insert(call_id, call);
+        let parties = [caller.user_id, callee.user_id];
+        presence::set_on_call(state, caller.tenant_id, &parties, true).await;
+        call_id
+    }
+
+    async fn status(state: &AppState, of: Endpoint) -> PresenceStatus {
+        let presence = state.presence.get(of.tenant_id, of.user_id).await;
+        presence.expect("online").status
+    }
+
+    /// Put a call from `caller` ringing `callee`, since `rang_at`, into the
+    /// table.
+    async fn ringing(state: &AppState, caller: Endpoint, callee: Uuid, rang_at: Instant) -> Uuid {
+        let call_id = Uuid::new_v4();
+        let mut call = Call::new(caller, callee);
+        call.transition(CallState::Ringing).unwrap();
+        call.rang_at = Some(rang_at);
+        state.calls.write().await.insert(call_id, call);
+        call_id
+    }
+
+    /// Frames sent to `session` so far.
+    fn received(session: &mut Session) -> Vec<ServerMessage> {
+        std::iter::from_fn(|| session.rx.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn refuses_illegal_transitions() {
+        use CallState::*;
+        let all = [Initiated, Ringing, Connected, Ended, Failed];
+        let legal = [
+            (Initiated, Ringing),
+            (Initiated, Ended),
+            (Initiated, Failed),
+            (Ringing, Connected),
+            (Ringing, Ended),
+            (Ringing, Failed),
+            (Connected, Ended),
+        ];
+        for from in all {
+            for to in all {
+                let mut call = call();
+                call.state = from;
+                let result = call.transition(to);
+                if legal.contains(&(from, to)) {
+                    assert!(result.is_ok(), "{from:?} -> {to:?}");
+                    assert_eq!(call.state, to);
+                } else {
+                    let err = result.unwrap_err();
+                    assert_eq!((err.from, err.to), (from, to));
+                    assert_eq!(call.state, from);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hold_narrows_each_side_direction() {
+        let mut call = connected();
+        assert_eq!(call.media_direction(Side::Caller), Direction::SendRecv);
+        assert_eq!(call.media_direction(Side::Callee), Direction::SendRecv);
+
+        // The holder stops receiving, the held party stops sending.
+        call.caller_hold = Some(Instant::now());
+        assert_eq!(call.media_direction(Side::Caller), Direction::SendOnly);
+        assert_eq!(call.media_direction(Side::Callee), Direction::RecvOnly);
+
+        call.callee_hold = Some(Instant::now());
+        assert_eq!(call.media_direction(Side::Caller), Direction::Inactive);
+        assert_eq!(call.media_direction(Side::Callee), Direction::Inactive);
+
+        call.caller_hold = None;
+        assert_eq!(call.media_direction(Side::Caller), Direction::RecvOnly);
+        assert_eq!(call.media_direction(Side::Callee), Direction::SendOnly);
+    }
+
+    #[test]
+    fn signals_announce_the_audio_ssrc() {
+        let sdp = |audio: &str| {
+            format!(
+                "v=0\r\no=- 1 1 IN IP4 198.51.100.7\r\ns=-\r\nc=IN IP4 198.51.100.7\r\nt=0 0\r\n\
+                 m=audio 4000 RTP/AVP 0\r\n{audio}"
+            )
+        };
+        let signal = |sdp: String| serde_json::json!({ "type": "offer", "sdp": sdp });
+        assert_eq!(
+            announced_ssrc(&signal(sdp("a=ssrc:42 cname:x\r\na=ssrc:43 cname:x\r\n"))),
+            Some(Some(42))
+        );
+        assert_eq!(announced_ssrc(&signal(sdp(""))), Some(None));
+        let candidate =
+            serde_json::json!({ "candidate": "candidate:1 1 udp 1 198.51.100.7 4000 typ host" });
+        assert_eq!(announced_ssrc(&candidate), None);
+    }
+
+    #[test]
+    fn only_connected_parties_are_engaged() {
+        let mut call = call();
+        let (caller, callee) = (call.caller.user_id, call.callee);
+        call.transition(CallState::Ringing).unwrap();
+        assert!(!call.engages(caller));
+        assert!(!call.engages(callee));
+
+        call.transition(CallState::Connected).unwrap();
+        assert!(call.engages(caller));
+        assert!(call.engages(callee));
+        assert!(!call.engages(Uuid::new_v4()));
+
+        call.parked = Some(Parked {
+            slot: 701,
+            by: Side::Callee,
+            timer: None,
+        });
+        assert!(call.engages(caller));
+        assert!(!call.engages(callee));
+    }
+
+    #[test]
+    fn a_transferee_is_engaged_while_the_new_call_rings() {
+        let mut call = call();
+        call.transferred = true;
+        let (caller, callee) = (call.caller.user_id, call.callee);
+        assert!(call.engages(caller));
+        call.transition(CallState::Ringing).unwrap();
+        assert!(call.engages(caller));
+        assert!(!call.engages(callee));
+        call.transition(CallState::Failed).unwrap();
+        assert!(!call.engages(caller));
+    }
+
+    /// Send `msg` from `from` as its socket would.
+    async fn client(state: &AppState, from: Endpoint, msg: ClientMessage) {
+        handle_client_message(state, from, msg).await;
+    }
+
+    #[tokio::test]
+    async fn a_call_rings_connects_and_ends_for_both_parties() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut caller = online(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let call_id = Uuid::new_v4();
+
+        let to = callee.endpoint.user_id.to_string();
+        client(
+            &state,
+            caller.endpoint,
+            ClientMessage::Initiate { call_id, to },
+        )
+        .await;
+        assert_eq!(state.calls.read().await[&call_id].state, CallState::Ringing);
+        assert!(matches!(
+            received(&mut caller)[..],
+            [ServerMessage::Ringing { call_id: id }] if id == call_id
+        ));
+        assert!(matches!(
+            received(&mut callee)[..],
+            [ServerMessage::Incoming { call_id: id, .. }] if id == call_id
+        ));
+
+        client(&state, callee.endpoint, ClientMessage::Answer { call_id }).await;
+        assert_eq!(
+            state.calls.read().await[&call_id].state,
+            CallState::Connected
+        );
+        for party in [&mut caller, &mut callee] {
+            assert!(matches!(
+                received(party)[..],
+                [ServerMessage::Connected { call_id: id }] if id == call_id
+            ));
+            assert_eq!(status(&state, party.endpoint).await, PresenceStatus::OnCall);
+        }
+
+        client(&state, caller.endpoint, ClientMessage::Ended { call_id }).await;
+        assert!(state.calls.read().await.is_empty());
+        // The caller hung up itself and is not told again.
+        assert!(received(&mut caller).is_empty());
+        assert!(matches!(
+            received(&mut callee)[..],
+            [ServerMessage::Ended {
+                call_id: id,
+                reason: EndReason::Hangup,
+            }] if id == call_id
+        ));
+        for party in [&caller, &callee] {
+            assert_eq!(
+                status(&state, party.endpoint).await,
+                PresenceStatus::Available
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_unanswered_call_ends_after_the_ring_timeout() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut caller = online(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let call_id = Uuid::new_v4();
+
+        let to = callee.endpoint.user_id.to_string();
+        client(
+            &state,
+            caller.endpoint,
+            ClientMessage::Initiate { call_id, to },
+        )
+        .await;
+        received(&mut caller);
+        received(&mut callee);
+
+        tokio::time::sleep(state.ring_timeout - Duration::from_secs(1)).await;
+        assert_eq!(state.calls.read().await[&call_id].state, CallState::Ringing);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(state.calls.read().await.is_empty());
+        for party in [&mut caller, &mut callee] {
+            assert!(matches!(
+                received(party)[..],
+                [ServerMessage::Ended {
+                    call_id: id,
+                    reason: EndReason::NoAnswer,
+                }] if id == call_id
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_call_is_answered_once() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut caller = online(&state, tenant_id).await;
+        let mut desk = online(&state, tenant_id).await;
+        let callee = desk.endpoint.user_id;
+        let mut mobile = Session::open(&state, tenant_id, callee, DeviceKind::default()).await;
+        let call_id = Uuid::new_v4();
+
+        let to = callee.to_string();
+        client(
+            &state,
+            caller.endpoint,
+            ClientMessage::Initiate { call_id, to },
+        )
+        .await;
+        // The caller cannot answer its own call.
+        client(&state, caller.endpoint, ClientMessage::Answer { call_id }).await;
+        assert!(received(&mut caller).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Error {
+                code: ErrorCode::UnknownCall,
+                ..
+            }
+        )));
+
+        // Both devices answer at once; the later one is no longer on the
+        // call and is refused.
+        client(&state, desk.endpoint, ClientMessage::Answer { call_id }).await;
+        client(&state, mobile.endpoint, ClientMessage::Answer { call_id }).await;
+        assert_eq!(
+            state.calls.read().await[&call_id].answered_by,
+            Some(desk.endpoint)
+        );
+        assert!(received(&mut mobile).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Error {
+                code: ErrorCode::UnknownCall,
+                ..
+            }
+        )));
+        // Nor does answering again on the same device connect twice.
+        client(&state, desk.endpoint, ClientMessage::Answer { call_id }).await;
+        let answered = received(&mut desk);
+        let connected = answered
+            .iter()
+            .filter(|msg| matches!(msg, ServerMessage::Connected { .. }))
+            .count();
+        assert_eq!(connected, 1);
+        assert!(answered.iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Error {
+                code: ErrorCode::InvalidState,
+                ..
+            }
+        )));
+        let connected = received(&mut caller)
+            .iter()
+            .filter(|msg| matches!(msg, ServerMessage::Connected { .. }))
+            .count();
+        assert_eq!(connected, 1);
+    }
+
+    /// The directions the hold events each party received asked for, in
+    /// order.
+    fn hold_directions(session: &mut Session) -> Vec<String> {
+        received(session)
+            .into_iter()
+            .filter_map(|msg| match msg {
+                ServerMessage::Held { direction, .. }
+                | ServerMessage::Resumed { direction, .. } => Some(direction),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn only_connected_calls_can_be_held() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut caller = online(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let call_id = Uuid::new_v4();
+        let to = callee.endpoint.user_id.to_string();
+        client(
+            &state,
+            caller.endpoint,
+            ClientMessage::Initiate { call_id, to },
+        )
+        .await;
+        received(&mut caller);
+        received(&mut callee);
+
+        for msg in [
+            ClientMessage::Hold { call_id },
+            ClientMessage::Resume { call_id },
+        ] {
+            client(&state, caller.endpoint, msg).await;
+            assert!(matches!(
+                received(&mut caller)[..],
+                [ServerMessage::Error {
+                    code: ErrorCode::InvalidState,
+                    ..
+                }]
+            ));
+        }
+        // The call rings on, untouched, and can still be answered.
+        assert!(received(&mut callee).is_empty());
+        assert_eq!(state.calls.read().await[&call_id].state, CallState::Ringing);
+        client(&state, callee.endpoint, ClientMessage::Answer { call_id }).await;
+        assert_eq!(
+            state.calls.read().await[&call_id].state,
+            CallState::Connected
+        );
+    }
+
+    #[tokio::test]
+    async fn resuming_restores_sendrecv_on_both_legs() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut caller = online(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let call_id = connect(&state, caller.endpoint, callee.endpoint).await;
+
+        client(&state, caller.endpoint, ClientMessage::Hold { call_id }).await;
+        assert_eq!(hold_directions(&mut caller), ["sendonly"]);
+        assert_eq!(hold_directions(&mut callee), ["recvonly"]);
+        client(&state, callee.endpoint, ClientMessage::Hold { call_id }).await;
+        assert_eq!(hold_directions(&mut caller), ["inactive"]);
+        assert_eq!(hold_directions(&mut callee), ["inactive"]);
+
+        // Media flows both ways again only once neither party holds.
+        client(&state, caller.endpoint, ClientMessage::Resume { call_id }).await;
+        assert_eq!(hold_directions(&mut caller), ["recvonly"]);
+        assert_eq!(hold_directions(&mut callee), ["sendonly"]);
+        client(&state, callee.endpoint, ClientMessage::Resume { call_id }).await;
+        assert_eq!(hold_directions(&mut caller), ["sendrecv"]);
+        assert_eq!(hold_directions(&mut callee), ["sendrecv"]);
+        let calls = state.calls.read().await;
+        assert_eq!(
+            calls[&call_id].media_direction(Side::Caller),
+            Direction::SendRecv
+        );
+        assert_eq!(
+            calls[&call_id].media_direction(Side::Callee),
+            Direction::SendRecv
+        );
+    }
+
+    #[tokio::test]
+    async fn every_device_rings_until_one_answers() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut caller = online(&state, tenant_id).await;
+        let mut desk = online(&state, tenant_id).await;
+        let callee = desk.endpoint.user_id;
+        let mut mobile = Session::open(&state, tenant_id, callee, DeviceKind::default()).await;
+
+        let call_id = Uuid::new_v4();
+        initiate(&state, caller.endpoint, call_id, &callee.to_string()).await;
+        for device in [&mut desk, &mut mobile] {
+            assert!(received(device).iter().any(|msg| matches!(
+                msg,
+                ServerMessage::Incoming { call_id: id, from, .. }
+                    if *id == call_id && *from == caller.endpoint.user_id
+            )));
+        }
+        assert!(received(&mut caller)
+            .iter()
+            .any(|msg| matches!(msg, ServerMessage::Ringing { call_id: id } if *id == call_id)));
+
+        answer(&state, mobile.endpoint, call_id).await;
+        assert_eq!(
+            state.calls.read().await[&call_id].answered_by,
+            Some(mobile.endpoint)
+        );
+        let stopped = received(&mut desk);
+        assert!(matches!(
+            stopped[..],
+            [ServerMessage::Ended {
+                call_id: id,
+                reason: EndReason::AnsweredElsewhere,
+            }] if id == call_id
+        ));
+        let answered = received(&mut mobile);
+        assert!(matches!(
+            answered[..],
+            [ServerMessage::Connected { call_id: id }] if id == call_id
+        ));
+        assert!(received(&mut caller)
+            .iter()
+            .any(|msg| matches!(msg, ServerMessage::Connected { call_id: id } if *id == call_id)));
+    }
+
+    #[tokio::test]
+    async fn a_blind_transfer_keeps_the_transferee_on_call() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let transferee = online(&state, tenant_id).await;
+        let transferor = online(&state, tenant_id).await;
+        let target = online(&state, tenant_id).await;
+        let call_id = connect(&state, transferee.endpoint, transferor.endpoint).await;
+        let mut presence = state.presence_tx.subscribe();
+
+        let to = target.endpoint.user_id.to_string();
+        transfer(&state, transferor.endpoint, call_id, &to).await;
+
+        let (new_call_id, call) = {
+            let calls = state.calls.read().await;
+            assert_eq!(calls.len(), 1);
+            let (id, call) = calls.iter().next().unwrap();
+            (*id, call.clone())
+        };
+        assert_eq!(call.state, CallState::Ringing);
+        assert_eq!(call.caller, transferee.endpoint);
+        assert_eq!(call.callee, target.endpoint.user_id);
+        assert_eq!(
+            status(&state, transferee.endpoint).await,
+            PresenceStatus::OnCall
+        );
+        assert_eq!(
+            status(&state, transferor.endpoint).await,
+            PresenceStatus::Available
+        );
+        // Nobody ever saw the transferee free.
+        while let Ok(event) = presence.try_recv() {
+            assert_ne!(event.presence.user_id, transferee.endpoint.user_id);
+        }
+
+        // Unanswered, the transferee is off the phone.
+        ring_timeout_elapsed(&state, new_call_id).await;
+        assert!(state.calls.read().await.is_empty());
+        assert_eq!(
+            status(&state, transferee.endpoint).await,
+            PresenceStatus::Available
+        );
+    }
+
+    #[tokio::test]
+    async fn a_blind_transfer_to_an_offline_target_keeps_the_call() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut transferee = online(&state, tenant_id).await;
+        let mut transferor = online(&state, tenant_id).await;
+        let call_id = connect(&state, transferee.endpoint, transferor.endpoint).await;
+        received(&mut transferee);
+        received(&mut transferor);
+
+        let offline = Uuid::new_v4().to_string();
+        transfer(&state, transferor.endpoint, call_id, &offline).await;
+
+        {
+            let calls = state.calls.read().await;
+            assert_eq!(calls.len(), 1);
+            assert_eq!(calls[&call_id].state, CallState::Connected);
+        }
+        assert!(received(&mut transferor).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::TransferFailed { call_id: id, code, .. }
+                if *id == call_id && *code == ErrorCode::Unavailable
+        )));
+        // The transferee never hears of the call that could not be placed.
+        assert!(received(&mut transferee).is_empty());
+        for endpoint in [transferee.endpoint, transferor.endpoint] {
+            assert_eq!(status(&state, endpoint).await, PresenceStatus::OnCall);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_parked_call_is_retrieved_on_a_new_call() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut party = online(&state, tenant_id).await;
+        let mut parker = online(&state, tenant_id).await;
+        let mut retriever = online(&state, tenant_id).await;
+        let call_id = connect(&state, party.endpoint, parker.endpoint).await;
+
+        park(&state, parker.endpoint, call_id, None).await;
+        {
+            let calls = state.calls.read().await;
+            let parked = calls[&call_id].parked.as_ref().expect("parked");
+            assert_eq!((parked.slot, parked.by), (1, Side::Callee));
+            assert!(calls[&call_id].callee_hold.is_some());
+        }
+        assert!(received(&mut parker).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Ended {
+                reason: EndReason::Parked,
+                ..
+            }
+        )));
+        assert!(received(&mut party)
+            .iter()
+            .any(|msg| matches!(msg, ServerMessage::Held { .. })));
+        assert!(received(&mut retriever).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::ParkSlot { slot: 1, parked: Some(parked) } if parked.call_id == call_id
+        )));
+        assert_eq!(status(&state, party.endpoint).await, PresenceStatus::OnCall);
+        assert_eq!(
+            status(&state, parker.endpoint).await,
+            PresenceStatus::Available
+        );
+
+        let new_call_id = Uuid::new_v4();
+        park::retrieve(&state, retriever.endpoint, 1, new_call_id).await;
+
+        let call = {
+            let calls = state.calls.read().await;
+            assert_eq!(calls.len(), 1);
+            calls[&new_call_id].clone()
+        };
+        assert_eq!(call.state, CallState::Connected);
+        assert_eq!(call.caller, party.endpoint);
+        assert_eq!(call.answered_by, Some(retriever.endpoint));
+        assert!(received(&mut party).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Transferred { call_id: old, new_call_id: new, peer, caller: true }
+                if (*old, *new, *peer) == (call_id, new_call_id, retriever.endpoint.user_id)
+        )));
+        let frames = received(&mut retriever);
+        assert!(frames.iter().any(
+            |msg| matches!(msg, ServerMessage::Connected { call_id } if *call_id == new_call_id)
+        ));
+        assert!(frames.iter().any(|msg| matches!(
+            msg,
+            ServerMessage::ParkSlot {
+                slot: 1,
+                parked: None
+            }
+        )));
+        assert_eq!(status(&state, party.endpoint).await, PresenceStatus::OnCall);
+        assert_eq!(
+            status(&state, retriever.endpoint).await,
+            PresenceStatus::OnCall
+        );
+    }
+
+    #[tokio::test]
+    async fn parking_needs_a_free_slot() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let first = [
+            online(&state, tenant_id).await,
+            online(&state, tenant_id).await,
+        ];
+        let mut second = [
+            online(&state, tenant_id).await,
+            online(&state, tenant_id).await,
+        ];
+        let parked = connect(&state, first[0].endpoint, first[1].endpoint).await;
+        let call_id = connect(&state, second[0].endpoint, second[1].endpoint).await;
+        park(&state, first[1].endpoint, parked, Some(3)).await;
+
+        for slot in [3, state.park_slots + 1] {
+            park(&state, second[1].endpoint, call_id, Some(slot)).await;
+            assert!(state.calls.read().await[&call_id].parked.is_none());
+            assert!(received(&mut second[1]).iter().any(|msg| matches!(
+                msg,
+                ServerMessage::TransferFailed { call_id: failed, .. } if *failed == call_id
+            )));
+        }
+        park(&state, second[1].endpoint, call_id, None).await;
+        let calls = state.calls.read().await;
+        assert_eq!(
+            calls[&call_id].parked.as_ref().map(|parked| parked.slot),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_parked_call_nobody_retrieves_rings_back() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut party = online(&state, tenant_id).await;
+        let mut parker = online(&state, tenant_id).await;
+        let call_id = connect(&state, party.endpoint, parker.endpoint).await;
+        park(&state, parker.endpoint, call_id, Some(2)).await;
+        received(&mut parker);
+
+        park_timeout_elapsed(&state, call_id).await;
+
+        let (new_call_id, call) = {
+            let calls = state.calls.read().await;
+            assert_eq!(calls.len(), 1);
+            let (id, call) = calls.iter().next().unwrap();
+            (*id, call.clone())
+        };
+        assert_eq!(call.state, CallState::Ringing);
+        assert_eq!(call.caller, party.endpoint);
+        assert_eq!(call.callee, parker.endpoint.user_id);
+        assert!(received(&mut party).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Transferred { new_call_id: new, peer, caller: true, .. }
+                if (*new, *peer) == (new_call_id, parker.endpoint.user_id)
+        )));
+        assert!(received(&mut parker).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::ParkSlot {
+                slot: 2,
+                parked: None
+            }
+        )));
+        assert_eq!(status(&state, party.endpoint).await, PresenceStatus::OnCall);
+
+        // A retrieval racing the ring-back finds the slot empty.
+        park::retrieve(&state, parker.endpoint, 2, Uuid::new_v4()).await;
+        assert!(state.calls.read().await.contains_key(&new_call_id));
+    }
+
+    #[tokio::test]
+    async fn a_directed_pickup_answers_the_named_colleague_s_call() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut caller = online(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let mut picker = online(&state, tenant_id).await;
+        let call_id = ringing(
+            &state,
+            caller.endpoint,
+            callee.endpoint.user_id,
+            Instant::now(),
+        )
+        .await;
+
+        // Neither party may pick up the call themselves.
+        let own = Uuid::new_v4();
+        park::pickup(&state, callee.endpoint, own, Some(callee.endpoint.user_id)).await;
+        park::pickup(&state, caller.endpoint, own, Some(callee.endpoint.user_id)).await;
+        assert!(!state.calls.read().await.contains_key(&own));
+        for session in [&mut callee, &mut caller] {
+            assert!(received(session).iter().any(|msg| matches!(
+                msg,
+                ServerMessage::Error { call_id: Some(id), code: ErrorCode::InvalidDestination, .. }
+                    if *id == own
+            )));
+        }
+
+        let new_call_id = Uuid::new_v4();
+        let colleague = Some(callee.endpoint.user_id);
+        park::pickup(&state, picker.endpoint, new_call_id, colleague).await;
+        let call = {
+            let calls = state.calls.read().await;
+            assert!(!calls.contains_key(&call_id));
+            calls[&new_call_id].clone()
+        };
+        assert_eq!(call.state, CallState::Connected);
+        assert_eq!(call.caller, caller.endpoint);
+        assert_eq!(call.answered_by, Some(picker.endpoint));
+        assert!(received(&mut caller).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Transferred { new_call_id: new, peer, .. }
+                if (*new, *peer) == (new_call_id, picker.endpoint.user_id)
+        )));
+        assert!(received(&mut callee).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Ended {
+                reason: EndReason::AnsweredElsewhere,
+                ..
+            }
+        )));
+        assert!(received(&mut picker).iter().any(
+            |msg| matches!(msg, ServerMessage::Connected { call_id } if *call_id == new_call_id)
+        ));
+
+        // Nothing is left ringing the colleague.
+        let again = Uuid::new_v4();
+        park::pickup(&state, picker.endpoint, again, colleague).await;
+        assert!(received(&mut picker).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Error {
+                code: ErrorCode::UnknownCall,
+                ..
+            }
+        )));
+    }
+
+    #[tokio::test]
+    async fn a_group_pickup_takes_the_call_ringing_longest() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let group = [Uuid::new_v4(), Uuid::new_v4()];
+        let since = Instant::now();
+        let later = ringing(&state, endpoint(tenant_id), group[0], since).await;
+        let first = ringing(
+            &state,
+            endpoint(tenant_id),
+            group[1],
+            since - Duration::from_secs(5),
+        )
+        .await;
+        // Older, but answered, outside the group, or of another tenant.
+        let answered = ringing(
+            &state,
+            endpoint(tenant_id),
+            group[0],
+            since - Duration::from_secs(9),
+        )
+        .await;
+        state.calls.write().await.get_mut(&answered).unwrap().state = CallState::Connected;
+        ringing(
+            &state,
+            endpoint(tenant_id),
+            Uuid::new_v4(),
+            since - Duration::from_secs(9),
+        )
+        .await;
+        ringing(
+            &state,
+            endpoint(Uuid::new_v4()),
+            group[1],
+            since - Duration::from_secs(9),
+        )
+        .await;
+
+        assert_eq!(
+            longest_ringing(&state, tenant_id, &group).await,
+            Some(first)
+        );
+        state.calls.write().await.remove(&first);
+        assert_eq!(
+            longest_ringing(&state, tenant_id, &group).await,
+            Some(later)
+        );
+        assert_eq!(longest_ringing(&state, tenant_id, &[]).await, None);
+    }
+}
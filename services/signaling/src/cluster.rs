@@ -0,0 +1,880 @@
+//! Cross-node delivery for horizontally scaled signaling replicas.
+//!
+//! Each replica only holds the sockets that happen to be connected to it, so
+//! when Redis is configured we keep a small directory of which node owns each
+//! connection (`signaling:conns:{tenant}:{user}`) and which node owns each call
+//! (`signaling:call:{callId}`, claimed only while free, so no two nodes hold
+//! calls by the same id). Every node keeps a heartbeat key
+//! (`signaling:node-alive:{node}`) fresh; connections of a node whose
+//! heartbeat lapsed, because it crashed, are dropped from the directory
+//! whenever it is read. Frames addressed to a remote connection are
+//! published on that node's channel (`signaling:node:{node}`); presence changes
+//! and tenant-wide frames go out on shared channels so every replica can relay
+//! them to its own subscribers. Occupied park slots
+//! (`signaling:park:{tenant}:{slot}`) and the calls ringing each user
+//! (`signaling:ringing:{tenant}:{user}`) are kept there too, so a call can be
+//! retrieved or picked up from any node. Without Redis everything degrades to
+//! the local registry.
+
+use crate::calls::{self, Command, Endpoint};
+use crate::presence::PresenceEvent;
+use crate::protocol::ServerMessage;
+use crate::registry::{DeviceKind, Registry};
+use crate::AppState;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+const PRESENCE_CHANNEL: &str = "signaling:presence";
+const TENANT_CHANNEL: &str = "signaling:tenant";
+const NODE_ALIVE_PREFIX: &str = "signaling:node-alive:";
+
+/// Directory entries outlive a crashed node by at most this long.
+const DIRECTORY_TTL_SECS: u64 = 120;
+
+/// A node counts as gone once its heartbeat is this old.
+const NODE_ALIVE_TTL_SECS: u64 = 15;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Apply an update to a user's connection directory, then drop the entries
+/// of nodes without a heartbeat and return the rest.
+///
+/// KEYS[1]: the directory. ARGV: this node, the heartbeat key prefix, `add`,
+/// `remove` or `read`, the connection id and the directory TTL.
+const DIRECTORY_SCRIPT: &str = r"
+if ARGV[3] == 'add' then
+    redis.call('HSET', KEYS[1], ARGV[4], ARGV[1])
+    redis.call('EXPIRE', KEYS[1], ARGV[5])
+elseif ARGV[3] == 'remove' then
+    redis.call('HDEL', KEYS[1], ARGV[4])
+end
+local entries = redis.call('HGETALL', KEYS[1])
+local live = {}
+for i = 1, #entries, 2 do
+    local node = entries[i + 1]
+    if node == ARGV[1] or redis.call('EXISTS', ARGV[2] .. node) == 1 then
+        table.insert(live, entries[i])
+        table.insert(live, node)
+    else
+        redis.call('HDEL', KEYS[1], entries[i])
+    end
+end
+return live
+";
+
+/// How often the subscriber forgets the queues of calls no longer held here.
+const CALL_QUEUE_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Upper bound on how long a call may stay pinned to its owning node.
+const CALL_OWNER_TTL_SECS: u64 = 6 * 60 * 60;
+
+/// Messages exchanged between replicas over Redis.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Envelope {
+    /// Deliver `msg` to local sockets of a user: a single connection when
+    /// `connection_id` is set, otherwise every device except `except`.
+    Deliver {
+        tenant_id: Uuid,
+        user_id: Uuid,
+        connection_id: Option<Uuid>,
+        except: Option<Uuid>,
+        msg: ServerMessage,
+    },
+    /// A call command raised on another node for a call we own.
+    Call { from: Endpoint, command: Command },
+    /// The media relay of a call we own was closed.
+    RelayClosed { call_id: Uuid },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PresenceEnvelope {
+    origin: Uuid,
+    event: PresenceEvent,
+}
+
+/// A frame for every socket of a tenant.
+#[derive(Debug, Serialize, Deserialize)]
+struct TenantEnvelope {
+    origin: Uuid,
+    tenant_id: Uuid,
+    msg: ServerMessage,
+}
+
+pub struct Cluster {
+    pub node_id: Uuid,
+    registry: Arc<Registry>,
+    redis: Option<redis::aio::ConnectionManager>,
+    presence_tx: broadcast::Sender<PresenceEvent>,
+    directory_script: redis::Script,
+}
+
+/// Which directory update [`Cluster::directory`] makes before reading.
+#[derive(Debug, Clone, Copy)]
+enum DirectoryOp {
+    Add(Uuid),
+    Remove(Uuid),
+    Read,
+}
+
+fn directory_key(tenant_id: Uuid, user_id: Uuid) -> String {
+    format!("signaling:conns:{tenant_id}:{user_id}")
+}
+
+fn call_key(call_id: Uuid) -> String {
+    format!("signaling:call:{call_id}")
+}
+
+fn park_key(tenant_id: Uuid, slot: u16) -> String {
+    format!("signaling:park:{tenant_id}:{slot}")
+}
+
+fn ringing_key(tenant_id: Uuid, user_id: Uuid) -> String {
+    format!("signaling:ringing:{tenant_id}:{user_id}")
+}
+
+fn node_channel(node_id: Uuid) -> String {
+    format!("signaling:node:{node_id}")
+}
+
+impl Cluster {
+    pub fn new(
+        registry: Arc<Registry>,
+        redis: Option<redis::aio::ConnectionManager>,
+        presence_tx: broadcast::Sender<PresenceEvent>,
+    ) -> Self {
+        Cluster {
+            node_id: Uuid::new_v4(),
+            registry,
+            redis,
+            presence_tx,
+            directory_script: redis::Script::new(DIRECTORY_SCRIPT),
+        }
+    }
+
+    /// Update the user's connection directory as `op` says and return its
+    /// live entries, connection id to node id.
+    async fn directory(
+        &self,
+        conn: &mut redis::aio::ConnectionManager,
+        tenant_id: Uuid,
+        user_id: Uuid,
+        op: DirectoryOp,
+    ) -> redis::RedisResult<HashMap<String, String>> {
+        let (op, connection_id) = match op {
+            DirectoryOp::Add(id) => ("add", id.to_string()),
+            DirectoryOp::Remove(id) => ("remove", id.to_string()),
+            DirectoryOp::Read => ("read", String::new()),
+        };
+        self.directory_script
+            .key(directory_key(tenant_id, user_id))
+            .arg(self.node_id.to_string())
+            .arg(NODE_ALIVE_PREFIX)
+            .arg(op)
+            .arg(connection_id)
+            .arg(DIRECTORY_TTL_SECS)
+            .invoke_async(conn)
+            .await
+    }
+
+    /// Mark this node alive, so other replicas keep its connections in the
+    /// directory.
+    async fn heartbeat(&self) {
+        let Some(mut conn) = self.redis.clone() else {
+            return;
+        };
+        let result = redis::cmd("SET")
+            .arg(format!("{NODE_ALIVE_PREFIX}{}", self.node_id))
+            .arg(1)
+            .arg("EX")
+            .arg(NODE_ALIVE_TTL_SECS)
+            .query_async::<_, ()>(&mut conn)
+            .await;
+        if let Err(err) = result {
+            tracing::warn!(error = %err, "node heartbeat failed");
+        }
+    }
+
+    /// Register a local socket and advertise it in the directory. Returns the
+    /// connection id and whether this is the user's first connection anywhere.
+    pub async fn register(
+        &self,
+        tenant_id: Uuid,
+        user_id: Uuid,
+        device: DeviceKind,
+        tx: mpsc::Sender<ServerMessage>,
+    ) -> (Uuid, bool) {
+        let connection_id = self.registry.register(tenant_id, user_id, device, tx).await;
+        let Some(mut conn) = self.redis.clone() else {
+            let first = self.registry.connection_count(tenant_id, user_id).await == 1;
+            return (connection_id, first);
+        };
+
+        let live = self
+            .directory(
+                &mut conn,
+                tenant_id,
+                user_id,
+                DirectoryOp::Add(connection_id),
+            )
+            .await;
+        match live {
+            Ok(live) => (connection_id, live.len() == 1),
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to update connection directory");
+                (connection_id, true)
+            }
+        }
+    }
+
+    /// Remove a local socket, returning `true` when the user has no
+    /// connection left on any node.
+    pub async fn unregister(&self, tenant_id: Uuid, user_id: Uuid, connection_id: Uuid) -> bool {
+        let last_local = self
+            .registry
+            .unregister(tenant_id, user_id, connection_id)
+            .await;
+        let Some(mut conn) = self.redis.clone() else {
+            return last_local;
+        };
+
+        let live = self
+            .directory(
+                &mut conn,
+                tenant_id,
+                user_id,
+                DirectoryOp::Remove(connection_id),
+            )
+            .await;
+        match live {
+            Ok(live) => live.is_empty(),
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to update connection directory");
+                last_local
+            }
+        }
+    }
+
+    /// Keep the user's directory entry alive while their socket stays up.
+    pub async fn refresh(&self, tenant_id: Uuid, user_id: Uuid) {
+        if let Some(mut conn) = self.redis.clone() {
+            let _ = redis::cmd("EXPIRE")
+                .arg(directory_key(tenant_id, user_id))
+                .arg(DIRECTORY_TTL_SECS)
+                .query_async::<_, ()>(&mut conn)
+                .await;
+        }
+    }
+
+    /// Fan a frame out to every device of the user, on every node, except the
+    /// connection `except`. Returns how many connections it was handed to;
+    /// connections on a node the frame could not be published to do not
+    /// count.
+    pub async fn send_to_user(
+        &self,
+        tenant_id: Uuid,
+        user_id: Uuid,
+        except: Option<Endpoint>,
+        msg: &ServerMessage,
+    ) -> usize {
+        let except_id = except.map(|e| e.connection_id);
+        let mut delivered = self
+            .registry
+            .send_to_user_except(tenant_id, user_id, except_id, msg)
+            .await;
+
+        let Some(mut conn) = self.redis.clone() else {
+            return delivered;
+        };
+        let directory = match self
+            .directory(&mut conn, tenant_id, user_id, DirectoryOp::Read)
+            .await
+        {
+            Ok(directory) => directory,
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to read connection directory");
+                return delivered;
+            }
+        };
+
+        let mut remote_nodes: HashMap<Uuid, usize> = HashMap::new();
+        for (connection_id, node_id) in directory {
+            let (Ok(connection_id), Ok(node_id)) =
+                (connection_id.parse::<Uuid>(), node_id.parse::<Uuid>())
+            else {
+                continue;
+            };
+            if node_id == self.node_id || Some(connection_id) == except_id {
+                continue;
+            }
+            *remote_nodes.entry(node_id).or_default() += 1;
+        }
+        for (node_id, connections) in remote_nodes {
+            let envelope = Envelope::Deliver {
+                tenant_id,
+                user_id,
+                connection_id: None,
+                except: except_id,
+                msg: msg.clone(),
+            };
+            if self.publish(&node_channel(node_id), &envelope).await {
+                delivered += connections;
+            }
+        }
+        delivered
+    }
+
+    /// Deliver a frame to one specific connection, wherever it lives.
+    pub async fn send_to(&self, to: Endpoint, msg: ServerMessage) -> bool {
+        if to.node_id == self.node_id {
+            return self
+                .registry
+                .send_to_connection(to.tenant_id, to.user_id, to.connection_id, msg)
+                .await;
+        }
+        let envelope = Envelope::Deliver {
+            tenant_id: to.tenant_id,
+            user_id: to.user_id,
+            connection_id: Some(to.connection_id),
+            except: None,
+            msg,
+        };
+        self.publish(&node_channel(to.node_id), &envelope).await
+    }
+
+    /// Record this node as the owner of a new call so other replicas know
+    /// where to forward frames for it; `false` when another node owns a call
+    /// by that id already. Without Redis, or when it fails, the local call
+    /// table is authoritative.
+    pub async fn claim_call(&self, call_id: Uuid) -> bool {
+        let Some(mut conn) = self.redis.clone() else {
+            return true;
+        };
+        let claimed: Result<Option<String>, _> = redis::cmd("SET")
+            .arg(call_key(call_id))
+            .arg(self.node_id.to_string())
+            .arg("NX")
+            .arg("EX")
+            .arg(CALL_OWNER_TTL_SECS)
+            .query_async(&mut conn)
+            .await;
+        match claimed {
+            Ok(reply) => reply.is_some(),
+            Err(err) => {
+                tracing::warn!(error = %err, %call_id, "call ownership claim failed");
+                true
+            }
+        }
+    }
+
+    pub async fn release_call(&self, call_id: Uuid) {
+        if let Some(mut conn) = self.redis.clone() {
+            let _ = redis::cmd("DEL")
+                .arg(call_key(call_id))
+                .query_async::<_, ()>(&mut conn)
+                .await;
+        }
+    }
+
+    /// Node that owns `call_id`, if it is known to the cluster.
+    pub async fn call_owner(&self, call_id: Uuid) -> Option<Uuid> {
+        let mut conn = self.redis.clone()?;
+        let owner: Option<String> = redis::cmd("GET")
+            .arg(call_key(call_id))
+            .query_async(&mut conn)
+            .await
+            .ok()?;
+        owner.and_then(|owner| owner.parse().ok())
+    }
+
+    /// Hand a call command over to the node that owns the call.
+    pub async fn forward(&self, owner: Uuid, from: Endpoint, command: Command) -> bool {
+        self.publish(&node_channel(owner), &Envelope::Call { from, command })
+            .await
+    }
+
+    /// Tell the node owning `call_id` that its media relay is gone.
+    pub async fn forward_relay_closed(&self, owner: Uuid, call_id: Uuid) -> bool {
+        self.publish(&node_channel(owner), &Envelope::RelayClosed { call_id })
+            .await
+    }
+
+    /// Take park slot `slot` of the tenant for `call_id`; `false` when it is
+    /// taken already. Without Redis the caller's own table is authoritative.
+    pub async fn claim_park_slot(&self, tenant_id: Uuid, slot: u16, call_id: Uuid) -> bool {
+        let Some(mut conn) = self.redis.clone() else {
+            return true;
+        };
+        let claimed: Result<Option<String>, _> = redis::cmd("SET")
+            .arg(park_key(tenant_id, slot))
+            .arg(call_id.to_string())
+            .arg("NX")
+            .arg("EX")
+            .arg(CALL_OWNER_TTL_SECS)
+            .query_async(&mut conn)
+            .await;
+        match claimed {
+            Ok(reply) => reply.is_some(),
+            Err(err) => {
+                tracing::warn!(error = %err, "park slot claim failed");
+                false
+            }
+        }
+    }
+
+    /// The call parked in `slot`, when it is parked on another replica.
+    pub async fn parked_call(&self, tenant_id: Uuid, slot: u16) -> Option<Uuid> {
+        let mut conn = self.redis.clone()?;
+        let call_id: Option<String> = redis::cmd("GET")
+            .arg(park_key(tenant_id, slot))
+            .query_async(&mut conn)
+            .await
+            .ok()?;
+        call_id.and_then(|id| id.parse().ok())
+    }
+
+    pub async fn release_park_slot(&self, tenant_id: Uuid, slot: u16) {
+        if let Some(mut conn) = self.redis.clone() {
+            let _ = redis::cmd("DEL")
+                .arg(park_key(tenant_id, slot))
+                .query_async::<_, ()>(&mut conn)
+                .await;
+        }
+    }
+
+    /// Record that `call_id` is ringing `user_id`, for pickups from other
+    /// replicas; or that it stopped.
+    pub async fn set_ringing(&self, tenant_id: Uuid, user_id: Uuid, call_id: Uuid, ringing: bool) {
+        let Some(mut conn) = self.redis.clone() else {
+            return;
+        };
+        let key = ringing_key(tenant_id, user_id);
+        let result = if ringing {
+            redis::pipe()
+                .cmd("SADD")
+                .arg(&key)
+                .arg(call_id.to_string())
+                .ignore()
+                .cmd("EXPIRE")
+                .arg(&key)
+                .arg(DIRECTORY_TTL_SECS)
+                .ignore()
+                .query_async::<_, ()>(&mut conn)
+                .await
+        } else {
+            redis::cmd("SREM")
+                .arg(&key)
+                .arg(call_id.to_string())
+                .query_async::<_, ()>(&mut conn)
+                .await
+        };
+        if let Err(err) = result {
+            tracing::warn!(error = %err, "ringing index update failed");
+        }
+    }
+
+    /// Calls ringing `user_id` anywhere in the cluster.
+    pub async fn ringing_calls(&self, tenant_id: Uuid, user_id: Uuid) -> Vec<Uuid> {
+        let Some(mut conn) = self.redis.clone() else {
+            return Vec::new();
+        };
+        let ids: Vec<String> = redis::cmd("SMEMBERS")
+            .arg(ringing_key(tenant_id, user_id))
+            .query_async(&mut conn)
+            .await
+            .unwrap_or_default();
+        ids.iter().filter_map(|id| id.parse().ok()).collect()
+    }
+
+    /// Deliver a frame to every socket of a tenant, here and on every other
+    /// replica.
+    pub async fn send_to_tenant(&self, tenant_id: Uuid, msg: ServerMessage) {
+        self.registry.send_to_tenant(tenant_id, &msg).await;
+        let envelope = TenantEnvelope {
+            origin: self.node_id,
+            tenant_id,
+            msg,
+        };
+        self.publish(TENANT_CHANNEL, &envelope).await;
+    }
+
+    /// Announce a presence change locally and to every other replica.
+    pub async fn publish_presence(&self, event: PresenceEvent) {
+        let _ = self.presence_tx.send(event.clone());
+        let envelope = PresenceEnvelope {
+            origin: self.node_id,
+            event,
+        };
+        self.publish(PRESENCE_CHANNEL, &envelope).await;
+    }
+
+    /// Publish on `channel`; `true` when at least one replica was
+    /// listening.
+    async fn publish<T: Serialize>(&self, channel: &str, payload: &T) -> bool {
+        let Some(mut conn) = self.redis.clone() else {
+            return false;
+        };
+        let payload = serde_json::to_string(payload).expect("envelopes always serialize");
+        match redis::cmd("PUBLISH")
+            .arg(channel)
+            .arg(payload)
+            .query_async::<_, usize>(&mut conn)
+            .await
+        {
+            Ok(receivers) => receivers > 0,
+            Err(err) => {
+                tracing::warn!(error = %err, channel, "redis publish failed");
+                false
+            }
+        }
+    }
+}
+
+/// Keep this node's heartbeat fresh for as long as the process runs.
+pub async fn run_heartbeat(cluster: Arc<Cluster>) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+        cluster.heartbeat().await;
+    }
+}
+
+/// Subscribe to this node's channel and the shared channels, applying
+/// whatever other replicas send us. Reconnects with a fixed back-off so a Redis
+/// restart does not permanently partition the node.
+pub async fn run_subscriber(client: redis::Client, state: AppState) {
+    let node_channel = node_channel(state.cluster.node_id);
+    loop {
+        match subscribe_once(&client, &node_channel, &state).await {
+            Ok(()) => tracing::warn!("redis subscription ended"),
+            Err(err) => tracing::warn!(error = %err, "redis subscription failed"),
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
+
+/// Work another replica handed over for a call this node holds.
+enum CallWork {
+    Command { from: Endpoint, command: Command },
+    RelayClosed,
+}
+
+/// Queues of forwarded work per call. Each call has its own task applying its
+/// work in arrival order, so a command waiting on the PBX or the media relay
+/// holds up that call and not the subscriber.
+type CallQueues = HashMap<Uuid, mpsc::UnboundedSender<CallWork>>;
+
+fn hand_over(state: &AppState, queues: &mut CallQueues, call_id: Uuid, work: CallWork) {
+    let work = match queues.get(&call_id) {
+        Some(tx) => match tx.send(work) {
+            Ok(()) => return,
+            Err(mpsc::error::SendError(work)) => work,
+        },
+        None => work,
+    };
+    let (tx, rx) = mpsc::unbounded_channel();
+    let _ = tx.send(work);
+    queues.insert(call_id, tx);
+    tokio::spawn(run_call_queue(state.clone(), call_id, rx));
+}
+
+async fn run_call_queue(state: AppState, call_id: Uuid, mut rx: mpsc::UnboundedReceiver<CallWork>) {
+    while let Some(work) = rx.recv().await {
+        match work {
+            CallWork::Command { from, command } => calls::apply(&state, from, command).await,
+            CallWork::RelayClosed => calls::media_lost(&state, call_id).await,
+        }
+    }
+}
+
+/// Drop the queues of calls that ended; their tasks finish what is queued
+/// and exit.
+async fn sweep_call_queues(state: &AppState, queues: &mut CallQueues) {
+    let mut ended = Vec::new();
+    for call_id in queues.keys() {
+        if !calls::is_local(state, *call_id).await {
+            ended.push(*call_id);
+        }
+    }
+    for call_id in ended {
+        queues.remove(&call_id);
+    }
+}
+
+async fn subscribe_once(
+    client: &redis::Client,
+    node_channel: &str,
+    state: &AppState,
+) -> redis::RedisResult<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(node_channel).await?;
+    pubsub.subscribe(PRESENCE_CHANNEL).await?;
+    pubsub.subscribe(TENANT_CHANNEL).await?;
+    tracing::info!(node_id = %state.cluster.node_id, "subscribed to cluster channels");
+
+    let mut messages = pubsub.on_message();
+    let mut call_queues = CallQueues::new();
+    let mut sweep = tokio::time::interval(CALL_QUEUE_SWEEP_INTERVAL);
+    loop {
+        let msg = tokio::select! {
+            msg = messages.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = sweep.tick() => {
+                sweep_call_queues(state, &mut call_queues).await;
+                continue;
+            }
+        };
+        let payload: String = match msg.get_payload() {
+            Ok(payload) => payload,
+            Err(err) => {
+                tracing::warn!(error = %err, "invalid cluster payload");
+                continue;
+            }
+        };
+
+        if msg.get_channel_name() == PRESENCE_CHANNEL {
+            match serde_json::from_str::<PresenceEnvelope>(&payload) {
+                // Our own announcements were already sent locally.
+                Ok(envelope) if envelope.origin != state.cluster.node_id => {
+                    let _ = state.presence_tx.send(envelope.event);
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!(error = %err, "invalid presence envelope"),
+            }
+            continue;
+        }
+        if msg.get_channel_name() == TENANT_CHANNEL {
+            match serde_json::from_str::<TenantEnvelope>(&payload) {
+                Ok(envelope) if envelope.origin != state.cluster.node_id => {
+                    state
+                        .registry
+                        .send_to_tenant(envelope.tenant_id, &envelope.msg)
+                        .await;
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!(error = %err, "invalid tenant envelope"),
+            }
+            continue;
+        }
+
+        match serde_json::from_str::<Envelope>(&payload) {
+            Ok(Envelope::Deliver {
+                tenant_id,
+                user_id,
+                connection_id: Some(connection_id),
+                msg,
+                ..
+            }) => {
+                state
+                    .registry
+                    .send_to_connection(tenant_id, user_id, connection_id, msg)
+                    .await;
+            }
+            Ok(Envelope::Deliver {
+                tenant_id,
+                user_id,
+                connection_id: None,
+                except,
+                msg,
+            }) => {
+                state
+                    .registry
+                    .send_to_user_except(tenant_id, user_id, except, &msg)
+                    .await;
+            }
+            Ok(Envelope::Call { from, command }) => match command.call_id() {
+                Some(call_id) => {
+                    let work = CallWork::Command { from, command };
+                    hand_over(state, &mut call_queues, call_id, work);
+                }
+                None => {
+                    let state = state.clone();
+                    tokio::spawn(async move { calls::apply(&state, from, command).await });
+                }
+            },
+            Ok(Envelope::RelayClosed { call_id }) => {
+                hand_over(state, &mut call_queues, call_id, CallWork::RelayClosed);
+            }
+            Err(err) => tracing::warn!(error = %err, "invalid cluster envelope"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calls::tests::{connect, online};
+    use crate::protocol::ClientMessage;
+
+    fn cluster(redis: Option<redis::aio::ConnectionManager>) -> Cluster {
+        let (presence_tx, _rx) = broadcast::channel(16);
+        Cluster::new(Arc::new(Registry::default()), redis, presence_tx)
+    }
+
+    /// Register a socket of `user_id` on `cluster`: its connection id.
+    async fn connect_socket(cluster: &Cluster, tenant_id: Uuid, user_id: Uuid) -> Uuid {
+        let (tx, _rx) = mpsc::channel(1);
+        let (connection_id, _) = cluster
+            .register(tenant_id, user_id, DeviceKind::default(), tx)
+            .await;
+        connection_id
+    }
+
+    #[tokio::test]
+    async fn the_directory_drops_connections_of_nodes_without_a_heartbeat() {
+        let Some(redis) = crate::test_redis().await else {
+            return;
+        };
+        let (alive, crashed) = (cluster(Some(redis.clone())), cluster(Some(redis.clone())));
+        alive.heartbeat().await;
+        let (tenant_id, user_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let ours = connect_socket(&alive, tenant_id, user_id).await;
+        let theirs = connect_socket(&crashed, tenant_id, user_id).await;
+
+        // A node always trusts its own entries.
+        let mut conn = redis.clone();
+        let seen = crashed
+            .directory(&mut conn, tenant_id, user_id, DirectoryOp::Read)
+            .await
+            .unwrap();
+        assert_eq!(seen.len(), 2);
+
+        let seen = alive
+            .directory(&mut conn, tenant_id, user_id, DirectoryOp::Read)
+            .await
+            .unwrap();
+        let expected = HashMap::from([(ours.to_string(), alive.node_id.to_string())]);
+        assert_eq!(seen, expected);
+        let stored: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(directory_key(tenant_id, user_id))
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(stored, expected, "{theirs} was left behind");
+
+        // The last connection gone, the user is offline everywhere.
+        assert!(alive.unregister(tenant_id, user_id, ours).await);
+    }
+
+    #[tokio::test]
+    async fn a_call_has_one_owner() {
+        let Some(redis) = crate::test_redis().await else {
+            return;
+        };
+        let (first, second) = (cluster(Some(redis.clone())), cluster(Some(redis)));
+        let call_id = Uuid::new_v4();
+        assert_eq!(first.call_owner(call_id).await, None);
+
+        assert!(first.claim_call(call_id).await);
+        assert!(!second.claim_call(call_id).await);
+        assert_eq!(second.call_owner(call_id).await, Some(first.node_id));
+
+        first.release_call(call_id).await;
+        assert_eq!(second.call_owner(call_id).await, None);
+        assert!(second.claim_call(call_id).await);
+        assert_eq!(first.call_owner(call_id).await, Some(second.node_id));
+        second.release_call(call_id).await;
+    }
+
+    #[tokio::test]
+    async fn a_call_id_owned_by_another_node_is_refused() {
+        let Some(redis) = crate::test_redis().await else {
+            return;
+        };
+        let mut state = AppState::for_tests();
+        state.cluster = Arc::new(Cluster::new(
+            state.registry.clone(),
+            Some(redis.clone()),
+            state.presence_tx.clone(),
+        ));
+        let tenant_id = Uuid::new_v4();
+        let mut caller = online(&state, tenant_id).await;
+        let callee = online(&state, tenant_id).await;
+        let call_id = Uuid::new_v4();
+        let other = cluster(Some(redis));
+        assert!(other.claim_call(call_id).await);
+
+        let to = callee.endpoint.user_id.to_string();
+        let initiate = ClientMessage::Initiate { call_id, to };
+        calls::apply(&state, caller.endpoint, Command::Client { msg: initiate }).await;
+        assert!(state.calls.read().await.is_empty());
+        let refused = std::iter::from_fn(|| caller.rx.try_recv().ok()).any(|msg| {
+            matches!(
+                msg,
+                ServerMessage::Error {
+                    code: crate::protocol::ErrorCode::DuplicateCall,
+                    ..
+                }
+            )
+        });
+        assert!(refused);
+        assert_eq!(other.call_owner(call_id).await, Some(other.node_id));
+        other.release_call(call_id).await;
+    }
+
+    #[tokio::test]
+    async fn without_redis_the_local_table_decides() {
+        let lone = cluster(None);
+        let call_id = Uuid::new_v4();
+        assert!(lone.claim_call(call_id).await);
+        assert!(lone.claim_call(call_id).await);
+        assert_eq!(lone.call_owner(call_id).await, None);
+    }
+
+    #[tokio::test]
+    async fn forwarded_commands_apply_in_arrival_order_per_call() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut caller = online(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let call_id = connect(&state, caller.endpoint, callee.endpoint).await;
+
+        let mut queues = CallQueues::new();
+        for msg in [
+            ClientMessage::Hold { call_id },
+            ClientMessage::Resume { call_id },
+            ClientMessage::Hold { call_id },
+            ClientMessage::Ended { call_id },
+        ] {
+            let command = Command::Client { msg };
+            let work = CallWork::Command {
+                from: caller.endpoint,
+                command,
+            };
+            hand_over(&state, &mut queues, call_id, work);
+        }
+        assert_eq!(queues.len(), 1);
+
+        let mut seen = Vec::new();
+        while seen.len() < 3 {
+            let msg = tokio::time::timeout(Duration::from_secs(10), caller.rx.recv())
+                .await
+                .expect("the queue drains")
+                .expect("the session is open");
+            seen.push(match msg {
+                ServerMessage::Held { .. } => "held",
+                ServerMessage::Resumed { .. } => "resumed",
+                _ => continue,
+            });
+        }
+        assert_eq!(seen, ["held", "resumed", "held"]);
+        // The hang-up came last.
+        let mut heard = Vec::new();
+        while !matches!(heard.last(), Some(ServerMessage::Ended { .. })) {
+            let msg = tokio::time::timeout(Duration::from_secs(10), callee.rx.recv()).await;
+            heard.push(msg.expect("the queue drains").expect("the session is open"));
+        }
+        assert_eq!(heard.len(), 4);
+
+        // The call is over: its queue goes at the next sweep.
+        sweep_call_queues(&state, &mut queues).await;
+        assert!(queues.is_empty());
+    }
+}
@@ -0,0 +1,726 @@
+//! Conference rooms: any number of parties, mixed by the media service.
+//!
+//! Every participant takes part over a call leg of its own. `conference.join`
+//! names the room and a fresh `callId`; the client's offer on that call id is
+//! answered by the media mixer, and `call.ended` on it leaves the room. SIP
+//! phones dial in by calling a number whose call-flow starts with a
+//! `conference` node, with any PIN after a comma (`<number>,<pin>`).
+//!
+//! Rooms configured in the PBX check PINs there, only for rooms of the
+//! joining user's own tenant, and whoever joins with the moderator PIN may
+//! mute, remove and lock. A user who keeps giving wrong PINs for a room is
+//! kept out of it for a while. Any other conference id opens an
+//! ad-hoc room moderated by whoever opened it, which others only join when
+//! invited. Invited users never need a PIN.
+//!
+//! A room lives on the node it was opened on: the conference id and every
+//! participant's call id are claimed in the cluster like calls, so commands
+//! arriving elsewhere are forwarded there.
+
+use crate::calls::{self, Endpoint};
+use crate::pbx::RoomAccess;
+use crate::presence;
+use crate::protocol::{ConferenceParticipant, EndReason, ErrorCode, ServerMessage};
+use crate::AppState;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// Largest ad-hoc room; configured rooms set their own limit.
+const AD_HOC_MAX_PARTICIPANTS: usize = 10;
+
+/// Rooms open on this node.
+#[derive(Debug, Default)]
+pub struct Conferences {
+    rooms: HashMap<Uuid, Room>,
+    /// Room of each participant's call leg.
+    legs: HashMap<Uuid, Uuid>,
+}
+
+impl Conferences {
+    /// Whether `id` is a room or a participant's call leg on this node.
+    pub fn contains(&self, id: Uuid) -> bool {
+        self.rooms.contains_key(&id) || self.legs.contains_key(&id)
+    }
+
+    /// Whether `user_id` takes part in a conference over any leg.
+    pub fn engages(&self, user_id: Uuid) -> bool {
+        self.legs.iter().any(|(call_id, conference_id)| {
+            self.rooms
+                .get(conference_id)
+                .and_then(|room| room.participants.get(call_id))
+                .is_some_and(|member| member.endpoint.user_id == user_id)
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Room {
+    tenant_id: Uuid,
+    /// Configured in the PBX, rather than opened ad hoc.
+    configured: bool,
+    locked: bool,
+    max_participants: usize,
+    /// Users asked in by a participant, who may join without a PIN.
+    invited: HashSet<Uuid>,
+    /// By the call id each participant joined over.
+    participants: HashMap<Uuid, Member>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Member {
+    endpoint: Endpoint,
+    moderator: bool,
+    muted: bool,
+}
+
+impl Room {
+    fn roster(&self) -> Vec<ConferenceParticipant> {
+        self.participants
+            .iter()
+            .map(|(call_id, member)| member.describe(*call_id))
+            .collect()
+    }
+
+    fn endpoints(&self) -> Vec<Endpoint> {
+        self.participants.values().map(|m| m.endpoint).collect()
+    }
+
+    /// The participant `from` is, with the call id it joined over.
+    fn member(&self, from: &Endpoint) -> Option<(Uuid, Member)> {
+        self.participants
+            .iter()
+            .find(|(_, member)| member.endpoint == *from)
+            .map(|(call_id, member)| (*call_id, *member))
+    }
+}
+
+impl Member {
+    fn describe(&self, call_id: Uuid) -> ConferenceParticipant {
+        ConferenceParticipant {
+            call_id,
+            user_id: self.endpoint.user_id,
+            moderator: self.moderator,
+            muted: self.muted,
+        }
+    }
+}
+
+async fn refuse(
+    state: &AppState,
+    to: Endpoint,
+    call_id: Option<Uuid>,
+    code: ErrorCode,
+    message: &str,
+) {
+    let err = ServerMessage::error(call_id, code, message);
+    state.cluster.send_to(to, err).await;
+}
+
+fn pin_failures_key(conference_id: Uuid, user_id: Uuid) -> String {
+    format!("conference:pinfail:{conference_id}:{user_id}")
+}
+
+async fn broadcast(state: &AppState, to: &[Endpoint], msg: &ServerMessage) {
+    for endpoint in to {
+        state.cluster.send_to(*endpoint, msg.clone()).await;
+    }
+}
+
+/// Join `from` to `conference_id` over the new call leg `call_id`.
+pub async fn join(
+    state: &AppState,
+    from: Endpoint,
+    conference_id: Uuid,
+    call_id: Uuid,
+    pin: Option<String>,
+) {
+    let in_use = state.calls.read().await.contains_key(&call_id)
+        || state.conferences.read().await.contains(call_id);
+    if in_use {
+        let message = "call id already in use";
+        refuse(
+            state,
+            from,
+            Some(call_id),
+            ErrorCode::DuplicateCall,
+            message,
+        )
+        .await;
+        return;
+    }
+
+    // Invitations and ad-hoc rooms are settled here, PINs by the PBX.
+    let local = state
+        .conferences
+        .read()
+        .await
+        .rooms
+        .get(&conference_id)
+        .map(|room| {
+            let invited = room.invited.contains(&from.user_id);
+            (
+                room.tenant_id,
+                room.configured,
+                invited,
+                room.max_participants,
+            )
+        });
+    let (moderator, configured, max_participants) = match local {
+        Some((tenant_id, ..)) if tenant_id != from.tenant_id => {
+            let message = "unknown conference";
+            refuse(
+                state,
+                from,
+                Some(call_id),
+                ErrorCode::InvalidDestination,
+                message,
+            )
+            .await;
+            return;
+        }
+        Some((_, configured, true, max)) if pin.is_none() => (false, configured, max),
+        Some((_, false, ..)) => {
+            let message = "this conference is by invitation only";
+            refuse(state, from, Some(call_id), ErrorCode::NotAllowed, message).await;
+            return;
+        }
+        _ => {
+            // Whoever keeps guessing wrong PINs for a room is kept out of it
+            // for a while.
+            let attempts = pin_failures_key(conference_id, from.user_id);
+            match state.conference_pins.locked(&attempts).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    let message = "too many wrong pins, try again later";
+                    refuse(state, from, Some(call_id), ErrorCode::NotAllowed, message).await;
+                    return;
+                }
+                Err(err) => {
+                    tracing::warn!(%conference_id, error = %err, "pin attempt lookup failed");
+                    let message = "conference unavailable";
+                    refuse(state, from, Some(call_id), ErrorCode::Unavailable, message).await;
+                    return;
+                }
+            }
+            match state
+                .pbx
+                .join_conference(from.tenant_id, conference_id, pin.as_deref())
+                .await
+            {
+                Ok(RoomAccess::Granted(access)) if access.tenant_id == from.tenant_id => {
+                    state.conference_pins.clear(&attempts).await;
+                    (access.moderator, true, access.max_participants)
+                }
+                Ok(RoomAccess::Denied) => {
+                    if state.conference_pins.record_failure(&attempts).await {
+                        tracing::warn!(
+                            %conference_id,
+                            user_id = %from.user_id,
+                            "conference pin locked after repeated failures"
+                        );
+                    }
+                    refuse(
+                        state,
+                        from,
+                        Some(call_id),
+                        ErrorCode::NotAllowed,
+                        "invalid pin",
+                    )
+                    .await;
+                    return;
+                }
+                Ok(RoomAccess::Unknown) if local.is_none() => {
+                    (true, false, AD_HOC_MAX_PARTICIPANTS)
+                }
+                // Rooms of other tenants are indistinguishable from unknown ones.
+                Ok(_) => {
+                    let message = "unknown conference";
+                    refuse(
+                        state,
+                        from,
+                        Some(call_id),
+                        ErrorCode::InvalidDestination,
+                        message,
+                    )
+                    .await;
+                    return;
+                }
+                Err(err) => {
+                    tracing::warn!(%conference_id, error = %err, "pbx conference lookup failed");
+                    let message = "conference unavailable";
+                    refuse(state, from, Some(call_id), ErrorCode::Unavailable, message).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    let member = Member {
+        endpoint: from,
+        moderator,
+        muted: false,
+    };
+    if !state.cluster.claim_call(call_id).await {
+        let message = "duplicate call id";
+        refuse(
+            state,
+            from,
+            Some(call_id),
+            ErrorCode::DuplicateCall,
+            message,
+        )
+        .await;
+        return;
+    }
+    let (opened, locked, roster, others) = {
+        let mut conferences = state.conferences.write().await;
+        let opened = !conferences.rooms.contains_key(&conference_id);
+        let room = conferences
+            .rooms
+            .entry(conference_id)
+            .or_insert_with(|| Room {
+                tenant_id: from.tenant_id,
+                configured,
+                locked: false,
+                max_participants,
+                invited: HashSet::new(),
+                participants: HashMap::new(),
+            });
+        let refusal = if room.locked && !moderator {
+            Some((ErrorCode::NotAllowed, "conference is locked"))
+        } else if room.participants.len() >= room.max_participants {
+            Some((ErrorCode::Unavailable, "conference is full"))
+        } else {
+            None
+        };
+        if let Some((code, message)) = refusal {
+            drop(conferences);
+            state.cluster.release_call(call_id).await;
+            refuse(state, from, Some(call_id), code, message).await;
+            return;
+        }
+        let others = room.endpoints();
+        room.invited.remove(&from.user_id);
+        room.participants.insert(call_id, member);
+        let (locked, roster) = (room.locked, room.roster());
+        conferences.legs.insert(call_id, conference_id);
+        (opened, locked, roster, others)
+    };
+    if opened {
+        state.cluster.claim_call(conference_id).await;
+    }
+    tracing::info!(%conference_id, %call_id, moderator, "conference join");
+
+    state
+        .cluster
+        .send_to(from, ServerMessage::Connected { call_id })
+        .await;
+    let joined_state = ServerMessage::ConferenceState {
+        conference_id,
+        call_id,
+        locked,
+        participants: roster,
+    };
+    state.cluster.send_to(from, joined_state).await;
+    let joined = ServerMessage::ConferenceJoined {
+        conference_id,
+        participant: member.describe(call_id),
+    };
+    broadcast(state, &others, &joined).await;
+    presence::set_on_call(state, from.tenant_id, &[from.user_id], true).await;
+}
+
+/// Media for a participant's leg. Offers are answered by the mixer; trickled
+/// candidates are dropped, as the mixer only speaks plain RTP. `false` when
+/// `call_id` is not a leg `from` joined a conference over.
+pub async fn signal(
+    state: &AppState,
+    from: Endpoint,
+    call_id: Uuid,
+    data: &serde_json::Value,
+) -> bool {
+    let member = {
+        let conferences = state.conferences.read().await;
+        conferences.legs.get(&call_id).and_then(|conference_id| {
+            let member = conferences
+                .rooms
+                .get(conference_id)?
+                .participants
+                .get(&call_id)?;
+            (member.endpoint == from).then_some((*conference_id, member.muted))
+        })
+    };
+    let Some((conference_id, muted)) = member else {
+        return false;
+    };
+    let offer = data
+        .get("sdp")
+        .and_then(|sdp| sdp.as_str())
+        .filter(|_| data.get("type").and_then(|t| t.as_str()) == Some("offer"));
+    let Some(offer) = offer else {
+        return true;
+    };
+
+    let media = &state.media;
+    match media.join_conference(conference_id, call_id, offer).await {
+        Ok(leg) => {
+            if muted {
+                if let Err(err) = media.mute_participant(conference_id, call_id, true).await {
+                    tracing::warn!(%conference_id, error = %err, "failed to mute participant");
+                }
+            }
+            let msg = ServerMessage::Signal {
+                call_id,
+                data: json!({ "type": "answer", "sdp": leg.sdp }),
+            };
+            state.cluster.send_to(from, msg).await;
+            state
+                .cluster
+                .send_to(from, leg.relay_message(call_id))
+                .await;
+        }
+        Err(err) => {
+            tracing::warn!(%conference_id, error = %err, "conference mixer refused participant");
+            remove(state, call_id, false).await;
+            let message = "conference media unavailable";
+            refuse(state, from, Some(call_id), ErrorCode::Unavailable, message).await;
+        }
+    }
+    true
+}
+
+/// `from` hung up (or lost) its leg `call_id`. `false` when it is not a leg
+/// `from` joined a conference over.
+pub async fn hang_up(state: &AppState, from: Endpoint, call_id: Uuid) -> bool {
+    let ours = {
+        let conferences = state.conferences.read().await;
+        conferences
+            .legs
+            .get(&call_id)
+            .and_then(|id| conferences.rooms.get(id)?.participants.get(&call_id))
+            .is_some_and(|member| member.endpoint == from)
+    };
+    if ours {
+        remove(state, call_id, false).await;
+    }
+    ours
+}
+
+/// Take the participant joined over `call_id` out of its room, closing the
+/// room when it was the last one.
+async fn remove(state: &AppState, call_id: Uuid, kicked: bool) -> Option<Endpoint> {
+    let (conference_id, member, remaining, closed) = {
+        let mut conferences = state.conferences.write().await;
+        let conference_id = conferences.legs.remove(&call_id)?;
+        let room = conferences.rooms.get_mut(&conference_id)?;
+        let member = room.participants.remove(&call_id)?;
+        let remaining = room.endpoints();
+        let closed = remaining.is_empty();
+        if closed {
+            conferences.rooms.remove(&conference_id);
+        }
+        (conference_id, member, remaining, closed)
+    };
+    state.cluster.release_call(call_id).await;
+    if closed {
+        state.cluster.release_call(conference_id).await;
+        tracing::info!(%conference_id, "conference closed");
+    }
+    if let Err(err) = state.media.remove_participant(conference_id, call_id).await {
+        tracing::warn!(%conference_id, error = %err, "failed to remove participant from mixer");
+    }
+
+    let left = ServerMessage::ConferenceLeft {
+        conference_id,
+        call_id,
+        user_id: member.endpoint.user_id,
+        kicked,
+    };
+    broadcast(state, &remaining, &left).await;
+    state.cluster.send_to(member.endpoint, left).await;
+    calls::leave_call(state, member.endpoint.tenant_id, &[member.endpoint.user_id]).await;
+    Some(member.endpoint)
+}
+
+/// The participant `from` is in `conference_id`, if it is one; refuses the
+/// command otherwise, or when `moderator` is required and it is not one.
+async fn participant(
+    state: &AppState,
+    from: Endpoint,
+    conference_id: Uuid,
+    moderator: bool,
+) -> Option<(Uuid, bool)> {
+    let member = state
+        .conferences
+        .read()
+        .await
+        .rooms
+        .get(&conference_id)
+        .and_then(|room| room.member(&from));
+    match member {
+        Some((call_id, member)) if member.moderator || !moderator => {
+            Some((call_id, member.moderator))
+        }
+        Some(_) => {
+            let message = "only moderators may do that";
+            refuse(state, from, None, ErrorCode::NotAllowed, message).await;
+            None
+        }
+        None => {
+            let message = "not in this conference";
+            refuse(state, from, None, ErrorCode::UnknownCall, message).await;
+            None
+        }
+    }
+}
+
+/// Ask `to` into a conference `from` takes part in.
+pub async fn invite(state: &AppState, from: Endpoint, conference_id: Uuid, to: &str) {
+    if participant(state, from, conference_id, false)
+        .await
+        .is_none()
+    {
+        return;
+    }
+    let user_id = match calls::resolve_user(state, from, to).await {
+        Ok(user_id) => user_id,
+        Err((code, message)) => {
+            refuse(state, from, None, code, message).await;
+            return;
+        }
+    };
+    if let Some(room) = state
+        .conferences
+        .write()
+        .await
+        .rooms
+        .get_mut(&conference_id)
+    {
+        room.invited.insert(user_id);
+    }
+    let invited = ServerMessage::ConferenceInvited {
+        conference_id,
+        by: from.user_id,
+    };
+    state
+        .cluster
+        .send_to_user(from.tenant_id, user_id, None, &invited)
+        .await;
+}
+
+pub async fn mute(
+    state: &AppState,
+    from: Endpoint,
+    conference_id: Uuid,
+    call_id: Uuid,
+    muted: bool,
+) {
+    let Some((own_call_id, moderator)) = participant(state, from, conference_id, false).await
+    else {
+        return;
+    };
+    if call_id != own_call_id && !moderator {
+        let message = "only moderators may mute others";
+        refuse(state, from, None, ErrorCode::NotAllowed, message).await;
+        return;
+    }
+    let everyone = {
+        let mut conferences = state.conferences.write().await;
+        let Some(room) = conferences.rooms.get_mut(&conference_id) else {
+            return;
+        };
+        let Some(member) = room.participants.get_mut(&call_id) else {
+            drop(conferences);
+            let message = "not in this conference";
+            refuse(state, from, None, ErrorCode::UnknownCall, message).await;
+            return;
+        };
+        member.muted = muted;
+        room.endpoints()
+    };
+    if let Err(err) = state
+        .media
+        .mute_participant(conference_id, call_id, muted)
+        .await
+    {
+        // Not an error before the participant's media is up; its offer
+        // applies the flag then.
+        tracing::debug!(%conference_id, error = %err, "mixer did not take the mute");
+    }
+    let msg = ServerMessage::ConferenceMuted {
+        conference_id,
+        call_id,
+        muted,
+    };
+    broadcast(state, &everyone, &msg).await;
+}
+
+pub async fn kick(state: &AppState, from: Endpoint, conference_id: Uuid, call_id: Uuid) {
+    if participant(state, from, conference_id, true)
+        .await
+        .is_none()
+    {
+        return;
+    }
+    let in_room = state.conferences.read().await.legs.get(&call_id) == Some(&conference_id);
+    if !in_room {
+        let message = "not in this conference";
+        refuse(state, from, None, ErrorCode::UnknownCall, message).await;
+        return;
+    }
+    tracing::info!(%conference_id, %call_id, "conference kick");
+    if let Some(endpoint) = remove(state, call_id, true).await {
+        let ended = ServerMessage::Ended {
+            call_id,
+            reason: EndReason::Kicked,
+        };
+        state.cluster.send_to(endpoint, ended).await;
+    }
+}
+
+pub async fn lock(state: &AppState, from: Endpoint, conference_id: Uuid, locked: bool) {
+    if participant(state, from, conference_id, true)
+        .await
+        .is_none()
+    {
+        return;
+    }
+    let everyone = {
+        let mut conferences = state.conferences.write().await;
+        let Some(room) = conferences.rooms.get_mut(&conference_id) else {
+            return;
+        };
+        room.locked = locked;
+        room.endpoints()
+    };
+    let msg = ServerMessage::ConferenceLocked {
+        conference_id,
+        locked,
+    };
+    broadcast(state, &everyone, &msg).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::DeviceKind;
+    use crate::session::Session;
+
+    /// A signed-in device of a new user.
+    async fn online(state: &AppState, tenant_id: Uuid) -> Session {
+        Session::open(state, tenant_id, Uuid::new_v4(), DeviceKind::default()).await
+    }
+
+    /// Open a room with `members` (each flagged moderator or not) in it,
+    /// returning its id and each member's call leg.
+    async fn room(state: &AppState, members: &[(Endpoint, bool)]) -> (Uuid, Vec<Uuid>) {
+        let conference_id = Uuid::new_v4();
+        let mut conferences = state.conferences.write().await;
+        let mut participants = HashMap::new();
+        let mut legs = Vec::new();
+        for (endpoint, moderator) in members {
+            let call_id = Uuid::new_v4();
+            let member = Member {
+                endpoint: *endpoint,
+                moderator: *moderator,
+                muted: false,
+            };
+            participants.insert(call_id, member);
+            conferences.legs.insert(call_id, conference_id);
+            legs.push(call_id);
+        }
+        let room = Room {
+            tenant_id: members[0].0.tenant_id,
+            configured: false,
+            locked: false,
+            max_participants: AD_HOC_MAX_PARTICIPANTS,
+            invited: HashSet::new(),
+            participants,
+        };
+        conferences.rooms.insert(conference_id, room);
+        (conference_id, legs)
+    }
+
+    /// The code of the first error `session` was sent, if any.
+    fn refusal(session: &mut Session) -> Option<ErrorCode> {
+        std::iter::from_fn(|| session.rx.try_recv().ok()).find_map(|msg| match msg {
+            ServerMessage::Error { code, .. } => Some(code),
+            _ => None,
+        })
+    }
+
+    async fn member(state: &AppState, conference_id: Uuid, call_id: Uuid) -> Option<Member> {
+        let conferences = state.conferences.read().await;
+        conferences.rooms[&conference_id]
+            .participants
+            .get(&call_id)
+            .copied()
+    }
+
+    #[tokio::test]
+    async fn only_moderators_mute_others() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let moderator = online(&state, tenant_id).await;
+        let mut guest = online(&state, tenant_id).await;
+        let members = [(moderator.endpoint, true), (guest.endpoint, false)];
+        let (conference_id, legs) = room(&state, &members).await;
+
+        mute(&state, guest.endpoint, conference_id, legs[0], true).await;
+        assert_eq!(refusal(&mut guest), Some(ErrorCode::NotAllowed));
+        assert!(!member(&state, conference_id, legs[0]).await.unwrap().muted);
+
+        // Muting oneself needs no moderator.
+        mute(&state, guest.endpoint, conference_id, legs[1], true).await;
+        assert_eq!(refusal(&mut guest), None);
+        assert!(member(&state, conference_id, legs[1]).await.unwrap().muted);
+        mute(&state, moderator.endpoint, conference_id, legs[1], false).await;
+        assert!(!member(&state, conference_id, legs[1]).await.unwrap().muted);
+    }
+
+    #[tokio::test]
+    async fn only_moderators_kick() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let moderator = online(&state, tenant_id).await;
+        let mut guest = online(&state, tenant_id).await;
+        let members = [(moderator.endpoint, true), (guest.endpoint, false)];
+        let (conference_id, legs) = room(&state, &members).await;
+
+        kick(&state, guest.endpoint, conference_id, legs[0]).await;
+        assert_eq!(refusal(&mut guest), Some(ErrorCode::NotAllowed));
+        assert!(member(&state, conference_id, legs[0]).await.is_some());
+
+        kick(&state, moderator.endpoint, conference_id, legs[1]).await;
+        assert!(member(&state, conference_id, legs[1]).await.is_none());
+        let kicked = std::iter::from_fn(|| guest.rx.try_recv().ok()).any(|msg| {
+            matches!(msg, ServerMessage::Ended { call_id, reason: EndReason::Kicked } if call_id == legs[1])
+        });
+        assert!(kicked);
+    }
+
+    #[tokio::test]
+    async fn only_moderators_lock() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let moderator = online(&state, tenant_id).await;
+        let mut guest = online(&state, tenant_id).await;
+        let mut outsider = online(&state, tenant_id).await;
+        let members = [(moderator.endpoint, true), (guest.endpoint, false)];
+        let (conference_id, _) = room(&state, &members).await;
+        let locked = |state: &AppState| {
+            let conferences = state.conferences.try_read().unwrap();
+            conferences.rooms[&conference_id].locked
+        };
+
+        lock(&state, guest.endpoint, conference_id, true).await;
+        assert_eq!(refusal(&mut guest), Some(ErrorCode::NotAllowed));
+        lock(&state, outsider.endpoint, conference_id, true).await;
+        assert_eq!(refusal(&mut outsider), Some(ErrorCode::UnknownCall));
+        assert!(!locked(&state));
+
+        lock(&state, moderator.endpoint, conference_id, true).await;
+        assert!(locked(&state));
+    }
+}
@@ -0,0 +1,287 @@
+//! Inbound flood protection for the websocket.
+//!
+//! Every frame a client sends costs us a parse, a routing decision and often
+//! a Redis round-trip, so one misbehaving softphone (or a script holding a
+//! stolen token) could otherwise starve a node. Frames are capped in size and
+//! metered by two token buckets: one per connection, which closes the socket
+//! when exhausted, and one per tenant, which sheds load without singling out
+//! any one client. Every rejection is counted and exported on `/metrics`.
+
+use axum::extract::ws::close_code;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Classic token bucket: `burst` tokens, refilled at `rate` per second.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket::starting_at(rate, burst, Instant::now())
+    }
+
+    /// A full bucket, as of `start`.
+    fn starting_at(rate: f64, burst: f64, start: Instant) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last: start,
+        }
+    }
+
+    /// Spend one token, returning `false` when the bucket is empty.
+    pub fn try_take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        // A clock reading older than the last one refills nothing, now or later.
+        self.last = self.last.max(now);
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket has refilled completely by `now`, so a fresh one
+    /// would do just as well.
+    fn full_at(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.burst
+    }
+}
+
+/// Why an inbound frame was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    FrameTooLarge,
+    ConnectionRate,
+    TenantRate,
+    /// Too many SIP UDP flows from unregistered addresses.
+    SipFlows,
+}
+
+impl Rejection {
+    fn label(self) -> &'static str {
+        match self {
+            Rejection::FrameTooLarge => "frame_too_large",
+            Rejection::ConnectionRate => "connection_rate",
+            Rejection::TenantRate => "tenant_rate",
+            Rejection::SipFlows => "sip_flows",
+        }
+    }
+
+    /// The close code and reason a websocket is shown the door with, or
+    /// `None` when only the frame is shed.
+    pub fn close_frame(self) -> Option<(u16, &'static str)> {
+        match self {
+            Rejection::FrameTooLarge => Some((close_code::SIZE, "frame too large")),
+            Rejection::ConnectionRate => Some((close_code::POLICY, "rate limit exceeded")),
+            Rejection::TenantRate | Rejection::SipFlows => None,
+        }
+    }
+}
+
+const REJECTIONS: [Rejection; 4] = [
+    Rejection::FrameTooLarge,
+    Rejection::ConnectionRate,
+    Rejection::TenantRate,
+    Rejection::SipFlows,
+];
+
+pub struct Limits {
+    /// Largest text or binary frame accepted, in bytes.
+    pub max_frame_bytes: usize,
+    connection_rate: f64,
+    connection_burst: f64,
+    tenant_rate: f64,
+    tenant_burst: f64,
+    tenants: Mutex<HashMap<Uuid, TokenBucket>>,
+    rejected: [AtomicU64; REJECTIONS.len()],
+}
+
+impl Limits {
+    /// Read limits from the environment; bursts default to twice the rate.
+    pub fn from_env() -> Self {
+        let connection_rate = env_var("WS_CONNECTION_MSGS_PER_SEC", 20.0);
+        let tenant_rate = env_var("WS_TENANT_MSGS_PER_SEC", 500.0);
+        Limits {
+            max_frame_bytes: env_var("WS_MAX_FRAME_BYTES", 64 * 1024),
+            connection_rate,
+            connection_burst: env_var("WS_CONNECTION_BURST", connection_rate * 2.0),
+            tenant_rate,
+            tenant_burst: env_var("WS_TENANT_BURST", tenant_rate * 2.0),
+            tenants: Mutex::new(HashMap::new()),
+            rejected: Default::default(),
+        }
+    }
+
+    /// A fresh bucket for a newly opened socket.
+    pub fn connection_bucket(&self) -> TokenBucket {
+        TokenBucket::new(self.connection_rate, self.connection_burst)
+    }
+
+    /// Charge one frame to the tenant's shared bucket.
+    pub fn take_tenant(&self, tenant_id: Uuid) -> bool {
+        self.take_tenant_at(tenant_id, Instant::now())
+    }
+
+    fn take_tenant_at(&self, tenant_id: Uuid, now: Instant) -> bool {
+        let mut tenants = self.tenants.lock().expect("tenant buckets poisoned");
+        // Whenever a tenant needs a bucket, drop those that refilled since
+        // their last frame, so only tenants active lately are kept.
+        if !tenants.contains_key(&tenant_id) {
+            tenants.retain(|_, bucket| !bucket.full_at(now));
+        }
+        tenants
+            .entry(tenant_id)
+            .or_insert_with(|| TokenBucket::starting_at(self.tenant_rate, self.tenant_burst, now))
+            .take_at(now)
+    }
+
+    pub fn record(&self, rejection: Rejection) {
+        tracing::debug!(reason = rejection.label(), "inbound frame rejected");
+        self.rejected[rejection as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Rejection counters in Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from(
+            "# HELP signaling_rejected_frames_total Inbound websocket frames refused by limits.\n\
+             # TYPE signaling_rejected_frames_total counter\n",
+        );
+        for rejection in REJECTIONS {
+            let count = self.rejected[rejection as usize].load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "signaling_rejected_frames_total{{reason=\"{}\"}} {count}",
+                rejection.label()
+            );
+        }
+        out
+    }
+}
+
+fn env_var<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    fn limits(tenant_rate: f64, tenant_burst: f64) -> Limits {
+        Limits {
+            max_frame_bytes: 1024,
+            connection_rate: 1.0,
+            connection_burst: 2.0,
+            tenant_rate,
+            tenant_burst,
+            tenants: Mutex::new(HashMap::new()),
+            rejected: Default::default(),
+        }
+    }
+
+    #[test]
+    fn a_bucket_refills_at_its_rate_up_to_its_burst() {
+        let mut bucket = TokenBucket::new(2.0, 3.0);
+        let start = bucket.last;
+        assert!((0..3).all(|_| bucket.take_at(start)));
+        assert!(!bucket.take_at(start));
+
+        // Half a second at two per second buys one frame, not two.
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take_at(later));
+        assert!(!bucket.take_at(later));
+
+        // A long quiet spell refills no more than the burst.
+        let much_later = later + Duration::from_secs(60);
+        assert!((0..3).all(|_| bucket.take_at(much_later)));
+        assert!(!bucket.take_at(much_later));
+    }
+
+    #[test]
+    fn a_clock_going_backwards_refills_nothing() {
+        let mut bucket = TokenBucket::new(1.0, 1.0);
+        let start = bucket.last;
+        assert!(bucket.take_at(start + Duration::from_secs(1)));
+        assert!(!bucket.take_at(start));
+        // Refills count from the latest reading, not the one that went back.
+        assert!(!bucket.take_at(start + Duration::from_millis(1500)));
+        assert!(bucket.take_at(start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn each_tenant_has_its_own_budget() {
+        // Slow enough that nothing refills while the test runs.
+        let limits = limits(0.001, 3.0);
+        let (noisy, quiet) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!((0..3).all(|_| limits.take_tenant(noisy)));
+        assert!(!limits.take_tenant(noisy));
+        assert!((0..3).all(|_| limits.take_tenant(quiet)));
+        assert!(!limits.take_tenant(quiet));
+    }
+
+    #[test]
+    fn refilled_tenant_buckets_are_dropped() {
+        let limits = limits(1.0, 2.0);
+        let (noisy, quiet, new) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+        assert!((0..2).all(|_| limits.take_tenant_at(noisy, start)));
+        assert!(limits.take_tenant_at(quiet, start));
+
+        // A second on, only the quiet tenant's bucket is full again.
+        let later = start + Duration::from_secs(1);
+        assert!(limits.take_tenant_at(new, later));
+        let kept: HashSet<Uuid> = limits.tenants.lock().unwrap().keys().copied().collect();
+        assert_eq!(kept, HashSet::from([noisy, new]));
+        // The noisy tenant still has only what it earned back.
+        assert!(limits.take_tenant_at(noisy, later));
+        assert!(!limits.take_tenant_at(noisy, later));
+    }
+
+    #[test]
+    fn only_the_connection_s_own_faults_close_it() {
+        assert_eq!(
+            Rejection::FrameTooLarge.close_frame(),
+            Some((close_code::SIZE, "frame too large"))
+        );
+        assert_eq!(
+            Rejection::ConnectionRate.close_frame(),
+            Some((close_code::POLICY, "rate limit exceeded"))
+        );
+        assert_eq!(Rejection::TenantRate.close_frame(), None);
+        assert_eq!(Rejection::SipFlows.close_frame(), None);
+    }
+
+    #[test]
+    fn rejections_are_counted_by_reason() {
+        let limits = limits(1.0, 1.0);
+        limits.record(Rejection::TenantRate);
+        limits.record(Rejection::TenantRate);
+        limits.record(Rejection::FrameTooLarge);
+        let metrics = limits.render_metrics();
+        assert!(metrics.contains("signaling_rejected_frames_total{reason=\"tenant_rate\"} 2\n"));
+        assert!(metrics.contains("signaling_rejected_frames_total{reason=\"frame_too_large\"} 1\n"));
+        assert!(metrics.contains("signaling_rejected_frames_total{reason=\"connection_rate\"} 0\n"));
+    }
+}
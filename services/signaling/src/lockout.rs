@@ -0,0 +1,137 @@
+//! Counting failed attempts at a secret, such as a SIP password or a
+//! conference PIN, and locking out whoever keeps getting it wrong.
+//!
+//! Failures within one window add up; the window starts at the first of
+//! them, and once `max_failures` is reached the key stays locked until the
+//! window is over. Counts are kept in Redis when configured so the limit holds
+//! across replicas.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Count a failure, opening the window with the first one.
+const FAILURE_SCRIPT: &str = r"
+local count = redis.call('INCR', KEYS[1])
+if redis.call('TTL', KEYS[1]) < 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+return count
+";
+
+pub struct Lockout {
+    max_failures: u32,
+    window: Duration,
+    redis: Option<redis::aio::ConnectionManager>,
+    failure_script: redis::Script,
+    /// Failure count and window start per key, when Redis is not configured.
+    failures: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl Lockout {
+    pub fn new(
+        max_failures: u32,
+        window: Duration,
+        redis: Option<redis::aio::ConnectionManager>,
+    ) -> Self {
+        Lockout {
+            max_failures,
+            window,
+            redis,
+            failure_script: redis::Script::new(FAILURE_SCRIPT),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `key` has used up its attempts. Callers should refuse when
+    /// Redis cannot tell.
+    pub async fn locked(&self, key: &str) -> Result<bool, redis::RedisError> {
+        let Some(mut conn) = self.redis.clone() else {
+            let failures = self.failures.lock().expect("failure table poisoned");
+            return Ok(failures.get(key).is_some_and(|(count, since)| {
+                *count >= self.max_failures && since.elapsed() < self.window
+            }));
+        };
+        let count: Option<u32> = redis::cmd("GET").arg(key).query_async(&mut conn).await?;
+        Ok(count.unwrap_or(0) >= self.max_failures)
+    }
+
+    /// Count a failed attempt; `true` when it is the one that locks `key`.
+    pub async fn record_failure(&self, key: &str) -> bool {
+        let Some(mut conn) = self.redis.clone() else {
+            let mut failures = self.failures.lock().expect("failure table poisoned");
+            let entry = failures
+                .entry(key.to_string())
+                .or_insert((0, Instant::now()));
+            if entry.1.elapsed() >= self.window {
+                *entry = (0, Instant::now());
+            }
+            entry.0 += 1;
+            return entry.0 == self.max_failures;
+        };
+        let count: Result<u32, _> = self
+            .failure_script
+            .key(key)
+            .arg(self.window.as_secs())
+            .invoke_async(&mut conn)
+            .await;
+        match count {
+            Ok(count) => count == self.max_failures,
+            Err(err) => {
+                tracing::warn!(error = %err, key, "failed to record failed attempt");
+                false
+            }
+        }
+    }
+
+    /// Forget the failures of `key`, after it got the secret right.
+    pub async fn clear(&self, key: &str) {
+        let Some(mut conn) = self.redis.clone() else {
+            self.failures
+                .lock()
+                .expect("failure table poisoned")
+                .remove(key);
+            return;
+        };
+        let _ = redis::cmd("DEL")
+            .arg(key)
+            .query_async::<_, ()>(&mut conn)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn locks_after_max_failures() {
+        let lockout = Lockout::new(3, Duration::from_secs(60), None);
+        assert!(!lockout.record_failure("a").await);
+        assert!(!lockout.record_failure("a").await);
+        assert!(!lockout.locked("a").await.unwrap());
+        assert!(lockout.record_failure("a").await);
+        assert!(lockout.locked("a").await.unwrap());
+        assert!(!lockout.locked("b").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn clearing_starts_the_count_over() {
+        let lockout = Lockout::new(2, Duration::from_secs(60), None);
+        lockout.record_failure("a").await;
+        lockout.clear("a").await;
+        assert!(!lockout.record_failure("a").await);
+        assert!(!lockout.locked("a").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn the_lock_ends_with_the_window() {
+        let lockout = Lockout::new(1, Duration::from_millis(20), None);
+        assert!(lockout.record_failure("a").await);
+        assert!(lockout.locked("a").await.unwrap());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(!lockout.locked("a").await.unwrap());
+        // A failure after the window opens a new one.
+        assert!(lockout.record_failure("a").await);
+    }
+}
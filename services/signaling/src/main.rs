@@ -1,38 +1,154 @@
-use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+mod auth;
+mod calls;
+mod cluster;
+mod conference;
+mod limits;
+mod lockout;
+mod media;
+mod park;
+mod pbx;
+mod presence;
+mod protocol;
+mod registry;
+mod session;
+mod sip;
+mod supervise;
+
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::response::{IntoResponse, Response};
 use axum::{
     extract::{Query, State},
     http::HeaderMap,
-    routing::get,
+    routing::{get, post},
     Router,
 };
-use axum::response::{IntoResponse, Response};
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use uuid::Uuid;
 
 /// Shared application state carried into each websocket session.
 ///
-/// We keep the compiled JWT keys, an optional Redis connection used for
-/// coarse presence tracking, and a broadcast channel that propagates events
-/// (online/offline) to every connected task.
+/// We keep the token verifier, the presence store (Redis-backed when
+/// configured), a broadcast channel that propagates typed presence events to
+/// every connected task, plus the registry of live
+/// sockets, the cluster view used to reach sockets on other replicas, and the
+/// table of calls owned by this node.
 #[derive(Clone)]
 struct AppState {
-    decoding_key: Arc<DecodingKey>,
-    validation: Validation,
-    redis: Option<redis::aio::ConnectionManager>,
+    auth: Arc<auth::Authenticator>,
+    presence: Arc<presence::PresenceStore>,
     // Broadcast presence updates so other connections can react.
-    presence_tx: broadcast::Sender<String>,
+    presence_tx: broadcast::Sender<presence::PresenceEvent>,
+    // Every live connection per (tenant, user), across all of their devices.
+    registry: Arc<registry::Registry>,
+    cluster: Arc<cluster::Cluster>,
+    calls: calls::Calls,
+    // How long a call may ring before it is ended as unanswered.
+    ring_timeout: Duration,
+    // Park slots per tenant, and how long a call stays parked before ringing
+    // back whoever parked it.
+    park_slots: u16,
+    park_timeout: Duration,
+    // Park slots taken by calls owned by this replica.
+    parking: Arc<RwLock<park::ParkingLot>>,
+    // Sessions by resume token; those whose socket dropped are kept for
+    // `resume_grace` awaiting a reconnect.
+    sessions: Arc<session::Sessions>,
+    resume_grace: Duration,
+    // Inbound frame size and rate caps, plus their rejection counters.
+    limits: Arc<limits::Limits>,
+    // Settings for SIP clients, over `/sip` or the native listeners.
+    sip: Arc<sip::Config>,
+    // Contact bindings of registered SIP phones.
+    registrar: Arc<sip::Registrar>,
+    // Password logins for SIP phones, checked against the PBX.
+    digest: Arc<sip::DigestAuth>,
+    // Number plan lookups for destinations that are not user ids.
+    pbx: Arc<pbx::PbxClient>,
+    // Wrong conference PINs per room and user.
+    conference_pins: Arc<lockout::Lockout>,
+    // Relay control for music on hold, and the conference mixer.
+    media: Arc<media::MediaClient>,
+    // Conference rooms opened on this replica.
+    conferences: Arc<RwLock<conference::Conferences>>,
+    // Supervisors on calls owned by this replica.
+    supervisions: Arc<RwLock<supervise::Supervisions>>,
+}
+
+/// The Redis named by `REDIS_URL`, for tests of what lives there. Those
+/// tests pass vacuously without one.
+#[cfg(test)]
+async fn test_redis() -> Option<redis::aio::ConnectionManager> {
+    let client = redis::Client::open(std::env::var("REDIS_URL").ok()?).ok()?;
+    client.get_connection_manager().await.ok()
 }
 
+#[cfg(test)]
+impl AppState {
+    /// A lone replica without Redis, with default settings and services.
+    fn for_tests() -> AppState {
+        let (presence_tx, _rx) = broadcast::channel(1024);
+        let registry = Arc::new(registry::Registry::default());
+        let cluster = Arc::new(cluster::Cluster::new(
+            registry.clone(),
+            None,
+            presence_tx.clone(),
+        ));
+        let sip = Arc::new(sip::Config::from_env());
+        let digest = sip::DigestAuth::from_env(sip.domain.clone(), None);
+        AppState {
+            auth: Arc::new(auth::Authenticator::with_secret("test secret")),
+            presence: Arc::new(presence::PresenceStore::new(None)),
+            presence_tx,
+            registry,
+            cluster,
+            calls: Arc::new(RwLock::new(HashMap::new())),
+            ring_timeout: Duration::from_secs(30),
+            park_slots: 10,
+            park_timeout: Duration::from_secs(120),
+            parking: Arc::new(RwLock::new(park::ParkingLot::default())),
+            sessions: Arc::new(session::Sessions::default()),
+            resume_grace: Duration::from_secs(20),
+            limits: Arc::new(limits::Limits::from_env()),
+            sip,
+            digest: Arc::new(digest),
+            registrar: Arc::new(sip::Registrar::new(None)),
+            pbx: Arc::new(pbx::PbxClient::from_env()),
+            conference_pins: Arc::new(lockout::Lockout::new(5, Duration::from_secs(300), None)),
+            media: Arc::new(media::MediaClient::from_env()),
+            conferences: Arc::new(RwLock::new(conference::Conferences::default())),
+            supervisions: Arc::new(RwLock::new(supervise::Supervisions::default())),
+        }
+    }
+}
+
+/// Close code sent when a socket's credentials lapse without `auth.refresh`.
+const CLOSE_TOKEN_EXPIRED: u16 = 4001;
+/// Close code sent when the session moved to a newer socket with its resume
+/// token.
+const CLOSE_SUPERSEDED: u16 = 4002;
+/// Close code sent when frames a resuming client missed are no longer held
+/// for replay; the session is ended and the client must start a fresh one.
+const CLOSE_CANNOT_RESUME: u16 = 4003;
+
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct WsParams {
     token: Option<String>,
+    #[serde(default)]
+    device: registry::DeviceKind,
+    /// Resume token from a previous `session` frame.
+    resume: Option<Uuid>,
+    /// Sequence number of the last frame the client received before dropping.
+    last_seq: Option<u64>,
 }
 
-fn bearer_token(headers: &HeaderMap) -> Option<String> {
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<String> {
     headers
         .get(axum::http::header::AUTHORIZATION)
         .and_then(|h| h.to_str().ok())
@@ -51,95 +167,317 @@ async fn ws_handler(
     Query(params): Query<WsParams>,
     State(state): State<AppState>,
 ) -> Response {
-    let token = bearer_token(&headers).or(params.token);
+    let token = bearer_token(&headers).or(params.token.clone());
     let Some(token) = token else {
         return axum::http::StatusCode::UNAUTHORIZED.into_response();
     };
 
-    let claims = match decode::<dto::AuthClaims>(&token, &state.decoding_key, &state.validation) {
-        Ok(d) => d.claims,
+    let claims = match state.auth.verify(&token).await {
+        Ok(claims) => claims,
         Err(err) => {
-            tracing::warn!(error = %err, "jwt decode failed");
+            tracing::warn!(error = %err, "jwt verification failed");
             return axum::http::StatusCode::UNAUTHORIZED.into_response();
         }
     };
 
-    ws.on_upgrade(move |socket| handle_socket(socket, state, claims))
+    // The transport ceiling only bounds memory; the configured limit is enforced
+    // in the receive loop so offenders get a proper 1009 close.
+    let ceiling = state.limits.max_frame_bytes.saturating_mul(4);
+    ws.max_message_size(ceiling)
+        .max_frame_size(ceiling)
+        .on_upgrade(move |socket| handle_socket(socket, state, claims, params))
 }
 
 /// Drive the lifetime of a single websocket connection.
 ///
-/// The task keeps track of per-user presence in Redis, echoes messages for now,
-/// and ensures the TTL is refreshed via a background task.  When the client
-/// disconnects we clean up the Redis keys and broadcast that the agent left.
-async fn handle_socket(mut socket: WebSocket, state: AppState, claims: dto::AuthClaims) {
-    let presence_key = format!(
-        "presence:{}:{}",
-        claims.tenant_id.as_hyphenated(),
-        claims.sub.as_hyphenated()
-    );
+/// The socket either opens a fresh [`session::Session`] (registering the
+/// connection and bringing the agent online) or, when the client presents a
+/// resume token, reattaches the session it left behind and replays the frames
+/// sent after `lastSeq`, ending it instead when some of those are no longer
+/// held for replay. Call-control frames are routed through
+/// [`calls::handle_client_message`]; outbound events addressed to this session
+/// are numbered and written from the same loop, alongside the tenant's
+/// presence feed (preceded by a snapshot of who is online). A socket that
+/// drops without a close frame only detaches its session for the grace
+/// window; a clean close ends it straight away. Resuming a session whose
+/// socket still looks alive closes that socket and moves the session over.
+///
+/// The token checked at upgrade only covers the socket until its `exp`. Shortly
+/// before that the client is sent `auth.expiring`; a valid `auth.refresh` for
+/// the same user extends the session, otherwise the socket is closed.
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: AppState,
+    mut claims: dto::AuthClaims,
+    params: WsParams,
+) {
+    // Subscribe before announcing ourselves so no change slips between the
+    // snapshot below and the live feed.
+    let mut presence_rx = state.presence_tx.subscribe();
 
-    // mark online and start TTL refresh
-    if let Some(mut conn) = state.redis.clone() {
-        let _ = redis::cmd("SET")
-            .arg(&presence_key)
-            .arg("online")
-            .arg("EX")
-            .arg(60)
-            .query_async::<_, ()>(&mut conn)
-            .await;
+    let resumed = match params.resume {
+        Some(token) => {
+            state
+                .sessions
+                .resume(token, claims.tenant_id, claims.sub)
+                .await
+        }
+        None => None,
+    };
+    let is_resumed = resumed.is_some();
+    let mut session = match resumed {
+        Some(session) => session,
+        None => session::Session::open(&state, claims.tenant_id, claims.sub, params.device).await,
+    };
+    let endpoint = session.endpoint;
+
+    let last_seq = params.last_seq.unwrap_or(0);
+    if is_resumed && session.replay.since(last_seq).is_none() {
+        tracing::debug!(connection_id = %endpoint.connection_id, last_seq, "session cannot be resumed");
+        close(&mut socket, CLOSE_CANNOT_RESUME, "missed frames are gone").await;
+        session.end(&state).await;
+        return;
     }
 
-    let mut refresh_interval = tokio::time::interval(std::time::Duration::from_secs(30));
-    let refresh_task_conn = state.redis.clone();
-    let presence_key_clone = presence_key.clone();
-    // Keep the presence indicator alive while the connection stays up.
-    let refresh_handle = tokio::spawn(async move {
-        loop {
-            refresh_interval.tick().await;
-            if let Some(mut conn) = refresh_task_conn.clone() {
-                let _ = redis::cmd("EXPIRE")
-                    .arg(&presence_key_clone)
-                    .arg(60)
-                    .query_async::<_, ()>(&mut conn)
-                    .await;
-            }
+    // A fresh token per attach, so a leaked one is only good until next use.
+    let resume_token = Uuid::new_v4();
+    let mut takeover = state.sessions.attach(resume_token, endpoint).await;
+    let hello = protocol::ServerMessage::Session {
+        resume_token,
+        resumed: is_resumed,
+    };
+    let _ = send_json(&mut socket, &hello).await;
+    if is_resumed {
+        tracing::debug!(connection_id = %endpoint.connection_id, last_seq, "session resumed");
+        for (seq, msg) in session.replay.since(last_seq).into_iter().flatten() {
+            let _ = send_sequenced(&mut socket, *seq, msg).await;
         }
-    });
-
-    // Notify other listeners that the agent is now available for routing.
-    let _ = state
-        .presence_tx
-        .send(format!("online:{}:{}", claims.tenant_id, claims.sub));
-
-    while let Some(Ok(msg)) = socket.recv().await {
-        match msg {
-            Message::Text(t) => {
-                tracing::debug!(payload = %t, "ws text");
-                let _ = socket.send(Message::Text(t)).await;
+    }
+
+    let snapshot = protocol::ServerMessage::PresenceSnapshot {
+        agents: state.presence.list(claims.tenant_id).await,
+    };
+    let _ = send_json(&mut socket, &snapshot).await;
+
+    // Fires first at the warning point, then again at the expiry itself.
+    let expiry = tokio::time::sleep_until(auth::deadline(claims.exp) - auth::EXPIRY_WARNING);
+    tokio::pin!(expiry);
+    let mut warned = false;
+    // Whether the client said goodbye (or was shown the door) rather than dropping.
+    let mut closed = false;
+    // Set when a newer socket resumed the session.
+    let mut handover = None;
+    let mut bucket = state.limits.connection_bucket();
+
+    loop {
+        tokio::select! {
+            inbound = socket.recv() => {
+                let Some(Ok(msg)) = inbound else { break };
+                // Only data frames are metered: control frames are tiny, and a
+                // close must always get through.
+                let size = match &msg {
+                    Message::Text(t) => Some(t.len()),
+                    Message::Binary(b) => Some(b.len()),
+                    _ => None,
+                };
+                let rejection = match size {
+                    Some(size) if size > state.limits.max_frame_bytes => {
+                        Some(limits::Rejection::FrameTooLarge)
+                    }
+                    Some(_) if !bucket.try_take() => Some(limits::Rejection::ConnectionRate),
+                    // The tenant budget is shared, so this client is not
+                    // necessarily the culprit: shed the frame but keep the socket.
+                    Some(_) if !state.limits.take_tenant(claims.tenant_id) => {
+                        Some(limits::Rejection::TenantRate)
+                    }
+                    _ => None,
+                };
+                if let Some(rejection) = rejection {
+                    state.limits.record(rejection);
+                    if let Some((code, reason)) = rejection.close_frame() {
+                        close(&mut socket, code, reason).await;
+                        closed = true;
+                        break;
+                    }
+                    let reply = protocol::ServerMessage::error(
+                        None,
+                        protocol::ErrorCode::RateLimited,
+                        "tenant rate limit exceeded",
+                    );
+                    if send_json(&mut socket, &reply).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                match msg {
+                    Message::Text(t) => {
+                        match serde_json::from_str::<protocol::ClientMessage>(&t) {
+                            Ok(protocol::ClientMessage::RefreshToken { token }) => {
+                                let reply = match state.auth.verify_refresh(&token, &claims).await {
+                                    Ok(fresh) => {
+                                        claims = fresh;
+                                        warned = false;
+                                        expiry.as_mut().reset(
+                                            auth::deadline(claims.exp) - auth::EXPIRY_WARNING,
+                                        );
+                                        protocol::ServerMessage::TokenRefreshed {
+                                            expires_at: claims.exp as u64,
+                                        }
+                                    }
+                                    Err(err) => {
+                                        tracing::warn!(error = %err, "token refresh rejected");
+                                        protocol::ServerMessage::error(
+                                            None,
+                                            protocol::ErrorCode::InvalidToken,
+                                            err.to_string(),
+                                        )
+                                    }
+                                };
+                                if send_json(&mut socket, &reply).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Ok(msg) => {
+                                if let Some(reply) = supervise::refusal(claims.role, &msg) {
+                                    if send_json(&mut socket, &reply).await.is_err() {
+                                        break;
+                                    }
+                                    continue;
+                                }
+                                match &msg {
+                                    protocol::ClientMessage::Initiate { call_id, .. }
+                                    | protocol::ClientMessage::ConferenceJoin { call_id, .. }
+                                    | protocol::ClientMessage::Retrieve { call_id, .. }
+                                    | protocol::ClientMessage::Pickup { call_id, .. }
+                                    | protocol::ClientMessage::Supervise {
+                                        leg_id: call_id, ..
+                                    } => {
+                                        session.active_calls.insert(*call_id);
+                                    }
+                                    _ => {}
+                                }
+                                calls::handle_client_message(&state, endpoint, msg).await;
+                            }
+                            Err(err) => {
+                                let reply = protocol::ServerMessage::error(
+                                    None,
+                                    protocol::ErrorCode::InvalidMessage,
+                                    format!("invalid message: {err}"),
+                                );
+                                if send_json(&mut socket, &reply).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Message::Binary(b) => {
+                        tracing::debug!(size = b.len(), "ws binary");
+                    }
+                    Message::Ping(p) => {
+                        let _ = socket.send(Message::Pong(p)).await;
+                    }
+                    Message::Close(_) => {
+                        closed = true;
+                        break;
+                    }
+                    _ => {}
+                }
             }
-            Message::Binary(b) => {
-                tracing::debug!(size = b.len(), "ws binary");
+            Some(outbound) = session.rx.recv() => {
+                let seq = session.record(&outbound);
+                if send_sequenced(&mut socket, seq, &outbound).await.is_err() {
+                    break;
+                }
             }
-            Message::Ping(p) => {
-                let _ = socket.send(Message::Pong(p)).await;
+            Some(to) = takeover.recv() => {
+                tracing::debug!(connection_id = %endpoint.connection_id, "session superseded");
+                close(&mut socket, CLOSE_SUPERSEDED, "session resumed elsewhere").await;
+                handover = Some(to);
+                break;
+            }
+            event = presence_rx.recv() => match event {
+                // Presence never crosses tenant boundaries.
+                Ok(event) if event.tenant_id == claims.tenant_id => {
+                    let msg = protocol::ServerMessage::Presence(event.presence);
+                    if send_json(&mut socket, &msg).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "presence feed lagged");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            () = &mut expiry => {
+                if warned {
+                    tracing::info!(
+                        tenant_id = %claims.tenant_id,
+                        user_id = %claims.sub,
+                        "token expired, closing socket"
+                    );
+                    close(&mut socket, CLOSE_TOKEN_EXPIRED, "token expired").await;
+                    closed = true;
+                    break;
+                }
+                warned = true;
+                let msg = protocol::ServerMessage::TokenExpiring {
+                    expires_at: claims.exp as u64,
+                };
+                if send_json(&mut socket, &msg).await.is_err() {
+                    break;
+                }
+                expiry.as_mut().reset(auth::deadline(claims.exp));
             }
-            Message::Close(_) => break,
-            _ => {}
         }
     }
 
-    refresh_handle.abort();
-    if let Some(mut conn) = state.redis.clone() {
-        let _ = redis::cmd("DEL")
-            .arg(&presence_key)
-            .query_async::<_, ()>(&mut conn)
+    let handover = match handover {
+        Some(handover) => Some(handover),
+        None => state.sessions.unattach(resume_token, &mut takeover).await,
+    };
+    if let Some(handover) = handover {
+        // The client carries on over its new socket, unless that gave up.
+        if let Err(session) = handover.send(session) {
+            session.end(&state).await;
+        }
+    } else if closed || state.resume_grace.is_zero() {
+        session.end(&state).await;
+    } else {
+        state
+            .sessions
+            .detach(&state, resume_token, session, state.resume_grace)
             .await;
     }
-    // Broadcast the offline signal so supervisor dashboards can update instantly.
-    let _ = state
-        .presence_tx
-        .send(format!("offline:{}:{}", claims.tenant_id, claims.sub));
+}
+
+async fn send_json(
+    socket: &mut WebSocket,
+    msg: &protocol::ServerMessage,
+) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(msg).expect("server messages always serialize");
+    socket.send(Message::Text(text)).await
+}
+
+/// Tell the client why we are hanging up before the socket goes away.
+async fn close(socket: &mut WebSocket, code: u16, reason: &'static str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
+
+/// Send a replayable frame, tagged with its `seq` so the client can report
+/// how far it got when resuming.
+async fn send_sequenced(
+    socket: &mut WebSocket,
+    seq: u64,
+    msg: &protocol::ServerMessage,
+) -> Result<(), axum::Error> {
+    let mut value = serde_json::to_value(msg).expect("server messages always serialize");
+    value["seq"] = seq.into();
+    socket.send(Message::Text(value.to_string())).await
 }
 
 /// Bootstrap the signaling service: configure tracing, JWT validation, Redis
@@ -153,49 +491,149 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let jwt_secret =
-        std::env::var("JWT_SECRET").unwrap_or_else(|_| "dev_secret_change_me".to_string());
-    let decoding_key = Arc::new(DecodingKey::from_secret(jwt_secret.as_bytes()));
-    let mut validation = Validation::new(Algorithm::HS256);
-    // JWTs issued by the API include expiry; enforce it at the edge so expired
-    // agents cannot reconnect without refreshing their session.
-    validation.validate_exp = true;
+    // Refuse to start rather than accept tokens signed with a guessable secret.
+    let auth = match auth::Authenticator::from_env().await {
+        Ok(auth) => Arc::new(auth),
+        Err(err) => {
+            tracing::error!(error = %err, "invalid jwt configuration");
+            std::process::exit(1);
+        }
+    };
+    let jwks_refresh = Duration::from_secs(
+        std::env::var("JWT_JWKS_REFRESH_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(300),
+    );
+    if jwks_refresh.is_zero() {
+        tracing::error!("JWT_JWKS_REFRESH_SECS must be at least 1");
+        std::process::exit(1);
+    }
+    tokio::spawn(auth::run_refresh(auth.clone(), jwks_refresh));
+
+    // Unanswered calls are torn down after this many seconds of ringing.
+    let ring_timeout = Duration::from_secs(
+        std::env::var("RING_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(30),
+    );
+
+    // Each tenant gets slots 1..=PARK_SLOTS; parked calls ring back after
+    // PARK_TIMEOUT_SECS.
+    let park_slots = std::env::var("PARK_SLOTS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(10);
+    let park_timeout = Duration::from_secs(
+        std::env::var("PARK_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(120),
+    );
+
+    // A dropped socket may reconnect and resume its session within this window.
+    let resume_grace = Duration::from_secs(
+        std::env::var("SESSION_RESUME_GRACE_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(20),
+    );
+
+    // Wrong PINs one user may try for a conference room before being kept
+    // out of it for the lockout window.
+    let conference_pin_max_failures = std::env::var("CONFERENCE_PIN_MAX_FAILURES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(5);
+    let conference_pin_lockout = Duration::from_secs(
+        std::env::var("CONFERENCE_PIN_LOCKOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(300),
+    );
 
     // Presence is optional; when configured we store agent availability in Redis so
     // other services (routing, analytics) can read it without binding to this process.
     let redis_url = std::env::var("REDIS_URL").ok();
-    let redis_manager = if let Some(url) = redis_url {
+    let (redis_client, redis_manager) = if let Some(url) = redis_url {
         match redis::Client::open(url) {
             Ok(client) => match client.get_connection_manager().await {
-                Ok(mgr) => Some(mgr),
+                Ok(mgr) => (Some(client), Some(mgr)),
                 Err(err) => {
                     tracing::warn!(error = %err, "failed to connect to redis");
-                    None
+                    (None, None)
                 }
             },
             Err(err) => {
                 tracing::warn!(error = %err, "invalid redis url");
-                None
+                (None, None)
             }
         }
     } else {
-        None
+        (None, None)
     };
 
     // High fan-out presence channel. If receivers lag behind we drop messages rather
     // than block signalling threads, hence the reasonably large buffer.
     let (presence_tx, _rx) = broadcast::channel(1024);
 
+    let registry = Arc::new(registry::Registry::default());
+    let cluster = Arc::new(cluster::Cluster::new(
+        registry.clone(),
+        redis_manager.clone(),
+        presence_tx.clone(),
+    ));
+
+    let sip_config = Arc::new(sip::Config::from_env());
+    let digest = sip::DigestAuth::from_env(sip_config.domain.clone(), redis_manager.clone());
+
     let state = AppState {
-        decoding_key,
-        validation,
-        redis: redis_manager,
+        auth,
+        presence: Arc::new(presence::PresenceStore::new(redis_manager.clone())),
         presence_tx,
+        registry,
+        cluster,
+        calls: Arc::new(RwLock::new(HashMap::new())),
+        ring_timeout,
+        park_slots,
+        park_timeout,
+        parking: Arc::new(RwLock::new(park::ParkingLot::default())),
+        sessions: Arc::new(session::Sessions::default()),
+        resume_grace,
+        limits: Arc::new(limits::Limits::from_env()),
+        sip: sip_config,
+        digest: Arc::new(digest),
+        registrar: Arc::new(sip::Registrar::new(redis_manager.clone())),
+        pbx: Arc::new(pbx::PbxClient::from_env()),
+        conference_pins: Arc::new(lockout::Lockout::new(
+            conference_pin_max_failures,
+            conference_pin_lockout,
+            redis_manager.clone(),
+        )),
+        media: Arc::new(media::MediaClient::from_env()),
+        conferences: Arc::new(RwLock::new(conference::Conferences::default())),
+        supervisions: Arc::new(RwLock::new(supervise::Supervisions::default())),
     };
 
+    // With Redis available, listen for frames and presence changes published
+    // by the other replicas behind the load balancer.
+    if let Some(client) = redis_client {
+        tokio::spawn(cluster::run_heartbeat(state.cluster.clone()));
+        tokio::spawn(cluster::run_subscriber(client, state.clone()));
+    }
+
+    // Native SIP for desk phones and trunks that cannot use websockets.
+    tokio::spawn(sip::serve_udp(state.clone()));
+    tokio::spawn(sip::serve_tcp(state.clone()));
+
     // Expose the websocket entry point consumed by the web softphone.
     let app = Router::new()
         .route("/health", get(|| async { "ok" }))
+        .route(
+            "/metrics",
+            get(|State(state): State<AppState>| async move { state.limits.render_metrics() }),
+        )
         .route(
             "/ws",
             get(
@@ -207,6 +645,10 @@ async fn main() {
                 },
             ),
         )
+        // SIP over WebSocket for desk phones and SIP softphones.
+        .route("/sip", get(sip::sip_handler))
+        // Relay tear-down events from the media service.
+        .route("/media/relay-closed", post(media::relay_closed))
         .with_state(state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
@@ -215,3 +657,189 @@ async fn main() {
         .await
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use jsonwebtoken::{EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::Message as Frame;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// A token `AppState::for_tests` accepts, lapsing `lifetime` from now.
+    fn token(user_id: Uuid, tenant_id: Uuid, lifetime: Duration) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let claims = dto::AuthClaims {
+            sub: user_id,
+            tenant_id,
+            exp: (now + lifetime).as_secs() as usize,
+            iat: now.as_secs() as usize,
+            role: dto::Role::Agent,
+        };
+        let key = EncodingKey::from_secret(b"test secret");
+        jsonwebtoken::encode(&Header::default(), &claims, &key).unwrap()
+    }
+
+    /// Serve `/ws` for `state` on a loopback port.
+    async fn serve(state: AppState) -> SocketAddr {
+        let app = Router::new()
+            .route("/ws", get(ws_handler))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    async fn connect(addr: SocketAddr, token: &str) -> Client {
+        let url = format!("ws://{addr}/ws?token={token}");
+        let (client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        client
+    }
+
+    async fn send(client: &mut Client, msg: serde_json::Value) {
+        client.send(Frame::text(msg.to_string())).await.unwrap();
+    }
+
+    /// The next frame of type `kind`, skipping any other.
+    async fn next_of(client: &mut Client, kind: &str) -> serde_json::Value {
+        loop {
+            match client.next().await {
+                Some(Ok(Frame::Text(text))) => {
+                    let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    if msg["type"] == kind {
+                        return msg;
+                    }
+                }
+                Some(Ok(Frame::Close(frame))) => panic!("closed waiting for {kind}: {frame:?}"),
+                Some(Ok(_)) => {}
+                other => panic!("socket gone waiting for {kind}: {other:?}"),
+            }
+        }
+    }
+
+    /// The close code the server hangs up with, skipping any other frame.
+    async fn close_code(client: &mut Client) -> u16 {
+        loop {
+            match client.next().await {
+                Some(Ok(Frame::Close(frame))) => return frame.expect("close frame").code.into(),
+                Some(Ok(_)) => {}
+                other => panic!("socket gone without a close frame: {other:?}"),
+            }
+        }
+    }
+
+    fn refresh(token: &str) -> serde_json::Value {
+        serde_json::json!({"type": "auth.refresh", "token": token})
+    }
+
+    fn set_presence(status: &str) -> serde_json::Value {
+        serde_json::json!({"type": "presence.set", "status": status})
+    }
+
+    #[tokio::test]
+    async fn bad_frames_are_answered_with_an_error_and_the_socket_stays_open() {
+        let addr = serve(AppState::for_tests()).await;
+        let token = token(Uuid::new_v4(), Uuid::new_v4(), Duration::from_secs(3600));
+        let mut client = connect(addr, &token).await;
+
+        for frame in [
+            "not json".to_string(),
+            serde_json::json!({"type": "call.unknown"}).to_string(),
+            serde_json::json!({"type": "call.initiate", "to": "1001"}).to_string(),
+        ] {
+            client.send(Frame::text(frame)).await.unwrap();
+            let refused = next_of(&mut client, "call.error").await;
+            assert_eq!(refused["code"], "invalid_message");
+        }
+
+        send(&mut client, set_presence("away")).await;
+        while next_of(&mut client, "presence").await["status"] != "away" {}
+    }
+
+    #[tokio::test]
+    async fn presence_changes_reach_the_same_tenant_only() {
+        let addr = serve(AppState::for_tests()).await;
+        let hour = Duration::from_secs(3600);
+        let (tenant_id, other_tenant) = (Uuid::new_v4(), Uuid::new_v4());
+        let (user_id, colleague_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut user = connect(addr, &token(user_id, tenant_id, hour)).await;
+        let mut colleague = connect(addr, &token(colleague_id, tenant_id, hour)).await;
+        let mut stranger = connect(addr, &token(Uuid::new_v4(), other_tenant, hour)).await;
+
+        let snapshot = next_of(&mut stranger, "presence.snapshot").await;
+        assert!(!snapshot.to_string().contains(&user_id.to_string()));
+
+        send(&mut user, set_presence("away")).await;
+        loop {
+            let presence = next_of(&mut colleague, "presence").await;
+            if presence["userId"] == user_id.to_string() && presence["status"] == "away" {
+                break;
+            }
+        }
+        // Changes are delivered in order, so the stranger's own change comes
+        // after the user's, had that crossed over.
+        send(&mut stranger, set_presence("busy")).await;
+        loop {
+            let presence = next_of(&mut stranger, "presence").await;
+            assert_ne!(presence["userId"], user_id.to_string());
+            if presence["status"] == "busy" {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_refresh_for_someone_else_is_refused() {
+        let addr = serve(AppState::for_tests()).await;
+        let (user_id, tenant_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let hour = Duration::from_secs(3600);
+        let mut client = connect(addr, &token(user_id, tenant_id, hour)).await;
+
+        for token in [
+            token(Uuid::new_v4(), tenant_id, hour),
+            token(user_id, Uuid::new_v4(), hour),
+        ] {
+            send(&mut client, refresh(&token)).await;
+            let refused = next_of(&mut client, "call.error").await;
+            assert_eq!(refused["code"], "invalid_token");
+        }
+
+        // The socket carries on with its own credentials.
+        let token = token(user_id, tenant_id, hour * 2);
+        send(&mut client, refresh(&token)).await;
+        next_of(&mut client, "auth.refreshed").await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_refresh_extends_the_close_deadline() {
+        let addr = serve(AppState::for_tests()).await;
+        let (user_id, tenant_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let lifetime = auth::EXPIRY_WARNING + Duration::from_secs(5);
+        let mut client = connect(addr, &token(user_id, tenant_id, lifetime)).await;
+
+        next_of(&mut client, "auth.expiring").await;
+        let fresh = token(user_id, tenant_id, Duration::from_secs(3600));
+        send(&mut client, refresh(&fresh)).await;
+        next_of(&mut client, "auth.refreshed").await;
+
+        // Well past the first token's expiry, the socket is still served.
+        tokio::time::sleep(lifetime * 2).await;
+        send(&mut client, set_presence("away")).await;
+        while next_of(&mut client, "presence").await["status"] != "away" {}
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_expired_token_closes_the_socket() {
+        let addr = serve(AppState::for_tests()).await;
+        let lifetime = auth::EXPIRY_WARNING + Duration::from_secs(5);
+        let mut client = connect(addr, &token(Uuid::new_v4(), Uuid::new_v4(), lifetime)).await;
+
+        next_of(&mut client, "auth.expiring").await;
+        assert_eq!(close_code(&mut client).await, CLOSE_TOKEN_EXPIRED);
+    }
+}
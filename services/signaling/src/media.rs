@@ -0,0 +1,337 @@
+//! Client for the media relay and conference mixer.
+//!
+//! A relay allocated for a call is registered under the call id, with side
+//! `a` carrying the caller's media and side `b` the callee's. Conference
+//! mixers are keyed by conference id, their participants by the call id each
+//! joined over. A supervisor joins a call's relay as a third leg of its own.
+//! Signaling only steers them; the endpoints exchange media with the media
+//! service directly.
+//!
+//! A call's relay is allocated when one of its parties asks for it (see
+//! `call.relay`); each party is then handed its own side's secret, which it
+//! signs the `HELLO` binding its media address with. Conference participants
+//! and supervisors get the secret of their leg's port with the answer to
+//! their offer, through `call.relay` as well: a leg binds as side `a` of a
+//! session named by its own id.
+//!
+//! The media service reports relays it closes (released, idle, or past their
+//! lifetime) to `POST /media/relay-closed`; a call whose relay is gone has no
+//! audio left, so it is ended. Reports carry `MEDIA_EVENTS_SECRET` as a bearer
+//! token, and without that secret configured none are accepted, since anyone
+//! able to post one could end calls at will. Signaling in turn releases a
+//! call's relay as soon as the call ends. The final quality statistics of the
+//! relay's RTP streams come with the report and are logged.
+
+use crate::protocol::{ServerMessage, SuperviseMode};
+use crate::{calls, AppState};
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A relay allocated for a call.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Allocation {
+    pub session_id: Uuid,
+    pub relay_ip: IpAddr,
+    pub relay_port: u16,
+    secrets: SideSecrets,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SideSecrets {
+    a: String,
+    b: String,
+}
+
+/// A mixer leg's answer, and the port its endpoint binds its address on.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Leg {
+    pub sdp: String,
+    pub relay_ip: IpAddr,
+    pub relay_port: u16,
+    secret: String,
+}
+
+impl Leg {
+    /// `call.relay` for the endpoint of leg `leg_id`, with the leg's secret.
+    pub fn relay_message(&self, leg_id: Uuid) -> ServerMessage {
+        ServerMessage::Relay {
+            call_id: leg_id,
+            session_id: leg_id,
+            host: self.relay_ip.to_string(),
+            port: self.relay_port,
+            side: "a".to_string(),
+            secret: self.secret.clone(),
+        }
+    }
+}
+
+impl Allocation {
+    /// The `HELLO` secret of `side` (`a` or `b`), for that side's endpoint
+    /// only.
+    pub fn secret(&self, side: &str) -> &str {
+        match side {
+            "a" => &self.secrets.a,
+            _ => &self.secrets.b,
+        }
+    }
+}
+
+pub struct MediaClient {
+    http: reqwest::Client,
+    base_url: String,
+    /// Bearer token the media service authenticates its reports with.
+    events_secret: Option<String>,
+}
+
+impl MediaClient {
+    pub fn from_env() -> Self {
+        let base_url =
+            std::env::var("MEDIA_URL").unwrap_or_else(|_| "http://localhost:8083".to_string());
+        MediaClient {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(3))
+                .build()
+                .expect("static http client config"),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            events_secret: std::env::var("MEDIA_EVENTS_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+        }
+    }
+
+    /// Allocate a relay for `call_id`.
+    pub async fn allocate(&self, call_id: Uuid) -> Result<Allocation, reqwest::Error> {
+        let url = format!("{}/alloc", self.base_url);
+        let body = serde_json::json!({ "call_id": call_id });
+        let res = self.http.post(url).json(&body).send().await?;
+        res.error_for_status()?.json().await
+    }
+
+    /// Release the relay carrying `call_id`, if it has one.
+    pub async fn release(&self, call_id: Uuid) -> Result<(), reqwest::Error> {
+        let url = format!("{}/calls/{call_id}/relay", self.base_url);
+        let res = self.http.delete(url).send().await?;
+        if res.status() != reqwest::StatusCode::NOT_FOUND {
+            res.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    /// Whether a report was sent by the media service.
+    fn authenticates(&self, headers: &HeaderMap) -> bool {
+        match (&self.events_secret, crate::bearer_token(headers)) {
+            (Some(secret), Some(token)) => {
+                // Compared in constant time, so the secret cannot be guessed
+                // byte by byte.
+                secret.len() == token.len()
+                    && secret
+                        .bytes()
+                        .zip(token.bytes())
+                        .fold(0, |diff, (a, b)| diff | (a ^ b))
+                        == 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Start or stop music on hold towards `side` (`a` or `b`) of the relay
+    /// carrying `call_id`. `Ok(false)` when the call has no relay, i.e. its
+    /// media flows directly between the endpoints.
+    pub async fn hold(
+        &self,
+        call_id: Uuid,
+        side: &str,
+        held: bool,
+    ) -> Result<bool, reqwest::Error> {
+        let url = format!("{}/calls/{call_id}/hold", self.base_url);
+        let body = serde_json::json!({ "side": side, "held": held });
+        let res = self.http.post(url).json(&body).send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        res.error_for_status()?;
+        Ok(true)
+    }
+
+    /// Tell the relay of `call_id` the SSRC `side` (`a` or `b`) announced,
+    /// or that it announced none. A call without a relay is left alone.
+    pub async fn expect_ssrc(
+        &self,
+        call_id: Uuid,
+        side: &str,
+        ssrc: Option<u32>,
+    ) -> Result<(), reqwest::Error> {
+        let url = format!("{}/calls/{call_id}/ssrc", self.base_url);
+        let body = serde_json::json!({ "side": side, "ssrc": ssrc });
+        let res = self.http.put(url).json(&body).send().await?;
+        if res.status() != reqwest::StatusCode::NOT_FOUND {
+            res.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    /// Add the supervisor leg `supervisor_id` with its `offer` to the relay
+    /// of `call_id`, or renegotiate it, returning the relay's answer with the
+    /// leg's port.
+    /// `agent_side` is the relay side (`a` or `b`) of the supervised agent.
+    /// `Ok(None)` when the call has no relay to join.
+    pub async fn supervise(
+        &self,
+        call_id: Uuid,
+        supervisor_id: Uuid,
+        offer: &str,
+        mode: SuperviseMode,
+        agent_side: &str,
+    ) -> Result<Option<Leg>, reqwest::Error> {
+        let url = format!("{}/calls/{call_id}/supervisor", self.base_url);
+        let body = serde_json::json!({
+            "supervisor_id": supervisor_id,
+            "sdp": offer,
+            "mode": mode,
+            "agent": agent_side,
+        });
+        let res = self.http.put(url).json(&body).send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(res.error_for_status()?.json().await?))
+    }
+
+    /// Switch the supervisor on `call_id` to `mode`. A supervisor whose
+    /// media is not up yet is not known to the relay.
+    pub async fn set_supervisor_mode(
+        &self,
+        call_id: Uuid,
+        mode: SuperviseMode,
+    ) -> Result<(), reqwest::Error> {
+        let url = format!("{}/calls/{call_id}/supervisor", self.base_url);
+        let body = serde_json::json!({ "mode": mode });
+        let res = self.http.patch(url).json(&body).send().await?;
+        if res.status() != reqwest::StatusCode::NOT_FOUND {
+            res.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    pub async fn end_supervision(&self, call_id: Uuid) -> Result<(), reqwest::Error> {
+        let url = format!("{}/calls/{call_id}/supervisor", self.base_url);
+        let res = self.http.delete(url).send().await?;
+        if res.status() != reqwest::StatusCode::NOT_FOUND {
+            res.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    /// Add `participant_id` to the mixer of `conference_id` with its `offer`,
+    /// returning the mixer's answer with the participant's port.
+    pub async fn join_conference(
+        &self,
+        conference_id: Uuid,
+        participant_id: Uuid,
+        offer: &str,
+    ) -> Result<Leg, reqwest::Error> {
+        let url = format!("{}/conferences/{conference_id}/participants", self.base_url);
+        let body = serde_json::json!({ "participant_id": participant_id, "sdp": offer });
+        let res = self.http.post(url).json(&body).send().await?;
+        res.error_for_status()?.json().await
+    }
+
+    pub async fn mute_participant(
+        &self,
+        conference_id: Uuid,
+        participant_id: Uuid,
+        muted: bool,
+    ) -> Result<(), reqwest::Error> {
+        let url = format!(
+            "{}/conferences/{conference_id}/participants/{participant_id}",
+            self.base_url
+        );
+        let body = serde_json::json!({ "muted": muted });
+        self.http
+            .patch(url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Take a participant out of the mixer; the mixer closes with its last
+    /// participant. A participant that never sent media is not known there.
+    pub async fn remove_participant(
+        &self,
+        conference_id: Uuid,
+        participant_id: Uuid,
+    ) -> Result<(), reqwest::Error> {
+        let url = format!(
+            "{}/conferences/{conference_id}/participants/{participant_id}",
+            self.base_url
+        );
+        let res = self.http.delete(url).send().await?;
+        if res.status() != reqwest::StatusCode::NOT_FOUND {
+            res.error_for_status()?;
+        }
+        Ok(())
+    }
+}
+
+/// A relay the media service closed.
+#[derive(Debug, Deserialize)]
+pub struct RelayClosed {
+    pub session_id: Uuid,
+    /// The call it carried, if it still did.
+    #[serde(default)]
+    pub call_id: Option<Uuid>,
+    pub reason: String,
+    #[serde(default)]
+    pub streams: Vec<StreamStats>,
+}
+
+/// Quality of one RTP stream through a relay.
+#[derive(Debug, Deserialize)]
+pub struct StreamStats {
+    pub ssrc: u32,
+    /// `a_to_b` or `b_to_a`.
+    pub direction: String,
+    pub packets: u64,
+    pub lost: u64,
+    pub reordered: u64,
+    pub duplicates: u64,
+    #[serde(default)]
+    pub jitter_ms: Option<f64>,
+}
+
+pub async fn relay_closed(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(event): Json<RelayClosed>,
+) -> StatusCode {
+    if !state.media.authenticates(&headers) {
+        tracing::warn!(session_id = %event.session_id, "unauthenticated relay report");
+        return StatusCode::UNAUTHORIZED;
+    }
+    tracing::debug!(session_id = %event.session_id, reason = %event.reason, "relay closed");
+    for stream in &event.streams {
+        tracing::info!(
+            session_id = %event.session_id,
+            call_id = ?event.call_id,
+            ssrc = stream.ssrc,
+            direction = %stream.direction,
+            packets = stream.packets,
+            lost = stream.lost,
+            reordered = stream.reordered,
+            duplicates = stream.duplicates,
+            jitter_ms = ?stream.jitter_ms,
+            "relay stream quality"
+        );
+    }
+    if let Some(call_id) = event.call_id {
+        calls::relay_closed(&state, call_id).await;
+    }
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,158 @@
+//! Call park and call pickup.
+//!
+//! `call.park` moves a connected call into a numbered slot of the tenant: the
+//! parking agent drops out (its part ends with reason `parked`) and the other
+//! party hears music on hold from the call's relay. Any agent of the tenant
+//! may then `call.retrieve` the slot and carry on with the parked party on a
+//! new call, as after an attended transfer. A call nobody retrieves within
+//! the park timeout rings the agent who parked it again, like a blind
+//! transfer back. Every agent of the tenant sees slots fill and empty through
+//! `park.slot`.
+//!
+//! `call.pickup` answers a call ringing someone else: a named colleague
+//! (directed pickup) or, without one, whichever member of the agent's PBX
+//! pickup group has been ringing longest (group pickup).
+//!
+//! Parked and ringing calls stay on the node owning them. Slots and the calls
+//! ringing each user are indexed in the cluster as well, so the retrieving
+//! agent's node can find the call and forward the takeover to its owner.
+
+use crate::calls::{self, Endpoint};
+use crate::protocol::{ErrorCode, ParkedCall, ServerMessage};
+use crate::AppState;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Park slots taken by calls owned by this node.
+#[derive(Debug, Default)]
+pub struct ParkingLot {
+    /// Parked call by tenant and slot.
+    slots: HashMap<(Uuid, u16), Uuid>,
+}
+
+async fn refuse(state: &AppState, to: Endpoint, call_id: Uuid, code: ErrorCode, message: &str) {
+    let err = ServerMessage::error(Some(call_id), code, message);
+    state.cluster.send_to(to, err).await;
+}
+
+/// Take slot `requested` of the tenant for `call_id`, or the lowest free one.
+pub async fn take_slot(
+    state: &AppState,
+    tenant_id: Uuid,
+    requested: Option<u16>,
+    call_id: Uuid,
+) -> Result<u16, (ErrorCode, &'static str)> {
+    let candidates = match requested {
+        Some(slot) if (1..=state.park_slots).contains(&slot) => slot..=slot,
+        Some(_) => return Err((ErrorCode::InvalidDestination, "no such park slot")),
+        None => 1..=state.park_slots,
+    };
+    for slot in candidates {
+        // Held locally first, so parks racing on this node never share a slot.
+        let reserved = {
+            let mut parking = state.parking.write().await;
+            let free = !parking.slots.contains_key(&(tenant_id, slot));
+            if free {
+                parking.slots.insert((tenant_id, slot), call_id);
+            }
+            free
+        };
+        if !reserved {
+            continue;
+        }
+        if state
+            .cluster
+            .claim_park_slot(tenant_id, slot, call_id)
+            .await
+        {
+            return Ok(slot);
+        }
+        state.parking.write().await.slots.remove(&(tenant_id, slot));
+    }
+    let message = match requested {
+        Some(_) => "the park slot is taken",
+        None => "every park slot is taken",
+    };
+    Err((ErrorCode::Unavailable, message))
+}
+
+/// Free a slot and tell the tenant.
+pub async fn vacate(state: &AppState, tenant_id: Uuid, slot: u16) {
+    state.parking.write().await.slots.remove(&(tenant_id, slot));
+    state.cluster.release_park_slot(tenant_id, slot).await;
+    announce(state, tenant_id, slot, None).await;
+}
+
+pub async fn announce(state: &AppState, tenant_id: Uuid, slot: u16, parked: Option<ParkedCall>) {
+    let msg = ServerMessage::ParkSlot { slot, parked };
+    state.cluster.send_to_tenant(tenant_id, msg).await;
+}
+
+/// Carry on with the call parked in `slot` as `call_id`.
+pub async fn retrieve(state: &AppState, from: Endpoint, slot: u16, call_id: Uuid) {
+    let local = state
+        .parking
+        .read()
+        .await
+        .slots
+        .get(&(from.tenant_id, slot))
+        .copied();
+    let parked = match local {
+        Some(parked) => Some(parked),
+        None => state.cluster.parked_call(from.tenant_id, slot).await,
+    };
+    let Some(parked) = parked else {
+        let message = "no call is parked in that slot";
+        refuse(state, from, call_id, ErrorCode::UnknownCall, message).await;
+        return;
+    };
+    calls::take_over(state, from, parked, call_id).await;
+}
+
+/// Answer, as `call_id`, a call ringing `user_id` or, without one, the
+/// caller's pickup group.
+pub async fn pickup(state: &AppState, from: Endpoint, call_id: Uuid, user_id: Option<Uuid>) {
+    let users = match user_id {
+        Some(user_id) if user_id == from.user_id => {
+            let message = "cannot pick up your own calls";
+            refuse(state, from, call_id, ErrorCode::InvalidDestination, message).await;
+            return;
+        }
+        Some(user_id) => vec![user_id],
+        None => match state.pbx.pickup_group(from.user_id).await {
+            Ok(Some(group)) if group.tenant_id == from.tenant_id => group
+                .members
+                .into_iter()
+                .filter(|member| *member != from.user_id)
+                .collect(),
+            Ok(_) => {
+                let message = "not in a pickup group";
+                refuse(state, from, call_id, ErrorCode::InvalidDestination, message).await;
+                return;
+            }
+            Err(err) => {
+                tracing::warn!(user_id = %from.user_id, error = %err, "pbx pickup group lookup failed");
+                let message = "pickup unavailable";
+                refuse(state, from, call_id, ErrorCode::Unavailable, message).await;
+                return;
+            }
+        },
+    };
+
+    // Calls ringing here are ordered by how long they have rung; elsewhere in
+    // the cluster any ringing call will do.
+    let mut ringing = calls::longest_ringing(state, from.tenant_id, &users).await;
+    for user_id in &users {
+        if ringing.is_some() {
+            break;
+        }
+        let remote = state.cluster.ringing_calls(from.tenant_id, *user_id).await;
+        ringing = remote.into_iter().next();
+    }
+    let Some(ringing) = ringing else {
+        let message = "no call to pick up";
+        refuse(state, from, call_id, ErrorCode::UnknownCall, message).await;
+        return;
+    };
+    calls::take_over(state, from, ringing, call_id).await;
+}
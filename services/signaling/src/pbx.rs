@@ -0,0 +1,186 @@
+//! Client for the PBX number plan and SIP credentials.
+//!
+//! Destinations that are not a user id (DIDs, extensions) are resolved by the
+//! PBX, which owns the call-flows. Only the answer's first hop is used: the
+//! user its entry node rings. The PBX also keeps the digest hashes SIP phones
+//! authenticate against, the hold intervals reported on calls, and the
+//! pickup groups agents answer each other's calls in.
+
+use serde::Deserialize;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Route {
+    pub tenant_id: Uuid,
+    pub flow_id: Uuid,
+    pub destination: Option<Destination>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Destination {
+    User { user_id: Uuid },
+    Conference { room_id: Uuid },
+}
+
+/// HA1 hashes of a user's SIP password, one per digest algorithm.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SipCredentials {
+    pub tenant_id: Uuid,
+    pub user_id: Uuid,
+    pub realm: String,
+    pub ha1_md5: String,
+    pub ha1_sha256: String,
+}
+
+/// What a PIN opens in a configured conference room.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConferenceAccess {
+    pub tenant_id: Uuid,
+    pub moderator: bool,
+    pub max_participants: usize,
+}
+
+/// Outcome of presenting a PIN for a conference room.
+#[derive(Debug, Clone)]
+pub enum RoomAccess {
+    /// The tenant has no room configured with that id.
+    Unknown,
+    Denied,
+    Granted(ConferenceAccess),
+}
+
+/// Users who may pick up each other's ringing calls.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PickupGroup {
+    pub tenant_id: Uuid,
+    pub members: Vec<Uuid>,
+}
+
+pub struct PbxClient {
+    http: reqwest::Client,
+    base_url: String,
+    /// Presented on the routes that hand out secrets or check PINs.
+    service_token: Option<String>,
+}
+
+impl PbxClient {
+    pub fn from_env() -> Self {
+        let base_url =
+            std::env::var("PBX_URL").unwrap_or_else(|_| "http://localhost:8081".to_string());
+        PbxClient {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(3))
+                .build()
+                .expect("static http client config"),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            service_token: std::env::var("PBX_SERVICE_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+        }
+    }
+
+    /// `request` with the service token attached, when one is configured.
+    fn authorized(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.service_token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Look up the flow answering `number`; `Ok(None)` when it is not assigned.
+    pub async fn resolve(&self, number: &str) -> Result<Option<Route>, reqwest::Error> {
+        let url = format!("{}/numbers/{}/route", self.base_url, encode(number));
+        let res = self.http.get(url).send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        res.error_for_status()?.json().await.map(Some)
+    }
+
+    /// Digest credentials of `user_id`; `Ok(None)` when it has none.
+    pub async fn sip_credentials(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<SipCredentials>, reqwest::Error> {
+        let url = format!("{}/sip-credentials/{user_id}", self.base_url);
+        let res = self.authorized(self.http.get(url)).send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        res.error_for_status()?.json().await.map(Some)
+    }
+
+    /// Check `pin` (if any) against the room `room_id` of `tenant_id`.
+    pub async fn join_conference(
+        &self,
+        tenant_id: Uuid,
+        room_id: Uuid,
+        pin: Option<&str>,
+    ) -> Result<RoomAccess, reqwest::Error> {
+        let url = format!("{}/conferences/{room_id}/join", self.base_url);
+        let body = serde_json::json!({ "tenant_id": tenant_id, "pin": pin });
+        let res = self
+            .authorized(self.http.post(url))
+            .json(&body)
+            .send()
+            .await?;
+        match res.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(RoomAccess::Unknown),
+            reqwest::StatusCode::FORBIDDEN => Ok(RoomAccess::Denied),
+            _ => res
+                .error_for_status()?
+                .json()
+                .await
+                .map(RoomAccess::Granted),
+        }
+    }
+
+    /// The pickup group of `user_id`; `Ok(None)` when it is in none.
+    pub async fn pickup_group(&self, user_id: Uuid) -> Result<Option<PickupGroup>, reqwest::Error> {
+        let url = format!("{}/users/{user_id}/pickup-group", self.base_url);
+        let res = self.http.get(url).send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        res.error_for_status()?.json().await.map(Some)
+    }
+
+    /// Record that `held_by` kept `call_id` on hold for `duration`.
+    pub async fn record_hold(
+        &self,
+        tenant_id: Uuid,
+        call_id: Uuid,
+        held_by: Uuid,
+        duration: Duration,
+    ) -> Result<(), reqwest::Error> {
+        let url = format!("{}/calls/{call_id}/holds", self.base_url);
+        let body = serde_json::json!({
+            "tenant_id": tenant_id,
+            "held_by": held_by,
+            "duration_ms": u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+        });
+        self.http
+            .post(url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Percent-encode a dialed string for use as a path segment (`+` and `#`
+/// are common in dial strings).
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
@@ -0,0 +1,711 @@
+//! Agent presence: storage, routing lookups and the live feed for clients.
+//!
+//! Each agent's presence is a Redis hash under `presence:{tenant}:{user}` with
+//! the fields `status`, `chosen` (the status the agent picked themselves, which
+//! is restored once a call ends), `note` and `updated_at`. The hash carries a
+//! short TTL that live sockets keep refreshing, so a crashed node cannot leave
+//! agents stuck online. A hash lost under a live socket anyway (Redis
+//! restarted, a refresh came late) is put back by the next refresh from the
+//! last version the node saw. Routing and other services read the same hash.
+//! Every change is made by a script that reads and writes the hash in one go,
+//! so a status picked on one device and a call starting on another cannot
+//! overwrite each other.
+//!
+//! Changes are broadcast as [`PresenceEvent`]s on the process-wide channel (and
+//! mirrored across replicas by the cluster). Every socket task subscribes to
+//! that channel and forwards the events that belong to its own tenant, after
+//! first sending a snapshot of who is currently online.
+
+use crate::calls::Endpoint;
+use crate::protocol::{ErrorCode, ServerMessage};
+use crate::AppState;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+/// Presence hashes expire this long after the last refresh.
+const PRESENCE_TTL_SECS: u64 = 60;
+
+/// Create the hash as `available` unless it exists, then return it.
+const ONLINE_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    redis.call('HSET', KEYS[1], 'status', 'available', 'chosen', 'available',
+        'note', '', 'updated_at', ARGV[1])
+end
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+return redis.call('HGETALL', KEYS[1])
+";
+
+/// Store the picked status and note, keeping `on_call` in place, and return
+/// the hash.
+const CHOOSE_SCRIPT: &str = r"
+local status = redis.call('HGET', KEYS[1], 'status')
+if status ~= 'on_call' then
+    status = ARGV[1]
+end
+redis.call('HSET', KEYS[1], 'status', status, 'chosen', ARGV[1],
+    'note', ARGV[2], 'updated_at', ARGV[3])
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+return redis.call('HGETALL', KEYS[1])
+";
+
+/// Switch an existing hash to `on_call` (ARGV[1] = 1) or back to the picked
+/// status. Returns the hash when the status changed, nothing otherwise.
+const ON_CALL_SCRIPT: &str = r"
+local fields = redis.call('HMGET', KEYS[1], 'status', 'chosen')
+local status = fields[1]
+if not status then
+    return {}
+end
+local wanted = fields[2] or status
+if ARGV[1] == '1' then
+    wanted = 'on_call'
+end
+if wanted == status then
+    return {}
+end
+redis.call('HSET', KEYS[1], 'status', wanted, 'updated_at', ARGV[2])
+redis.call('EXPIRE', KEYS[1], ARGV[3])
+return redis.call('HGETALL', KEYS[1])
+";
+
+/// Extend the hash's TTL, first putting it back from ARGV when it is gone.
+/// Returns 1 when it had to be put back.
+const REFRESH_SCRIPT: &str = r"
+local missing = redis.call('EXISTS', KEYS[1]) == 0
+if missing then
+    redis.call('HSET', KEYS[1], 'status', ARGV[1], 'chosen', ARGV[2],
+        'note', ARGV[3], 'updated_at', ARGV[4])
+end
+redis.call('EXPIRE', KEYS[1], ARGV[5])
+if missing then
+    return 1
+end
+return 0
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Available,
+    Away,
+    Busy,
+    DoNotDisturb,
+    /// Set automatically while the agent is connected to a call.
+    OnCall,
+    WrapUp,
+    /// Never stored; reported when the agent's last connection goes away.
+    Offline,
+}
+
+impl PresenceStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PresenceStatus::Available => "available",
+            PresenceStatus::Away => "away",
+            PresenceStatus::Busy => "busy",
+            PresenceStatus::DoNotDisturb => "do_not_disturb",
+            PresenceStatus::OnCall => "on_call",
+            PresenceStatus::WrapUp => "wrap_up",
+            PresenceStatus::Offline => "offline",
+        }
+    }
+
+    /// Statuses an agent may pick for themselves; the rest are derived from
+    /// connection and call state.
+    pub fn is_selectable(self) -> bool {
+        !matches!(self, PresenceStatus::OnCall | PresenceStatus::Offline)
+    }
+
+    /// Whether routing should offer new calls to an agent in this status.
+    pub fn accepts_calls(self) -> bool {
+        !matches!(self, PresenceStatus::DoNotDisturb | PresenceStatus::Offline)
+    }
+}
+
+impl FromStr for PresenceStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "available" => PresenceStatus::Available,
+            "away" => PresenceStatus::Away,
+            "busy" => PresenceStatus::Busy,
+            "do_not_disturb" => PresenceStatus::DoNotDisturb,
+            "on_call" => PresenceStatus::OnCall,
+            "wrap_up" => PresenceStatus::WrapUp,
+            "offline" => PresenceStatus::Offline,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Presence of a single agent as exposed to clients and other replicas.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Presence {
+    pub user_id: Uuid,
+    pub status: PresenceStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// Unix timestamp (seconds) of the last change.
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceEvent {
+    pub tenant_id: Uuid,
+    pub presence: Presence,
+}
+
+impl PresenceEvent {
+    pub fn offline(tenant_id: Uuid, user_id: Uuid) -> Self {
+        PresenceEvent {
+            tenant_id,
+            presence: Presence {
+                user_id,
+                status: PresenceStatus::Offline,
+                note: None,
+                updated_at: now(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    status: PresenceStatus,
+    chosen: PresenceStatus,
+    note: Option<String>,
+    updated_at: u64,
+}
+
+impl Record {
+    fn from_hash(hash: &HashMap<String, String>) -> Option<Record> {
+        let status = hash.get("status")?.parse().ok()?;
+        let chosen = hash
+            .get("chosen")
+            .and_then(|chosen| chosen.parse().ok())
+            .unwrap_or(status);
+        Some(Record {
+            status,
+            chosen,
+            note: hash.get("note").filter(|note| !note.is_empty()).cloned(),
+            updated_at: hash
+                .get("updated_at")
+                .and_then(|ts| ts.parse().ok())
+                .unwrap_or_default(),
+        })
+    }
+
+    fn available() -> Record {
+        Record {
+            status: PresenceStatus::Available,
+            chosen: PresenceStatus::Available,
+            note: None,
+            updated_at: now(),
+        }
+    }
+
+    fn presence(&self, user_id: Uuid) -> Presence {
+        Presence {
+            user_id,
+            status: self.status,
+            note: self.note.clone(),
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn presence_key(tenant_id: Uuid, user_id: Uuid) -> String {
+    format!(
+        "presence:{}:{}",
+        tenant_id.as_hyphenated(),
+        user_id.as_hyphenated()
+    )
+}
+
+/// Presence persistence. Redis is the source of truth when configured; a local
+/// map stands in for single-node development setups.
+pub struct PresenceStore {
+    redis: Option<redis::aio::ConnectionManager>,
+    online_script: redis::Script,
+    choose_script: redis::Script,
+    on_call_script: redis::Script,
+    refresh_script: redis::Script,
+    local: RwLock<HashMap<(Uuid, Uuid), Record>>,
+    /// With Redis, the last record this node saw of each agent connected
+    /// here, to put back a hash that went missing.
+    seen: RwLock<HashMap<(Uuid, Uuid), Record>>,
+}
+
+impl PresenceStore {
+    pub fn new(redis: Option<redis::aio::ConnectionManager>) -> Self {
+        PresenceStore {
+            redis,
+            online_script: redis::Script::new(ONLINE_SCRIPT),
+            choose_script: redis::Script::new(CHOOSE_SCRIPT),
+            on_call_script: redis::Script::new(ON_CALL_SCRIPT),
+            refresh_script: redis::Script::new(REFRESH_SCRIPT),
+            local: RwLock::new(HashMap::new()),
+            seen: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Note the record Redis holds for an agent and return their presence.
+    async fn saw(&self, tenant_id: Uuid, user_id: Uuid, record: Record) -> Presence {
+        let presence = record.presence(user_id);
+        self.seen.write().await.insert((tenant_id, user_id), record);
+        presence
+    }
+
+    async fn load(&self, tenant_id: Uuid, user_id: Uuid) -> redis::RedisResult<Option<Record>> {
+        let Some(mut conn) = self.redis.clone() else {
+            return Ok(self.local.read().await.get(&(tenant_id, user_id)).cloned());
+        };
+        let hash: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(presence_key(tenant_id, user_id))
+            .query_async(&mut conn)
+            .await?;
+        Ok(Record::from_hash(&hash))
+    }
+
+    /// Current presence of an agent, or `None` when they are offline or it
+    /// cannot be read.
+    pub async fn get(&self, tenant_id: Uuid, user_id: Uuid) -> Option<Presence> {
+        match self.load(tenant_id, user_id).await {
+            Ok(record) => record.map(|record| record.presence(user_id)),
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to load presence");
+                None
+            }
+        }
+    }
+
+    /// Make sure the agent has a presence record, keeping whatever status an
+    /// already-connected device established. Nothing is stored when Redis
+    /// fails; the agent shows as available until their next change.
+    pub async fn mark_online(&self, tenant_id: Uuid, user_id: Uuid) -> Presence {
+        let Some(mut conn) = self.redis.clone() else {
+            let mut local = self.local.write().await;
+            let record = local
+                .entry((tenant_id, user_id))
+                .or_insert_with(Record::available);
+            return record.presence(user_id);
+        };
+        let hash: redis::RedisResult<HashMap<String, String>> = self
+            .online_script
+            .key(presence_key(tenant_id, user_id))
+            .arg(now())
+            .arg(PRESENCE_TTL_SECS)
+            .invoke_async(&mut conn)
+            .await;
+        match hash.map(|hash| Record::from_hash(&hash)) {
+            Ok(Some(record)) => self.saw(tenant_id, user_id, record).await,
+            Ok(None) => Record::available().presence(user_id),
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to mark presence online");
+                Record::available().presence(user_id)
+            }
+        }
+    }
+
+    /// Apply a status picked by the agent. While they are on a call the choice
+    /// is remembered and takes effect when the call ends.
+    pub async fn set_chosen(
+        &self,
+        tenant_id: Uuid,
+        user_id: Uuid,
+        status: PresenceStatus,
+        note: Option<String>,
+    ) -> Presence {
+        let mut record = Record {
+            status,
+            chosen: status,
+            note,
+            updated_at: now(),
+        };
+        let Some(mut conn) = self.redis.clone() else {
+            let mut local = self.local.write().await;
+            let on_call = local
+                .get(&(tenant_id, user_id))
+                .is_some_and(|record| record.status == PresenceStatus::OnCall);
+            if on_call {
+                record.status = PresenceStatus::OnCall;
+            }
+            local.insert((tenant_id, user_id), record.clone());
+            return record.presence(user_id);
+        };
+        let hash: redis::RedisResult<HashMap<String, String>> = self
+            .choose_script
+            .key(presence_key(tenant_id, user_id))
+            .arg(status.as_str())
+            .arg(record.note.as_deref().unwrap_or(""))
+            .arg(record.updated_at)
+            .arg(PRESENCE_TTL_SECS)
+            .invoke_async(&mut conn)
+            .await;
+        match hash.map(|hash| Record::from_hash(&hash)) {
+            Ok(Some(stored)) => self.saw(tenant_id, user_id, stored).await,
+            Ok(None) => record.presence(user_id),
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to store presence");
+                record.presence(user_id)
+            }
+        }
+    }
+
+    /// Flip the agent into or out of `on_call`, returning the new presence when
+    /// it changed.
+    pub async fn set_on_call(
+        &self,
+        tenant_id: Uuid,
+        user_id: Uuid,
+        on_call: bool,
+    ) -> Option<Presence> {
+        let Some(mut conn) = self.redis.clone() else {
+            let mut local = self.local.write().await;
+            let record = local.get_mut(&(tenant_id, user_id))?;
+            let status = if on_call {
+                PresenceStatus::OnCall
+            } else {
+                record.chosen
+            };
+            if record.status == status {
+                return None;
+            }
+            record.status = status;
+            record.updated_at = now();
+            return Some(record.presence(user_id));
+        };
+        let hash: redis::RedisResult<HashMap<String, String>> = self
+            .on_call_script
+            .key(presence_key(tenant_id, user_id))
+            .arg(u8::from(on_call))
+            .arg(now())
+            .arg(PRESENCE_TTL_SECS)
+            .invoke_async(&mut conn)
+            .await;
+        match hash {
+            Ok(hash) => match Record::from_hash(&hash) {
+                Some(record) => Some(self.saw(tenant_id, user_id, record).await),
+                None => None,
+            },
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to update presence");
+                None
+            }
+        }
+    }
+
+    /// Keep the agent's hash alive, putting it back from the last record seen
+    /// here (or as `available`) when it went missing meanwhile.
+    pub async fn refresh(&self, tenant_id: Uuid, user_id: Uuid) {
+        let Some(mut conn) = self.redis.clone() else {
+            return;
+        };
+        let record = self
+            .seen
+            .read()
+            .await
+            .get(&(tenant_id, user_id))
+            .cloned()
+            .unwrap_or_else(Record::available);
+        let restored: redis::RedisResult<u8> = self
+            .refresh_script
+            .key(presence_key(tenant_id, user_id))
+            .arg(record.status.as_str())
+            .arg(record.chosen.as_str())
+            .arg(record.note.as_deref().unwrap_or(""))
+            .arg(record.updated_at)
+            .arg(PRESENCE_TTL_SECS)
+            .invoke_async(&mut conn)
+            .await;
+        match restored {
+            Ok(1) => tracing::warn!(%tenant_id, %user_id, "presence had expired, restored it"),
+            Ok(_) => {}
+            Err(err) => tracing::warn!(error = %err, "failed to refresh presence"),
+        }
+    }
+
+    /// Drop what this node remembers of an agent with no connection left here.
+    pub async fn forget(&self, tenant_id: Uuid, user_id: Uuid) {
+        self.seen.write().await.remove(&(tenant_id, user_id));
+    }
+
+    pub async fn clear(&self, tenant_id: Uuid, user_id: Uuid) {
+        self.forget(tenant_id, user_id).await;
+        let Some(mut conn) = self.redis.clone() else {
+            self.local.write().await.remove(&(tenant_id, user_id));
+            return;
+        };
+        let _ = redis::cmd("DEL")
+            .arg(presence_key(tenant_id, user_id))
+            .query_async::<_, ()>(&mut conn)
+            .await;
+    }
+
+    /// Presence of every online agent in `tenant_id`.
+    pub async fn list(&self, tenant_id: Uuid) -> Vec<Presence> {
+        let Some(mut conn) = self.redis.clone() else {
+            return self
+                .local
+                .read()
+                .await
+                .iter()
+                .filter(|((tenant, _), _)| *tenant == tenant_id)
+                .map(|((_, user_id), record)| record.presence(*user_id))
+                .collect();
+        };
+
+        let pattern = format!("presence:{}:*", tenant_id.as_hyphenated());
+        let mut users = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let page: redis::RedisResult<(u64, Vec<String>)> = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(200)
+                .query_async(&mut conn)
+                .await;
+            let (next, keys) = match page {
+                Ok(page) => page,
+                Err(err) => {
+                    tracing::warn!(error = %err, "presence scan failed");
+                    break;
+                }
+            };
+            users.extend(
+                keys.iter()
+                    .filter_map(|key| key.rsplit(':').next())
+                    .filter_map(|id| id.parse::<Uuid>().ok()),
+            );
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        users.sort_unstable();
+        users.dedup();
+
+        let mut agents = Vec::with_capacity(users.len());
+        for user_id in users {
+            if let Some(presence) = self.get(tenant_id, user_id).await {
+                agents.push(presence);
+            }
+        }
+        agents
+    }
+}
+
+/// Tell every replica (and through them, every client) about a change.
+pub async fn announce(state: &AppState, tenant_id: Uuid, presence: Presence) {
+    state
+        .cluster
+        .publish_presence(PresenceEvent {
+            tenant_id,
+            presence,
+        })
+        .await;
+}
+
+/// Longest note an agent may attach to their status.
+const MAX_NOTE_LEN: usize = 140;
+
+/// Handle `presence.set` from a client.
+pub async fn set_from_client(
+    state: &AppState,
+    from: Endpoint,
+    status: PresenceStatus,
+    note: Option<String>,
+) {
+    if !status.is_selectable() {
+        let msg = ServerMessage::error(
+            None,
+            ErrorCode::NotAllowed,
+            format!("status {} is set automatically", status.as_str()),
+        );
+        state.cluster.send_to(from, msg).await;
+        return;
+    }
+    let note = note
+        .map(|note| note.trim().chars().take(MAX_NOTE_LEN).collect::<String>())
+        .filter(|note| !note.is_empty());
+    let presence = state
+        .presence
+        .set_chosen(from.tenant_id, from.user_id, status, note)
+        .await;
+    announce(state, from.tenant_id, presence).await;
+}
+
+/// Mark the parties of a call as on (or off) a call.
+pub async fn set_on_call(state: &AppState, tenant_id: Uuid, users: &[Uuid], on_call: bool) {
+    for user_id in users {
+        if let Some(presence) = state
+            .presence
+            .set_on_call(tenant_id, *user_id, on_call)
+            .await
+        {
+            announce(state, tenant_id, presence).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store of each kind: local, and backed by Redis when `REDIS_URL`
+    /// names one, so the scripts are held to what the local map does.
+    async fn stores() -> Vec<PresenceStore> {
+        let redis = crate::test_redis().await;
+        let mut stores = vec![PresenceStore::new(None)];
+        stores.extend(redis.map(|redis| PresenceStore::new(Some(redis))));
+        stores
+    }
+
+    #[tokio::test]
+    async fn a_chosen_status_waits_for_the_call_to_end() {
+        for store in stores().await {
+            let (tenant, user) = (Uuid::new_v4(), Uuid::new_v4());
+            assert!(store.set_on_call(tenant, user, true).await.is_none());
+            assert_eq!(
+                store.mark_online(tenant, user).await.status,
+                PresenceStatus::Available
+            );
+
+            let on_call = store.set_on_call(tenant, user, true).await.unwrap();
+            assert_eq!(on_call.status, PresenceStatus::OnCall);
+            assert!(store.set_on_call(tenant, user, true).await.is_none());
+
+            // A status picked on another device never overrides the call.
+            let chosen = store
+                .set_chosen(tenant, user, PresenceStatus::DoNotDisturb, None)
+                .await;
+            assert_eq!(chosen.status, PresenceStatus::OnCall);
+            // Nor does another device connecting.
+            assert_eq!(
+                store.mark_online(tenant, user).await.status,
+                PresenceStatus::OnCall
+            );
+
+            let after = store.set_on_call(tenant, user, false).await.unwrap();
+            assert_eq!(after.status, PresenceStatus::DoNotDisturb);
+            assert!(store.set_on_call(tenant, user, false).await.is_none());
+            assert_eq!(
+                store.mark_online(tenant, user).await.status,
+                PresenceStatus::DoNotDisturb
+            );
+            store.clear(tenant, user).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn notes_are_kept_until_the_next_pick() {
+        for store in stores().await {
+            let (tenant, user) = (Uuid::new_v4(), Uuid::new_v4());
+            store.mark_online(tenant, user).await;
+            let note = Some("back at 3".to_string());
+            let away = store
+                .set_chosen(tenant, user, PresenceStatus::Away, note.clone())
+                .await;
+            assert_eq!(away.note, note);
+            store.set_on_call(tenant, user, true).await.unwrap();
+            let after = store.set_on_call(tenant, user, false).await.unwrap();
+            assert_eq!((after.status, after.note), (PresenceStatus::Away, note));
+
+            let busy = store
+                .set_chosen(tenant, user, PresenceStatus::Busy, None)
+                .await;
+            assert_eq!(busy.note, None);
+            assert_eq!(store.get(tenant, user).await.unwrap().note, None);
+            store.clear(tenant, user).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn a_tenant_lists_its_own_agents_only() {
+        for store in stores().await {
+            let (tenant, other) = (Uuid::new_v4(), Uuid::new_v4());
+            let mut users = [Uuid::new_v4(), Uuid::new_v4()];
+            users.sort_unstable();
+            let stranger = Uuid::new_v4();
+            for user in users {
+                store.mark_online(tenant, user).await;
+            }
+            store.mark_online(other, stranger).await;
+
+            let mut listed: Vec<Uuid> = store
+                .list(tenant)
+                .await
+                .iter()
+                .map(|presence| presence.user_id)
+                .collect();
+            listed.sort_unstable();
+            assert_eq!(listed, users);
+
+            store.clear(tenant, users[0]).await;
+            let listed = store.list(tenant).await;
+            assert_eq!(listed.len(), 1);
+            assert_eq!(listed[0].user_id, users[1]);
+            for (tenant, user) in [(tenant, users[1]), (other, stranger)] {
+                store.clear(tenant, user).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_lost_hash_is_put_back_by_the_next_refresh() {
+        let Some(mut redis) = crate::test_redis().await else {
+            return;
+        };
+        let store = PresenceStore::new(Some(redis.clone()));
+        let (tenant, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let key = presence_key(tenant, user);
+        store.mark_online(tenant, user).await;
+        let ttl: i64 = redis::cmd("TTL")
+            .arg(&key)
+            .query_async(&mut redis)
+            .await
+            .unwrap();
+        assert!(ttl > 0 && ttl <= PRESENCE_TTL_SECS as i64);
+        store
+            .set_chosen(tenant, user, PresenceStatus::Away, None)
+            .await;
+        store.set_on_call(tenant, user, true).await.unwrap();
+
+        let _: () = redis::cmd("DEL")
+            .arg(&key)
+            .query_async(&mut redis)
+            .await
+            .unwrap();
+        assert!(store.get(tenant, user).await.is_none());
+        store.refresh(tenant, user).await;
+        let restored = store.get(tenant, user).await.unwrap();
+        assert_eq!(restored.status, PresenceStatus::OnCall);
+        // The picked status came back with it.
+        let after = store.set_on_call(tenant, user, false).await.unwrap();
+        assert_eq!(after.status, PresenceStatus::Away);
+
+        // A live hash is only kept alive, whatever this node last saw.
+        store.forget(tenant, user).await;
+        store.refresh(tenant, user).await;
+        assert_eq!(
+            store.get(tenant, user).await.unwrap().status,
+            PresenceStatus::Away
+        );
+        store.clear(tenant, user).await;
+    }
+}
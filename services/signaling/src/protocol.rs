@@ -0,0 +1,416 @@
+//! Wire format spoken between the softphone and the signaling service.
+//!
+//! Every frame is a JSON object discriminated by its `type` field, mirroring the
+//! events emitted and consumed by `frontend/web/src/store/softphone.ts`. Keeping
+//! the protocol in one place means the socket loop only has to deal with typed
+//! values and the routing logic never touches raw JSON.
+
+use crate::presence::{Presence, PresenceStatus};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Frames sent by a connected client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientMessage {
+    /// Start a new call towards `to` (the callee's user id for now).
+    #[serde(rename = "call.initiate", rename_all = "camelCase")]
+    Initiate { call_id: Uuid, to: String },
+    /// Accept a call previously announced through `call.incoming`.
+    #[serde(rename = "call.answer", rename_all = "camelCase")]
+    Answer { call_id: Uuid },
+    /// Hang up, cancel or decline a call.
+    #[serde(rename = "call.ended", rename_all = "camelCase")]
+    Ended { call_id: Uuid },
+    /// Opaque SDP/ICE payload forwarded verbatim to the other party.
+    #[serde(rename = "signal", rename_all = "camelCase")]
+    Signal {
+        call_id: Uuid,
+        data: serde_json::Value,
+    },
+    /// Blind transfer: ring `to` on behalf of the other party and drop out.
+    #[serde(rename = "call.transfer", rename_all = "camelCase")]
+    Transfer { call_id: Uuid, to: String },
+    /// Start an attended transfer: place `consult_call_id` to `to` while the
+    /// other party of `call_id` waits.
+    #[serde(rename = "call.consult", rename_all = "camelCase")]
+    Consult {
+        call_id: Uuid,
+        consult_call_id: Uuid,
+        to: String,
+    },
+    /// Finish an attended transfer by joining the other parties of `call_id`
+    /// and the consult call. Without `consult_call_id` the consultation
+    /// started by `call.consult` is used.
+    #[serde(rename = "call.transfer.complete", rename_all = "camelCase")]
+    CompleteTransfer {
+        call_id: Uuid,
+        #[serde(default)]
+        consult_call_id: Option<Uuid>,
+    },
+    /// Abandon an attended transfer: hang up the consult call and return to
+    /// `call_id`.
+    #[serde(rename = "call.transfer.cancel", rename_all = "camelCase")]
+    CancelTransfer { call_id: Uuid },
+    /// Put a connected call on hold; the other party hears music on hold.
+    /// Answered with `call.held`, even when the call already was.
+    #[serde(rename = "call.hold", rename_all = "camelCase")]
+    Hold { call_id: Uuid },
+    /// Take a call this client put on hold off hold again. Answered with
+    /// `call.resumed`, even when the call was not held.
+    #[serde(rename = "call.resume", rename_all = "camelCase")]
+    Resume { call_id: Uuid },
+    /// Ask for a media relay for a connected call, for endpoints sending
+    /// their RTP through one. Both parties receive `call.relay`.
+    #[serde(rename = "call.relay", rename_all = "camelCase")]
+    Relay { call_id: Uuid },
+    /// Park a connected call in park slot `slot` of the tenant, or the lowest
+    /// free one. The other party hears music on hold until an agent retrieves
+    /// it; the client's own part in the call ends with reason `parked`.
+    #[serde(rename = "call.park", rename_all = "camelCase")]
+    Park {
+        call_id: Uuid,
+        #[serde(default)]
+        slot: Option<u16>,
+    },
+    /// Take the call parked in `slot`, carrying on with it as `callId`.
+    #[serde(rename = "call.retrieve", rename_all = "camelCase")]
+    Retrieve { slot: u16, call_id: Uuid },
+    /// Answer a call ringing `userId` as `callId`. Without `userId`, the
+    /// longest ringing call of the client's pickup group is taken.
+    #[serde(rename = "call.pickup", rename_all = "camelCase")]
+    Pickup {
+        call_id: Uuid,
+        #[serde(default)]
+        user_id: Option<Uuid>,
+    },
+    /// Join `conferenceId` over a call leg of its own, `callId`: the offer
+    /// is sent on it with `signal` and `call.ended` leaves. Rooms configured
+    /// in the PBX may require `pin`; any other id opens an ad-hoc room, which
+    /// others can only join when invited.
+    #[serde(rename = "conference.join", rename_all = "camelCase")]
+    ConferenceJoin {
+        conference_id: Uuid,
+        call_id: Uuid,
+        #[serde(default)]
+        pin: Option<String>,
+    },
+    /// Ask `to` into a conference the client takes part in; they may join
+    /// without a PIN.
+    #[serde(rename = "conference.invite", rename_all = "camelCase")]
+    ConferenceInvite { conference_id: Uuid, to: String },
+    /// Mute or unmute the participant joined over `callId`. Moderators may
+    /// mute anyone, everyone else only themselves.
+    #[serde(rename = "conference.mute", rename_all = "camelCase")]
+    ConferenceMute {
+        conference_id: Uuid,
+        call_id: Uuid,
+        muted: bool,
+    },
+    /// Moderators: remove the participant joined over `callId`.
+    #[serde(rename = "conference.kick", rename_all = "camelCase")]
+    ConferenceKick { conference_id: Uuid, call_id: Uuid },
+    /// Moderators: refuse (or allow again) further joins.
+    #[serde(rename = "conference.lock", rename_all = "camelCase")]
+    ConferenceLock { conference_id: Uuid, locked: bool },
+    /// Supervisors: listen in on `callId`, whisper to `agentId` on it, or
+    /// barge in. The supervisor's media runs over a call leg of its own,
+    /// `legId`, like a conference participant's; sending the frame again
+    /// with the same leg switches mode, and `call.ended` on it leaves.
+    #[serde(rename = "call.supervise", rename_all = "camelCase")]
+    Supervise {
+        call_id: Uuid,
+        leg_id: Uuid,
+        agent_id: Uuid,
+        mode: SuperviseMode,
+    },
+    /// Pick a presence status, optionally with a short free-text note.
+    #[serde(rename = "presence.set", rename_all = "camelCase")]
+    SetPresence {
+        status: PresenceStatus,
+        #[serde(default)]
+        note: Option<String>,
+    },
+    /// Replace the credentials of the open socket before they expire.
+    #[serde(rename = "auth.refresh")]
+    RefreshToken { token: String },
+}
+
+impl ClientMessage {
+    /// Call this frame refers to, unless it is creating a new one.
+    pub fn existing_call_id(&self) -> Option<Uuid> {
+        match self {
+            ClientMessage::Initiate { .. }
+            | ClientMessage::Retrieve { .. }
+            | ClientMessage::Pickup { .. }
+            | ClientMessage::SetPresence { .. }
+            | ClientMessage::RefreshToken { .. } => None,
+            ClientMessage::Answer { call_id }
+            | ClientMessage::Ended { call_id }
+            | ClientMessage::Signal { call_id, .. }
+            | ClientMessage::Transfer { call_id, .. }
+            | ClientMessage::Consult { call_id, .. }
+            | ClientMessage::CompleteTransfer { call_id, .. }
+            | ClientMessage::CancelTransfer { call_id }
+            | ClientMessage::Hold { call_id }
+            | ClientMessage::Resume { call_id }
+            | ClientMessage::Relay { call_id }
+            | ClientMessage::Park { call_id, .. }
+            | ClientMessage::Supervise { call_id, .. } => Some(*call_id),
+            // Conference commands belong to the node the room lives on.
+            ClientMessage::ConferenceJoin { conference_id, .. }
+            | ClientMessage::ConferenceInvite { conference_id, .. }
+            | ClientMessage::ConferenceMute { conference_id, .. }
+            | ClientMessage::ConferenceKick { conference_id, .. }
+            | ClientMessage::ConferenceLock { conference_id, .. } => Some(*conference_id),
+        }
+    }
+}
+
+/// Frames pushed to a connected client.
+///
+/// Frames produced by call routing additionally carry a `seq` number on the
+/// wire; the client reports the last one it saw when resuming a session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerMessage {
+    /// First frame on every socket: the token to resume this session with.
+    #[serde(rename = "session", rename_all = "camelCase")]
+    Session { resume_token: Uuid, resumed: bool },
+    #[serde(rename = "call.incoming", rename_all = "camelCase")]
+    Incoming {
+        call_id: Uuid,
+        from: Uuid,
+        /// Set when `from` is being transferred here by this user.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transferred_by: Option<Uuid>,
+    },
+    #[serde(rename = "call.ringing", rename_all = "camelCase")]
+    Ringing { call_id: Uuid },
+    #[serde(rename = "call.connected", rename_all = "camelCase")]
+    Connected { call_id: Uuid },
+    #[serde(rename = "call.ended", rename_all = "camelCase")]
+    Ended { call_id: Uuid, reason: EndReason },
+    #[serde(rename = "call.error", rename_all = "camelCase")]
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        call_id: Option<Uuid>,
+        code: ErrorCode,
+        message: String,
+    },
+    #[serde(rename = "signal", rename_all = "camelCase")]
+    Signal {
+        call_id: Uuid,
+        data: serde_json::Value,
+    },
+    /// The recipient's part in `call_id` carries on as `new_call_id`, now
+    /// with `peer`. A `caller` sends the first offer on the new call; the
+    /// old one ends with reason `transferred` right after.
+    #[serde(rename = "call.transferred", rename_all = "camelCase")]
+    Transferred {
+        call_id: Uuid,
+        new_call_id: Uuid,
+        peer: Uuid,
+        caller: bool,
+    },
+    /// A transfer (or park) of `call_id` could not be carried out; the call
+    /// itself is unaffected.
+    #[serde(rename = "call.transfer.failed", rename_all = "camelCase")]
+    TransferFailed {
+        call_id: Uuid,
+        code: ErrorCode,
+        message: String,
+    },
+    /// `by` put `call_id` on hold. `direction` is the SDP direction the
+    /// recipient's media should now use: `sendonly` for the party holding,
+    /// `recvonly` for the one held, `inactive` when both hold.
+    #[serde(rename = "call.held", rename_all = "camelCase")]
+    Held {
+        call_id: Uuid,
+        by: Uuid,
+        direction: String,
+    },
+    /// The media relay of `callId`: the recipient sends its RTP to
+    /// `host:port` as relay side `side`, after binding its address with
+    /// `HELLO <side> <unix time> <hex HMAC-SHA256(secret, "<sessionId>:<side>:<unix time>")>`
+    /// sent from it. The secret is the recipient's alone. A conference
+    /// participant or supervisor gets one with the answer on its leg
+    /// `callId`, to bind as side `a` of session `callId`.
+    #[serde(rename = "call.relay", rename_all = "camelCase")]
+    Relay {
+        call_id: Uuid,
+        session_id: Uuid,
+        host: String,
+        port: u16,
+        side: String,
+        secret: String,
+    },
+    /// `by` took `call_id` off hold; `direction` as for `call.held`.
+    #[serde(rename = "call.resumed", rename_all = "camelCase")]
+    Resumed {
+        call_id: Uuid,
+        by: Uuid,
+        direction: String,
+    },
+    /// `supervisor` is now on `callId` in `mode`, or left it (`mode` null).
+    /// Sent to the supervisor, and to the agent unless merely monitored.
+    #[serde(rename = "call.supervised", rename_all = "camelCase")]
+    Supervised {
+        call_id: Uuid,
+        supervisor: Uuid,
+        mode: Option<SuperviseMode>,
+    },
+    /// Park slot `slot` of the tenant now holds `parked`, or was vacated
+    /// (`parked` null). Sent to every agent of the tenant.
+    #[serde(rename = "park.slot", rename_all = "camelCase")]
+    ParkSlot {
+        slot: u16,
+        parked: Option<ParkedCall>,
+    },
+    /// Sent to a new participant of `conferenceId` (joined over `callId`):
+    /// the room as it stands, the new participant included.
+    #[serde(rename = "conference.state", rename_all = "camelCase")]
+    ConferenceState {
+        conference_id: Uuid,
+        call_id: Uuid,
+        locked: bool,
+        participants: Vec<ConferenceParticipant>,
+    },
+    /// Someone else joined the conference.
+    #[serde(rename = "conference.joined", rename_all = "camelCase")]
+    ConferenceJoined {
+        conference_id: Uuid,
+        participant: ConferenceParticipant,
+    },
+    /// A participant left, or was removed by a moderator (`kicked`).
+    #[serde(rename = "conference.left", rename_all = "camelCase")]
+    ConferenceLeft {
+        conference_id: Uuid,
+        call_id: Uuid,
+        user_id: Uuid,
+        kicked: bool,
+    },
+    #[serde(rename = "conference.muted", rename_all = "camelCase")]
+    ConferenceMuted {
+        conference_id: Uuid,
+        call_id: Uuid,
+        muted: bool,
+    },
+    #[serde(rename = "conference.locked", rename_all = "camelCase")]
+    ConferenceLocked { conference_id: Uuid, locked: bool },
+    /// `by` asks the recipient into a conference; `conference.join` accepts.
+    #[serde(rename = "conference.invited", rename_all = "camelCase")]
+    ConferenceInvited { conference_id: Uuid, by: Uuid },
+    /// A colleague in the same tenant changed status (including going offline).
+    #[serde(rename = "presence")]
+    Presence(Presence),
+    /// Sent once after connecting: everyone in the tenant currently online.
+    #[serde(rename = "presence.snapshot", rename_all = "camelCase")]
+    PresenceSnapshot { agents: Vec<Presence> },
+    /// The socket's token lapses at `expiresAt` (unix seconds) unless the
+    /// client sends `auth.refresh` first.
+    #[serde(rename = "auth.expiring", rename_all = "camelCase")]
+    TokenExpiring { expires_at: u64 },
+    /// A refreshed token was accepted; the session now lasts until `expiresAt`.
+    #[serde(rename = "auth.refreshed", rename_all = "camelCase")]
+    TokenRefreshed { expires_at: u64 },
+}
+
+/// Why a call stopped, reported alongside `call.ended`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    /// The other party hung up a connected call.
+    Hangup,
+    /// The caller gave up before the call was answered.
+    Cancelled,
+    /// The callee rejected the call while it was ringing.
+    Declined,
+    /// Nobody answered within the ring timeout.
+    NoAnswer,
+    /// Another device of the same user picked the call up.
+    AnsweredElsewhere,
+    /// The other party's connection dropped.
+    ConnectionLost,
+    /// The call was handed over to another one (see `call.transferred`).
+    Transferred,
+    /// A conference moderator removed the participant.
+    Kicked,
+    /// The recipient parked the call; it waits in a park slot.
+    Parked,
+    /// The call's media relay was torn down, e.g. after carrying no audio
+    /// for too long.
+    MediaLost,
+}
+
+/// How a supervisor takes part in an agent's call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuperviseMode {
+    /// Listen to both parties, unheard.
+    Monitor,
+    /// Also talk to the agent; the customer does not hear it.
+    Whisper,
+    /// Talk to both parties.
+    Barge,
+}
+
+impl SuperviseMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SuperviseMode::Monitor => "monitor",
+            SuperviseMode::Whisper => "whisper",
+            SuperviseMode::Barge => "barge",
+        }
+    }
+}
+
+/// A call waiting in a park slot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParkedCall {
+    pub call_id: Uuid,
+    /// The party on hold in the slot.
+    pub party: Uuid,
+    pub parked_by: Uuid,
+}
+
+/// A participant of a conference, identified by the call it joined over.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConferenceParticipant {
+    pub call_id: Uuid,
+    pub user_id: Uuid,
+    pub moderator: bool,
+    pub muted: bool,
+}
+
+/// Machine-readable cause attached to `call.error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidMessage,
+    InvalidDestination,
+    DuplicateCall,
+    UnknownCall,
+    /// The frame is not legal in the call's current state.
+    InvalidState,
+    /// Nobody could be reached: the callee has no live connection.
+    Unavailable,
+    DoNotDisturb,
+    NotAllowed,
+    /// An `auth.refresh` token failed verification or names someone else.
+    InvalidToken,
+    /// The tenant is sending more frames than the node accepts; retry later.
+    RateLimited,
+}
+
+impl ServerMessage {
+    pub fn error(call_id: Option<Uuid>, code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            call_id,
+            code,
+            message: message.into(),
+        }
+    }
+}
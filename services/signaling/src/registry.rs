@@ -0,0 +1,134 @@
+//! Registry of live websocket connections, keyed by tenant and user.
+//!
+//! An agent is frequently signed in from several devices at once (desktop app,
+//! browser tab, mobile). Every socket registers its outbound queue here under
+//! a fresh connection id so events can be fanned out to all of a user's
+//! devices, or addressed to the single device that answered a call.
+
+use crate::protocol::ServerMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::{mpsc, RwLock};
+use uuid::Uuid;
+
+/// Client platform reported through the `device` query parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    Desktop,
+    #[default]
+    Web,
+    Mobile,
+    /// A SIP phone attached through the SIP adapter.
+    Sip,
+}
+
+struct Connection {
+    device: DeviceKind,
+    tx: mpsc::Sender<ServerMessage>,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    users: RwLock<HashMap<(Uuid, Uuid), HashMap<Uuid, Connection>>>,
+}
+
+impl Registry {
+    /// Add a connection for `(tenant_id, user_id)` and return its id.
+    pub async fn register(
+        &self,
+        tenant_id: Uuid,
+        user_id: Uuid,
+        device: DeviceKind,
+        tx: mpsc::Sender<ServerMessage>,
+    ) -> Uuid {
+        let id = Uuid::new_v4();
+        self.users
+            .write()
+            .await
+            .entry((tenant_id, user_id))
+            .or_default()
+            .insert(id, Connection { device, tx });
+        tracing::debug!(%tenant_id, %user_id, connection_id = %id, ?device, "connection registered");
+        id
+    }
+
+    /// Drop a connection, returning `true` when it was the user's last one.
+    pub async fn unregister(&self, tenant_id: Uuid, user_id: Uuid, connection_id: Uuid) -> bool {
+        let mut users = self.users.write().await;
+        let Some(connections) = users.get_mut(&(tenant_id, user_id)) else {
+            return true;
+        };
+        connections.remove(&connection_id);
+        if connections.is_empty() {
+            users.remove(&(tenant_id, user_id));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Fan a frame out to every device of the user except `except`, returning
+    /// how many queues accepted it.
+    pub async fn send_to_user_except(
+        &self,
+        tenant_id: Uuid,
+        user_id: Uuid,
+        except: Option<Uuid>,
+        msg: &ServerMessage,
+    ) -> usize {
+        let users = self.users.read().await;
+        let Some(connections) = users.get(&(tenant_id, user_id)) else {
+            return 0;
+        };
+        connections
+            .iter()
+            .filter(|(id, _)| Some(**id) != except)
+            .filter(|(id, conn)| {
+                // A full buffer means the client stopped reading; dropping is
+                // preferable to stalling the sender's socket task.
+                let ok = conn.tx.try_send(msg.clone()).is_ok();
+                if !ok {
+                    tracing::warn!(connection_id = %id, device = ?conn.device, "outbound queue full");
+                }
+                ok
+            })
+            .count()
+    }
+
+    /// Fan a frame out to every device in the tenant on this node.
+    pub async fn send_to_tenant(&self, tenant_id: Uuid, msg: &ServerMessage) -> usize {
+        let users = self.users.read().await;
+        users
+            .iter()
+            .filter(|((tenant, _), _)| *tenant == tenant_id)
+            .flat_map(|(_, connections)| connections.values())
+            .filter(|conn| conn.tx.try_send(msg.clone()).is_ok())
+            .count()
+    }
+
+    /// Number of live connections the user has on this node.
+    pub async fn connection_count(&self, tenant_id: Uuid, user_id: Uuid) -> usize {
+        self.users
+            .read()
+            .await
+            .get(&(tenant_id, user_id))
+            .map_or(0, HashMap::len)
+    }
+
+    /// Deliver a frame to one specific device.
+    pub async fn send_to_connection(
+        &self,
+        tenant_id: Uuid,
+        user_id: Uuid,
+        connection_id: Uuid,
+        msg: ServerMessage,
+    ) -> bool {
+        self.users
+            .read()
+            .await
+            .get(&(tenant_id, user_id))
+            .and_then(|connections| connections.get(&connection_id))
+            .is_some_and(|conn| conn.tx.try_send(msg).is_ok())
+    }
+}
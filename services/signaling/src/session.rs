@@ -0,0 +1,478 @@
+//! Websocket sessions that outlive a single socket.
+//!
+//! Softphones lose their socket routinely (Wi-Fi handoff, laptop lid, mobile
+//! network switch) and usually reconnect within seconds. Tearing everything
+//! down on every drop would end ringing calls and flash the agent offline to
+//! the whole tenant, so a dropped socket only *detaches* its session: the
+//! connection stays registered, frames addressed to it keep being numbered
+//! into the replay buffer, and the presence record is kept alive. A client
+//! reconnecting with the session's resume token inside the grace window picks
+//! up where it left off; frames it may have missed are replayed by sequence
+//! number. If some of them already fell out of the replay buffer the session
+//! cannot be resumed faithfully and is ended instead. Only when the window
+//! passes is the session ended for real.
+//!
+//! A client may also notice a dead socket before the server does, and
+//! reconnect while its old socket still looks attached. The resume token then
+//! takes the session over: the old socket is closed and hands the session to
+//! the new one. A detached session is held by a task standing in for its
+//! socket, so resuming it is a takeover too.
+//!
+//! Sessions are kept in the memory of the replica that opened them, and resume
+//! tokens are only honoured there. Reconnects must be routed back to the same
+//! replica (sticky sessions at the load balancer); one landing elsewhere opens
+//! a fresh session, and the old one ends once its grace window passes.
+
+use crate::calls::{self, Endpoint};
+use crate::presence;
+use crate::protocol::ServerMessage;
+use crate::registry::DeviceKind;
+use crate::AppState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::AbortHandle;
+use uuid::Uuid;
+
+/// Frames queued for a single socket before we start dropping them.
+const OUTBOUND_BUFFER: usize = 64;
+
+/// Recently sent frames kept for replay after a reconnect.
+const REPLAY_BUFFER: usize = 64;
+
+/// Keep-alive cadence for the presence record and directory entry.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a socket resuming an attached session waits for the old socket
+/// to let go of it.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asks the socket holding a session to close and pass the session on.
+pub type Handover = oneshot::Sender<Session>;
+
+/// The last frames written to a session's socket, numbered in send order.
+#[derive(Default)]
+pub struct ReplayBuffer {
+    next_seq: u64,
+    frames: VecDeque<(u64, ServerMessage)>,
+}
+
+impl ReplayBuffer {
+    /// Record a frame about to be sent and return its sequence number.
+    pub fn push(&mut self, msg: ServerMessage) -> u64 {
+        self.next_seq += 1;
+        if self.frames.len() == REPLAY_BUFFER {
+            self.frames.pop_front();
+        }
+        self.frames.push_back((self.next_seq, msg));
+        self.next_seq
+    }
+
+    /// Frames sent after `last_seq`, oldest first, or `None` when some of
+    /// them were already dropped from the buffer.
+    pub fn since(&self, last_seq: u64) -> Option<impl Iterator<Item = &(u64, ServerMessage)>> {
+        let oldest = self
+            .frames
+            .front()
+            .map_or(self.next_seq + 1, |(seq, _)| *seq);
+        if last_seq.saturating_add(1) < oldest {
+            return None;
+        }
+        Some(self.frames.iter().filter(move |(seq, _)| *seq > last_seq))
+    }
+}
+
+/// Everything a socket needs to carry on after a reconnect.
+pub struct Session {
+    pub endpoint: Endpoint,
+    /// Frames addressed to this session.
+    pub rx: mpsc::Receiver<ServerMessage>,
+    pub replay: ReplayBuffer,
+    /// Calls this session is party to, so they can be cleaned up when it ends.
+    pub active_calls: HashSet<Uuid>,
+    refresh: AbortHandle,
+}
+
+impl Session {
+    /// Register a brand new connection and bring the agent online.
+    pub async fn open(
+        state: &AppState,
+        tenant_id: Uuid,
+        user_id: Uuid,
+        device: DeviceKind,
+    ) -> Session {
+        let (tx, rx) = mpsc::channel(OUTBOUND_BUFFER);
+        let (connection_id, first_connection) =
+            state.cluster.register(tenant_id, user_id, device, tx).await;
+        let current = state.presence.mark_online(tenant_id, user_id).await;
+
+        let refresh_presence = state.presence.clone();
+        let refresh_cluster = state.cluster.clone();
+        // Keep the presence record and directory entry alive while the session
+        // lasts, including any grace window after its socket dropped.
+        let refresh = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+            loop {
+                interval.tick().await;
+                refresh_presence.refresh(tenant_id, user_id).await;
+                refresh_cluster.refresh(tenant_id, user_id).await;
+            }
+        })
+        .abort_handle();
+
+        // Notify other listeners (on every node) that the agent is now available
+        // for routing. Additional devices of an already-online agent stay quiet.
+        if first_connection {
+            presence::announce(state, tenant_id, current).await;
+        }
+
+        Session {
+            endpoint: Endpoint {
+                tenant_id,
+                user_id,
+                connection_id,
+                node_id: state.cluster.node_id,
+            },
+            rx,
+            replay: ReplayBuffer::default(),
+            active_calls: HashSet::new(),
+            refresh,
+        }
+    }
+
+    /// Note a frame about to go out to the client and return its sequence
+    /// number.
+    pub fn record(&mut self, msg: &ServerMessage) -> u64 {
+        calls::track_outbound(&mut self.active_calls, msg);
+        self.replay.push(msg.clone())
+    }
+
+    /// Tear the session down: end its calls, drop the connection and, if it
+    /// was the user's last one, take the agent offline.
+    pub async fn end(self, state: &AppState) {
+        let Endpoint {
+            tenant_id,
+            user_id,
+            connection_id,
+            ..
+        } = self.endpoint;
+        if !self.active_calls.is_empty() {
+            calls::handle_disconnect(state, self.endpoint, self.active_calls).await;
+        }
+        let last_connection = state
+            .cluster
+            .unregister(tenant_id, user_id, connection_id)
+            .await;
+        self.refresh.abort();
+        if state.registry.connection_count(tenant_id, user_id).await == 0 {
+            state.presence.forget(tenant_id, user_id).await;
+        }
+        // Other devices keep the agent online; only the last one to leave clears presence.
+        if last_connection {
+            state.presence.clear(tenant_id, user_id).await;
+            // Broadcast the offline signal so supervisor dashboards can update instantly.
+            state
+                .cluster
+                .publish_presence(presence::PresenceEvent::offline(tenant_id, user_id))
+                .await;
+        }
+    }
+}
+
+/// Sessions of this replica, keyed by their current resume token.
+#[derive(Default)]
+pub struct Sessions {
+    /// Sessions held by a socket, or by the task standing in for it while
+    /// detached, by who they belong to, with the channel the holder listens
+    /// on for a takeover.
+    attached: Mutex<HashMap<Uuid, (Endpoint, mpsc::Sender<Handover>)>>,
+}
+
+impl Sessions {
+    /// Record that a socket now carries the session of `endpoint` under
+    /// `resume_token`. A takeover arrives on the returned channel; the socket
+    /// must then close and send the session through the handover.
+    pub async fn attach(&self, resume_token: Uuid, endpoint: Endpoint) -> mpsc::Receiver<Handover> {
+        let (tx, rx) = mpsc::channel(1);
+        self.attached
+            .lock()
+            .await
+            .insert(resume_token, (endpoint, tx));
+        rx
+    }
+
+    /// The socket carrying `resume_token` is done with it. Returns the
+    /// handover of a takeover that came in meanwhile, if any.
+    pub async fn unattach(
+        &self,
+        resume_token: Uuid,
+        takeover: &mut mpsc::Receiver<Handover>,
+    ) -> Option<Handover> {
+        let mut attached = self.attached.lock().await;
+        if attached.remove(&resume_token).is_some() {
+            return None;
+        }
+        // Taken over: the request was queued when the entry was removed.
+        takeover.try_recv().ok()
+    }
+
+    /// Hold on to a session whose socket dropped, ending it if nobody resumes
+    /// it within `grace`. Frames addressed to it meanwhile go straight into
+    /// its replay buffer rather than filling up its outbound queue.
+    pub async fn detach(
+        &self,
+        state: &AppState,
+        resume_token: Uuid,
+        mut session: Session,
+        grace: Duration,
+    ) {
+        tracing::debug!(connection_id = %session.endpoint.connection_id, "session detached");
+        let mut takeover = self.attach(resume_token, session.endpoint).await;
+        let state = state.clone();
+        tokio::spawn(async move {
+            let expiry = tokio::time::sleep(grace);
+            tokio::pin!(expiry);
+            let mut handover = None;
+            loop {
+                tokio::select! {
+                    Some(outbound) = session.rx.recv() => {
+                        session.record(&outbound);
+                    }
+                    Some(to) = takeover.recv() => {
+                        handover = Some(to);
+                        break;
+                    }
+                    () = &mut expiry => break,
+                }
+            }
+            let handover = match handover {
+                Some(handover) => Some(handover),
+                None => state.sessions.unattach(resume_token, &mut takeover).await,
+            };
+            match handover {
+                Some(handover) => {
+                    if let Err(session) = handover.send(session) {
+                        session.end(&state).await;
+                    }
+                }
+                None => {
+                    tracing::debug!(connection_id = %session.endpoint.connection_id, "session expired");
+                    session.end(&state).await;
+                }
+            }
+        });
+    }
+
+    /// Reattach the session of `resume_token` for the same user: a detached
+    /// one if still within grace, or one still attached to another socket,
+    /// which is closed.
+    pub async fn resume(
+        &self,
+        resume_token: Uuid,
+        tenant_id: Uuid,
+        user_id: Uuid,
+    ) -> Option<Session> {
+        let (handover, session) = oneshot::channel();
+        {
+            let mut attached = self.attached.lock().await;
+            let (endpoint, _) = attached.get(&resume_token)?;
+            // The token alone is not enough; it must be presented by its owner.
+            if endpoint.tenant_id != tenant_id || endpoint.user_id != user_id {
+                return None;
+            }
+            let (_, takeover) = attached.remove(&resume_token)?;
+            // Queued under the lock, so the socket finds it even if it is
+            // just letting go of the session.
+            takeover.try_send(handover).ok()?;
+        }
+        let session = tokio::time::timeout(HANDOVER_TIMEOUT, session).await;
+        let session = session.ok()?.ok()?;
+        tracing::debug!(connection_id = %session.endpoint.connection_id, "session taken over");
+        Some(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> ServerMessage {
+        ServerMessage::Ringing {
+            call_id: Uuid::new_v4(),
+        }
+    }
+
+    fn seqs<'a>(frames: impl Iterator<Item = &'a (u64, ServerMessage)>) -> Vec<u64> {
+        frames.map(|(seq, _)| *seq).collect()
+    }
+
+    async fn open(state: &AppState) -> Session {
+        Session::open(state, Uuid::new_v4(), Uuid::new_v4(), DeviceKind::default()).await
+    }
+
+    /// Queue `count` frames for `endpoint`, letting a detached session's task
+    /// take each one in turn.
+    async fn deliver(state: &AppState, endpoint: Endpoint, count: usize) {
+        for _ in 0..count {
+            assert!(state.cluster.send_to(endpoint, frame()).await);
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn replays_only_what_the_client_missed() {
+        let mut replay = ReplayBuffer::default();
+        assert_eq!(seqs(replay.since(0).unwrap()), Vec::<u64>::new());
+        for _ in 0..3 {
+            replay.push(frame());
+        }
+        assert_eq!(seqs(replay.since(0).unwrap()), [1, 2, 3]);
+        assert_eq!(seqs(replay.since(2).unwrap()), [3]);
+        assert_eq!(seqs(replay.since(3).unwrap()), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn a_gap_past_the_buffer_cannot_be_replayed() {
+        let mut replay = ReplayBuffer::default();
+        for _ in 0..REPLAY_BUFFER + 10 {
+            replay.push(frame());
+        }
+        // Frames 1..=10 are gone.
+        assert!(replay.since(0).is_none());
+        assert!(replay.since(9).is_none());
+        assert_eq!(replay.since(10).unwrap().count(), REPLAY_BUFFER);
+        assert_eq!(seqs(replay.since(73).unwrap()), [74]);
+    }
+
+    #[tokio::test]
+    async fn frames_sent_while_detached_are_kept_for_replay() {
+        let state = AppState::for_tests();
+        let session = open(&state).await;
+        let endpoint = session.endpoint;
+        let token = Uuid::new_v4();
+        let grace = Duration::from_secs(60);
+        state.sessions.detach(&state, token, session, grace).await;
+
+        // More than the outbound queue holds.
+        deliver(&state, endpoint, OUTBOUND_BUFFER + 6).await;
+
+        let session = state
+            .sessions
+            .resume(token, endpoint.tenant_id, endpoint.user_id)
+            .await
+            .expect("resumable within grace");
+        assert_eq!(session.endpoint, endpoint);
+        let replayed = session
+            .replay
+            .since(6)
+            .expect("nothing after 6 was dropped");
+        assert_eq!(replayed.count(), OUTBOUND_BUFFER);
+        // More frames went out than the replay buffer holds.
+        assert!(session.replay.since(0).is_none());
+        session.end(&state).await;
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_resumes_a_session() {
+        let state = AppState::for_tests();
+        let session = open(&state).await;
+        let endpoint = session.endpoint;
+        let token = Uuid::new_v4();
+        let grace = Duration::from_secs(60);
+        state.sessions.detach(&state, token, session, grace).await;
+
+        let sessions = &state.sessions;
+        assert!(sessions
+            .resume(token, endpoint.tenant_id, Uuid::new_v4())
+            .await
+            .is_none());
+        assert!(sessions
+            .resume(token, Uuid::new_v4(), endpoint.user_id)
+            .await
+            .is_none());
+        assert!(sessions
+            .resume(Uuid::new_v4(), endpoint.tenant_id, endpoint.user_id)
+            .await
+            .is_none());
+        let session = sessions
+            .resume(token, endpoint.tenant_id, endpoint.user_id)
+            .await
+            .expect("the owner resumes");
+        // A token is good for one resume.
+        assert!(sessions
+            .resume(token, endpoint.tenant_id, endpoint.user_id)
+            .await
+            .is_none());
+        session.end(&state).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_session_nobody_resumes_ends_after_the_grace_window() {
+        let state = AppState::for_tests();
+        let session = open(&state).await;
+        let endpoint = session.endpoint;
+        let token = Uuid::new_v4();
+        let grace = Duration::from_secs(20);
+        state.sessions.detach(&state, token, session, grace).await;
+        let connections = || {
+            state
+                .registry
+                .connection_count(endpoint.tenant_id, endpoint.user_id)
+        };
+
+        tokio::time::sleep(grace - Duration::from_secs(1)).await;
+        assert_eq!(connections().await, 1);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(connections().await, 0);
+        assert!(state
+            .sessions
+            .resume(token, endpoint.tenant_id, endpoint.user_id)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn resuming_an_attached_session_takes_it_over() {
+        let state = AppState::for_tests();
+        let mut session = open(&state).await;
+        let endpoint = session.endpoint;
+        let token = Uuid::new_v4();
+        let mut takeover = state.sessions.attach(token, endpoint).await;
+        session.record(&frame());
+
+        // The socket holding the session, until told to hand it over.
+        let socket = tokio::spawn(async move {
+            let handover = takeover.recv().await.expect("takeover requested");
+            assert!(handover.send(session).is_ok());
+        });
+        let session = state
+            .sessions
+            .resume(token, endpoint.tenant_id, endpoint.user_id)
+            .await
+            .expect("taken over");
+        socket.await.unwrap();
+        assert_eq!(session.endpoint, endpoint);
+        assert_eq!(seqs(session.replay.since(0).unwrap()), [1]);
+        session.end(&state).await;
+    }
+
+    #[tokio::test]
+    async fn a_socket_letting_go_normally_is_not_taken_over() {
+        let state = AppState::for_tests();
+        let session = open(&state).await;
+        let token = Uuid::new_v4();
+        let mut takeover = state.sessions.attach(token, session.endpoint).await;
+        assert!(state
+            .sessions
+            .unattach(token, &mut takeover)
+            .await
+            .is_none());
+        let endpoint = session.endpoint;
+        assert!(state
+            .sessions
+            .resume(token, endpoint.tenant_id, endpoint.user_id)
+            .await
+            .is_none());
+        session.end(&state).await;
+    }
+}
@@ -0,0 +1,1763 @@
+//! Translation between a SIP user agent and the JSON call routing.
+//!
+//! A SIP client is plugged into [`crate::calls`] exactly like a softphone
+//! socket: once authenticated it opens a [`Session`], which registers a
+//! connection in the cluster and gives it an outbound queue of
+//! [`ServerMessage`]s. Requests from the phone become [`ClientMessage`]s and
+//! routing events become SIP requests and responses, so neither side can tell
+//! what the other one speaks.
+//!
+//! SDP travels inside `signal` frames in the shape the web softphone uses
+//! (`{"type": "offer" | "answer", "sdp": ...}`). Browsers trickle their ICE
+//! candidates separately, which SIP cannot carry, so SDP headed for the phone
+//! is held for a short window and the candidates that arrive meanwhile are
+//! folded into it before it is sent.
+//!
+//! Transfers map onto REFER (RFC 3515): a phone referring the other party
+//! elsewhere starts a blind transfer, or completes an attended one when the
+//! Refer-To carries a `Replaces` for its consult dialog (RFC 3891), and hears
+//! the outcome through NOTIFY. When the phone itself is the party being
+//! moved its dialog stays up, gets the new call id and is re-INVITEd with the
+//! new peer's SDP.
+//!
+//! Hold follows RFC 6337: a phone holds with a `sendonly` or `inactive`
+//! re-INVITE, answered from our last SDP so the peer never renegotiates, and
+//! is re-INVITEd with `sendonly` itself when the peer holds.
+//!
+//! Routing errors only fail calls the phone has not answered yet. On an
+//! established dialog they answer the re-INVITE or REFER they concern and
+//! leave the call up.
+
+use super::digest::DigestError;
+use crate::auth::{self, AuthError};
+use crate::calls;
+use crate::protocol::{ClientMessage, EndReason, ErrorCode, ServerMessage};
+use crate::session::Session;
+use crate::AppState;
+use ::sip::{Message, Method, NameAddr, ParseMode, Request, Response};
+use dto::AuthClaims;
+use sdp::{Attribute, Candidate, SessionDescription};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// How long SDP from a trickling browser is held to collect its candidates.
+const CANDIDATE_WINDOW: Duration = Duration::from_millis(500);
+
+/// Registration lifetime granted when the phone does not ask for one.
+const DEFAULT_EXPIRES: u32 = 600;
+
+const ALLOW: &str = "INVITE, ACK, BYE, CANCEL, OPTIONS, REGISTER, REFER";
+
+/// Settings shared by every SIP transport.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Domain of the address-of-record, `sip:<user id>@<domain>`.
+    pub domain: String,
+    /// Where the UDP and TCP listeners bind.
+    pub listen_addr: SocketAddr,
+    /// Address phones reach us at, used in Via and Contact on UDP and TCP.
+    pub public_host: String,
+    /// UDP flows from addresses that have not registered, served at a time.
+    pub udp_max_flows: usize,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let listen_addr = std::env::var("SIP_LISTEN_ADDR")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 5060)));
+        let public_host = std::env::var("SIP_PUBLIC_HOST")
+            .unwrap_or_else(|_| format!("localhost:{}", listen_addr.port()));
+        Config {
+            domain: std::env::var("SIP_DOMAIN").unwrap_or_else(|_| "voip.local".to_string()),
+            listen_addr,
+            public_host,
+            udp_max_flows: std::env::var("SIP_UDP_MAX_FLOWS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(1024),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    /// The phone placed the call.
+    Inbound,
+    /// The phone is being rung.
+    Outbound,
+}
+
+/// SDP waiting to be sent to the phone.
+struct PendingSdp {
+    sdp: String,
+    ready_at: Instant,
+    /// An offer from the peer, whose answer has to be passed back.
+    offer: bool,
+}
+
+/// One SIP dialog, mapped onto a routed call.
+struct Leg {
+    call_id: Uuid,
+    direction: Direction,
+    sip_call_id: String,
+    local_tag: String,
+    /// Our side of the dialog as a name-addr (without tag).
+    local_uri: String,
+    /// The phone's side of the dialog, including its tag once known.
+    remote: String,
+    /// Where in-dialog requests to the phone are sent.
+    remote_target: String,
+    local_cseq: u32,
+    /// The phone's INVITE (inbound legs), kept so it can be answered.
+    invite: Option<Request>,
+    /// Our INVITE (outbound legs), kept for CANCEL and ACK.
+    our_invite: Option<Request>,
+    pending: Option<PendingSdp>,
+    connected: bool,
+    established: bool,
+    /// We cancelled our INVITE and only wait for its final response.
+    cancelling: bool,
+    /// The phone's latest SDP, offered to whoever the call is transferred to.
+    remote_sdp: Option<String>,
+    /// Our re-INVITE awaits its final response; `true` when it carried the
+    /// peer's offer.
+    reinvite: Option<bool>,
+    /// The phone asked to transfer this call and waits for a NOTIFY.
+    refer_pending: bool,
+    /// The SDP we last gave the phone, the basis of hold re-INVITEs.
+    local_sdp: Option<String>,
+    /// The phone has put the call on hold.
+    holding: bool,
+    /// The peer has put the call on hold.
+    held: bool,
+    /// The phone's re-INVITE changing hold, answered once routing took it.
+    hold_change: Option<HoldChange>,
+}
+
+/// A hold or resume by the phone that routing has yet to confirm.
+struct HoldChange {
+    req: Request,
+    /// Our 200 OK, sent on confirmation.
+    res: Response,
+    holding: bool,
+}
+
+impl Leg {
+    /// Our media direction towards the phone given who holds the call.
+    fn media_direction(&self) -> sdp::Direction {
+        sdp::Direction::from_flags(!self.holding, !self.held)
+    }
+}
+
+pub struct Adapter {
+    state: AppState,
+    config: std::sync::Arc<Config>,
+    /// Transport token used in Via and Contact (`WSS`, `UDP`, `TCP`).
+    transport: &'static str,
+    /// Host we put in Via and Contact headers.
+    host: String,
+    claims: Option<AuthClaims>,
+    session: Option<Session>,
+    contact: Option<String>,
+    registered_until: Option<Instant>,
+    legs: HashMap<Uuid, Leg>,
+    by_sip_call_id: HashMap<String, Uuid>,
+    outbox: Vec<Message>,
+}
+
+impl Adapter {
+    pub fn new(
+        state: AppState,
+        transport: &'static str,
+        host: String,
+        claims: Option<AuthClaims>,
+    ) -> Self {
+        Adapter {
+            config: state.sip.clone(),
+            state,
+            transport,
+            host,
+            claims,
+            session: None,
+            contact: None,
+            registered_until: None,
+            legs: HashMap::new(),
+            by_sip_call_id: HashMap::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    /// Messages to write to the phone, in order.
+    pub fn drain(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    pub fn tenant_id(&self) -> Option<Uuid> {
+        self.claims.as_ref().map(|claims| claims.tenant_id)
+    }
+
+    /// Next routing event for this phone; pending forever until registered.
+    pub async fn next_event(&mut self) -> Option<ServerMessage> {
+        match &mut self.session {
+            Some(session) => session.rx.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Earliest time held SDP becomes due.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        // SDP queued behind our re-INVITE goes out with its final response.
+        self.legs
+            .values()
+            .filter(|leg| leg.reinvite.is_none())
+            .filter_map(|leg| leg.pending.as_ref().map(|p| p.ready_at))
+            .min()
+    }
+
+    /// Tear down: hang up every call and drop the registration.
+    pub async fn close(mut self) {
+        self.unregister().await;
+    }
+
+    /// When the phone's registration lapses unless refreshed.
+    pub fn registered_until(&self) -> Option<Instant> {
+        self.registered_until
+    }
+
+    /// Whether any call is still in progress on this phone.
+    pub fn has_calls(&self) -> bool {
+        !self.legs.is_empty()
+    }
+
+    pub async fn on_message(&mut self, msg: Message) {
+        match msg {
+            Message::Request(req) => self.on_request(req).await,
+            Message::Response(res) => self.on_response(res).await,
+        }
+    }
+
+    async fn on_request(&mut self, req: Request) {
+        match req.method {
+            Method::Register => self.register(req).await,
+            Method::Options => {
+                let mut res = Response::to(&req, 200, "OK");
+                res.headers.push("Allow", ALLOW);
+                self.outbox.push(Message::Response(res));
+            }
+            Method::Invite => self.invite(req).await,
+            // Nothing to do: 2xx ACKs end our INVITE transaction, and the
+            // ACK for an error response needs no answer.
+            Method::Ack => {}
+            Method::Bye => self.bye(req).await,
+            Method::Cancel => self.cancel(req).await,
+            Method::Refer => self.refer(req).await,
+            Method::Notify | Method::Other(_) => {
+                let mut res = Response::to(&req, 405, "Method Not Allowed");
+                res.headers.push("Allow", ALLOW);
+                self.outbox.push(Message::Response(res));
+            }
+        }
+    }
+
+    /// Check credentials, taking a fresh bearer token (RFC 8898) or digest
+    /// response from `Authorization` when present. Replies with an error and
+    /// returns `false` when the request may not proceed.
+    async fn authenticate(&mut self, req: &Request) -> bool {
+        let authorization = req.headers.get("Authorization").map(str::trim);
+        let bearer = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+        let digest = authorization.filter(|value| {
+            value
+                .get(..7)
+                .is_some_and(|scheme| scheme.eq_ignore_ascii_case("Digest "))
+        });
+        if let Some(token) = bearer {
+            let verified = match &self.claims {
+                Some(current) => self.state.auth.verify_refresh(token, current).await,
+                None => self.state.auth.verify(token).await,
+            };
+            match verified {
+                Ok(claims) => self.claims = Some(claims),
+                Err(AuthError::PrincipalMismatch) => {
+                    self.respond(req, 403, "Forbidden");
+                    return false;
+                }
+                Err(err) => {
+                    tracing::warn!(error = %err, "sip bearer token rejected");
+                    self.challenge(req, false);
+                    return false;
+                }
+            }
+        } else if let Some(digest) = digest {
+            let verified = self
+                .state
+                .digest
+                .verify(&self.state.pbx, req.method.as_str(), &req.uri, digest)
+                .await;
+            match verified {
+                Ok((tenant_id, user_id)) => {
+                    let switched = self
+                        .claims
+                        .as_ref()
+                        .is_some_and(|c| c.tenant_id != tenant_id || c.sub != user_id);
+                    if switched {
+                        self.respond(req, 403, "Forbidden");
+                        return false;
+                    }
+                    self.claims = Some(digest_claims(tenant_id, user_id));
+                }
+                Err(DigestError::Stale) => {
+                    self.challenge(req, true);
+                    return false;
+                }
+                Err(DigestError::LockedOut) => {
+                    self.respond(req, 403, "Forbidden");
+                    return false;
+                }
+                Err(DigestError::Lookup(err)) => {
+                    tracing::warn!(error = %err, "sip credential lookup failed");
+                    self.respond(req, 503, "Service Unavailable");
+                    return false;
+                }
+                Err(err) => {
+                    tracing::warn!(error = %err, "sip digest rejected");
+                    self.challenge(req, false);
+                    return false;
+                }
+            }
+        }
+        let Some(claims) = &self.claims else {
+            self.challenge(req, false);
+            return false;
+        };
+        if auth::deadline(claims.exp) <= Instant::now() {
+            self.challenge(req, false);
+            return false;
+        }
+        // The phone may only act as the user its token names.
+        let from_user = req
+            .headers
+            .get("From")
+            .map(::sip::uri_of)
+            .and_then(::sip::user_of);
+        if from_user != Some(claims.sub.to_string().as_str()) {
+            self.respond(req, 403, "Forbidden");
+            return false;
+        }
+        true
+    }
+
+    /// Ask for credentials with every scheme we accept: digest for phones
+    /// provisioned with a password, bearer for those holding a token.
+    fn challenge(&mut self, req: &Request, stale: bool) {
+        let mut res = Response::to(req, 401, "Unauthorized");
+        for challenge in self.state.digest.challenges(stale) {
+            res.headers.push("WWW-Authenticate", challenge);
+        }
+        res.headers.push(
+            "WWW-Authenticate",
+            format!("Bearer realm=\"{}\"", self.config.domain),
+        );
+        self.outbox.push(Message::Response(res));
+    }
+
+    /// Open the routing session on first use.
+    async fn ensure_session(&mut self) -> Option<calls::Endpoint> {
+        if self.session.is_none() {
+            let claims = self.claims.as_ref()?;
+            let session = Session::open(
+                &self.state,
+                claims.tenant_id,
+                claims.sub,
+                crate::registry::DeviceKind::Sip,
+            )
+            .await;
+            self.session = Some(session);
+        }
+        self.session.as_ref().map(|session| session.endpoint)
+    }
+
+    async fn register(&mut self, req: Request) {
+        if !self.authenticate(&req).await {
+            return;
+        }
+        let Some(claims) = self.claims.clone() else {
+            return;
+        };
+        let contact = req.headers.get("Contact");
+        let expires = contact
+            .and_then(|c| ::sip::param(c, "expires"))
+            .or_else(|| req.headers.get("Expires"))
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_EXPIRES)
+            .min(DEFAULT_EXPIRES);
+
+        if expires == 0 || contact == Some("*") {
+            self.unregister().await;
+        } else if let Some(contact) = contact {
+            let uri = ::sip::uri_of(contact).to_string();
+            if self.contact.as_ref().is_some_and(|old| *old != uri) {
+                self.unregister().await;
+            }
+            self.state
+                .registrar
+                .bind(claims.tenant_id, claims.sub, &uri, expires)
+                .await;
+            self.contact = Some(uri);
+            self.registered_until = Some(Instant::now() + Duration::from_secs(expires.into()));
+            self.ensure_session().await;
+        }
+        // Without a Contact this is a query; either way the response lists
+        // every binding of the address-of-record, on any device.
+        let mut res = Response::to(&req, 200, "OK");
+        for binding in self
+            .state
+            .registrar
+            .bindings(claims.tenant_id, claims.sub)
+            .await
+        {
+            res.headers.push(
+                "Contact",
+                format!("<{}>;expires={}", binding.contact, binding.remaining()),
+            );
+        }
+        tag_to(&mut res, &new_tag());
+        self.outbox.push(Message::Response(res));
+    }
+
+    /// Drop this phone's binding and routing session.
+    async fn unregister(&mut self) {
+        if let (Some(contact), Some(claims)) = (self.contact.take(), &self.claims) {
+            self.state
+                .registrar
+                .unbind(claims.tenant_id, claims.sub, &contact)
+                .await;
+        }
+        self.registered_until = None;
+        if let Some(session) = self.session.take() {
+            session.end(&self.state).await;
+        }
+    }
+
+    async fn invite(&mut self, req: Request) {
+        let sip_call_id = req.call_id().unwrap_or_default().to_string();
+        if self.by_sip_call_id.contains_key(&sip_call_id) {
+            self.reinvite(req).await;
+            return;
+        }
+        if !self.authenticate(&req).await {
+            return;
+        }
+        let Some(from) = self.ensure_session().await else {
+            return;
+        };
+        if self.contact.is_none() {
+            self.contact = req
+                .headers
+                .get("Contact")
+                .map(|c| ::sip::uri_of(c).to_string());
+        }
+        self.respond(&req, 100, "Trying");
+
+        let call_id = Uuid::new_v4();
+        let to = ::sip::user_of(&req.uri).unwrap_or_default().to_string();
+        let leg = Leg {
+            call_id,
+            direction: Direction::Inbound,
+            sip_call_id: sip_call_id.clone(),
+            local_tag: new_tag(),
+            local_uri: req.headers.get("To").map(strip_tag).unwrap_or_default(),
+            remote: req.headers.get("From").unwrap_or_default().to_string(),
+            remote_target: req
+                .headers
+                .get("Contact")
+                .map(|c| ::sip::uri_of(c).to_string())
+                .unwrap_or_default(),
+            local_cseq: 0,
+            invite: Some(req.clone()),
+            our_invite: None,
+            pending: None,
+            connected: false,
+            established: false,
+            cancelling: false,
+            remote_sdp: (!req.body.is_empty()).then(|| req.body.clone()),
+            reinvite: None,
+            refer_pending: false,
+            local_sdp: None,
+            holding: false,
+            held: false,
+            hold_change: None,
+        };
+        self.legs.insert(call_id, leg);
+        self.by_sip_call_id.insert(sip_call_id, call_id);
+        if let Some(session) = &mut self.session {
+            session.active_calls.insert(call_id);
+        }
+
+        calls::handle_client_message(&self.state, from, ClientMessage::Initiate { call_id, to })
+            .await;
+        if !req.body.is_empty() {
+            let signal = ClientMessage::Signal {
+                call_id,
+                data: json!({ "type": "offer", "sdp": req.body }),
+            };
+            calls::handle_client_message(&self.state, from, signal).await;
+        }
+    }
+
+    /// A re-INVITE from the phone. Only a change of hold is acted on; the
+    /// answer is our last SDP in the directions the offer allows.
+    async fn reinvite(&mut self, req: Request) {
+        let contact = self.contact_header();
+        let call_id = self.by_sip_call_id.get(req.call_id().unwrap_or_default());
+        let Some(leg) = call_id.copied().and_then(|id| self.legs.get_mut(&id)) else {
+            return;
+        };
+        if let Some(change) = &leg.hold_change {
+            // A retransmission waits for the answer to the original.
+            if change.req.cseq() != req.cseq() {
+                self.respond(&req, 491, "Request Pending");
+            }
+            return;
+        }
+        if !leg.established || leg.reinvite.is_some() {
+            // Our own INVITE is still outstanding (RFC 3261 section 14.2).
+            self.respond(&req, 491, "Request Pending");
+            return;
+        }
+        let offer = req.body.parse::<SessionDescription>().ok();
+        let local = leg
+            .local_sdp
+            .as_deref()
+            .and_then(|sdp| sdp.parse::<SessionDescription>().ok());
+        let (Some(offer), Some(mut answer)) = (offer, local) else {
+            self.respond(&req, 488, "Not Acceptable Here");
+            return;
+        };
+        if offer.media.len() != answer.media.len() {
+            self.respond(&req, 488, "Not Acceptable Here");
+            return;
+        }
+
+        // RFC 6337 section 5.1: on hold when no stream may be sent to the
+        // phone, or (RFC 2543 style) its connection address is 0.0.0.0.
+        let holding = (0..offer.media.len())
+            .filter(|&index| !offer.media[index].is_rejected())
+            .all(|index| {
+                !offer.direction_of(index).receives()
+                    || offer
+                        .connection_of(index)
+                        .is_some_and(|c| c.address == "0.0.0.0")
+            });
+        let ours = sdp::Direction::from_flags(!holding, !leg.held);
+        answer.origin.session_version += 1;
+        for (index, media) in answer.media.iter_mut().enumerate() {
+            media.set_direction(offer.direction_of(index).answer(ours));
+        }
+        let call_id = leg.call_id;
+        let mut res = Response::to(&req, 200, "OK");
+        res.headers.push("Contact", contact);
+        res.headers.push("Content-Type", "application/sdp");
+        res.body = answer.to_string();
+
+        let from = self.session.as_ref().map(|s| s.endpoint);
+        let (true, Some(from)) = (holding != leg.holding, from) else {
+            leg.local_sdp = Some(res.body.clone());
+            leg.remote_sdp = Some(req.body.clone());
+            self.outbox.push(Message::Response(res));
+            return;
+        };
+        // Answered once routing confirms, so a refused hold leaves the
+        // dialog as it was.
+        leg.hold_change = Some(HoldChange { req, res, holding });
+        let msg = if holding {
+            ClientMessage::Hold { call_id }
+        } else {
+            ClientMessage::Resume { call_id }
+        };
+        calls::handle_client_message(&self.state, from, msg).await;
+    }
+
+    /// Routing took the phone's hold or resume: send the answer held back.
+    fn hold_taken(&mut self, call_id: Uuid) {
+        let Some(leg) = self.legs.get_mut(&call_id) else {
+            return;
+        };
+        let Some(change) = leg.hold_change.take() else {
+            return;
+        };
+        leg.holding = change.holding;
+        leg.local_sdp = Some(change.res.body.clone());
+        leg.remote_sdp = Some(change.req.body.clone());
+        self.outbox.push(Message::Response(change.res));
+    }
+
+    async fn bye(&mut self, req: Request) {
+        let Some(leg) = self.take_leg_for(&req) else {
+            self.respond(&req, 481, "Call/Transaction Does Not Exist");
+            return;
+        };
+        self.respond(&req, 200, "OK");
+        self.hang_up(leg.call_id).await;
+    }
+
+    async fn cancel(&mut self, req: Request) {
+        let pending = self
+            .leg_for(&req)
+            .is_some_and(|leg| leg.direction == Direction::Inbound && !leg.established);
+        if !pending {
+            self.respond(&req, 481, "Call/Transaction Does Not Exist");
+            return;
+        }
+        let leg = self.take_leg_for(&req).expect("leg checked above");
+        self.respond(&req, 200, "OK");
+        if let Some(invite) = &leg.invite {
+            let mut res = Response::to(invite, 487, "Request Terminated");
+            tag_to(&mut res, &leg.local_tag);
+            self.outbox.push(Message::Response(res));
+        }
+        self.hang_up(leg.call_id).await;
+    }
+
+    /// The phone transfers the other party of an established call: to the
+    /// Refer-To target, or onto its consult call when that dialog is named
+    /// in an escaped `Replaces`.
+    async fn refer(&mut self, req: Request) {
+        let Some(call_id) = self
+            .leg_for(&req)
+            .filter(|leg| leg.established)
+            .map(|leg| leg.call_id)
+        else {
+            self.respond(&req, 481, "Call/Transaction Does Not Exist");
+            return;
+        };
+        let Some(from) = self.session.as_ref().map(|s| s.endpoint) else {
+            return;
+        };
+        let target = req
+            .headers
+            .get("Refer-To")
+            .and_then(|value| NameAddr::parse(value, ParseMode::Lenient).ok())
+            .and_then(|addr| addr.uri.as_sip().cloned());
+        let Some(target) = target else {
+            self.respond(&req, 400, "Bad Refer-To");
+            return;
+        };
+        let replaces = target
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Replaces"))
+            .map(|(_, value)| ::sip::unescape(value));
+        let msg = match replaces {
+            Some(replaces) => {
+                // `call-id;to-tag=...;from-tag=...` of the phone's dialog with us.
+                let sip_call_id = replaces.split(';').next().unwrap_or_default().trim();
+                let Some(&consult_call_id) = self.by_sip_call_id.get(sip_call_id) else {
+                    self.respond(&req, 481, "Call/Transaction Does Not Exist");
+                    return;
+                };
+                ClientMessage::CompleteTransfer {
+                    call_id,
+                    consult_call_id: Some(consult_call_id),
+                }
+            }
+            None => ClientMessage::Transfer {
+                call_id,
+                to: target.user.clone().unwrap_or_default(),
+            },
+        };
+        self.respond(&req, 202, "Accepted");
+        if let Some(leg) = self.legs.get_mut(&call_id) {
+            leg.refer_pending = true;
+        }
+        self.notify_refer(call_id, 100, "Trying");
+        calls::handle_client_message(&self.state, from, msg).await;
+    }
+
+    /// Report the progress of a transfer the phone asked for, as a sipfrag
+    /// of the status line; a final status ends the implicit subscription.
+    fn notify_refer(&mut self, call_id: Uuid, status: u16, phrase: &str) {
+        let Some(mut notify) = self.in_dialog_request(call_id, Method::Notify, true) else {
+            return;
+        };
+        let subscription = if status < 200 {
+            "active;expires=60"
+        } else {
+            "terminated;reason=noresource"
+        };
+        notify.headers.push("Event", "refer");
+        notify.headers.push("Subscription-State", subscription);
+        notify
+            .headers
+            .push("Content-Type", "message/sipfrag;version=2.0");
+        notify.body = format!("SIP/2.0 {status} {phrase}\r\n");
+        self.outbox.push(Message::Request(notify));
+    }
+
+    async fn on_response(&mut self, res: Response) {
+        let Some(&call_id) = res
+            .headers
+            .get("Call-ID")
+            .and_then(|id| self.by_sip_call_id.get(id))
+        else {
+            return;
+        };
+        if res.cseq().map(|cseq| cseq.method) != Some(Method::Invite) || res.status < 200 {
+            return;
+        }
+        let Some(leg) = self.legs.get_mut(&call_id) else {
+            return;
+        };
+        let Some(invite) = leg.our_invite.clone() else {
+            return;
+        };
+        if let Some(to) = res.headers.get("To") {
+            leg.remote = to.to_string();
+        }
+        let (cseq, cancelling) = (leg.local_cseq, leg.cancelling);
+        let reinvite = leg.reinvite.take();
+
+        if res.status >= 300 {
+            // Non-2xx ACK belongs to the INVITE transaction: same branch.
+            let mut ack = Request::new(Method::Ack, invite.uri.clone());
+            for name in ["Via", "Max-Forwards", "From", "Call-ID"] {
+                if let Some(value) = invite.headers.get(name) {
+                    ack.headers.push(name, value);
+                }
+            }
+            ack.headers.push("To", leg.remote.clone());
+            ack.headers.push("CSeq", format!("{cseq} ACK"));
+            self.outbox.push(Message::Request(ack));
+            if reinvite.is_some() {
+                // A refused re-INVITE leaves the call as it was.
+                tracing::info!(%call_id, status = res.status, "phone refused re-invite");
+                self.progress(call_id);
+                return;
+            }
+            self.remove_leg(call_id);
+            if !cancelling {
+                // The phone declined (486, 603, ...).
+                self.hang_up(call_id).await;
+            }
+            return;
+        }
+
+        if let Some(contact) = res.headers.get("Contact") {
+            leg.remote_target = ::sip::uri_of(contact).to_string();
+        }
+        let first_answer = !leg.established;
+        leg.established = true;
+        if !res.body.is_empty() {
+            leg.remote_sdp = Some(res.body.clone());
+        }
+        let ack = self.in_dialog_request(call_id, Method::Ack, false);
+        self.outbox.extend(ack.map(Message::Request));
+        if let Some(offer) = reinvite {
+            // The phone's answer to the peer's offer goes back to the peer;
+            // an answer we relayed as an offer needs nothing further.
+            if offer && !res.body.is_empty() {
+                self.send_signal(call_id, "answer", res.body).await;
+            }
+            // SDP that arrived meanwhile waited for this one to finish.
+            self.progress(call_id);
+            return;
+        }
+        if !first_answer {
+            // Retransmitted 2xx: the ACK above is all it needs.
+            return;
+        }
+        if cancelling {
+            // Our CANCEL lost the race with the answer; hang up instead.
+            let bye = self.in_dialog_request(call_id, Method::Bye, true);
+            self.outbox.extend(bye.map(Message::Request));
+            self.remove_leg(call_id);
+            return;
+        }
+        let Some(from) = self.session.as_ref().map(|s| s.endpoint) else {
+            return;
+        };
+        calls::handle_client_message(&self.state, from, ClientMessage::Answer { call_id }).await;
+        if !res.body.is_empty() {
+            self.send_signal(call_id, "answer", res.body).await;
+        }
+    }
+
+    /// Pass SDP from the phone on to routing.
+    async fn send_signal(&mut self, call_id: Uuid, kind: &str, sdp: String) {
+        let Some(from) = self.session.as_ref().map(|s| s.endpoint) else {
+            return;
+        };
+        let signal = ClientMessage::Signal {
+            call_id,
+            data: json!({ "type": kind, "sdp": sdp }),
+        };
+        calls::handle_client_message(&self.state, from, signal).await;
+    }
+
+    /// Apply a routing event addressed to this phone.
+    pub async fn on_event(&mut self, event: ServerMessage) {
+        if let Some(session) = &mut self.session {
+            calls::track_outbound(&mut session.active_calls, &event);
+        }
+        match event {
+            ServerMessage::Incoming { call_id, from, .. } => self.incoming(call_id, from),
+            ServerMessage::Ringing { call_id } => {
+                if let Some((invite, tag)) = self.inbound_invite(call_id) {
+                    let mut res = Response::to(&invite, 180, "Ringing");
+                    tag_to(&mut res, &tag);
+                    self.outbox.push(Message::Response(res));
+                }
+            }
+            ServerMessage::Connected { call_id } => {
+                if let Some(leg) = self.legs.get_mut(&call_id) {
+                    leg.connected = true;
+                }
+                self.progress(call_id);
+            }
+            ServerMessage::Signal { call_id, data } => self.signal(call_id, data),
+            ServerMessage::Transferred {
+                call_id,
+                new_call_id,
+                caller,
+                ..
+            } => self.transferred(call_id, new_call_id, caller).await,
+            ServerMessage::Held { call_id, by, .. } => self.peer_hold(call_id, by, true),
+            ServerMessage::Resumed { call_id, by, .. } => self.peer_hold(call_id, by, false),
+            ServerMessage::TransferFailed { call_id, code, .. } if self.take_refer(call_id) => {
+                let (status, phrase) = error_status(code);
+                self.notify_refer(call_id, status, phrase);
+            }
+            ServerMessage::Ended { call_id, reason } => {
+                if self.take_refer(call_id) {
+                    let (status, phrase) = match reason {
+                        EndReason::Transferred => (200, "OK"),
+                        _ => (487, "Request Terminated"),
+                    };
+                    self.notify_refer(call_id, status, phrase);
+                }
+                let (status, phrase) = match reason {
+                    EndReason::Declined => (603, "Decline"),
+                    EndReason::AnsweredElsewhere => (200, "Call completed elsewhere"),
+                    EndReason::NoAnswer | EndReason::ConnectionLost => {
+                        (480, "Temporarily Unavailable")
+                    }
+                    _ => (487, "Request Terminated"),
+                };
+                self.terminate(call_id, status, phrase);
+            }
+            ServerMessage::Error {
+                call_id: Some(call_id),
+                code,
+                ..
+            } => self.refused(call_id, code),
+            _ => {}
+        }
+    }
+
+    /// Someone is calling this phone; the INVITE goes out once the caller's
+    /// offer arrives.
+    fn incoming(&mut self, call_id: Uuid, from: Uuid) {
+        let (Some(contact), Some(claims)) = (self.contact.clone(), &self.claims) else {
+            return;
+        };
+        let domain = &self.config.domain;
+        let sip_call_id = format!("{call_id}@{}", self.host);
+        let leg = Leg {
+            call_id,
+            direction: Direction::Outbound,
+            sip_call_id: sip_call_id.clone(),
+            local_tag: new_tag(),
+            local_uri: format!("<sip:{from}@{domain}>"),
+            remote: format!("<sip:{}@{domain}>", claims.sub),
+            remote_target: contact,
+            local_cseq: 0,
+            invite: None,
+            our_invite: None,
+            pending: None,
+            connected: false,
+            established: false,
+            cancelling: false,
+            remote_sdp: None,
+            reinvite: None,
+            refer_pending: false,
+            local_sdp: None,
+            holding: false,
+            held: false,
+            hold_change: None,
+        };
+        self.legs.insert(call_id, leg);
+        self.by_sip_call_id.insert(sip_call_id, call_id);
+    }
+
+    /// Routing refused a request about `call_id`. A call not answered yet
+    /// fails with the matching status; an established one stays up, and only
+    /// the request in flight is answered with it.
+    fn refused(&mut self, call_id: Uuid, code: ErrorCode) {
+        let (status, phrase) = error_status(code);
+        let Some(leg) = self.legs.get_mut(&call_id) else {
+            return;
+        };
+        if !leg.established && !leg.connected {
+            self.terminate(call_id, status, phrase);
+        } else if let Some(change) = leg.hold_change.take() {
+            self.respond(&change.req, status, phrase);
+        } else if self.take_refer(call_id) {
+            self.notify_refer(call_id, status, phrase);
+        } else {
+            tracing::debug!(%call_id, ?code, "routing refused a request on an established call");
+        }
+    }
+
+    /// The peer put the call on hold or resumed it: offer the phone our last
+    /// SDP in the new direction. A hold by the phone itself is confirmed by
+    /// answering its re-INVITE.
+    fn peer_hold(&mut self, call_id: Uuid, by: Uuid, held: bool) {
+        if self
+            .session
+            .as_ref()
+            .is_some_and(|s| s.endpoint.user_id == by)
+        {
+            self.hold_taken(call_id);
+            return;
+        }
+        let Some(leg) = self.legs.get_mut(&call_id) else {
+            return;
+        };
+        leg.held = held;
+        let Some(mut desc) = leg
+            .local_sdp
+            .as_deref()
+            .and_then(|sdp| sdp.parse::<SessionDescription>().ok())
+        else {
+            return;
+        };
+        let direction = leg.media_direction();
+        desc.origin.session_version += 1;
+        for media in &mut desc.media {
+            media.set_direction(direction);
+        }
+        leg.pending = Some(PendingSdp {
+            sdp: desc.to_string(),
+            ready_at: Instant::now(),
+            offer: false,
+        });
+        self.progress(call_id);
+    }
+
+    /// Whether the phone waits to hear how its transfer of `call_id` went,
+    /// clearing the flag.
+    fn take_refer(&mut self, call_id: Uuid) -> bool {
+        self.legs
+            .get_mut(&call_id)
+            .is_some_and(|leg| std::mem::take(&mut leg.refer_pending))
+    }
+
+    /// The phone's call carries on as `new_call_id` with a new peer. The
+    /// dialog stays as it is; a `caller` offers the phone's current SDP to
+    /// the new peer, and whatever SDP comes back is sent in a re-INVITE.
+    async fn transferred(&mut self, call_id: Uuid, new_call_id: Uuid, caller: bool) {
+        let Some(mut leg) = self.legs.remove(&call_id) else {
+            return;
+        };
+        leg.call_id = new_call_id;
+        leg.pending = None;
+        self.by_sip_call_id
+            .insert(leg.sip_call_id.clone(), new_call_id);
+        let offer = leg.remote_sdp.clone().filter(|_| caller);
+        self.legs.insert(new_call_id, leg);
+        if let Some(sdp) = offer {
+            self.send_signal(new_call_id, "offer", sdp).await;
+        }
+    }
+
+    fn signal(&mut self, call_id: Uuid, data: serde_json::Value) {
+        let Some(leg) = self.legs.get_mut(&call_id) else {
+            return;
+        };
+        if let Some(sdp) = data.get("sdp").and_then(|sdp| sdp.as_str()) {
+            leg.pending = Some(PendingSdp {
+                sdp: sdp.to_string(),
+                ready_at: Instant::now() + CANDIDATE_WINDOW,
+                offer: data.get("type").and_then(|t| t.as_str()) == Some("offer"),
+            });
+            return;
+        }
+        let candidate = data.get("candidate");
+        let line = candidate
+            .and_then(|c| c.get("candidate"))
+            .or(candidate)
+            .and_then(|c| c.as_str());
+        let index = candidate
+            .and_then(|c| c.get("sdpMLineIndex"))
+            .and_then(|i| i.as_u64())
+            .unwrap_or(0) as usize;
+        match (line, &mut leg.pending) {
+            (Some(line), Some(pending)) if !line.is_empty() => {
+                pending.sdp = add_candidate(&pending.sdp, index, line);
+            }
+            // Once the SDP went out SIP has no way to add candidates.
+            _ => tracing::debug!(%call_id, "dropping late ice candidate"),
+        }
+    }
+
+    /// Release held SDP whose candidate window has passed.
+    pub fn on_timer(&mut self) {
+        let now = Instant::now();
+        let due: Vec<Uuid> = self
+            .legs
+            .values()
+            .filter(|leg| leg.pending.as_ref().is_some_and(|p| p.ready_at <= now))
+            .map(|leg| leg.call_id)
+            .collect();
+        for call_id in due {
+            self.progress(call_id);
+        }
+    }
+
+    /// Send whatever the leg is now able to send: our INVITE once the offer
+    /// is ready, the 200 OK once the call is connected and answered, or a
+    /// re-INVITE when new SDP arrives for an established dialog.
+    fn progress(&mut self, call_id: Uuid) {
+        let contact = self.contact_header();
+        let Some(leg) = self.legs.get_mut(&call_id) else {
+            return;
+        };
+        if leg
+            .pending
+            .as_ref()
+            .is_none_or(|p| p.ready_at > Instant::now())
+        {
+            return;
+        }
+        match leg.direction {
+            Direction::Outbound if leg.our_invite.is_none() => {
+                let sdp = leg.pending.take().map(|p| p.sdp).unwrap_or_default();
+                let Some(mut invite) = self.in_dialog_request(call_id, Method::Invite, true) else {
+                    return;
+                };
+                invite.headers.push("Content-Type", "application/sdp");
+                invite.body = sdp;
+                if let Some(leg) = self.legs.get_mut(&call_id) {
+                    leg.our_invite = Some(invite.clone());
+                    leg.local_sdp = Some(invite.body.clone());
+                }
+                self.outbox.push(Message::Request(invite));
+            }
+            Direction::Inbound if leg.connected && !leg.established => {
+                let Some(invite) = &leg.invite else {
+                    return;
+                };
+                let mut res = Response::to(invite, 200, "OK");
+                tag_to(&mut res, &leg.local_tag);
+                res.headers.push("Contact", contact);
+                res.headers.push("Content-Type", "application/sdp");
+                res.body = leg.pending.take().map(|p| p.sdp).unwrap_or_default();
+                leg.local_sdp = Some(res.body.clone());
+                leg.established = true;
+                self.outbox.push(Message::Response(res));
+            }
+            _ if leg.established && leg.reinvite.is_none() => {
+                let Some(pending) = leg.pending.take() else {
+                    return;
+                };
+                leg.reinvite = Some(pending.offer);
+                let Some(mut invite) = self.in_dialog_request(call_id, Method::Invite, true) else {
+                    return;
+                };
+                invite.headers.push("Content-Type", "application/sdp");
+                invite.body = pending.sdp;
+                if let Some(leg) = self.legs.get_mut(&call_id) {
+                    leg.our_invite = Some(invite.clone());
+                    leg.local_sdp = Some(invite.body.clone());
+                }
+                self.outbox.push(Message::Request(invite));
+            }
+            _ => {}
+        }
+    }
+
+    /// Routing ended the call: tell the phone in whatever way fits the
+    /// dialog's state.
+    fn terminate(&mut self, call_id: Uuid, status: u16, phrase: &str) {
+        let Some(leg) = self.legs.get_mut(&call_id) else {
+            return;
+        };
+        match (leg.direction, leg.established, leg.our_invite.clone()) {
+            (_, true, _) => {
+                let bye = self.in_dialog_request(call_id, Method::Bye, true);
+                self.outbox.extend(bye.map(Message::Request));
+            }
+            (Direction::Inbound, false, _) => {
+                // Success codes only make sense as a CANCEL reason.
+                let (status, phrase) = if status < 300 {
+                    (487, "Request Terminated")
+                } else {
+                    (status, phrase)
+                };
+                if let Some(invite) = &leg.invite {
+                    let mut res = Response::to(invite, status, phrase);
+                    tag_to(&mut res, &leg.local_tag);
+                    self.outbox.push(Message::Response(res));
+                }
+            }
+            (Direction::Outbound, false, Some(invite)) => {
+                let mut cancel = Request::new(Method::Cancel, invite.uri.clone());
+                for name in ["Via", "Max-Forwards", "From", "To", "Call-ID"] {
+                    if let Some(value) = invite.headers.get(name) {
+                        cancel.headers.push(name, value);
+                    }
+                }
+                cancel
+                    .headers
+                    .push("CSeq", format!("{} CANCEL", leg.local_cseq));
+                if status != 487 {
+                    cancel
+                        .headers
+                        .push("Reason", format!("SIP;cause={status};text=\"{phrase}\""));
+                }
+                self.outbox.push(Message::Request(cancel));
+                // Keep the leg until the INVITE's final response is ACKed.
+                leg.cancelling = true;
+                return;
+            }
+            // Still collecting the offer; the phone never heard of this call.
+            (Direction::Outbound, false, None) => {}
+        }
+        self.remove_leg(call_id);
+    }
+
+    /// Build a request inside the leg's dialog. `new_transaction` bumps the
+    /// CSeq (everything but ACK).
+    fn in_dialog_request(
+        &mut self,
+        call_id: Uuid,
+        method: Method,
+        new_transaction: bool,
+    ) -> Option<Request> {
+        let contact = self.contact_header();
+        let via = format!(
+            "SIP/2.0/{} {};branch=z9hG4bK{}",
+            self.transport,
+            self.host,
+            Uuid::new_v4().simple()
+        );
+        let leg = self.legs.get_mut(&call_id)?;
+        if new_transaction {
+            leg.local_cseq += 1;
+        }
+        let mut req = Request::new(method.clone(), leg.remote_target.clone());
+        req.headers.push("Via", via);
+        req.headers.push("Max-Forwards", "70");
+        let local = format!("{};tag={}", leg.local_uri, leg.local_tag);
+        // From/To follow the direction of the request, not of the call.
+        req.headers.push("From", local);
+        req.headers.push("To", leg.remote.clone());
+        req.headers.push("Call-ID", leg.sip_call_id.clone());
+        req.headers
+            .push("CSeq", format!("{} {}", leg.local_cseq, method));
+        if method == Method::Invite {
+            req.headers.push("Contact", contact);
+        }
+        Some(req)
+    }
+
+    fn contact_header(&self) -> String {
+        format!(
+            "<sip:{}@{};transport={}>",
+            self.state.cluster.node_id.simple(),
+            self.host,
+            self.transport.to_ascii_lowercase()
+        )
+    }
+
+    fn inbound_invite(&self, call_id: Uuid) -> Option<(Request, String)> {
+        let leg = self.legs.get(&call_id)?;
+        Some((leg.invite.clone()?, leg.local_tag.clone()))
+    }
+
+    fn leg_for(&self, req: &Request) -> Option<&Leg> {
+        let call_id = self.by_sip_call_id.get(req.call_id()?)?;
+        self.legs.get(call_id)
+    }
+
+    fn take_leg_for(&mut self, req: &Request) -> Option<Leg> {
+        let call_id = *self.by_sip_call_id.get(req.call_id()?)?;
+        self.remove_leg(call_id)
+    }
+
+    fn remove_leg(&mut self, call_id: Uuid) -> Option<Leg> {
+        let leg = self.legs.remove(&call_id)?;
+        self.by_sip_call_id.remove(&leg.sip_call_id);
+        Some(leg)
+    }
+
+    /// The phone ended the call; tell routing.
+    async fn hang_up(&mut self, call_id: Uuid) {
+        let Some(from) = self.session.as_ref().map(|s| s.endpoint) else {
+            return;
+        };
+        calls::handle_client_message(&self.state, from, ClientMessage::Ended { call_id }).await;
+    }
+
+    fn respond(&mut self, req: &Request, status: u16, phrase: &str) {
+        self.outbox
+            .push(Message::Response(Response::to(req, status, phrase)));
+    }
+}
+
+/// Claims standing in for a token after a digest login. They last as long as
+/// the longest registration, and phones re-authenticate on every refresh.
+fn digest_claims(tenant_id: Uuid, user_id: Uuid) -> AuthClaims {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as usize;
+    AuthClaims {
+        sub: user_id,
+        tenant_id,
+        iat: now,
+        exp: now + DEFAULT_EXPIRES as usize,
+        role: dto::Role::Agent,
+    }
+}
+
+fn new_tag() -> String {
+    Uuid::new_v4().simple().to_string()[..10].to_string()
+}
+
+/// Add our tag to the To header of a response, unless it already has one.
+fn tag_to(res: &mut Response, tag: &str) {
+    let Some(to) = res.headers.get("To") else {
+        return;
+    };
+    if ::sip::param(to, "tag").is_none() {
+        let tagged = format!("{to};tag={tag}");
+        res.headers.set("To", tagged);
+    }
+}
+
+fn strip_tag(value: &str) -> String {
+    match value.find(";tag=") {
+        Some(at) => value[..at].to_string(),
+        None => value.to_string(),
+    }
+}
+
+/// SIP status for a routing error (RFC 3261 section 21).
+fn error_status(code: ErrorCode) -> (u16, &'static str) {
+    match code {
+        ErrorCode::InvalidDestination => (404, "Not Found"),
+        ErrorCode::Unavailable => (480, "Temporarily Unavailable"),
+        ErrorCode::DoNotDisturb => (486, "Busy Here"),
+        ErrorCode::UnknownCall => (481, "Call/Transaction Does Not Exist"),
+        ErrorCode::DuplicateCall => (482, "Loop Detected"),
+        ErrorCode::NotAllowed | ErrorCode::InvalidToken => (403, "Forbidden"),
+        ErrorCode::RateLimited => (503, "Service Unavailable"),
+        ErrorCode::InvalidMessage | ErrorCode::InvalidState => (500, "Server Internal Error"),
+    }
+}
+
+/// Fold a trickled candidate into the `index`-th media section of `sdp`.
+/// SDP or a candidate that does not parse is left out rather than mangled.
+fn add_candidate(sdp: &str, index: usize, candidate: &str) -> String {
+    let (Ok(mut desc), Ok(candidate)) = (
+        sdp.parse::<SessionDescription>(),
+        candidate.parse::<Candidate>(),
+    ) else {
+        tracing::debug!("dropping ice candidate that does not fit the sdp");
+        return sdp.to_string();
+    };
+    let Some(media) = desc.media.get_mut(index) else {
+        return sdp.to_string();
+    };
+    media
+        .attributes
+        .push(Attribute::new("candidate", candidate.to_string()));
+    desc.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calls::tests::online;
+
+    const SDP: &str = "v=0\r\n\
+                       o=- 1 1 IN IP4 198.51.100.7\r\n\
+                       s=-\r\n\
+                       c=IN IP4 198.51.100.7\r\n\
+                       t=0 0\r\n\
+                       m=audio 4000 RTP/AVP 0\r\n";
+
+    /// A phone of a new user of `tenant_id`, signed in and reachable.
+    async fn phone(state: &AppState, tenant_id: Uuid) -> Adapter {
+        let claims = digest_claims(tenant_id, Uuid::new_v4());
+        let host = "sip.example.com".to_string();
+        let mut phone = Adapter::new(state.clone(), "TCP", host, Some(claims));
+        phone.contact = Some("sip:phone@198.51.100.7".to_string());
+        phone.ensure_session().await;
+        phone
+    }
+
+    fn user(phone: &Adapter) -> Uuid {
+        phone.claims.as_ref().expect("signed in").sub
+    }
+
+    /// A request from `phone` to `to` in dialog `dialog`.
+    fn request(
+        phone: &Adapter,
+        method: &str,
+        to: Uuid,
+        dialog: &str,
+        seq: u32,
+        body: &str,
+    ) -> Request {
+        let from = user(phone);
+        let content_type = if body.is_empty() {
+            ""
+        } else {
+            "Content-Type: application/sdp\r\n"
+        };
+        let raw = format!(
+            "{method} sip:{to}@voip.local SIP/2.0\r\n\
+             Via: SIP/2.0/TCP 198.51.100.7;branch=z9hG4bK{dialog}{seq}{method}\r\n\
+             Max-Forwards: 70\r\n\
+             From: <sip:{from}@voip.local>;tag=phone\r\n\
+             To: <sip:{to}@voip.local>\r\n\
+             Call-ID: {dialog}\r\n\
+             CSeq: {seq} {method}\r\n\
+             Contact: <sip:phone@198.51.100.7>\r\n\
+             {content_type}Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        match raw.parse() {
+            Ok(Message::Request(req)) => req,
+            other => panic!("not a request: {other:?}"),
+        }
+    }
+
+    /// `SDP` with every stream in `direction`.
+    fn sdp(direction: &str) -> String {
+        format!("{SDP}a={direction}\r\n")
+    }
+
+    /// Feed the phone every routing event addressed to it so far.
+    async fn pump(phone: &mut Adapter) {
+        while let Some(event) = phone.session.as_mut().and_then(|s| s.rx.try_recv().ok()) {
+            phone.on_event(event).await;
+        }
+    }
+
+    /// Release held SDP without waiting out the candidate window.
+    fn flush(phone: &mut Adapter) {
+        for leg in phone.legs.values_mut() {
+            if let Some(pending) = &mut leg.pending {
+                pending.ready_at = Instant::now();
+            }
+        }
+        phone.on_timer();
+    }
+
+    fn statuses(sent: &[Message]) -> Vec<u16> {
+        sent.iter()
+            .filter_map(|msg| match msg {
+                Message::Response(res) => Some(res.status),
+                Message::Request(_) => None,
+            })
+            .collect()
+    }
+
+    fn requests(sent: &[Message]) -> Vec<Request> {
+        sent.iter()
+            .filter_map(|msg| match msg {
+                Message::Request(req) => Some(req.clone()),
+                Message::Response(_) => None,
+            })
+            .collect()
+    }
+
+    fn received(session: &mut Session) -> Vec<ServerMessage> {
+        std::iter::from_fn(|| session.rx.try_recv().ok()).collect()
+    }
+
+    /// The phone calls `callee` in `dialog` and `callee` answers: the call
+    /// id.
+    async fn call_answered(
+        state: &AppState,
+        phone: &mut Adapter,
+        callee: &mut Session,
+        dialog: &str,
+    ) -> Uuid {
+        let to = callee.endpoint.user_id;
+        let invite = request(phone, "INVITE", to, dialog, 1, SDP);
+        phone.on_message(Message::Request(invite)).await;
+        let call_id = phone.by_sip_call_id[dialog];
+        for msg in [
+            ClientMessage::Answer { call_id },
+            ClientMessage::Signal {
+                call_id,
+                data: json!({ "type": "answer", "sdp": sdp("sendrecv") }),
+            },
+        ] {
+            calls::handle_client_message(state, callee.endpoint, msg).await;
+        }
+        pump(phone).await;
+        flush(phone);
+        phone.drain();
+        received(callee);
+        call_id
+    }
+
+    #[tokio::test]
+    async fn an_invite_rings_the_callee_and_is_answered_once_connected() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let to = callee.endpoint.user_id;
+
+        let invite = request(&phone, "INVITE", to, "dialog", 1, SDP);
+        phone.on_message(Message::Request(invite)).await;
+        assert_eq!(statuses(&phone.drain()), [100]);
+        let call_id = phone.legs.keys().next().copied().expect("a leg");
+        let frames = received(&mut callee);
+        assert!(frames.iter().any(
+            |msg| matches!(msg, ServerMessage::Incoming { call_id: id, .. } if *id == call_id)
+        ));
+        assert!(frames.iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Signal { data, .. } if data["type"] == "offer" && data["sdp"] == SDP
+        )));
+
+        pump(&mut phone).await;
+        assert_eq!(statuses(&phone.drain()), [180]);
+
+        calls::handle_client_message(&state, callee.endpoint, ClientMessage::Answer { call_id })
+            .await;
+        let signal = ClientMessage::Signal {
+            call_id,
+            data: json!({ "type": "answer", "sdp": sdp("sendrecv") }),
+        };
+        calls::handle_client_message(&state, callee.endpoint, signal).await;
+        pump(&mut phone).await;
+        // The 200 waits for the answer's candidates.
+        assert!(phone.drain().is_empty());
+        flush(&mut phone);
+        let sent = phone.drain();
+        assert_eq!(statuses(&sent), [200]);
+        let Message::Response(ok) = &sent[0] else {
+            unreachable!()
+        };
+        assert_eq!(ok.body, sdp("sendrecv"));
+        assert!(::sip::param(ok.headers.get("To").unwrap(), "tag").is_some());
+    }
+
+    #[tokio::test]
+    async fn a_bye_hangs_up() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        call_answered(&state, &mut phone, &mut callee, "dialog").await;
+
+        let bye = request(&phone, "BYE", callee.endpoint.user_id, "dialog", 2, "");
+        phone.on_message(Message::Request(bye.clone())).await;
+        assert_eq!(statuses(&phone.drain()), [200]);
+        assert!(state.calls.read().await.is_empty());
+        assert!(received(&mut callee)
+            .iter()
+            .any(|msg| matches!(msg, ServerMessage::Ended { .. })));
+
+        // The dialog is gone.
+        phone.on_message(Message::Request(bye)).await;
+        assert_eq!(statuses(&phone.drain()), [481]);
+    }
+
+    #[tokio::test]
+    async fn a_hangup_elsewhere_sends_a_bye() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let call_id = call_answered(&state, &mut phone, &mut callee, "dialog").await;
+
+        calls::handle_client_message(&state, callee.endpoint, ClientMessage::Ended { call_id })
+            .await;
+        pump(&mut phone).await;
+        let sent = requests(&phone.drain());
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].method, Method::Bye);
+        assert!(!phone.has_calls());
+    }
+
+    #[tokio::test]
+    async fn a_cancel_before_the_answer_ends_the_call() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let to = callee.endpoint.user_id;
+        let invite = request(&phone, "INVITE", to, "dialog", 1, SDP);
+        phone.on_message(Message::Request(invite)).await;
+        pump(&mut phone).await;
+        phone.drain();
+
+        let cancel = request(&phone, "CANCEL", to, "dialog", 1, "");
+        phone.on_message(Message::Request(cancel.clone())).await;
+        assert_eq!(statuses(&phone.drain()), [200, 487]);
+        assert!(state.calls.read().await.is_empty());
+        assert!(received(&mut callee)
+            .iter()
+            .any(|msg| matches!(msg, ServerMessage::Ended { .. })));
+
+        phone.on_message(Message::Request(cancel)).await;
+        assert_eq!(statuses(&phone.drain()), [481]);
+    }
+
+    #[tokio::test]
+    async fn a_cancel_after_the_answer_is_refused() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        call_answered(&state, &mut phone, &mut callee, "dialog").await;
+
+        let cancel = request(&phone, "CANCEL", callee.endpoint.user_id, "dialog", 1, "");
+        phone.on_message(Message::Request(cancel)).await;
+        assert_eq!(statuses(&phone.drain()), [481]);
+        assert_eq!(state.calls.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_sendonly_or_inactive_reinvite_holds_the_call() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let call_id = call_answered(&state, &mut phone, &mut callee, "dialog").await;
+        let to = callee.endpoint.user_id;
+
+        // The callee is told its new direction only when the hold changes.
+        for (seq, offered, answered, told) in [
+            (2, "sendonly", "recvonly", Some("recvonly")),
+            (3, "inactive", "inactive", None),
+            (4, "sendrecv", "sendrecv", Some("sendrecv")),
+        ] {
+            let reinvite = request(&phone, "INVITE", to, "dialog", seq, &sdp(offered));
+            phone.on_message(Message::Request(reinvite)).await;
+            pump(&mut phone).await;
+            let sent = phone.drain();
+            assert_eq!(statuses(&sent), [200], "{offered}");
+            let Message::Response(ok) = &sent[0] else {
+                unreachable!()
+            };
+            let answer: SessionDescription = ok.body.parse().unwrap();
+            assert_eq!(answer.direction_of(0).as_str(), answered);
+            let heard = received(&mut callee).into_iter().find_map(|msg| match msg {
+                ServerMessage::Held { direction, .. }
+                | ServerMessage::Resumed { direction, .. } => Some(direction),
+                _ => None,
+            });
+            assert_eq!(heard.as_deref(), told, "{offered}");
+        }
+        assert!(!phone.legs[&call_id].holding);
+    }
+
+    #[tokio::test]
+    async fn a_refused_hold_keeps_the_dialog_up() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let call_id = call_answered(&state, &mut phone, &mut callee, "dialog").await;
+        let to = callee.endpoint.user_id;
+
+        // Parked by the callee, the phone is re-INVITEd on hold and accepts.
+        let park = ClientMessage::Park {
+            call_id,
+            slot: None,
+        };
+        calls::handle_client_message(&state, callee.endpoint, park).await;
+        pump(&mut phone).await;
+        let ours = requests(&phone.drain());
+        assert_eq!(ours.len(), 1);
+        assert_eq!(ours[0].method, Method::Invite);
+        let mut ok = Response::to(&ours[0], 200, "OK");
+        tag_to(&mut ok, "phone");
+        ok.body = sdp("recvonly");
+        phone.on_message(Message::Response(ok)).await;
+        assert_eq!(requests(&phone.drain())[0].method, Method::Ack);
+
+        // A parked call cannot be held.
+        let reinvite = request(&phone, "INVITE", to, "dialog", 2, &sdp("sendonly"));
+        phone.on_message(Message::Request(reinvite)).await;
+        pump(&mut phone).await;
+        let sent = phone.drain();
+        assert_eq!(statuses(&sent), [500]);
+        assert!(requests(&sent).is_empty());
+        let leg = &phone.legs[&call_id];
+        assert!(leg.established && !leg.holding && leg.hold_change.is_none());
+        assert!(state.calls.read().await.contains_key(&call_id));
+    }
+
+    #[tokio::test]
+    async fn a_refused_request_fails_only_an_unanswered_call() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut callee = online(&state, tenant_id).await;
+        let call_id = call_answered(&state, &mut phone, &mut callee, "dialog").await;
+
+        let stray = ServerMessage::error(Some(call_id), ErrorCode::UnknownCall, "unknown call");
+        phone.on_event(stray).await;
+        assert!(phone.drain().is_empty());
+        assert!(phone.legs.contains_key(&call_id));
+
+        // Calling nobody fails the INVITE.
+        let invite = request(&phone, "INVITE", user(&phone), "other", 1, SDP);
+        phone.on_message(Message::Request(invite)).await;
+        pump(&mut phone).await;
+        assert_eq!(statuses(&phone.drain()), [100, 404]);
+        assert_eq!(phone.legs.len(), 1);
+    }
+
+    /// A REFER from `phone` in `dialog`, to `refer_to`.
+    fn refer(phone: &Adapter, to: Uuid, dialog: &str, refer_to: &str) -> Request {
+        let mut refer = request(phone, "REFER", to, dialog, 2, "");
+        refer.headers.push("Refer-To", refer_to);
+        refer
+    }
+
+    /// Status lines reported to the phone in NOTIFYs about its REFER.
+    fn notified(sent: &[Message]) -> Vec<String> {
+        requests(sent)
+            .into_iter()
+            .filter(|req| req.method == Method::Notify)
+            .map(|req| req.body.trim_end().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_refer_transfers_the_other_party() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut transferee = online(&state, tenant_id).await;
+        let mut target = online(&state, tenant_id).await;
+        let call_id = call_answered(&state, &mut phone, &mut transferee, "dialog").await;
+
+        let to = transferee.endpoint.user_id;
+        let refer_to = format!("<sip:{}@voip.local>", target.endpoint.user_id);
+        phone
+            .on_message(Message::Request(refer(&phone, to, "dialog", &refer_to)))
+            .await;
+        let sent = phone.drain();
+        assert_eq!(statuses(&sent), [202]);
+        assert_eq!(notified(&sent), ["SIP/2.0 100 Trying"]);
+        assert!(received(&mut target)
+            .iter()
+            .any(|msg| matches!(msg, ServerMessage::Incoming { .. })));
+        assert!(received(&mut transferee).iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Transferred { call_id: id, peer, .. }
+                if *id == call_id && *peer == target.endpoint.user_id
+        )));
+
+        // The phone hears the outcome, then drops out.
+        pump(&mut phone).await;
+        let sent = requests(&phone.drain());
+        let methods: Vec<_> = sent.iter().map(|req| req.method.clone()).collect();
+        assert_eq!(methods, [Method::Notify, Method::Bye]);
+        assert_eq!(sent[0].body, "SIP/2.0 200 OK\r\n");
+        assert!(!phone.has_calls());
+    }
+
+    #[tokio::test]
+    async fn a_refer_with_replaces_completes_an_attended_transfer() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut transferee = online(&state, tenant_id).await;
+        let mut target = online(&state, tenant_id).await;
+        let call_id = call_answered(&state, &mut phone, &mut transferee, "first").await;
+        let consult_call_id = call_answered(&state, &mut phone, &mut target, "consult").await;
+
+        let to = transferee.endpoint.user_id;
+        let refer_to = format!(
+            "<sip:{}@voip.local?Replaces=consult%3Bto-tag%3Dus%3Bfrom-tag%3Dphone>",
+            target.endpoint.user_id
+        );
+        phone
+            .on_message(Message::Request(refer(&phone, to, "first", &refer_to)))
+            .await;
+        assert_eq!(statuses(&phone.drain()), [202]);
+
+        let new_call_id = {
+            let calls = state.calls.read().await;
+            assert_eq!(calls.len(), 1);
+            let (new_call_id, call) = calls.iter().next().unwrap();
+            assert_eq!(call.caller, transferee.endpoint);
+            assert_eq!(call.answered_by, Some(target.endpoint));
+            *new_call_id
+        };
+        for (party, old) in [(&mut transferee, call_id), (&mut target, consult_call_id)] {
+            assert!(received(party).iter().any(|msg| matches!(
+                msg,
+                ServerMessage::Transferred { call_id, new_call_id: new, .. }
+                    if *call_id == old && *new == new_call_id
+            )));
+        }
+        pump(&mut phone).await;
+        assert!(notified(&phone.drain()).contains(&"SIP/2.0 200 OK".to_string()));
+        assert!(!phone.has_calls());
+    }
+
+    #[tokio::test]
+    async fn a_refused_refer_keeps_the_call_up() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let mut phone = phone(&state, tenant_id).await;
+        let mut transferee = online(&state, tenant_id).await;
+        let call_id = call_answered(&state, &mut phone, &mut transferee, "dialog").await;
+
+        // Nobody can be transferred to the phone's own user.
+        let to = transferee.endpoint.user_id;
+        let refer_to = format!("<sip:{}@voip.local>", user(&phone));
+        phone
+            .on_message(Message::Request(refer(&phone, to, "dialog", &refer_to)))
+            .await;
+        pump(&mut phone).await;
+        let sent = phone.drain();
+        assert_eq!(
+            notified(&sent),
+            ["SIP/2.0 100 Trying", "SIP/2.0 404 Not Found"]
+        );
+        assert!(requests(&sent).iter().all(|req| req.method != Method::Bye));
+        assert!(phone.legs.contains_key(&call_id));
+        assert!(state.calls.read().await.contains_key(&call_id));
+    }
+
+    #[test]
+    fn routing_errors_map_onto_sip_statuses() {
+        for (code, status) in [
+            (ErrorCode::InvalidDestination, 404),
+            (ErrorCode::Unavailable, 480),
+            (ErrorCode::DoNotDisturb, 486),
+            (ErrorCode::UnknownCall, 481),
+            (ErrorCode::DuplicateCall, 482),
+            (ErrorCode::NotAllowed, 403),
+            (ErrorCode::InvalidToken, 403),
+            (ErrorCode::RateLimited, 503),
+            (ErrorCode::InvalidMessage, 500),
+            (ErrorCode::InvalidState, 500),
+        ] {
+            assert_eq!(error_status(code).0, status, "{code:?}");
+        }
+    }
+}
@@ -0,0 +1,718 @@
+//! SIP digest authentication (RFC 3261 section 22, RFC 8760).
+//!
+//! Phones that cannot obtain a bearer token log in with a password. Only the
+//! HA1 hashes live in the PBX database; a response is checked by recomputing
+//! it from the stored hash for whichever algorithm the phone picked. We offer
+//! SHA-256 first and keep MD5 for the many devices that know nothing else.
+//!
+//! Nonces are stateless: a timestamp and some randomness sealed with an HMAC,
+//! so any replica can check a nonce another one issued. Each nonce-count may
+//! be used only once, which stops a captured request from being replayed, and
+//! a user whose password keeps failing is locked out for a while. Both are
+//! tracked in Redis when configured so the limits hold across replicas; when
+//! Redis cannot be reached nobody is let in. A response only counts for the
+//! request whose Request-URI it names.
+
+use crate::lockout::Lockout;
+use crate::pbx::{PbxClient, SipCredentials};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DigestError {
+    #[error("malformed digest credentials")]
+    Malformed,
+    #[error("unsupported digest algorithm {0:?}")]
+    Algorithm(String),
+    #[error("credentials are for realm {0:?}")]
+    Realm(String),
+    #[error("nonce was not issued by us")]
+    ForeignNonce,
+    #[error("nonce expired")]
+    Stale,
+    #[error("nonce count reused")]
+    Replay,
+    #[error("no sip credentials for the user")]
+    UnknownUser,
+    #[error("digest response does not match")]
+    Mismatch,
+    #[error("digest uri {0:?} is not the request uri")]
+    Uri(String),
+    #[error("too many failed attempts")]
+    LockedOut,
+    #[error("credential lookup failed: {0}")]
+    Lookup(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<reqwest::Error> for DigestError {
+    fn from(err: reqwest::Error) -> Self {
+        DigestError::Lookup(err.into())
+    }
+}
+
+impl From<redis::RedisError> for DigestError {
+    fn from(err: redis::RedisError) -> Self {
+        DigestError::Lookup(err.into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    fn hash(self, input: &str) -> String {
+        match self {
+            Algorithm::Md5 => hex::encode(Md5::digest(input.as_bytes())),
+            Algorithm::Sha256 => hex::encode(Sha256::digest(input.as_bytes())),
+        }
+    }
+}
+
+/// Nonce layout: issue time (8 bytes), randomness (8), truncated HMAC (16).
+const NONCE_LEN: usize = 32;
+
+pub struct DigestAuth {
+    realm: String,
+    secret: Vec<u8>,
+    nonce_ttl: Duration,
+    redis: Option<redis::aio::ConnectionManager>,
+    /// Used nonce-counts and their expiry, when Redis is not configured.
+    used: Mutex<HashMap<(String, u32), Instant>>,
+    /// Failed logins per user.
+    failures: Lockout,
+}
+
+impl DigestAuth {
+    /// Nonces are sealed with `SIP_NONCE_SECRET`, which replicas must share;
+    /// without it each process picks its own and phones may see a few extra
+    /// challenges when moved between replicas.
+    pub fn from_env(realm: String, redis: Option<redis::aio::ConnectionManager>) -> Self {
+        let secret = match std::env::var("SIP_NONCE_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => rand::thread_rng().gen::<[u8; 32]>().to_vec(),
+        };
+        DigestAuth::new(
+            realm,
+            secret,
+            Duration::from_secs(env_u64("SIP_NONCE_TTL_SECS", 300)),
+            env_u64("SIP_AUTH_MAX_FAILURES", 5) as u32,
+            Duration::from_secs(env_u64("SIP_AUTH_LOCKOUT_SECS", 900)),
+            redis,
+        )
+    }
+
+    fn new(
+        realm: String,
+        secret: Vec<u8>,
+        nonce_ttl: Duration,
+        max_failures: u32,
+        lockout: Duration,
+        redis: Option<redis::aio::ConnectionManager>,
+    ) -> Self {
+        DigestAuth {
+            realm,
+            secret,
+            nonce_ttl,
+            failures: Lockout::new(max_failures, lockout, redis.clone()),
+            redis,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `WWW-Authenticate` values for a fresh challenge, strongest algorithm
+    /// first (RFC 8760 section 2.4). `stale` tells the phone its password was
+    /// right and it only needs to retry with the new nonce.
+    pub fn challenges(&self, stale: bool) -> Vec<String> {
+        [Algorithm::Sha256, Algorithm::Md5]
+            .into_iter()
+            .map(|algorithm| {
+                let mut value = format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"",
+                    self.realm,
+                    algorithm.name(),
+                    self.issue_nonce()
+                );
+                if stale {
+                    value.push_str(", stale=true");
+                }
+                value
+            })
+            .collect()
+    }
+
+    /// Check `Authorization: Digest ...` on a `method` request for
+    /// `request_uri`, returning the tenant and user it proves.
+    pub async fn verify(
+        &self,
+        pbx: &PbxClient,
+        method: &str,
+        request_uri: &str,
+        header: &str,
+    ) -> Result<(Uuid, Uuid), DigestError> {
+        let digest = DigestResponse::parse(header)?;
+        if digest.realm != self.realm {
+            return Err(DigestError::Realm(digest.realm));
+        }
+        if !same_uri(&digest.uri, request_uri) {
+            return Err(DigestError::Uri(digest.uri));
+        }
+        let user_id: Uuid = digest
+            .username
+            .parse()
+            .map_err(|_| DigestError::UnknownUser)?;
+
+        if self.locked(user_id).await? {
+            return Err(DigestError::LockedOut);
+        }
+        let issued_at = self.check_nonce(&digest.nonce)?;
+
+        let Some(credentials) = pbx.sip_credentials(user_id).await? else {
+            self.record_failure(user_id).await;
+            return Err(DigestError::UnknownUser);
+        };
+        self.check(&digest, method, &credentials, issued_at).await?;
+        Ok((credentials.tenant_id, credentials.user_id))
+    }
+
+    /// Check a response against the user's stored credentials, then spend
+    /// its nonce-count.
+    async fn check(
+        &self,
+        digest: &DigestResponse,
+        method: &str,
+        credentials: &SipCredentials,
+        issued_at: Instant,
+    ) -> Result<(), DigestError> {
+        let ha1 = match digest.algorithm {
+            Algorithm::Md5 => &credentials.ha1_md5,
+            Algorithm::Sha256 => &credentials.ha1_sha256,
+        };
+        let expected = response(
+            digest.algorithm,
+            ha1,
+            &digest.nonce,
+            Some((&digest.nc, &digest.cnonce)),
+            method,
+            &digest.uri,
+        );
+        if credentials.realm != self.realm || !constant_time_eq(&expected, &digest.response) {
+            self.record_failure(credentials.user_id).await;
+            return Err(DigestError::Mismatch);
+        }
+
+        if issued_at.elapsed() > self.nonce_ttl {
+            return Err(DigestError::Stale);
+        }
+        if !self.first_use(&digest.nonce, digest.count).await? {
+            return Err(DigestError::Replay);
+        }
+        self.clear_failures(credentials.user_id).await;
+        Ok(())
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac accepts any key length");
+        mac.update(self.realm.as_bytes());
+        mac
+    }
+
+    fn issue_nonce(&self) -> String {
+        let mut raw = [0u8; NONCE_LEN];
+        raw[..8].copy_from_slice(&unix_now().to_be_bytes());
+        rand::thread_rng().fill(&mut raw[8..16]);
+        let mut mac = self.mac();
+        mac.update(&raw[..16]);
+        raw[16..].copy_from_slice(&mac.finalize().into_bytes()[..NONCE_LEN - 16]);
+        URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    /// Verify a nonce's seal and return when it was issued.
+    fn check_nonce(&self, nonce: &str) -> Result<Instant, DigestError> {
+        let raw = URL_SAFE_NO_PAD
+            .decode(nonce)
+            .ok()
+            .filter(|raw| raw.len() == NONCE_LEN)
+            .ok_or(DigestError::ForeignNonce)?;
+        let mut mac = self.mac();
+        mac.update(&raw[..16]);
+        mac.verify_truncated_left(&raw[16..])
+            .map_err(|_| DigestError::ForeignNonce)?;
+        let issued = u64::from_be_bytes(raw[..8].try_into().expect("eight bytes"));
+        let age = Duration::from_secs(unix_now().saturating_sub(issued));
+        Ok(Instant::now().checked_sub(age).unwrap_or_else(Instant::now))
+    }
+
+    /// Claim a nonce-count; `false` when it was already used.
+    async fn first_use(&self, nonce: &str, nc: u32) -> Result<bool, DigestError> {
+        let Some(mut conn) = self.redis.clone() else {
+            let now = Instant::now();
+            let mut used = self.used.lock().expect("nonce table poisoned");
+            used.retain(|_, expires| *expires > now);
+            return Ok(used
+                .insert((nonce.to_string(), nc), now + self.nonce_ttl)
+                .is_none());
+        };
+        // A use we could not record may be a replay; refuse it.
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(format!("sip:nonce:{nonce}:{nc:08x}"))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(self.nonce_ttl.as_secs())
+            .query_async(&mut conn)
+            .await?;
+        Ok(claimed.is_some())
+    }
+
+    async fn locked(&self, user_id: Uuid) -> Result<bool, DigestError> {
+        Ok(self.failures.locked(&failures_key(user_id)).await?)
+    }
+
+    async fn record_failure(&self, user_id: Uuid) {
+        if self.failures.record_failure(&failures_key(user_id)).await {
+            tracing::warn!(%user_id, "sip credentials locked after repeated failures");
+        }
+    }
+
+    async fn clear_failures(&self, user_id: Uuid) {
+        self.failures.clear(&failures_key(user_id)).await;
+    }
+}
+
+fn failures_key(user_id: Uuid) -> String {
+    format!("sip:authfail:{user_id}")
+}
+
+/// The fields of an `Authorization: Digest` header that are checked.
+#[derive(Debug)]
+struct DigestResponse {
+    algorithm: Algorithm,
+    username: String,
+    realm: String,
+    nonce: String,
+    uri: String,
+    response: String,
+    nc: String,
+    /// `nc` as a number.
+    count: u32,
+    cnonce: String,
+}
+
+impl DigestResponse {
+    fn parse(header: &str) -> Result<Self, DigestError> {
+        let mut params = parse_params(header).ok_or(DigestError::Malformed)?;
+        let algorithm = match params.get("algorithm").map_or("MD5", String::as_str) {
+            a if a.eq_ignore_ascii_case("MD5") => Algorithm::Md5,
+            a if a.eq_ignore_ascii_case("SHA-256") => Algorithm::Sha256,
+            other => return Err(DigestError::Algorithm(other.to_string())),
+        };
+        let mut field = |name: &str| params.remove(name).ok_or(DigestError::Malformed);
+        let (username, realm, nonce, uri, response) = (
+            field("username")?,
+            field("realm")?,
+            field("nonce")?,
+            field("uri")?,
+            field("response")?,
+        );
+        // Only qop=auth is offered; the legacy RFC 2069 form has no
+        // nonce-count and so no replay protection.
+        if field("qop")? != "auth" {
+            return Err(DigestError::Malformed);
+        }
+        let (nc, cnonce) = (field("nc")?, field("cnonce")?);
+        let count = u32::from_str_radix(&nc, 16).map_err(|_| DigestError::Malformed)?;
+        Ok(DigestResponse {
+            algorithm,
+            username,
+            realm,
+            nonce,
+            uri,
+            response,
+            nc,
+            count,
+            cnonce,
+        })
+    }
+}
+
+/// The digest response to a challenge (RFC 2617 section 3.2.2.1), with
+/// `qop=auth` when `qop` carries the nonce-count and client nonce.
+fn response(
+    algorithm: Algorithm,
+    ha1: &str,
+    nonce: &str,
+    qop: Option<(&str, &str)>,
+    method: &str,
+    uri: &str,
+) -> String {
+    let ha2 = algorithm.hash(&format!("{method}:{uri}"));
+    match qop {
+        Some((nc, cnonce)) => algorithm.hash(&format!("{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}")),
+        None => algorithm.hash(&format!("{ha1}:{nonce}:{ha2}")),
+    }
+}
+
+/// Whether the digest `uri` names the Request-URI: the same text, or the same
+/// SIP URI written differently.
+fn same_uri(digest_uri: &str, request_uri: &str) -> bool {
+    if digest_uri == request_uri {
+        return true;
+    }
+    match (
+        digest_uri.parse::<::sip::Uri>(),
+        request_uri.parse::<::sip::Uri>(),
+    ) {
+        (Ok(digest_uri), Ok(request_uri)) => digest_uri == request_uri,
+        _ => false,
+    }
+}
+
+/// Split `Digest name=value, name="quoted, value"` into lowercase names and
+/// unquoted values.
+fn parse_params(header: &str) -> Option<HashMap<String, String>> {
+    let header = header.trim_start();
+    let (scheme, rest) = header.split_once(char::is_whitespace)?;
+    if !scheme.eq_ignore_ascii_case("Digest") {
+        return None;
+    }
+    let mut params = HashMap::new();
+    let mut chars = rest.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        let name: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=')).collect();
+        if name.trim().is_empty() {
+            break;
+        }
+        chars.next()?;
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => value.push(chars.next()?),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value.extend(std::iter::from_fn(|| chars.next_if(|c| *c != ',')));
+        }
+        params.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+    Some(params)
+}
+
+/// Compare two hex digests without leaking where they differ. Phones may
+/// send hex in either case, so both sides are compared case-insensitively.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| {
+            diff | (x.to_ascii_lowercase() ^ y.to_ascii_lowercase())
+        }) == 0
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn env_u64(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REALM: &str = "sip.example.com";
+
+    fn auth(nonce_ttl: Duration, max_failures: u32) -> DigestAuth {
+        DigestAuth::new(
+            REALM.to_string(),
+            b"nonce secret".to_vec(),
+            nonce_ttl,
+            max_failures,
+            Duration::from_secs(900),
+            None,
+        )
+    }
+
+    fn credentials(password: &str) -> SipCredentials {
+        let user_id = Uuid::new_v4();
+        let a1 = format!("{user_id}:{REALM}:{password}");
+        SipCredentials {
+            tenant_id: Uuid::new_v4(),
+            user_id,
+            realm: REALM.to_string(),
+            ha1_md5: Algorithm::Md5.hash(&a1),
+            ha1_sha256: Algorithm::Sha256.hash(&a1),
+        }
+    }
+
+    /// What a phone sends for REGISTER with `password`, as nonce-count `nc`.
+    fn answer(
+        credentials: &SipCredentials,
+        password: &str,
+        algorithm: Algorithm,
+        nonce: &str,
+        nc: u32,
+    ) -> DigestResponse {
+        let uri = format!("sip:{REALM}");
+        let ha1 = algorithm.hash(&format!("{}:{REALM}:{password}", credentials.user_id));
+        let nc_text = format!("{nc:08x}");
+        let header = format!(
+            "Digest username=\"{}\", realm=\"{REALM}\", nonce=\"{nonce}\", uri=\"{uri}\", \
+             response=\"{}\", algorithm={}, qop=auth, nc={nc_text}, cnonce=\"0a4f113b\"",
+            credentials.user_id,
+            response(
+                algorithm,
+                &ha1,
+                nonce,
+                Some((&nc_text, "0a4f113b")),
+                "REGISTER",
+                &uri
+            ),
+            algorithm.name(),
+        );
+        DigestResponse::parse(&header).expect("well-formed header")
+    }
+
+    /// A nonce sealed by `auth` as if issued `age` ago.
+    fn nonce_issued(auth: &DigestAuth, age: Duration) -> String {
+        let mut raw = [0u8; NONCE_LEN];
+        raw[..8].copy_from_slice(&(unix_now() - age.as_secs()).to_be_bytes());
+        let mut mac = auth.mac();
+        mac.update(&raw[..16]);
+        raw[16..].copy_from_slice(&mac.finalize().into_bytes()[..NONCE_LEN - 16]);
+        URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    #[test]
+    fn md5_responses() {
+        // RFC 2617 section 3.5.
+        let ha1 = Algorithm::Md5.hash("Mufasa:testrealm@host.com:Circle Of Life");
+        assert_eq!(ha1, "939e7578ed9e3c518a452acee763bce9");
+        let nonce = "dcd98b7102dd2f0e8b11d0f600bfb0c093";
+        let qop = Some(("00000001", "0a4f113b"));
+        assert_eq!(
+            response(Algorithm::Md5, &ha1, nonce, qop, "GET", "/dir/index.html"),
+            "6629fae49393a05397450978507c4ef1"
+        );
+        assert_eq!(
+            response(Algorithm::Md5, &ha1, nonce, None, "GET", "/dir/index.html"),
+            "670fd8c2df070c60b045671b8b24ff02"
+        );
+    }
+
+    #[test]
+    fn sha256_responses() {
+        // RFC 7616 section 3.9.1.
+        let ha1 = Algorithm::Sha256.hash("Mufasa:http-auth@example.org:Circle of Life");
+        let nonce = "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v";
+        let qop = Some(("00000001", "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ"));
+        assert_eq!(
+            response(
+                Algorithm::Sha256,
+                &ha1,
+                nonce,
+                qop,
+                "GET",
+                "/dir/index.html"
+            ),
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+        assert_eq!(
+            response(
+                Algorithm::Sha256,
+                &ha1,
+                nonce,
+                None,
+                "GET",
+                "/dir/index.html"
+            ),
+            "a1306b0595a6c7fe96c448631fb5cfbd5107bd1fe1da729d978dd7446b812363"
+        );
+    }
+
+    #[test]
+    fn digests_compare_either_way_round_in_any_case() {
+        let lower = "6629fae49393a05397450978507c4ef1";
+        let upper = lower.to_ascii_uppercase();
+        assert!(constant_time_eq(lower, &upper));
+        assert!(constant_time_eq(&upper, lower));
+        assert!(!constant_time_eq(lower, &lower[1..]));
+        assert!(!constant_time_eq(lower, "6629fae49393a05397450978507c4ef2"));
+    }
+
+    #[test]
+    fn responses_without_qop_are_refused() {
+        let header = "Digest username=\"u\", realm=\"r\", nonce=\"n\", uri=\"sip:r\", \
+                      response=\"670fd8c2df070c60b045671b8b24ff02\"";
+        assert!(matches!(
+            DigestResponse::parse(header),
+            Err(DigestError::Malformed)
+        ));
+        let header = format!("{header}, qop=auth-int, nc=00000001, cnonce=\"c\"");
+        assert!(matches!(
+            DigestResponse::parse(&header),
+            Err(DigestError::Malformed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn valid_responses_pass_once() {
+        let auth = auth(Duration::from_secs(300), 5);
+        let credentials = credentials("secret");
+        for algorithm in [Algorithm::Md5, Algorithm::Sha256] {
+            let nonce = auth.issue_nonce();
+            let issued_at = auth.check_nonce(&nonce).unwrap();
+            let digest = answer(&credentials, "secret", algorithm, &nonce, 1);
+            assert!(auth
+                .check(&digest, "REGISTER", &credentials, issued_at)
+                .await
+                .is_ok());
+            // The same nonce-count again is a replay; the next one is fine.
+            assert!(matches!(
+                auth.check(&digest, "REGISTER", &credentials, issued_at)
+                    .await,
+                Err(DigestError::Replay)
+            ));
+            let digest = answer(&credentials, "secret", algorithm, &nonce, 2);
+            assert!(auth
+                .check(&digest, "REGISTER", &credentials, issued_at)
+                .await
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn nonces_are_sealed() {
+        let auth = auth(Duration::from_secs(300), 5);
+        let nonce = auth.issue_nonce();
+        assert!(auth.check_nonce(&nonce).is_ok());
+
+        let mut raw = URL_SAFE_NO_PAD.decode(&nonce).unwrap();
+        raw[9] ^= 1;
+        let tampered = URL_SAFE_NO_PAD.encode(raw);
+        let other = DigestAuth::new(
+            REALM.to_string(),
+            b"another secret".to_vec(),
+            Duration::from_secs(300),
+            5,
+            Duration::from_secs(900),
+            None,
+        );
+        for nonce in [tampered, other.issue_nonce(), "short".to_string()] {
+            assert!(matches!(
+                auth.check_nonce(&nonce),
+                Err(DigestError::ForeignNonce)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_nonces_are_stale() {
+        let auth = auth(Duration::from_secs(1), 5);
+        let credentials = credentials("secret");
+        let nonce = nonce_issued(&auth, Duration::from_secs(5));
+        let issued_at = auth.check_nonce(&nonce).unwrap();
+        assert!(issued_at.elapsed() >= Duration::from_secs(4));
+
+        // Stale only once the password was shown to be right.
+        let digest = answer(&credentials, "secret", Algorithm::Sha256, &nonce, 1);
+        assert!(matches!(
+            auth.check(&digest, "REGISTER", &credentials, issued_at)
+                .await,
+            Err(DigestError::Stale)
+        ));
+        let digest = answer(&credentials, "wrong", Algorithm::Sha256, &nonce, 2);
+        assert!(matches!(
+            auth.check(&digest, "REGISTER", &credentials, issued_at)
+                .await,
+            Err(DigestError::Mismatch)
+        ));
+    }
+
+    #[test]
+    fn stale_challenges_say_so() {
+        let auth = auth(Duration::from_secs(300), 5);
+        let stale = auth.challenges(true);
+        assert_eq!(stale.len(), 2);
+        assert!(stale[0].contains("algorithm=SHA-256") && stale[1].contains("algorithm=MD5"));
+        assert!(stale.iter().all(|value| value.ends_with(", stale=true")));
+        assert!(auth
+            .challenges(false)
+            .iter()
+            .all(|value| !value.contains("stale")));
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_lock_the_user_out() {
+        let auth = auth(Duration::from_secs(300), 3);
+        let credentials = credentials("secret");
+        let user_id = credentials.user_id;
+        let nonce = auth.issue_nonce();
+        let issued_at = auth.check_nonce(&nonce).unwrap();
+
+        // A success in between starts the count over.
+        for nc in 1..=2 {
+            let digest = answer(&credentials, "wrong", Algorithm::Md5, &nonce, nc);
+            let _ = auth
+                .check(&digest, "REGISTER", &credentials, issued_at)
+                .await;
+        }
+        let digest = answer(&credentials, "secret", Algorithm::Md5, &nonce, 3);
+        assert!(auth
+            .check(&digest, "REGISTER", &credentials, issued_at)
+            .await
+            .is_ok());
+        assert!(!auth.locked(user_id).await.unwrap());
+
+        for nc in 4..=6 {
+            assert!(!auth.locked(user_id).await.unwrap());
+            let digest = answer(&credentials, "wrong", Algorithm::Md5, &nonce, nc);
+            assert!(matches!(
+                auth.check(&digest, "REGISTER", &credentials, issued_at)
+                    .await,
+                Err(DigestError::Mismatch)
+            ));
+        }
+        assert!(auth.locked(user_id).await.unwrap());
+        assert!(!auth.locked(Uuid::new_v4()).await.unwrap());
+    }
+
+    #[test]
+    fn digest_uri_must_name_the_request() {
+        assert!(same_uri("sip:sip.example.com", "sip:sip.example.com"));
+        assert!(same_uri(
+            "sip:alice@sip.example.com;transport=tcp",
+            "sip:alice@sip.example.com;transport=tcp"
+        ));
+        assert!(!same_uri("sip:sip.example.com", "sip:bob@sip.example.com"));
+        assert!(!same_uri("sip:other.example.com", "sip:sip.example.com"));
+    }
+}
@@ -0,0 +1,31 @@
+//! SIP support for phones that do not speak the JSON dialect.
+//!
+//! Desk phones and third-party softphones (JsSIP, SIP.js) talk SIP. The
+//! shared `sip` crate parses and serializes it, the [`adapter`] maps a SIP
+//! user agent onto the same call routing the web softphone uses, and each
+//! transport only moves messages between its socket and an adapter. Contact
+//! bindings of registered phones are kept by the [`registrar`], though calls
+//! reach phones through their routing sessions; phones without a token log in
+//! through [`digest`].
+
+mod adapter;
+mod digest;
+mod registrar;
+mod tcp;
+mod udp;
+mod ws;
+
+pub use adapter::Config;
+pub use digest::DigestAuth;
+pub use registrar::Registrar;
+pub use tcp::serve_tcp;
+pub use udp::serve_udp;
+pub use ws::sip_handler;
+
+/// Sleep until `deadline`, or forever when there is none.
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,160 @@
+//! SIP registrar: contact bindings per address-of-record (RFC 3261 section 10).
+//!
+//! Bindings live in a Redis hash per user, `sip:reg:{tenant}:{user}`, mapping
+//! each contact URI to a JSON [`Binding`]. Redis cannot expire single hash
+//! fields, so every binding carries its own deadline, stale ones are pruned
+//! whenever the set is read, and the key itself expires with the longest
+//! binding. A local map stands in when Redis is not configured.
+//!
+//! Bindings only answer REGISTER, which lists every contact of the
+//! address-of-record. Calls do not consult them: a registered phone opens a
+//! routing session like any other client, and INVITEs reach it through the
+//! session registry and the cluster directory.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Binding {
+    pub contact: String,
+    /// Unix seconds after which the binding is void.
+    pub expires_at: u64,
+}
+
+impl Binding {
+    /// Seconds left, as reported in the REGISTER response.
+    pub fn remaining(&self) -> u64 {
+        self.expires_at.saturating_sub(now())
+    }
+}
+
+/// Store a binding and extend the key's TTL to its lifetime, never shorten
+/// it.
+const BIND_SCRIPT: &str = r"
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+if redis.call('TTL', KEYS[1]) < tonumber(ARGV[3]) then
+    redis.call('EXPIRE', KEYS[1], ARGV[3])
+end
+return 1
+";
+
+fn registrar_key(tenant_id: Uuid, user_id: Uuid) -> String {
+    format!("sip:reg:{tenant_id}:{user_id}")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub struct Registrar {
+    redis: Option<redis::aio::ConnectionManager>,
+    bind_script: redis::Script,
+    local: RwLock<HashMap<(Uuid, Uuid), HashMap<String, Binding>>>,
+}
+
+impl Registrar {
+    pub fn new(redis: Option<redis::aio::ConnectionManager>) -> Self {
+        Registrar {
+            redis,
+            bind_script: redis::Script::new(BIND_SCRIPT),
+            local: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Add or refresh a binding for `expires` seconds.
+    pub async fn bind(&self, tenant_id: Uuid, user_id: Uuid, contact: &str, expires: u32) {
+        let binding = Binding {
+            contact: contact.to_string(),
+            expires_at: now() + u64::from(expires),
+        };
+        let Some(mut conn) = self.redis.clone() else {
+            self.local
+                .write()
+                .await
+                .entry((tenant_id, user_id))
+                .or_default()
+                .insert(binding.contact.clone(), binding);
+            return;
+        };
+        let key = registrar_key(tenant_id, user_id);
+        let value = serde_json::to_string(&binding).expect("bindings always serialize");
+        // Only ever extend the key's TTL; shorter bindings are pruned on read.
+        let result = self
+            .bind_script
+            .key(key)
+            .arg(contact)
+            .arg(value)
+            .arg(expires)
+            .invoke_async::<_, ()>(&mut conn)
+            .await;
+        if let Err(err) = result {
+            tracing::warn!(error = %err, "failed to store sip binding");
+        }
+    }
+
+    /// Remove one binding, e.g. on `Expires: 0` or when the flow closes.
+    pub async fn unbind(&self, tenant_id: Uuid, user_id: Uuid, contact: &str) {
+        let Some(mut conn) = self.redis.clone() else {
+            let mut local = self.local.write().await;
+            if let Some(bindings) = local.get_mut(&(tenant_id, user_id)) {
+                bindings.remove(contact);
+                if bindings.is_empty() {
+                    local.remove(&(tenant_id, user_id));
+                }
+            }
+            return;
+        };
+        let result = redis::cmd("HDEL")
+            .arg(registrar_key(tenant_id, user_id))
+            .arg(contact)
+            .query_async::<_, ()>(&mut conn)
+            .await;
+        if let Err(err) = result {
+            tracing::warn!(error = %err, "failed to remove sip binding");
+        }
+    }
+
+    /// Live bindings of a user, pruning expired ones on the way.
+    pub async fn bindings(&self, tenant_id: Uuid, user_id: Uuid) -> Vec<Binding> {
+        let now = now();
+        let Some(mut conn) = self.redis.clone() else {
+            let mut local = self.local.write().await;
+            let Some(bindings) = local.get_mut(&(tenant_id, user_id)) else {
+                return Vec::new();
+            };
+            bindings.retain(|_, binding| binding.expires_at > now);
+            return bindings.values().cloned().collect();
+        };
+        let key = registrar_key(tenant_id, user_id);
+        let raw: HashMap<String, String> =
+            match redis::cmd("HGETALL").arg(&key).query_async(&mut conn).await {
+                Ok(raw) => raw,
+                Err(err) => {
+                    tracing::warn!(error = %err, "failed to load sip bindings");
+                    return Vec::new();
+                }
+            };
+        let mut live = Vec::new();
+        let mut stale = Vec::new();
+        for (contact, value) in raw {
+            match serde_json::from_str::<Binding>(&value) {
+                Ok(binding) if binding.expires_at > now => live.push(binding),
+                _ => stale.push(contact),
+            }
+        }
+        if !stale.is_empty() {
+            let _ = redis::cmd("HDEL")
+                .arg(&key)
+                .arg(&stale)
+                .query_async::<_, ()>(&mut conn)
+                .await;
+        }
+        live
+    }
+}
@@ -0,0 +1,112 @@
+//! SIP over TCP (RFC 3261 section 18).
+//!
+//! A connection carries a stream of messages, each delimited by the blank
+//! line after its headers and its mandatory Content-Length. The transport is
+//! reliable, so nothing is retransmitted; one adapter serves the connection
+//! for its whole life.
+
+use super::adapter::Adapter;
+use super::sleep_until;
+use crate::limits::Rejection;
+use crate::AppState;
+use ::sip::Message;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Bind the TCP listener and serve each connection on its own task.
+pub async fn serve_tcp(state: AppState) {
+    let addr = state.sip.listen_addr;
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!(error = %err, %addr, "failed to bind sip tcp listener");
+            return;
+        }
+    };
+    tracing::info!(%addr, "sip tcp listener starting");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_connection(state.clone(), stream));
+            }
+            Err(err) => tracing::debug!(error = %err, "sip tcp accept failed"),
+        }
+    }
+}
+
+async fn handle_connection(state: AppState, stream: TcpStream) {
+    let (mut reader, mut writer) = stream.into_split();
+    let host = state.sip.public_host.clone();
+    let mut adapter = Adapter::new(state.clone(), "TCP", host, None);
+    let mut bucket = state.limits.connection_bucket();
+    let mut buf: Vec<u8> = Vec::with_capacity(4096);
+
+    'connection: loop {
+        let deadline = adapter.next_deadline();
+        tokio::select! {
+            read = reader.read_buf(&mut buf) => {
+                if !matches!(read, Ok(n) if n > 0) {
+                    break;
+                }
+                loop {
+                    // Blank lines between messages are keep-alives; a double
+                    // CRLF ping is answered with a single CRLF (RFC 5626).
+                    let blank = buf.iter().take_while(|b| matches!(b, b'\r' | b'\n')).count();
+                    if blank >= 4 && writer.write_all(b"\r\n").await.is_err() {
+                        break 'connection;
+                    }
+                    buf.drain(..blank);
+
+                    let len = match ::sip::frame_len(&buf) {
+                        Ok(Some(len)) => len,
+                        Ok(None) if buf.len() > state.limits.max_frame_bytes => {
+                            state.limits.record(Rejection::FrameTooLarge);
+                            break 'connection;
+                        }
+                        Ok(None) => break,
+                        // Without a usable Content-Length the stream cannot
+                        // be resynchronised.
+                        Err(err) => {
+                            tracing::debug!(error = %err, "invalid sip stream");
+                            break 'connection;
+                        }
+                    };
+                    // Refused on its head, before waiting for the body.
+                    if len > state.limits.max_frame_bytes {
+                        state.limits.record(Rejection::FrameTooLarge);
+                        break 'connection;
+                    }
+                    if buf.len() < len {
+                        break;
+                    }
+                    let raw: Vec<u8> = buf.drain(..len).collect();
+                    if !bucket.try_take() {
+                        state.limits.record(Rejection::ConnectionRate);
+                        break 'connection;
+                    }
+                    if let Some(tenant_id) = adapter.tenant_id() {
+                        if !state.limits.take_tenant(tenant_id) {
+                            state.limits.record(Rejection::TenantRate);
+                            continue;
+                        }
+                    }
+                    match Message::parse(&raw) {
+                        Ok(msg) => adapter.on_message(msg).await,
+                        Err(err) => tracing::debug!(error = %err, "invalid sip message"),
+                    }
+                }
+            }
+            Some(event) = adapter.next_event() => adapter.on_event(event).await,
+            () = sleep_until(deadline), if deadline.is_some() => adapter.on_timer(),
+        }
+
+        for msg in adapter.drain() {
+            if writer.write_all(msg.to_string().as_bytes()).await.is_err() {
+                break 'connection;
+            }
+        }
+    }
+
+    adapter.close().await;
+}
@@ -0,0 +1,417 @@
+//! SIP over UDP (RFC 3261 section 18).
+//!
+//! One socket serves every phone. Datagrams are demultiplexed by source
+//! address into flows, each driving its own [`Adapter`] as if it were a
+//! connection. Replies always go back to the address a flow came from, which
+//! keeps phones behind NAT reachable for as long as they keep refreshing
+//! their registration.
+//!
+//! UDP loses messages, so each flow carries the part of the transaction layer
+//! the adapter relies on: our requests and our final responses to INVITE are
+//! retransmitted with backoff until answered or acknowledged, and requests
+//! the phone retransmits are answered from a response cache instead of being
+//! processed twice.
+//!
+//! A source address is all it takes to open a flow, and source addresses are
+//! easily forged, so only `SIP_UDP_MAX_FLOWS` flows of phones that have not
+//! registered are served at a time; datagrams opening any more are dropped.
+
+use super::adapter::Adapter;
+use super::sleep_until;
+use crate::limits::{Rejection, TokenBucket};
+use crate::AppState;
+use ::sip::{Message, Method};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Round-trip estimate and backoff cap (RFC 3261 section 17.1.1.1).
+const T1: Duration = Duration::from_millis(500);
+const T2: Duration = Duration::from_secs(4);
+
+/// Lifetime of a transaction, 64*T1. Also how long an unregistered flow
+/// without calls lingers after its last datagram.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(32);
+
+/// Datagrams queued for one flow before further ones are dropped.
+const FLOW_BUFFER: usize = 32;
+
+/// How often a flow checks whether it is still needed.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the listener forgets flows that wound down.
+const FLOW_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Call-ID, CSeq number and CSeq method.
+type TransactionKey = (String, u32, Method);
+
+fn key_of(msg: &Message) -> Option<TransactionKey> {
+    let (headers, cseq) = match msg {
+        Message::Request(req) => (&req.headers, req.cseq()?),
+        Message::Response(res) => (&res.headers, res.cseq()?),
+    };
+    Some((headers.get("Call-ID")?.to_string(), cseq.seq, cseq.method))
+}
+
+/// A message we keep resending until the phone reacts to it.
+struct Retransmit {
+    key: TransactionKey,
+    raw: String,
+    /// A response waiting for its ACK rather than a request waiting for a
+    /// response.
+    awaits_ack: bool,
+    interval: Duration,
+    next: Instant,
+    give_up: Instant,
+}
+
+impl Retransmit {
+    fn new(key: TransactionKey, raw: String, awaits_ack: bool) -> Self {
+        let now = Instant::now();
+        Retransmit {
+            key,
+            raw,
+            awaits_ack,
+            interval: T1,
+            next: now + T1,
+            give_up: now + TRANSACTION_TIMEOUT,
+        }
+    }
+}
+
+/// Retransmission and duplicate suppression for one flow.
+#[derive(Default)]
+struct Transactions {
+    /// Latest response sent per server transaction, until it expires.
+    responses: HashMap<TransactionKey, (String, Instant)>,
+    retransmits: Vec<Retransmit>,
+}
+
+impl Transactions {
+    /// Note an inbound message. Returns the response to resend when it is a
+    /// retransmitted request that must not reach the adapter again.
+    fn inbound(&mut self, msg: &Message) -> Option<String> {
+        let key = key_of(msg)?;
+        match msg {
+            Message::Request(req) if req.method == Method::Ack => {
+                let invite = (key.0, key.1, Method::Invite);
+                self.retransmits
+                    .retain(|r| !(r.awaits_ack && r.key == invite));
+                None
+            }
+            Message::Request(_) => self.responses.get(&key).map(|(raw, _)| raw.clone()),
+            Message::Response(_) => {
+                self.retransmits.retain(|r| r.awaits_ack || r.key != key);
+                None
+            }
+        }
+    }
+
+    /// Note an outbound message and return its wire form.
+    fn outbound(&mut self, msg: &Message) -> String {
+        let raw = msg.to_string();
+        let Some(key) = key_of(msg) else {
+            return raw;
+        };
+        match msg {
+            Message::Request(req) if req.method == Method::Ack => {}
+            Message::Request(_) => {
+                self.retransmits
+                    .push(Retransmit::new(key, raw.clone(), false));
+            }
+            Message::Response(res) => {
+                let expires = Instant::now() + TRANSACTION_TIMEOUT;
+                if res.status >= 200 && key.2 == Method::Invite {
+                    self.retransmits
+                        .push(Retransmit::new(key.clone(), raw.clone(), true));
+                }
+                self.responses.insert(key, (raw.clone(), expires));
+            }
+        }
+        raw
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.retransmits.iter().map(|r| r.next).min()
+    }
+
+    /// Messages due for another copy, doubling each one's interval up to T2.
+    fn due(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        self.retransmits.retain(|r| r.give_up > now);
+        let mut due = Vec::new();
+        for retransmit in &mut self.retransmits {
+            if retransmit.next <= now {
+                retransmit.interval = (retransmit.interval * 2).min(T2);
+                retransmit.next = now + retransmit.interval;
+                due.push(retransmit.raw.clone());
+            }
+        }
+        due
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.responses.retain(|_, (_, expires)| *expires > now);
+        self.retransmits.retain(|r| r.give_up > now);
+    }
+
+    fn is_idle(&self) -> bool {
+        self.responses.is_empty() && self.retransmits.is_empty()
+    }
+}
+
+/// Bind the UDP listener and feed every datagram into its flow.
+pub async fn serve_udp(state: AppState) {
+    let addr = state.sip.listen_addr;
+    let socket = match UdpSocket::bind(addr).await {
+        Ok(socket) => Arc::new(socket),
+        Err(err) => {
+            tracing::error!(error = %err, %addr, "failed to bind sip udp listener");
+            return;
+        }
+    };
+    tracing::info!(%addr, "sip udp listener starting");
+
+    let mut flows: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let unregistered = Arc::new(Semaphore::new(state.sip.udp_max_flows));
+    let mut sweep = tokio::time::interval(FLOW_SWEEP_INTERVAL);
+    let mut buf = vec![0u8; 65_535];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = sweep.tick() => {
+                flows.retain(|_, tx| !tx.is_closed());
+                continue;
+            }
+        };
+        let (len, peer) = match received {
+            Ok(received) => received,
+            Err(err) => {
+                tracing::debug!(error = %err, "sip udp receive failed");
+                continue;
+            }
+        };
+        if len > state.limits.max_frame_bytes {
+            state.limits.record(Rejection::FrameTooLarge);
+            continue;
+        }
+        let datagram = match flows.get(&peer) {
+            None => buf[..len].to_vec(),
+            Some(tx) => match tx.try_send(buf[..len].to_vec()) {
+                // A flow that is not keeping up loses the datagram, as UDP may.
+                Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => continue,
+                // The flow wound down; start a fresh one for this peer.
+                Err(mpsc::error::TrySendError::Closed(datagram)) => datagram,
+            },
+        };
+        let Ok(permit) = unregistered.clone().try_acquire_owned() else {
+            state.limits.record(Rejection::SipFlows);
+            continue;
+        };
+        let (tx, rx) = mpsc::channel(FLOW_BUFFER);
+        let _ = tx.try_send(datagram);
+        flows.insert(peer, tx);
+        tokio::spawn(run_flow(state.clone(), socket.clone(), peer, rx, permit));
+    }
+}
+
+/// Serve one phone, identified by its source address, until it is no longer
+/// registered, has no calls and has gone quiet. The flow counts against the
+/// cap on unregistered flows, through `permit`, until the phone registers.
+async fn run_flow(
+    state: AppState,
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    permit: OwnedSemaphorePermit,
+) {
+    let mut permit = Some(permit);
+    let host = state.sip.public_host.clone();
+    let mut adapter = Adapter::new(state.clone(), "UDP", host, None);
+    let mut transactions = Transactions::default();
+    let mut bucket = state.limits.connection_bucket();
+    let mut last_heard = Instant::now();
+    let mut housekeeping = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+
+    loop {
+        let deadline = [adapter.next_deadline(), transactions.next_deadline()]
+            .into_iter()
+            .flatten()
+            .min();
+        tokio::select! {
+            datagram = rx.recv() => {
+                let Some(raw) = datagram else { break };
+                last_heard = Instant::now();
+                if !admit(&state, &mut bucket, adapter.tenant_id()) {
+                    continue;
+                }
+                match Message::parse(&raw) {
+                    Ok(msg) => match transactions.inbound(&msg) {
+                        Some(cached) => send(&socket, peer, &cached).await,
+                        None => adapter.on_message(msg).await,
+                    },
+                    // Unparseable messages are dropped (RFC 3261 section 16.3).
+                    Err(err) => tracing::debug!(error = %err, %peer, "invalid sip message"),
+                }
+            }
+            Some(event) = adapter.next_event() => adapter.on_event(event).await,
+            () = sleep_until(deadline), if deadline.is_some() => {
+                adapter.on_timer();
+                for raw in transactions.due() {
+                    send(&socket, peer, &raw).await;
+                }
+            }
+            _ = housekeeping.tick() => {
+                transactions.expire();
+                let now = Instant::now();
+                let registered = adapter.registered_until().is_some_and(|until| until > now);
+                let quiet = now.duration_since(last_heard) >= TRANSACTION_TIMEOUT;
+                if !registered && !adapter.has_calls() && transactions.is_idle() && quiet {
+                    break;
+                }
+            }
+        }
+
+        for msg in adapter.drain() {
+            let raw = transactions.outbound(&msg);
+            send(&socket, peer, &raw).await;
+        }
+        if permit.is_some()
+            && adapter
+                .registered_until()
+                .is_some_and(|until| until > Instant::now())
+        {
+            permit = None;
+        }
+    }
+
+    adapter.close().await;
+}
+
+/// Apply the websocket limits to a datagram. There is no connection to
+/// close, so anything over budget is simply dropped.
+fn admit(state: &AppState, bucket: &mut TokenBucket, tenant_id: Option<uuid::Uuid>) -> bool {
+    if !bucket.try_take() {
+        state.limits.record(Rejection::ConnectionRate);
+        return false;
+    }
+    if tenant_id.is_some_and(|tenant_id| !state.limits.take_tenant(tenant_id)) {
+        state.limits.record(Rejection::TenantRate);
+        return false;
+    }
+    true
+}
+
+async fn send(socket: &UdpSocket, peer: SocketAddr, raw: &str) {
+    if let Err(err) = socket.send_to(raw.as_bytes(), peer).await {
+        tracing::debug!(error = %err, %peer, "sip udp send failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::sip::Response;
+
+    fn request(method: &str, seq: u32) -> Message {
+        format!(
+            "{method} sip:alice@sip.example.com SIP/2.0\r\n\
+             Via: SIP/2.0/UDP 10.0.0.31:5060;branch=z9hG4bK{method}{seq}\r\n\
+             Max-Forwards: 70\r\n\
+             From: <sip:bob@sip.example.com>;tag=1\r\n\
+             To: <sip:alice@sip.example.com>\r\n\
+             Call-ID: call-1\r\n\
+             CSeq: {seq} {method}\r\n\
+             Content-Length: 0\r\n\r\n"
+        )
+        .parse()
+        .unwrap()
+    }
+
+    fn response(req: &Message, status: u16) -> Message {
+        let Message::Request(req) = req else {
+            panic!("not a request");
+        };
+        Message::Response(Response::to(req, status, "Whatever"))
+    }
+
+    /// Make every pending retransmission due now.
+    fn elapse(transactions: &mut Transactions) {
+        for retransmit in &mut transactions.retransmits {
+            retransmit.next = Instant::now();
+        }
+    }
+
+    #[test]
+    fn retransmits_with_doubling_intervals_up_to_t2() {
+        let mut transactions = Transactions::default();
+        let raw = transactions.outbound(&request("OPTIONS", 1));
+        assert!(transactions.due().is_empty());
+        let mut intervals = Vec::new();
+        for _ in 0..5 {
+            elapse(&mut transactions);
+            assert_eq!(transactions.due(), std::slice::from_ref(&raw));
+            intervals.push(transactions.retransmits[0].interval);
+        }
+        let expected = [1000, 2000, 4000, 4000, 4000].map(Duration::from_millis);
+        assert_eq!(intervals, expected);
+
+        // A retransmission past the transaction timeout is dropped.
+        transactions.retransmits[0].give_up = Instant::now();
+        assert!(transactions.due().is_empty());
+        assert!(transactions.is_idle());
+    }
+
+    #[test]
+    fn a_response_stops_the_request_retransmission() {
+        let mut transactions = Transactions::default();
+        let options = request("OPTIONS", 1);
+        transactions.outbound(&options);
+        transactions.outbound(&request("OPTIONS", 2));
+        assert_eq!(transactions.inbound(&response(&options, 200)), None);
+        assert_eq!(transactions.retransmits.len(), 1);
+        assert_eq!(transactions.retransmits[0].key.1, 2);
+    }
+
+    #[test]
+    fn an_ack_stops_the_final_invite_response() {
+        let mut transactions = Transactions::default();
+        let invite = request("INVITE", 1);
+        transactions.outbound(&response(&invite, 180));
+        assert!(transactions.retransmits.is_empty());
+        transactions.outbound(&response(&invite, 200));
+        assert_eq!(transactions.retransmits.len(), 1);
+
+        // An ACK for another transaction leaves it alone.
+        transactions.inbound(&request("ACK", 2));
+        assert_eq!(transactions.retransmits.len(), 1);
+        assert_eq!(transactions.inbound(&request("ACK", 1)), None);
+        assert!(transactions.retransmits.is_empty());
+    }
+
+    #[test]
+    fn retransmitted_requests_get_the_cached_response() {
+        let mut transactions = Transactions::default();
+        let register = request("REGISTER", 1);
+        assert_eq!(transactions.inbound(&register), None);
+        let raw = transactions.outbound(&response(&register, 200));
+        assert_eq!(transactions.inbound(&register), Some(raw));
+        // The next request of the dialog is new.
+        assert_eq!(transactions.inbound(&request("REGISTER", 2)), None);
+
+        transactions
+            .responses
+            .values_mut()
+            .for_each(|(_, expires)| {
+                *expires = Instant::now();
+            });
+        transactions.expire();
+        assert_eq!(transactions.inbound(&register), None);
+        assert!(transactions.is_idle());
+    }
+}
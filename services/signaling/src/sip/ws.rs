@@ -0,0 +1,136 @@
+//! SIP over WebSocket transport (RFC 7118).
+//!
+//! Each websocket message carries exactly one SIP message, so there is no
+//! stream framing to do. Clients must negotiate the `sip` subprotocol. They
+//! authenticate either with a bearer token on the upgrade request, like the
+//! JSON route, or with an `Authorization` header (bearer or digest) on their
+//! first REGISTER.
+
+use super::adapter::Adapter;
+use super::sleep_until;
+use crate::limits::Rejection;
+use crate::AppState;
+use ::sip::Message as SipMessage;
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct SipParams {
+    token: Option<String>,
+}
+
+/// Upgrade `/sip`, refusing clients that did not offer the `sip` subprotocol.
+pub async fn sip_handler(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(params): Query<SipParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let offers_sip = headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|proto| proto.trim().eq_ignore_ascii_case("sip"))
+        });
+    if !offers_sip {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let token = crate::bearer_token(&headers).or(params.token);
+    let claims = match token {
+        Some(token) => match state.auth.verify(&token).await {
+            Ok(claims) => Some(claims),
+            Err(err) => {
+                tracing::warn!(error = %err, "jwt verification failed");
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+        },
+        None => None,
+    };
+
+    let ceiling = state.limits.max_frame_bytes.saturating_mul(4);
+    ws.protocols(["sip"])
+        .max_message_size(ceiling)
+        .max_frame_size(ceiling)
+        .on_upgrade(move |socket| handle_socket(socket, state, claims))
+}
+
+async fn handle_socket(mut socket: WebSocket, state: AppState, claims: Option<dto::AuthClaims>) {
+    // RFC 7118 section 5.2: a random host in the .invalid domain, since the
+    // client cannot reach us by address anyway.
+    let host = format!(
+        "{}.invalid",
+        &uuid::Uuid::new_v4().simple().to_string()[..12]
+    );
+    let mut adapter = Adapter::new(state.clone(), "WSS", host, claims);
+    let mut bucket = state.limits.connection_bucket();
+
+    loop {
+        let deadline = adapter.next_deadline();
+        tokio::select! {
+            inbound = socket.recv() => {
+                let Some(Ok(frame)) = inbound else { break };
+                let raw = match frame {
+                    Message::Text(text) => text.into_bytes(),
+                    Message::Binary(bytes) => bytes,
+                    Message::Ping(payload) => {
+                        let _ = socket.send(Message::Pong(payload)).await;
+                        continue;
+                    }
+                    Message::Close(_) => break,
+                    Message::Pong(_) => continue,
+                };
+                let rejection = if raw.len() > state.limits.max_frame_bytes {
+                    Some(Rejection::FrameTooLarge)
+                } else if !bucket.try_take() {
+                    Some(Rejection::ConnectionRate)
+                } else if adapter
+                    .tenant_id()
+                    .is_some_and(|tenant_id| !state.limits.take_tenant(tenant_id))
+                {
+                    Some(Rejection::TenantRate)
+                } else {
+                    None
+                };
+                if let Some(rejection) = rejection {
+                    state.limits.record(rejection);
+                    match rejection.close_frame() {
+                        Some((code, reason)) => {
+                            close(&mut socket, code, reason).await;
+                            break;
+                        }
+                        None => continue,
+                    }
+                }
+                match SipMessage::parse(&raw) {
+                    Ok(msg) => adapter.on_message(msg).await,
+                    // Unparseable messages are dropped (RFC 3261 section 16.3).
+                    Err(err) => tracing::debug!(error = %err, "invalid sip message"),
+                }
+            }
+            Some(event) = adapter.next_event() => adapter.on_event(event).await,
+            () = sleep_until(deadline), if deadline.is_some() => adapter.on_timer(),
+        }
+
+        for msg in adapter.drain() {
+            if socket.send(Message::Text(msg.to_string())).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    adapter.close().await;
+}
+
+async fn close(socket: &mut WebSocket, code: u16, reason: &'static str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
@@ -0,0 +1,384 @@
+//! Supervisors listening in on, whispering to, and barging into agents' calls.
+//!
+//! `call.supervise` is only accepted from tokens with a supervisor (or admin)
+//! role. The supervisor names the call, the agent on it, a mode and a fresh
+//! `legId`; its offer on that leg is answered by the call's media relay,
+//! which mixes the supervisor in as a third party (see the media service's
+//! `supervision` module for who hears whom). The answer comes with a
+//! `call.relay` for the leg, whose secret binds the supervisor's media
+//! address. Calls whose media does not run through the relay cannot be
+//! supervised.
+//!
+//! Merely monitoring is silent; the agent is told when a supervisor starts
+//! whispering or barges in, and when it stops. A call has at most one
+//! supervisor, and the supervision lives on the node owning the call; the
+//! leg ends with the call.
+
+use crate::calls::{self, Endpoint};
+use crate::protocol::{ClientMessage, EndReason, ErrorCode, ServerMessage, SuperviseMode};
+use crate::AppState;
+use dto::Role;
+use serde_json::json;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Supervisor legs on calls owned by this node.
+#[derive(Debug, Default)]
+pub struct Supervisions {
+    legs: HashMap<Uuid, Leg>,
+    /// Leg supervising each call.
+    by_call: HashMap<Uuid, Uuid>,
+}
+
+impl Supervisions {
+    /// Whether `id` is a supervisor leg on this node.
+    pub fn contains(&self, id: Uuid) -> bool {
+        self.legs.contains_key(&id)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Leg {
+    call_id: Uuid,
+    supervisor: Endpoint,
+    agent: Endpoint,
+    /// Relay side (`a` or `b`) the agent's media is on.
+    agent_side: &'static str,
+    mode: SuperviseMode,
+}
+
+async fn refuse(state: &AppState, to: Endpoint, call_id: Uuid, code: ErrorCode, message: &str) {
+    let err = ServerMessage::error(Some(call_id), code, message);
+    state.cluster.send_to(to, err).await;
+}
+
+/// Tell the supervisor, and the agent unless it is only monitored now and
+/// was before, that the supervision of a call changed.
+async fn announce(state: &AppState, leg: &Leg, mode: Option<SuperviseMode>, was: SuperviseMode) {
+    let msg = ServerMessage::Supervised {
+        call_id: leg.call_id,
+        supervisor: leg.supervisor.user_id,
+        mode,
+    };
+    let heard = |mode: SuperviseMode| mode != SuperviseMode::Monitor;
+    if mode.is_some_and(heard) || heard(was) {
+        state.cluster.send_to(leg.agent, msg.clone()).await;
+    }
+    if mode.is_some() {
+        state.cluster.send_to(leg.supervisor, msg).await;
+    }
+}
+
+/// The refusal of `msg` from a token with `role`, when it is a
+/// `call.supervise` and the role may not supervise.
+pub fn refusal(role: Role, msg: &ClientMessage) -> Option<ServerMessage> {
+    match msg {
+        ClientMessage::Supervise { call_id, .. } if !role.can_supervise() => {
+            let message = "only supervisors may supervise calls";
+            Some(ServerMessage::error(
+                Some(*call_id),
+                ErrorCode::NotAllowed,
+                message,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Join `from` to `call_id` next to `agent_id` over `leg_id`, or switch the
+/// mode of the leg it already has there.
+pub async fn start(
+    state: &AppState,
+    from: Endpoint,
+    call_id: Uuid,
+    leg_id: Uuid,
+    agent_id: Uuid,
+    mode: SuperviseMode,
+) {
+    let (agent, agent_side) = match calls::supervised_agent(state, from, call_id, agent_id).await {
+        Ok(agent) => agent,
+        Err((code, message)) => {
+            refuse(state, from, leg_id, code, message).await;
+            return;
+        }
+    };
+
+    // `None` refuses the frame; `Some(None)` opened a new leg, and
+    // `Some(Some(was))` switched an existing one away from mode `was`.
+    let previous = {
+        let mut supervisions = state.supervisions.write().await;
+        match supervisions.by_call.get(&call_id).copied() {
+            Some(current) if current == leg_id => {
+                let leg = supervisions.legs.get_mut(&leg_id).expect("indexed leg");
+                if leg.supervisor != from {
+                    None
+                } else {
+                    let was = leg.mode;
+                    leg.mode = mode;
+                    Some(Some(was))
+                }
+            }
+            Some(_) => None,
+            None if supervisions.legs.contains_key(&leg_id)
+                || state.calls.read().await.contains_key(&leg_id) =>
+            {
+                drop(supervisions);
+                let message = "call id already in use";
+                refuse(state, from, leg_id, ErrorCode::DuplicateCall, message).await;
+                return;
+            }
+            None => {
+                let leg = Leg {
+                    call_id,
+                    supervisor: from,
+                    agent,
+                    agent_side,
+                    mode,
+                };
+                supervisions.legs.insert(leg_id, leg);
+                supervisions.by_call.insert(call_id, leg_id);
+                Some(None)
+            }
+        }
+    };
+    let Some(previous) = previous else {
+        let message = "the call is already supervised";
+        refuse(state, from, leg_id, ErrorCode::NotAllowed, message).await;
+        return;
+    };
+    tracing::info!(%call_id, %leg_id, mode = mode.as_str(), "supervision");
+
+    let leg = Leg {
+        call_id,
+        supervisor: from,
+        agent,
+        agent_side,
+        mode,
+    };
+    match previous {
+        Some(was) => {
+            if let Err(err) = state.media.set_supervisor_mode(call_id, mode).await {
+                tracing::warn!(%call_id, error = %err, "relay did not take the supervisor mode");
+            }
+            announce(state, &leg, Some(mode), was).await;
+        }
+        None => {
+            if !state.cluster.claim_call(leg_id).await {
+                let mut supervisions = state.supervisions.write().await;
+                supervisions.legs.remove(&leg_id);
+                supervisions.by_call.remove(&call_id);
+                drop(supervisions);
+                let message = "call id already in use";
+                refuse(state, from, leg_id, ErrorCode::DuplicateCall, message).await;
+                return;
+            }
+            let connected = ServerMessage::Connected { call_id: leg_id };
+            state.cluster.send_to(from, connected).await;
+            announce(state, &leg, Some(mode), SuperviseMode::Monitor).await;
+        }
+    }
+}
+
+/// Media for a supervisor leg: its offer goes to the call's relay. `false`
+/// when `leg_id` is not a leg of `from`.
+pub async fn signal(
+    state: &AppState,
+    from: Endpoint,
+    leg_id: Uuid,
+    data: &serde_json::Value,
+) -> bool {
+    let leg = state.supervisions.read().await.legs.get(&leg_id).copied();
+    let Some(leg) = leg.filter(|leg| leg.supervisor == from) else {
+        return false;
+    };
+    let offer = data
+        .get("sdp")
+        .and_then(|sdp| sdp.as_str())
+        .filter(|_| data.get("type").and_then(|t| t.as_str()) == Some("offer"));
+    let Some(offer) = offer else {
+        // The relay only speaks plain RTP; candidates have nowhere to go.
+        return true;
+    };
+
+    let joined = state
+        .media
+        .supervise(leg.call_id, leg_id, offer, leg.mode, leg.agent_side)
+        .await;
+    let refusal = match joined {
+        Ok(Some(joined)) => {
+            let msg = ServerMessage::Signal {
+                call_id: leg_id,
+                data: json!({ "type": "answer", "sdp": joined.sdp }),
+            };
+            state.cluster.send_to(from, msg).await;
+            state
+                .cluster
+                .send_to(from, joined.relay_message(leg_id))
+                .await;
+            return true;
+        }
+        Ok(None) => "the call's media does not pass through the relay",
+        Err(err) => {
+            tracing::warn!(call_id = %leg.call_id, error = %err, "relay refused supervisor");
+            "supervision unavailable"
+        }
+    };
+    end(state, leg_id).await;
+    refuse(state, from, leg_id, ErrorCode::Unavailable, refusal).await;
+    true
+}
+
+/// `from` hung up (or lost) its leg `leg_id`. `false` when it is not a leg
+/// of `from`.
+pub async fn hang_up(state: &AppState, from: Endpoint, leg_id: Uuid) -> bool {
+    let ours = state
+        .supervisions
+        .read()
+        .await
+        .legs
+        .get(&leg_id)
+        .is_some_and(|leg| leg.supervisor == from);
+    if ours {
+        end(state, leg_id).await;
+    }
+    ours
+}
+
+/// The supervised call `call_id` ended; so does its supervisor's leg.
+pub async fn call_ended(state: &AppState, call_id: Uuid) {
+    let leg_id = state
+        .supervisions
+        .read()
+        .await
+        .by_call
+        .get(&call_id)
+        .copied();
+    let Some(leg_id) = leg_id else {
+        return;
+    };
+    if let Some(leg) = end(state, leg_id).await {
+        let ended = ServerMessage::Ended {
+            call_id: leg_id,
+            reason: EndReason::Hangup,
+        };
+        state.cluster.send_to(leg.supervisor, ended).await;
+    }
+}
+
+async fn end(state: &AppState, leg_id: Uuid) -> Option<Leg> {
+    let leg = {
+        let mut supervisions = state.supervisions.write().await;
+        let leg = supervisions.legs.remove(&leg_id)?;
+        supervisions.by_call.remove(&leg.call_id);
+        leg
+    };
+    state.cluster.release_call(leg_id).await;
+    if let Err(err) = state.media.end_supervision(leg.call_id).await {
+        tracing::warn!(call_id = %leg.call_id, error = %err, "failed to drop supervisor leg");
+    }
+    tracing::info!(call_id = %leg.call_id, %leg_id, "supervision ended");
+    announce(state, &leg, None, leg.mode).await;
+    Some(leg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calls::tests::{connect, online};
+    use crate::session::Session;
+
+    fn supervise(call_id: Uuid) -> ClientMessage {
+        ClientMessage::Supervise {
+            call_id,
+            leg_id: Uuid::new_v4(),
+            agent_id: Uuid::new_v4(),
+            mode: SuperviseMode::Monitor,
+        }
+    }
+
+    /// The code of the first error `session` was sent, if any.
+    fn refused(session: &mut Session) -> Option<ErrorCode> {
+        std::iter::from_fn(|| session.rx.try_recv().ok()).find_map(|msg| match msg {
+            ServerMessage::Error { code, .. } => Some(code),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn only_supervisors_and_admins_may_supervise() {
+        let call_id = Uuid::new_v4();
+        for role in [Role::Admin, Role::Supervisor] {
+            assert!(refusal(role, &supervise(call_id)).is_none(), "{role:?}");
+        }
+        let refused = refusal(Role::Agent, &supervise(call_id));
+        assert!(matches!(
+            refused,
+            Some(ServerMessage::Error { call_id: Some(id), code: ErrorCode::NotAllowed, .. })
+                if id == call_id
+        ));
+        // Everything else is up to the call's own checks.
+        let answer = ClientMessage::Answer { call_id };
+        assert!(refusal(Role::Agent, &answer).is_none());
+    }
+
+    #[tokio::test]
+    async fn a_call_takes_one_supervisor_from_outside_it() {
+        let state = AppState::for_tests();
+        let tenant_id = Uuid::new_v4();
+        let customer = online(&state, tenant_id).await;
+        let mut agent = online(&state, tenant_id).await;
+        let mut supervisor = online(&state, tenant_id).await;
+        let mut second = online(&state, tenant_id).await;
+        let mut stranger = online(&state, Uuid::new_v4()).await;
+        let call_id = connect(&state, customer.endpoint, agent.endpoint).await;
+        let agent_id = agent.endpoint.user_id;
+        let monitor = SuperviseMode::Monitor;
+
+        // Nobody supervises their own call, or another tenant's.
+        start(
+            &state,
+            agent.endpoint,
+            call_id,
+            Uuid::new_v4(),
+            agent_id,
+            monitor,
+        )
+        .await;
+        assert_eq!(refused(&mut agent), Some(ErrorCode::NotAllowed));
+        start(
+            &state,
+            stranger.endpoint,
+            call_id,
+            Uuid::new_v4(),
+            agent_id,
+            monitor,
+        )
+        .await;
+        assert_eq!(refused(&mut stranger), Some(ErrorCode::UnknownCall));
+
+        let leg_id = Uuid::new_v4();
+        start(
+            &state,
+            supervisor.endpoint,
+            call_id,
+            leg_id,
+            agent_id,
+            monitor,
+        )
+        .await;
+        assert_eq!(refused(&mut supervisor), None);
+        assert!(state.supervisions.read().await.contains(leg_id));
+
+        start(
+            &state,
+            second.endpoint,
+            call_id,
+            Uuid::new_v4(),
+            agent_id,
+            monitor,
+        )
+        .await;
+        assert_eq!(refused(&mut second), Some(ErrorCode::NotAllowed));
+        assert_eq!(state.supervisions.read().await.legs.len(), 1);
+    }
+}
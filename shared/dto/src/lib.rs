@@ -13,4 +13,25 @@ pub struct AuthClaims {
     pub tenant_id: Uuid,
     pub exp: usize,
     pub iat: usize,
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// What a user may do beyond their own calls.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Supervisor,
+    /// Also what tokens without a role, or with one we do not know, get.
+    #[default]
+    #[serde(other)]
+    Agent,
+}
+
+impl Role {
+    /// Whether the user may listen in on, and join, other agents' calls.
+    pub fn can_supervise(self) -> bool {
+        matches!(self, Role::Admin | Role::Supervisor)
+    }
 }
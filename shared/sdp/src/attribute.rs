@@ -0,0 +1,507 @@
+//! Typed views of the attributes negotiation depends on.
+
+use crate::session::ParseError;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Media direction (RFC 8866 section 6.7).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    #[default]
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::SendRecv => "sendrecv",
+            Direction::SendOnly => "sendonly",
+            Direction::RecvOnly => "recvonly",
+            Direction::Inactive => "inactive",
+        }
+    }
+
+    pub fn from_attribute(name: &str) -> Option<Self> {
+        match name {
+            "sendrecv" => Some(Direction::SendRecv),
+            "sendonly" => Some(Direction::SendOnly),
+            "recvonly" => Some(Direction::RecvOnly),
+            "inactive" => Some(Direction::Inactive),
+            _ => None,
+        }
+    }
+
+    pub fn sends(self) -> bool {
+        matches!(self, Direction::SendRecv | Direction::SendOnly)
+    }
+
+    pub fn receives(self) -> bool {
+        matches!(self, Direction::SendRecv | Direction::RecvOnly)
+    }
+
+    /// The direction that sends and receives as given.
+    pub fn from_flags(sends: bool, receives: bool) -> Self {
+        match (sends, receives) {
+            (true, true) => Direction::SendRecv,
+            (true, false) => Direction::SendOnly,
+            (false, true) => Direction::RecvOnly,
+            (false, false) => Direction::Inactive,
+        }
+    }
+
+    /// The same stream seen from the other end.
+    pub fn reverse(self) -> Self {
+        Direction::from_flags(self.receives(), self.sends())
+    }
+
+    /// The direction to answer an offer in when this side wants `local`
+    /// (RFC 3264 section 6.1): we only send what the offerer will receive
+    /// and only receive what it will send.
+    pub fn answer(self, local: Direction) -> Self {
+        Direction::from_flags(
+            self.receives() && local.sends(),
+            self.sends() && local.receives(),
+        )
+    }
+}
+
+/// `a=rtpmap:<payload> <encoding>/<clock rate>[/<channels>]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpMap {
+    pub payload: u8,
+    pub encoding: String,
+    pub clock_rate: u32,
+    pub channels: Option<u16>,
+}
+
+impl FromStr for RtpMap {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::Attribute("rtpmap");
+        let (payload, encoding) = s.split_once(' ').ok_or_else(invalid)?;
+        let mut parts = encoding.trim().split('/');
+        let (Some(encoding), Some(clock_rate)) = (parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let channels = parts
+            .next()
+            .map(|channels| channels.parse().map_err(|_| invalid()))
+            .transpose()?;
+        if encoding.is_empty() || parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(RtpMap {
+            payload: payload.parse().map_err(|_| invalid())?,
+            encoding: encoding.to_string(),
+            clock_rate: clock_rate.parse().map_err(|_| invalid())?,
+            channels,
+        })
+    }
+}
+
+impl fmt::Display for RtpMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}/{}", self.payload, self.encoding, self.clock_rate)?;
+        if let Some(channels) = self.channels {
+            write!(f, "/{channels}")?;
+        }
+        Ok(())
+    }
+}
+
+/// `a=fmtp:<payload> <format parameters>`, the parameters kept as written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fmtp {
+    pub payload: u8,
+    pub params: String,
+}
+
+impl FromStr for Fmtp {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (payload, params) = s.split_once(' ').ok_or(ParseError::Attribute("fmtp"))?;
+        Ok(Fmtp {
+            payload: payload.parse().map_err(|_| ParseError::Attribute("fmtp"))?,
+            params: params.trim().to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Fmtp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.payload, self.params)
+    }
+}
+
+/// An RTP codec as offered in a media section: its rtpmap and fmtp combined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Codec {
+    pub payload: u8,
+    pub name: String,
+    pub clock_rate: u32,
+    pub channels: Option<u16>,
+    pub fmtp: Option<String>,
+}
+
+impl Codec {
+    pub fn new(payload: u8, name: &str, clock_rate: u32) -> Self {
+        Codec {
+            payload,
+            name: name.to_string(),
+            clock_rate,
+            channels: None,
+            fmtp: None,
+        }
+    }
+
+    /// The RFC 3551 definition of a static payload type.
+    pub fn static_payload(payload: u8) -> Option<Self> {
+        let (name, clock_rate) = match payload {
+            0 => ("PCMU", 8000),
+            3 => ("GSM", 8000),
+            4 => ("G723", 8000),
+            8 => ("PCMA", 8000),
+            9 => ("G722", 8000),
+            13 => ("CN", 8000),
+            18 => ("G729", 8000),
+            26 => ("JPEG", 90000),
+            31 => ("H261", 90000),
+            34 => ("H263", 90000),
+            _ => return None,
+        };
+        Some(Codec::new(payload, name, clock_rate))
+    }
+
+    /// Same encoding, regardless of payload number and format parameters.
+    /// Audio codecs without a channel count are mono.
+    pub fn matches(&self, other: &Codec) -> bool {
+        self.name.eq_ignore_ascii_case(&other.name)
+            && self.clock_rate == other.clock_rate
+            && self.channels.unwrap_or(1) == other.channels.unwrap_or(1)
+    }
+
+    pub fn rtpmap(&self) -> RtpMap {
+        RtpMap {
+            payload: self.payload,
+            encoding: self.name.clone(),
+            clock_rate: self.clock_rate,
+            channels: self.channels,
+        }
+    }
+}
+
+impl From<RtpMap> for Codec {
+    fn from(map: RtpMap) -> Self {
+        Codec {
+            payload: map.payload,
+            name: map.encoding,
+            clock_rate: map.clock_rate,
+            channels: map.channels,
+            fmtp: None,
+        }
+    }
+}
+
+/// `a=candidate:` (RFC 8839 section 5.1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub foundation: String,
+    pub component: u16,
+    pub transport: String,
+    pub priority: u32,
+    pub address: String,
+    pub port: u16,
+    /// `host`, `srflx`, `prflx` or `relay`.
+    pub kind: String,
+    pub related_address: Option<String>,
+    pub related_port: Option<u16>,
+    /// Extension attributes such as `generation 0` or `ufrag abcd`.
+    pub extensions: Vec<(String, String)>,
+}
+
+impl Candidate {
+    /// A UDP host candidate with the highest type preference.
+    pub fn host(component: u16, address: IpAddr, port: u16) -> Self {
+        Candidate {
+            foundation: "1".to_string(),
+            component,
+            transport: "udp".to_string(),
+            priority: (126 << 24) | (65535 << 8) | (256 - u32::from(component)),
+            address: address.to_string(),
+            port,
+            kind: "host".to_string(),
+            related_address: None,
+            related_port: None,
+            extensions: Vec::new(),
+        }
+    }
+}
+
+/// Accepts the attribute value with or without the `candidate:` prefix that
+/// trickled candidates carry.
+impl FromStr for Candidate {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::Attribute("candidate");
+        let s = s.trim();
+        let s = s.strip_prefix("a=").unwrap_or(s);
+        let s = s.strip_prefix("candidate:").unwrap_or(s);
+        let mut fields = s.split_ascii_whitespace();
+        let mut next = || fields.next().ok_or_else(invalid);
+        let foundation = next()?.to_string();
+        let component = next()?.parse().map_err(|_| invalid())?;
+        let transport = next()?.to_string();
+        let priority = next()?.parse().map_err(|_| invalid())?;
+        let address = next()?.to_string();
+        let port = next()?.parse().map_err(|_| invalid())?;
+        if next()? != "typ" {
+            return Err(invalid());
+        }
+        let kind = next()?.to_string();
+        let mut candidate = Candidate {
+            foundation,
+            component,
+            transport,
+            priority,
+            address,
+            port,
+            kind,
+            related_address: None,
+            related_port: None,
+            extensions: Vec::new(),
+        };
+        while let Some(name) = fields.next() {
+            let value = fields.next().ok_or_else(invalid)?;
+            match name {
+                "raddr" => candidate.related_address = Some(value.to_string()),
+                "rport" => candidate.related_port = Some(value.parse().map_err(|_| invalid())?),
+                _ => candidate
+                    .extensions
+                    .push((name.to_string(), value.to_string())),
+            }
+        }
+        Ok(candidate)
+    }
+}
+
+/// The attribute value, without the `candidate:` prefix.
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {} typ {}",
+            self.foundation,
+            self.component,
+            self.transport,
+            self.priority,
+            self.address,
+            self.port,
+            self.kind
+        )?;
+        if let Some(address) = &self.related_address {
+            write!(f, " raddr {address}")?;
+        }
+        if let Some(port) = self.related_port {
+            write!(f, " rport {port}")?;
+        }
+        for (name, value) in &self.extensions {
+            write!(f, " {name} {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// `a=fingerprint:<hash function> <hex pairs>` (RFC 8122 section 5).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub hash: String,
+    pub value: String,
+}
+
+impl FromStr for Fingerprint {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::Attribute("fingerprint");
+        let (hash, value) = s.trim().split_once(' ').ok_or_else(invalid)?;
+        let value_ok = value
+            .split(':')
+            .all(|pair| pair.len() == 2 && pair.bytes().all(|b| b.is_ascii_hexdigit()));
+        if hash.is_empty() || !value_ok {
+            return Err(invalid());
+        }
+        Ok(Fingerprint {
+            hash: hash.to_ascii_lowercase(),
+            value: value.to_ascii_uppercase(),
+        })
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.hash, self.value)
+    }
+}
+
+/// `a=group:<semantics> <mid>...` (RFC 5888), e.g. `BUNDLE 0 1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub semantics: String,
+    pub mids: Vec<String>,
+}
+
+impl FromStr for Group {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_ascii_whitespace();
+        let semantics = fields.next().ok_or(ParseError::Attribute("group"))?;
+        Ok(Group {
+            semantics: semantics.to_string(),
+            mids: fields.map(str::to_string).collect(),
+        })
+    }
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.semantics)?;
+        for mid in &self.mids {
+            write!(f, " {mid}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIRECTIONS: [Direction; 4] = [
+        Direction::SendRecv,
+        Direction::SendOnly,
+        Direction::RecvOnly,
+        Direction::Inactive,
+    ];
+
+    #[test]
+    fn directions_from_flags() {
+        assert_eq!(Direction::from_flags(true, true), Direction::SendRecv);
+        assert_eq!(Direction::from_flags(true, false), Direction::SendOnly);
+        assert_eq!(Direction::from_flags(false, true), Direction::RecvOnly);
+        assert_eq!(Direction::from_flags(false, false), Direction::Inactive);
+        for direction in DIRECTIONS {
+            assert_eq!(
+                Direction::from_flags(direction.sends(), direction.receives()),
+                direction
+            );
+            assert_eq!(
+                Direction::from_attribute(direction.as_str()),
+                Some(direction)
+            );
+            assert_eq!(direction.reverse().reverse(), direction);
+        }
+        assert_eq!(Direction::SendOnly.reverse(), Direction::RecvOnly);
+        assert_eq!(Direction::from_attribute("rtcp-mux"), None);
+    }
+
+    #[test]
+    fn answers_mirror_the_offer() {
+        use Direction::*;
+        // (offered, wanted locally, answered) per RFC 3264 section 6.1.
+        let cases = [
+            (SendRecv, SendRecv, SendRecv),
+            (SendRecv, SendOnly, SendOnly),
+            (SendRecv, RecvOnly, RecvOnly),
+            (SendRecv, Inactive, Inactive),
+            (SendOnly, SendRecv, RecvOnly),
+            (SendOnly, RecvOnly, RecvOnly),
+            (SendOnly, SendOnly, Inactive),
+            (RecvOnly, SendRecv, SendOnly),
+            (RecvOnly, SendOnly, SendOnly),
+            (RecvOnly, RecvOnly, Inactive),
+        ];
+        for (offered, local, answered) in cases {
+            assert_eq!(offered.answer(local), answered, "{offered:?} / {local:?}");
+        }
+        for local in DIRECTIONS {
+            assert_eq!(Inactive.answer(local), Inactive);
+        }
+    }
+
+    #[test]
+    fn rtpmaps() {
+        let map: RtpMap = "111 opus/48000/2".parse().unwrap();
+        assert_eq!(map.encoding, "opus");
+        assert_eq!(map.clock_rate, 48000);
+        assert_eq!(map.channels, Some(2));
+        assert_eq!(map.to_string(), "111 opus/48000/2");
+        assert_eq!("0 PCMU/8000".parse::<RtpMap>().unwrap().channels, None);
+        for bad in [
+            "opus/48000",
+            "x opus/48000",
+            "111 opus",
+            "111 /8000",
+            "111 a/1/2/3",
+        ] {
+            assert!(bad.parse::<RtpMap>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn codecs_match_by_encoding() {
+        let opus = Codec::from("111 opus/48000/2".parse::<RtpMap>().unwrap());
+        let mut theirs = Codec::new(96, "OPUS", 48000);
+        theirs.channels = Some(2);
+        theirs.fmtp = Some("useinbandfec=1".to_string());
+        assert!(opus.matches(&theirs));
+        assert!(!opus.matches(&Codec::new(111, "opus", 48000)));
+        // No channel count means mono.
+        let mut mono = Codec::new(0, "PCMU", 8000);
+        assert!(mono.matches(&Codec::static_payload(0).unwrap()));
+        mono.channels = Some(1);
+        assert!(mono.matches(&Codec::static_payload(0).unwrap()));
+        assert!(!mono.matches(&Codec::new(0, "PCMU", 16000)));
+        assert_eq!(Codec::static_payload(101), None);
+    }
+
+    #[test]
+    fn candidates() {
+        let line = "candidate:1876313031 1 udp 1686052607 203.0.113.7 52473 typ srflx raddr 192.168.1.20 rport 52473 generation 0";
+        let candidate: Candidate = line.parse().unwrap();
+        assert_eq!(candidate.foundation, "1876313031");
+        assert_eq!(candidate.priority, 1686052607);
+        assert_eq!(candidate.port, 52473);
+        assert_eq!(
+            candidate.extensions,
+            [("generation".to_string(), "0".to_string())]
+        );
+        assert_eq!(format!("candidate:{candidate}"), line);
+        assert!("1 1 udp 1 10.0.0.1 5000 host".parse::<Candidate>().is_err());
+        assert!("1 1 udp 1 10.0.0.1 5000 typ host generation"
+            .parse::<Candidate>()
+            .is_err());
+
+        let host = Candidate::host(1, IpAddr::from([10, 0, 0, 1]), 40000);
+        assert_eq!(
+            host.to_string(),
+            "1 1 udp 2130706431 10.0.0.1 40000 typ host"
+        );
+        assert_eq!(host.to_string().parse::<Candidate>().unwrap(), host);
+    }
+
+    #[test]
+    fn fingerprints_are_normalized() {
+        let fingerprint: Fingerprint = "SHA-256 4a:ad:b9".parse().unwrap();
+        assert_eq!(fingerprint.to_string(), "sha-256 4A:AD:B9");
+        assert!("sha-256 4A:AD:B".parse::<Fingerprint>().is_err());
+        assert!("sha-256".parse::<Fingerprint>().is_err());
+    }
+}
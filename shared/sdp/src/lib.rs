@@ -0,0 +1,20 @@
+//! SDP parser, serializer and offer/answer negotiation (RFC 8866, RFC 3264).
+//!
+//! A [`SessionDescription`] keeps every line it was parsed from, so a
+//! description that is only rewritten (connection addresses pointed at a
+//! media relay, a candidate added) serializes back with nothing lost.
+//! Attributes are stored as raw name/value pairs; the ones negotiation cares
+//! about (rtpmap, fmtp, candidates, fingerprints, directions, groups) have
+//! typed views in [`attribute`].
+
+pub mod attribute;
+mod negotiate;
+#[cfg(test)]
+mod samples;
+mod session;
+
+pub use attribute::{Candidate, Codec, Direction, Fingerprint, Fmtp, Group, RtpMap};
+pub use negotiate::{answer, LocalMedia};
+pub use session::{
+    Attribute, Bandwidth, Connection, Media, Origin, ParseError, SessionDescription, Timing,
+};
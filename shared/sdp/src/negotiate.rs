@@ -0,0 +1,239 @@
+//! Answering an offer (RFC 3264 section 6).
+
+use crate::attribute::{Codec, Direction, Group};
+use crate::session::{Attribute, Connection, Media, Origin, SessionDescription, Timing};
+
+/// What this side can receive, and where.
+#[derive(Debug, Clone)]
+pub struct LocalMedia {
+    pub origin: Origin,
+    pub connection: Connection,
+    /// Every accepted section is answered on this port; a relay tells the
+    /// streams apart by their source.
+    pub port: u16,
+    /// Supported RTP codecs, most preferred first. Only the encoding has to
+    /// match an offered codec; the answer keeps the offer's payload number
+    /// and format parameters.
+    pub codecs: Vec<Codec>,
+    /// Media kinds accepted as-is without codec matching, e.g.
+    /// `application` for data channels.
+    pub passthrough: Vec<String>,
+    pub direction: Direction,
+}
+
+/// Build the answer to `offer`. A section is rejected (port zero) when none
+/// of its codecs are supported; a section the offer rejected stays rejected.
+/// Accepted sections keep their mid and stay in the offer's BUNDLE group.
+pub fn answer(offer: &SessionDescription, local: &LocalMedia) -> SessionDescription {
+    let media: Vec<Media> = offer
+        .media
+        .iter()
+        .enumerate()
+        .map(|(index, offered)| answer_media(offered, offer.direction_of(index), local))
+        .collect();
+
+    let mut attributes = Vec::new();
+    if let Some(bundle) = offer.bundle() {
+        let mids: Vec<String> = bundle
+            .mids
+            .into_iter()
+            .filter(|mid| {
+                media
+                    .iter()
+                    .any(|m| !m.is_rejected() && m.mid() == Some(mid.as_str()))
+            })
+            .collect();
+        if !mids.is_empty() {
+            let group = Group {
+                semantics: bundle.semantics,
+                mids,
+            };
+            attributes.push(Attribute::new("group", group.to_string()));
+        }
+    }
+
+    SessionDescription {
+        origin: local.origin.clone(),
+        session_name: "-".to_string(),
+        info: None,
+        uri: None,
+        emails: Vec::new(),
+        phones: Vec::new(),
+        connection: Some(local.connection.clone()),
+        bandwidths: Vec::new(),
+        timing: vec![Timing {
+            start: 0,
+            stop: 0,
+            repeats: Vec::new(),
+        }],
+        zone: None,
+        key: None,
+        attributes,
+        media,
+    }
+}
+
+fn answer_media(offered: &Media, direction: Direction, local: &LocalMedia) -> Media {
+    let mut media = Media {
+        kind: offered.kind.clone(),
+        port: 0,
+        port_count: None,
+        protocol: offered.protocol.clone(),
+        formats: offered.formats.clone(),
+        info: None,
+        connections: Vec::new(),
+        bandwidths: Vec::new(),
+        key: None,
+        attributes: Vec::new(),
+    };
+    if let Some(mid) = offered.mid() {
+        media.attributes.push(Attribute::new("mid", mid));
+    }
+    if offered.is_rejected() {
+        return media;
+    }
+
+    if local.passthrough.contains(&offered.kind) {
+        media.port = local.port;
+        media.set_direction(direction.answer(local.direction));
+        return media;
+    }
+
+    // Our preference order, the offerer's numbering.
+    let codecs: Vec<Codec> = local
+        .codecs
+        .iter()
+        .filter_map(|ours| offered.codecs().into_iter().find(|c| c.matches(ours)))
+        .collect();
+    if codecs.is_empty() {
+        return media;
+    }
+
+    media.port = local.port;
+    media.formats = codecs.iter().map(|c| c.payload.to_string()).collect();
+    for codec in &codecs {
+        media
+            .attributes
+            .push(Attribute::new("rtpmap", codec.rtpmap().to_string()));
+        if let Some(fmtp) = &codec.fmtp {
+            media
+                .attributes
+                .push(Attribute::new("fmtp", format!("{} {fmtp}", codec.payload)));
+        }
+    }
+    if offered.attribute("rtcp-mux").is_some() {
+        media.attributes.push(Attribute::flag("rtcp-mux"));
+    }
+    media.set_direction(direction.answer(local.direction));
+    media
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samples::{BROWSER_OFFER, PHONE_HOLD, PHONE_OFFER};
+    use std::net::IpAddr;
+
+    fn ours(codecs: Vec<Codec>) -> LocalMedia {
+        let address = IpAddr::from([198, 51, 100, 10]);
+        LocalMedia {
+            origin: Origin::new(7, address),
+            connection: Connection::from(address),
+            port: 40000,
+            codecs,
+            passthrough: Vec::new(),
+            direction: Direction::SendRecv,
+        }
+    }
+
+    fn opus() -> Codec {
+        let mut opus = Codec::new(96, "opus", 48000);
+        opus.channels = Some(2);
+        opus
+    }
+
+    fn offer(sdp: &str) -> SessionDescription {
+        sdp.parse().unwrap()
+    }
+
+    #[test]
+    fn codecs_follow_our_preference_with_their_numbering() {
+        let local = ours(vec![
+            Codec::new(8, "PCMA", 8000),
+            opus(),
+            Codec::new(101, "telephone-event", 8000),
+        ]);
+        let sdp = answer(&offer(BROWSER_OFFER), &local);
+        let audio = &sdp.media[0];
+        assert_eq!(audio.port, 40000);
+        assert_eq!(audio.formats, ["8", "111", "126"]);
+        let codecs = audio.codecs();
+        assert_eq!(codecs[0], Codec::new(8, "PCMA", 8000));
+        assert_eq!(codecs[1].name, "opus");
+        assert_eq!(
+            codecs[1].fmtp.as_deref(),
+            Some("minptime=10;useinbandfec=1")
+        );
+        assert_eq!(codecs[2], Codec::new(126, "telephone-event", 8000));
+        assert_eq!(audio.mid(), Some("0"));
+        assert_eq!(audio.attribute("rtcp-mux"), Some(""));
+        assert_eq!(audio.direction(), Some(Direction::SendRecv));
+        assert_eq!(sdp.connection_of(0).unwrap().address, "198.51.100.10");
+        assert_eq!(sdp.origin.session_id, "7");
+    }
+
+    #[test]
+    fn unsupported_sections_are_rejected_and_leave_the_bundle() {
+        let sdp = answer(&offer(BROWSER_OFFER), &ours(vec![opus()]));
+        let data = &sdp.media[1];
+        assert!(data.is_rejected());
+        assert_eq!(data.mid(), Some("1"));
+        assert_eq!(data.formats, ["webrtc-datachannel"]);
+        assert_eq!(sdp.bundle().unwrap().mids, ["0"]);
+
+        // Unless passed through.
+        let mut local = ours(vec![opus()]);
+        local.passthrough.push("application".to_string());
+        let sdp = answer(&offer(BROWSER_OFFER), &local);
+        assert_eq!(sdp.media[1].port, 40000);
+        assert_eq!(sdp.bundle().unwrap().mids, ["0", "1"]);
+
+        // Nothing in common at all: no group either.
+        let sdp = answer(&offer(BROWSER_OFFER), &ours(vec![]));
+        assert!(sdp.media.iter().all(Media::is_rejected));
+        assert_eq!(sdp.bundle(), None);
+    }
+
+    #[test]
+    fn static_payloads_without_rtpmap_are_matched() {
+        let local = ours(vec![Codec::new(0, "PCMU", 8000)]);
+        let sdp = answer(&offer(PHONE_OFFER), &local);
+        let audio = &sdp.media[0];
+        assert_eq!(audio.formats, ["0"]);
+        assert_eq!(audio.attribute("rtpmap"), Some("0 PCMU/8000"));
+        assert_eq!(audio.attribute("rtcp-mux"), None);
+        assert_eq!(audio.attribute("mid"), None);
+    }
+
+    #[test]
+    fn directions_answer_the_offer() {
+        let mut local = ours(vec![Codec::new(0, "PCMU", 8000)]);
+        let sdp = answer(&offer(PHONE_HOLD), &local);
+        assert_eq!(sdp.direction_of(0), Direction::RecvOnly);
+        assert!(sdp.media[1].is_rejected());
+        assert_eq!(sdp.media[1].direction(), None);
+
+        local.direction = Direction::SendOnly;
+        let sdp = answer(&offer(PHONE_HOLD), &local);
+        assert_eq!(sdp.direction_of(0), Direction::Inactive);
+    }
+
+    #[test]
+    fn answers_serialize_and_parse_back() {
+        let sdp = answer(&offer(BROWSER_OFFER), &ours(vec![opus()]));
+        let text = sdp.to_string();
+        let parsed: SessionDescription = text.parse().unwrap();
+        assert_eq!(parsed.to_string(), text);
+        assert_eq!(parsed.media[0].codecs(), sdp.media[0].codecs());
+    }
+}
@@ -0,0 +1,84 @@
+//! Descriptions as real endpoints send them, for the tests.
+
+/// An audio call offer from Chrome, with a data channel alongside.
+pub const BROWSER_OFFER: &str = concat!(
+    "v=0\r\n",
+    "o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n",
+    "s=-\r\n",
+    "t=0 0\r\n",
+    "a=group:BUNDLE 0 1\r\n",
+    "a=extmap-allow-mixed\r\n",
+    "a=msid-semantic: WMS 6b3a4a1e-58a4-4c3b-9d3f-2f0c2b2b9f11\r\n",
+    "m=audio 9 UDP/TLS/RTP/SAVPF 111 63 9 0 8 13 110 126\r\n",
+    "c=IN IP4 0.0.0.0\r\n",
+    "a=rtcp:9 IN IP4 0.0.0.0\r\n",
+    "a=candidate:3001459924 1 udp 2122260223 192.168.1.20 52473 typ host generation 0 network-id 1\r\n",
+    "a=candidate:1876313031 1 udp 1686052607 203.0.113.7 52473 typ srflx raddr 192.168.1.20 rport 52473 generation 0 network-id 1\r\n",
+    "a=ice-ufrag:Ty1W\r\n",
+    "a=ice-pwd:n2VtLg3cG0yZ8uT5Ea0f4Qk1\r\n",
+    "a=ice-options:trickle\r\n",
+    "a=fingerprint:sha-256 4A:AD:B9:B1:3F:82:18:3B:54:02:12:DF:3E:5D:49:6B:19:E5:7C:AB:3F:1B:2D:5C:E0:A2:8F:7C:1E:D4:9A:33\r\n",
+    "a=setup:actpass\r\n",
+    "a=mid:0\r\n",
+    "a=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r\n",
+    "a=extmap:2 http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time\r\n",
+    "a=sendrecv\r\n",
+    "a=msid:6b3a4a1e-58a4-4c3b-9d3f-2f0c2b2b9f11 0c2fd3a4-8f0e-4a55-9c43-65d1f3f0a6a2\r\n",
+    "a=rtcp-mux\r\n",
+    "a=rtpmap:111 opus/48000/2\r\n",
+    "a=rtcp-fb:111 transport-cc\r\n",
+    "a=fmtp:111 minptime=10;useinbandfec=1\r\n",
+    "a=rtpmap:63 red/48000/2\r\n",
+    "a=fmtp:63 111/111\r\n",
+    "a=rtpmap:9 G722/8000\r\n",
+    "a=rtpmap:0 PCMU/8000\r\n",
+    "a=rtpmap:8 PCMA/8000\r\n",
+    "a=rtpmap:13 CN/8000\r\n",
+    "a=rtpmap:110 telephone-event/48000\r\n",
+    "a=rtpmap:126 telephone-event/8000\r\n",
+    "a=ssrc:3520396130 cname:Yx3c0v+Qe1VdTf0N\r\n",
+    "a=ssrc:3520396130 msid:6b3a4a1e-58a4-4c3b-9d3f-2f0c2b2b9f11 0c2fd3a4-8f0e-4a55-9c43-65d1f3f0a6a2\r\n",
+    "m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n",
+    "c=IN IP4 0.0.0.0\r\n",
+    "a=candidate:3001459924 1 udp 2122260223 192.168.1.20 52473 typ host generation 0 network-id 1\r\n",
+    "a=ice-ufrag:Ty1W\r\n",
+    "a=ice-pwd:n2VtLg3cG0yZ8uT5Ea0f4Qk1\r\n",
+    "a=ice-options:trickle\r\n",
+    "a=fingerprint:sha-256 4A:AD:B9:B1:3F:82:18:3B:54:02:12:DF:3E:5D:49:6B:19:E5:7C:AB:3F:1B:2D:5C:E0:A2:8F:7C:1E:D4:9A:33\r\n",
+    "a=setup:actpass\r\n",
+    "a=mid:1\r\n",
+    "a=sctp-port:5000\r\n",
+    "a=max-message-size:262144\r\n",
+);
+
+/// A desk phone's INVITE offer: plain RTP, session-level connection, and
+/// static payload types with and without an rtpmap.
+pub const PHONE_OFFER: &str = concat!(
+    "v=0\r\n",
+    "o=- 20042 20042 IN IP4 10.0.0.31\r\n",
+    "s=SDP data\r\n",
+    "c=IN IP4 10.0.0.31\r\n",
+    "t=0 0\r\n",
+    "m=audio 11796 RTP/AVP 9 0 8 18 101\r\n",
+    "a=rtpmap:9 G722/8000\r\n",
+    "a=rtpmap:18 G729/8000\r\n",
+    "a=fmtp:18 annexb=no\r\n",
+    "a=ptime:20\r\n",
+    "a=sendrecv\r\n",
+    "a=rtpmap:101 telephone-event/8000\r\n",
+    "a=fmtp:101 0-15\r\n",
+);
+
+/// The same phone putting the call on hold in a re-INVITE, with the
+/// direction given once for the whole session.
+pub const PHONE_HOLD: &str = concat!(
+    "v=0\r\n",
+    "o=- 20042 20043 IN IP4 10.0.0.31\r\n",
+    "s=SDP data\r\n",
+    "c=IN IP4 10.0.0.31\r\n",
+    "t=0 0\r\n",
+    "a=sendonly\r\n",
+    "m=audio 11796 RTP/AVP 0 101\r\n",
+    "a=rtpmap:101 telephone-event/8000\r\n",
+    "m=video 0 RTP/AVP 34\r\n",
+);
@@ -0,0 +1,814 @@
+//! Session and media descriptions (RFC 8866 section 5).
+
+use crate::attribute::{Candidate, Codec, Direction, Fingerprint, Fmtp, Group, RtpMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("line {0} is not of the form <type>=<value>")]
+    Syntax(usize),
+    #[error("line {line}: invalid {field}")]
+    Invalid { line: usize, field: &'static str },
+    #[error("line {0}: unexpected line type")]
+    Unexpected(usize),
+    #[error("missing {0}= line")]
+    Missing(char),
+    #[error("media section {0} has no connection address")]
+    NoConnection(usize),
+    #[error("invalid {0} attribute")]
+    Attribute(&'static str),
+}
+
+/// `o=<username> <sess-id> <sess-version> <nettype> <addrtype> <address>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    pub username: String,
+    /// Numeric, but often wider than 64 bits, so kept as written.
+    pub session_id: String,
+    pub session_version: u64,
+    pub net_type: String,
+    pub addr_type: String,
+    pub address: String,
+}
+
+impl Origin {
+    /// A fresh origin for a description this side creates.
+    pub fn new(session_id: u64, address: IpAddr) -> Self {
+        let connection = Connection::from(address);
+        Origin {
+            username: "-".to_string(),
+            session_id: session_id.to_string(),
+            session_version: session_id,
+            net_type: connection.net_type,
+            addr_type: connection.addr_type,
+            address: connection.address,
+        }
+    }
+}
+
+/// `c=<nettype> <addrtype> <address>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connection {
+    pub net_type: String,
+    pub addr_type: String,
+    /// May carry a `/ttl` or `/count` suffix for multicast.
+    pub address: String,
+}
+
+impl From<IpAddr> for Connection {
+    fn from(address: IpAddr) -> Self {
+        Connection {
+            net_type: "IN".to_string(),
+            addr_type: if address.is_ipv4() { "IP4" } else { "IP6" }.to_string(),
+            address: address.to_string(),
+        }
+    }
+}
+
+/// `b=<bwtype>:<bandwidth>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bandwidth {
+    pub kind: String,
+    pub value: u64,
+}
+
+/// `t=<start> <stop>` and the `r=` lines repeating it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timing {
+    pub start: u64,
+    pub stop: u64,
+    pub repeats: Vec<String>,
+}
+
+/// `a=<name>` or `a=<name>:<value>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribute {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl Attribute {
+    pub fn new(name: &str, value: impl Into<String>) -> Self {
+        Attribute {
+            name: name.to_string(),
+            value: Some(value.into()),
+        }
+    }
+
+    pub fn flag(name: &str) -> Self {
+        Attribute {
+            name: name.to_string(),
+            value: None,
+        }
+    }
+}
+
+/// One `m=` section and the lines following it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Media {
+    /// `audio`, `video`, `application`, ...
+    pub kind: String,
+    /// Zero for a rejected or disabled section.
+    pub port: u16,
+    pub port_count: Option<u16>,
+    /// `RTP/AVP`, `UDP/TLS/RTP/SAVPF`, ...
+    pub protocol: String,
+    /// RTP payload types, or protocol-specific format names.
+    pub formats: Vec<String>,
+    pub info: Option<String>,
+    pub connections: Vec<Connection>,
+    pub bandwidths: Vec<Bandwidth>,
+    pub key: Option<String>,
+    pub attributes: Vec<Attribute>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionDescription {
+    pub origin: Origin,
+    pub session_name: String,
+    pub info: Option<String>,
+    pub uri: Option<String>,
+    pub emails: Vec<String>,
+    pub phones: Vec<String>,
+    pub connection: Option<Connection>,
+    pub bandwidths: Vec<Bandwidth>,
+    pub timing: Vec<Timing>,
+    pub zone: Option<String>,
+    pub key: Option<String>,
+    pub attributes: Vec<Attribute>,
+    pub media: Vec<Media>,
+}
+
+fn values<'a>(attributes: &'a [Attribute], name: &'a str) -> impl Iterator<Item = &'a str> {
+    attributes
+        .iter()
+        .filter(move |a| a.name == name)
+        .map(|a| a.value.as_deref().unwrap_or_default())
+}
+
+fn first<'a>(attributes: &'a [Attribute], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|a| a.name == name)
+        .map(|a| a.value.as_deref().unwrap_or_default())
+}
+
+fn direction(attributes: &[Attribute]) -> Option<Direction> {
+    attributes
+        .iter()
+        .find_map(|a| Direction::from_attribute(&a.name))
+}
+
+impl Media {
+    /// Value of the first `name` attribute; `Some("")` for a flag.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        first(&self.attributes, name)
+    }
+
+    pub fn attribute_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        values(&self.attributes, name)
+    }
+
+    pub fn remove_attribute(&mut self, name: &str) {
+        self.attributes.retain(|a| a.name != name);
+    }
+
+    pub fn mid(&self) -> Option<&str> {
+        self.attribute("mid")
+    }
+
+    pub fn is_rejected(&self) -> bool {
+        self.port == 0
+    }
+
+    /// The direction attribute of this section, if it has its own.
+    pub fn direction(&self) -> Option<Direction> {
+        direction(&self.attributes)
+    }
+
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.attributes
+            .retain(|a| Direction::from_attribute(&a.name).is_none());
+        self.attributes.push(Attribute::flag(direction.as_str()));
+    }
+
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        self.attribute("fingerprint")?.parse().ok()
+    }
+
+    /// The SSRC the section's sender uses: the source of its first
+    /// `a=ssrc` line.
+    pub fn ssrc(&self) -> Option<u32> {
+        self.attribute_values("ssrc")
+            .find_map(|value| value.split(' ').next()?.parse().ok())
+    }
+
+    /// ICE candidates of this section; malformed ones are skipped.
+    pub fn candidates(&self) -> Vec<Candidate> {
+        self.attribute_values("candidate")
+            .filter_map(|value| value.parse().ok())
+            .collect()
+    }
+
+    /// The RTP codecs offered, in the order of the `m=` line. Static payload
+    /// types without an rtpmap fall back to their RFC 3551 definitions;
+    /// formats that name no known codec are skipped.
+    pub fn codecs(&self) -> Vec<Codec> {
+        let rtpmaps: Vec<RtpMap> = self
+            .attribute_values("rtpmap")
+            .filter_map(|value| value.parse().ok())
+            .collect();
+        let fmtps: Vec<Fmtp> = self
+            .attribute_values("fmtp")
+            .filter_map(|value| value.parse().ok())
+            .collect();
+        self.formats
+            .iter()
+            .filter_map(|format| format.parse::<u8>().ok())
+            .filter_map(|payload| {
+                let mut codec = match rtpmaps.iter().find(|map| map.payload == payload) {
+                    Some(map) => Codec::from(map.clone()),
+                    None => Codec::static_payload(payload)?,
+                };
+                codec.fmtp = fmtps
+                    .iter()
+                    .find(|fmtp| fmtp.payload == payload)
+                    .map(|fmtp| fmtp.params.clone());
+                Some(codec)
+            })
+            .collect()
+    }
+}
+
+impl SessionDescription {
+    /// Value of the first session-level `name` attribute; `Some("")` for a
+    /// flag.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        first(&self.attributes, name)
+    }
+
+    pub fn attribute_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        values(&self.attributes, name)
+    }
+
+    /// `a=group` lines; malformed ones are skipped.
+    pub fn groups(&self) -> Vec<Group> {
+        self.attribute_values("group")
+            .filter_map(|value| value.parse().ok())
+            .collect()
+    }
+
+    /// The first BUNDLE group (RFC 9143).
+    pub fn bundle(&self) -> Option<Group> {
+        self.groups()
+            .into_iter()
+            .find(|group| group.semantics.eq_ignore_ascii_case("BUNDLE"))
+    }
+
+    /// Effective direction of the `index`-th media section: its own
+    /// attribute, else the session-level one, else sendrecv.
+    pub fn direction_of(&self, index: usize) -> Direction {
+        self.media
+            .get(index)
+            .and_then(Media::direction)
+            .or_else(|| direction(&self.attributes))
+            .unwrap_or(Direction::SendRecv)
+    }
+
+    /// Effective fingerprint of the `index`-th media section.
+    pub fn fingerprint_of(&self, index: usize) -> Option<Fingerprint> {
+        self.media
+            .get(index)
+            .and_then(Media::fingerprint)
+            .or_else(|| self.attribute("fingerprint")?.parse().ok())
+    }
+
+    /// Effective connection of the `index`-th media section.
+    pub fn connection_of(&self, index: usize) -> Option<&Connection> {
+        self.media
+            .get(index)
+            .and_then(|media| media.connections.first())
+            .or(self.connection.as_ref())
+    }
+
+    /// Point every active media section at `address:port`, as a media relay
+    /// standing in for the endpoint does. Candidates and RTCP addresses
+    /// describe the endpoint and are dropped; sections using ICE get a single
+    /// host candidate for the relay instead.
+    pub fn rewrite_connection(&mut self, address: IpAddr, port: u16) {
+        let connection = Connection::from(address);
+        self.connection = Some(connection.clone());
+        let session_ice = self.attribute("ice-ufrag").is_some();
+        for media in self.media.iter_mut().filter(|m| !m.is_rejected()) {
+            media.port = port;
+            media.port_count = None;
+            media.connections.clear();
+            for name in ["candidate", "end-of-candidates", "rtcp"] {
+                media.remove_attribute(name);
+            }
+            if session_ice || media.attribute("ice-ufrag").is_some() {
+                let candidate = Candidate::host(1, address, port);
+                media
+                    .attributes
+                    .push(Attribute::new("candidate", candidate.to_string()));
+                media.attributes.push(Attribute::flag("end-of-candidates"));
+            }
+        }
+    }
+}
+
+/// A parsed `<type>=<value>` line with its 1-based line number.
+struct Line<'a> {
+    number: usize,
+    kind: u8,
+    value: &'a str,
+}
+
+impl Line<'_> {
+    fn invalid(&self, field: &'static str) -> ParseError {
+        ParseError::Invalid {
+            line: self.number,
+            field,
+        }
+    }
+
+    fn fields<const N: usize>(&self, field: &'static str) -> Result<[&str; N], ParseError> {
+        let fields: Vec<&str> = self.value.split(' ').collect();
+        fields.try_into().map_err(|_| self.invalid(field))
+    }
+
+    fn connection(&self) -> Result<Connection, ParseError> {
+        let [net_type, addr_type, address] = self.fields("connection")?;
+        Ok(Connection {
+            net_type: net_type.to_string(),
+            addr_type: addr_type.to_string(),
+            address: address.to_string(),
+        })
+    }
+
+    fn bandwidth(&self) -> Result<Bandwidth, ParseError> {
+        let (kind, value) = self
+            .value
+            .split_once(':')
+            .ok_or_else(|| self.invalid("bandwidth"))?;
+        Ok(Bandwidth {
+            kind: kind.to_string(),
+            value: value.parse().map_err(|_| self.invalid("bandwidth"))?,
+        })
+    }
+
+    fn attribute(&self) -> Attribute {
+        match self.value.split_once(':') {
+            Some((name, value)) => Attribute::new(name, value),
+            None => Attribute::flag(self.value),
+        }
+    }
+
+    fn origin(&self) -> Result<Origin, ParseError> {
+        let [username, session_id, version, net_type, addr_type, address] =
+            self.fields("origin")?;
+        Ok(Origin {
+            username: username.to_string(),
+            session_id: session_id.to_string(),
+            session_version: version.parse().map_err(|_| self.invalid("origin"))?,
+            net_type: net_type.to_string(),
+            addr_type: addr_type.to_string(),
+            address: address.to_string(),
+        })
+    }
+
+    fn timing(&self) -> Result<Timing, ParseError> {
+        let [start, stop] = self.fields("timing")?;
+        let parse = |s: &str| s.parse().map_err(|_| self.invalid("timing"));
+        Ok(Timing {
+            start: parse(start)?,
+            stop: parse(stop)?,
+            repeats: Vec::new(),
+        })
+    }
+
+    fn media(&self) -> Result<Media, ParseError> {
+        let invalid = || self.invalid("media");
+        let mut fields = self.value.split(' ');
+        let (Some(kind), Some(ports), Some(protocol)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let (port, port_count) = match ports.split_once('/') {
+            Some((port, count)) => (port, Some(count.parse().map_err(|_| invalid())?)),
+            None => (ports, None),
+        };
+        let formats: Vec<String> = fields.map(str::to_string).collect();
+        if kind.is_empty() || protocol.is_empty() || formats.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Media {
+            kind: kind.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+            port_count,
+            protocol: protocol.to_string(),
+            formats,
+            info: None,
+            connections: Vec::new(),
+            bandwidths: Vec::new(),
+            key: None,
+            attributes: Vec::new(),
+        })
+    }
+}
+
+impl FromStr for SessionDescription {
+    type Err = ParseError;
+
+    /// Lines may end in CRLF or a bare LF. Session-level lines are accepted
+    /// in any order after `v=`, but only media-level ones may follow the
+    /// first `m=`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lines = s
+            .split('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line))
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| match line.as_bytes() {
+                [kind, b'=', ..] if kind.is_ascii_lowercase() => Ok(Line {
+                    number: i + 1,
+                    kind: *kind,
+                    value: &line[2..],
+                }),
+                _ => Err(ParseError::Syntax(i + 1)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut lines = lines.into_iter();
+        match lines.next() {
+            Some(line) if line.kind == b'v' && line.value == "0" => {}
+            Some(line) if line.kind == b'v' => return Err(line.invalid("version")),
+            _ => return Err(ParseError::Missing('v')),
+        }
+
+        let mut origin = None;
+        let mut session_name = None;
+        let mut desc = SessionDescription {
+            origin: Origin::new(0, IpAddr::from([0, 0, 0, 0])),
+            session_name: String::new(),
+            info: None,
+            uri: None,
+            emails: Vec::new(),
+            phones: Vec::new(),
+            connection: None,
+            bandwidths: Vec::new(),
+            timing: Vec::new(),
+            zone: None,
+            key: None,
+            attributes: Vec::new(),
+            media: Vec::new(),
+        };
+        for line in lines {
+            if let Some(media) = desc.media.last_mut() {
+                match line.kind {
+                    b'm' => desc.media.push(line.media()?),
+                    b'i' => media.info = Some(line.value.to_string()),
+                    b'c' => media.connections.push(line.connection()?),
+                    b'b' => media.bandwidths.push(line.bandwidth()?),
+                    b'k' => media.key = Some(line.value.to_string()),
+                    b'a' => media.attributes.push(line.attribute()),
+                    _ => return Err(ParseError::Unexpected(line.number)),
+                }
+                continue;
+            }
+            match line.kind {
+                b'o' => origin = Some(line.origin()?),
+                b's' => session_name = Some(line.value.to_string()),
+                b'i' => desc.info = Some(line.value.to_string()),
+                b'u' => desc.uri = Some(line.value.to_string()),
+                b'e' => desc.emails.push(line.value.to_string()),
+                b'p' => desc.phones.push(line.value.to_string()),
+                b'c' => desc.connection = Some(line.connection()?),
+                b'b' => desc.bandwidths.push(line.bandwidth()?),
+                b't' => desc.timing.push(line.timing()?),
+                b'r' => desc
+                    .timing
+                    .last_mut()
+                    .ok_or(ParseError::Unexpected(line.number))?
+                    .repeats
+                    .push(line.value.to_string()),
+                b'z' => desc.zone = Some(line.value.to_string()),
+                b'k' => desc.key = Some(line.value.to_string()),
+                b'a' => desc.attributes.push(line.attribute()),
+                b'm' => desc.media.push(line.media()?),
+                _ => return Err(ParseError::Unexpected(line.number)),
+            }
+        }
+
+        desc.origin = origin.ok_or(ParseError::Missing('o'))?;
+        desc.session_name = session_name.ok_or(ParseError::Missing('s'))?;
+        if desc.timing.is_empty() {
+            return Err(ParseError::Missing('t'));
+        }
+        // Rejected sections are commonly sent without an address.
+        if desc.connection.is_none() {
+            if let Some(index) = desc
+                .media
+                .iter()
+                .position(|m| m.connections.is_empty() && !m.is_rejected())
+            {
+                return Err(ParseError::NoConnection(index));
+            }
+        }
+        Ok(desc)
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.net_type, self.addr_type, self.address)
+    }
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}:{value}", self.name),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+fn write_common(
+    f: &mut fmt::Formatter<'_>,
+    bandwidths: &[Bandwidth],
+    key: &Option<String>,
+    attributes: &[Attribute],
+) -> fmt::Result {
+    for bandwidth in bandwidths {
+        write!(f, "b={}:{}\r\n", bandwidth.kind, bandwidth.value)?;
+    }
+    if let Some(key) = key {
+        write!(f, "k={key}\r\n")?;
+    }
+    for attribute in attributes {
+        write!(f, "a={attribute}\r\n")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Media {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m={} {}", self.kind, self.port)?;
+        if let Some(count) = self.port_count {
+            write!(f, "/{count}")?;
+        }
+        write!(f, " {} {}\r\n", self.protocol, self.formats.join(" "))?;
+        if let Some(info) = &self.info {
+            write!(f, "i={info}\r\n")?;
+        }
+        for connection in &self.connections {
+            write!(f, "c={connection}\r\n")?;
+        }
+        write_common(f, &self.bandwidths, &self.key, &self.attributes)
+    }
+}
+
+/// Serializes in the order RFC 8866 section 5 prescribes, with CRLF line
+/// endings.
+impl fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let o = &self.origin;
+        f.write_str("v=0\r\n")?;
+        write!(
+            f,
+            "o={} {} {} {} {} {}\r\n",
+            o.username, o.session_id, o.session_version, o.net_type, o.addr_type, o.address
+        )?;
+        write!(f, "s={}\r\n", self.session_name)?;
+        if let Some(info) = &self.info {
+            write!(f, "i={info}\r\n")?;
+        }
+        if let Some(uri) = &self.uri {
+            write!(f, "u={uri}\r\n")?;
+        }
+        for email in &self.emails {
+            write!(f, "e={email}\r\n")?;
+        }
+        for phone in &self.phones {
+            write!(f, "p={phone}\r\n")?;
+        }
+        if let Some(connection) = &self.connection {
+            write!(f, "c={connection}\r\n")?;
+        }
+        for bandwidth in &self.bandwidths {
+            write!(f, "b={}:{}\r\n", bandwidth.kind, bandwidth.value)?;
+        }
+        for timing in &self.timing {
+            write!(f, "t={} {}\r\n", timing.start, timing.stop)?;
+            for repeat in &timing.repeats {
+                write!(f, "r={repeat}\r\n")?;
+            }
+        }
+        if let Some(zone) = &self.zone {
+            write!(f, "z={zone}\r\n")?;
+        }
+        write_common(f, &[], &self.key, &self.attributes)?;
+        for media in &self.media {
+            media.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samples::{BROWSER_OFFER, PHONE_HOLD, PHONE_OFFER};
+
+    fn parse(sdp: &str) -> SessionDescription {
+        sdp.parse().unwrap()
+    }
+
+    #[test]
+    fn descriptions_serialize_back_unchanged() {
+        for sdp in [BROWSER_OFFER, PHONE_OFFER, PHONE_HOLD] {
+            assert_eq!(parse(sdp).to_string(), sdp);
+        }
+    }
+
+    #[test]
+    fn bare_line_feeds_are_accepted() {
+        let lf = PHONE_OFFER.replace("\r\n", "\n");
+        assert_eq!(parse(&lf).to_string(), PHONE_OFFER);
+    }
+
+    #[test]
+    fn browser_offer() {
+        let sdp = parse(BROWSER_OFFER);
+        assert_eq!(sdp.origin.session_id, "4611731400430051336");
+        assert_eq!(sdp.origin.session_version, 2);
+        assert_eq!(sdp.bundle().unwrap().mids, ["0", "1"]);
+        assert_eq!(
+            sdp.attribute("msid-semantic").map(str::trim),
+            Some("WMS 6b3a4a1e-58a4-4c3b-9d3f-2f0c2b2b9f11")
+        );
+        assert_eq!(sdp.media.len(), 2);
+
+        let audio = &sdp.media[0];
+        assert_eq!(audio.kind, "audio");
+        assert_eq!(audio.port, 9);
+        assert_eq!(audio.protocol, "UDP/TLS/RTP/SAVPF");
+        assert_eq!(audio.mid(), Some("0"));
+        assert_eq!(audio.attribute("rtcp-mux"), Some(""));
+        assert_eq!(audio.attribute_values("extmap").count(), 2);
+        assert_eq!(audio.ssrc(), Some(3520396130));
+        assert_eq!(sdp.direction_of(0), Direction::SendRecv);
+        assert_eq!(sdp.connection_of(0).unwrap().address, "0.0.0.0");
+        let fingerprint = sdp.fingerprint_of(0).unwrap();
+        assert_eq!(fingerprint.hash, "sha-256");
+        assert!(fingerprint.value.starts_with("4A:AD:B9"));
+
+        let candidates = audio.candidates();
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].kind, "host");
+        assert_eq!(candidates[0].address, "192.168.1.20");
+        assert_eq!(candidates[1].kind, "srflx");
+        assert_eq!(
+            candidates[1].related_address.as_deref(),
+            Some("192.168.1.20")
+        );
+        assert_eq!(candidates[1].related_port, Some(52473));
+        assert_eq!(
+            candidates[1].to_string(),
+            audio.attribute_values("candidate").nth(1).unwrap()
+        );
+
+        let codecs = audio.codecs();
+        let payloads: Vec<u8> = codecs.iter().map(|codec| codec.payload).collect();
+        assert_eq!(payloads, [111, 63, 9, 0, 8, 13, 110, 126]);
+        assert_eq!(codecs[0].name, "opus");
+        assert_eq!(codecs[0].clock_rate, 48000);
+        assert_eq!(codecs[0].channels, Some(2));
+        assert_eq!(
+            codecs[0].fmtp.as_deref(),
+            Some("minptime=10;useinbandfec=1")
+        );
+        assert_eq!(codecs[3], Codec::new(0, "PCMU", 8000));
+
+        let data = &sdp.media[1];
+        assert_eq!(data.kind, "application");
+        assert_eq!(data.formats, ["webrtc-datachannel"]);
+        assert!(data.codecs().is_empty());
+        assert_eq!(data.attribute("sctp-port"), Some("5000"));
+    }
+
+    #[test]
+    fn phone_offer() {
+        let sdp = parse(PHONE_OFFER);
+        assert_eq!(sdp.session_name, "SDP data");
+        assert_eq!(sdp.connection.as_ref().unwrap().address, "10.0.0.31");
+        let audio = &sdp.media[0];
+        assert!(audio.connections.is_empty());
+        assert_eq!(sdp.connection_of(0).unwrap().address, "10.0.0.31");
+        assert_eq!(audio.attribute("ptime"), Some("20"));
+        assert_eq!(sdp.fingerprint_of(0), None);
+        assert_eq!(audio.ssrc(), None);
+        assert!(audio.candidates().is_empty());
+
+        // PCMU and PCMA come without an rtpmap.
+        let codecs = audio.codecs();
+        let names: Vec<&str> = codecs.iter().map(|codec| codec.name.as_str()).collect();
+        assert_eq!(names, ["G722", "PCMU", "PCMA", "G729", "telephone-event"]);
+        assert_eq!(codecs[3].fmtp.as_deref(), Some("annexb=no"));
+        assert_eq!(codecs[4].fmtp.as_deref(), Some("0-15"));
+    }
+
+    #[test]
+    fn session_level_direction_applies_to_every_section() {
+        let sdp = parse(PHONE_HOLD);
+        assert_eq!(sdp.media[0].direction(), None);
+        assert_eq!(sdp.direction_of(0), Direction::SendOnly);
+        assert!(sdp.media[1].is_rejected());
+
+        let mut answer = parse(PHONE_OFFER);
+        answer.media[0].set_direction(Direction::RecvOnly);
+        assert_eq!(answer.direction_of(0), Direction::RecvOnly);
+        assert_eq!(answer.media[0].attribute_values("sendrecv").count(), 0);
+    }
+
+    #[test]
+    fn rewriting_points_ice_sections_at_the_relay() {
+        let mut sdp = parse(BROWSER_OFFER);
+        let relay = IpAddr::from([198, 51, 100, 10]);
+        sdp.rewrite_connection(relay, 40000);
+
+        assert_eq!(
+            sdp.connection.as_ref().unwrap().to_string(),
+            "IN IP4 198.51.100.10"
+        );
+        for media in &sdp.media {
+            assert_eq!(media.port, 40000);
+            assert!(media.connections.is_empty());
+            assert_eq!(media.attribute("rtcp"), None);
+            let candidates = media.candidates();
+            assert_eq!(candidates.len(), 1);
+            assert_eq!(candidates[0].address, "198.51.100.10");
+            assert_eq!(candidates[0].port, 40000);
+            assert_eq!(candidates[0].kind, "host");
+            assert_eq!(media.attribute("end-of-candidates"), Some(""));
+            // Everything else is left alone.
+            assert_eq!(media.attribute("ice-ufrag"), Some("Ty1W"));
+        }
+        assert_eq!(sdp.media[0].codecs().len(), 8);
+
+        // Still a valid description, and stable from here.
+        let text = sdp.to_string();
+        assert_eq!(parse(&text).to_string(), text);
+    }
+
+    #[test]
+    fn rewriting_plain_rtp_keeps_rejected_sections() {
+        let mut sdp = parse(PHONE_HOLD);
+        sdp.rewrite_connection(IpAddr::from([198, 51, 100, 10]), 40002);
+        assert_eq!(sdp.connection.as_ref().unwrap().address, "198.51.100.10");
+        assert_eq!(sdp.media[0].port, 40002);
+        assert!(sdp.media[0].candidates().is_empty());
+        assert_eq!(sdp.media[0].attribute("end-of-candidates"), None);
+        assert_eq!(sdp.media[1].port, 0);
+
+        let mut sdp = parse(PHONE_OFFER);
+        sdp.rewrite_connection("2001:db8::10".parse().unwrap(), 40004);
+        assert_eq!(
+            sdp.connection.as_ref().unwrap().to_string(),
+            "IN IP6 2001:db8::10"
+        );
+    }
+
+    #[test]
+    fn malformed_descriptions_are_refused() {
+        let parse = |sdp: &str| sdp.parse::<SessionDescription>().unwrap_err();
+        assert_eq!(parse(""), ParseError::Missing('v'));
+        assert_eq!(
+            parse("v=1\r\n"),
+            ParseError::Invalid {
+                line: 1,
+                field: "version"
+            }
+        );
+        assert_eq!(parse("v=0\r\nbogus\r\n"), ParseError::Syntax(2));
+        assert_eq!(parse("v=0\r\ns=-\r\nt=0 0\r\n"), ParseError::Missing('o'));
+        let no_timing = "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\n";
+        assert_eq!(parse(no_timing), ParseError::Missing('t'));
+        let no_connection = format!("{no_timing}t=0 0\r\nm=audio 5004 RTP/AVP 0\r\n");
+        assert_eq!(parse(&no_connection), ParseError::NoConnection(0));
+        let session_line_in_media = format!("{no_connection}c=IN IP4 10.0.0.1\r\nt=0 0\r\n");
+        assert_eq!(parse(&session_line_in_media), ParseError::Unexpected(7));
+        let bad_port = format!("{no_timing}t=0 0\r\nm=audio x RTP/AVP 0\r\n");
+        assert_eq!(
+            parse(&bad_port),
+            ParseError::Invalid {
+                line: 5,
+                field: "media"
+            }
+        );
+    }
+}
@@ -0,0 +1,413 @@
+//! Typed views of the headers routing depends on (RFC 3261 section 20).
+//!
+//! Each parser takes a [`ParseMode`]. Strict parsing follows the grammar;
+//! lenient parsing forgives what real phones get wrong but can still be
+//! understood: whitespace inside `<...>`, unquoted display names with
+//! separators in them, and empty list elements or parameters.
+
+use crate::message::{is_token_char, Method, ParseError, ParseMode};
+use crate::uri::{parse_host_port, Uri};
+use std::fmt;
+
+/// `;name=value` parameters of a header value, values kept as written
+/// (quoted values keep their quotes).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params(Vec<(String, Option<String>)>);
+
+impl Params {
+    /// Value of a parameter with its quotes removed; `Some("")` for a flag.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_deref().unwrap_or_default().trim_matches('"'))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn set(&mut self, name: &str, value: Option<&str>) {
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.0.push((name.to_string(), value.map(str::to_string)));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_deref()))
+    }
+
+    /// Parse the `;`-separated tail of a header value (without its leading
+    /// `;`).
+    fn parse(s: &str, mode: ParseMode, header: &str) -> Result<Self, ParseError> {
+        let invalid = || ParseError::Header(header.to_string());
+        let mut params = Vec::new();
+        for param in split_outside_quotes(s, ';') {
+            let param = param.trim();
+            if param.is_empty() {
+                if mode == ParseMode::Strict {
+                    return Err(invalid());
+                }
+                continue;
+            }
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (param, None),
+            };
+            if name.is_empty() || !name.bytes().all(is_token_char) {
+                return Err(invalid());
+            }
+            if let Some(value) = value {
+                let quoted = value.len() >= 2 && value.starts_with('"') && value.ends_with('"');
+                // Tokens, hosts (IPv6 brackets, colons) or quoted strings.
+                let bare = !value.is_empty()
+                    && value
+                        .bytes()
+                        .all(|b| is_token_char(b) || b"[]:".contains(&b));
+                if mode == ParseMode::Strict && !quoted && !bare {
+                    return Err(invalid());
+                }
+            }
+            params.push((name.to_string(), value.map(str::to_string)));
+        }
+        Ok(Params(params))
+    }
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.0 {
+            write!(f, ";{name}")?;
+            if let Some(value) = value {
+                write!(f, "={value}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One hop of a `Via` header: `SIP/2.0/UDP host:port;branch=...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Via {
+    /// Protocol name and version, normally `SIP/2.0`.
+    pub protocol: String,
+    pub transport: String,
+    pub host: String,
+    pub port: Option<u16>,
+    pub params: Params,
+}
+
+impl Via {
+    pub fn branch(&self) -> Option<&str> {
+        self.params.get("branch")
+    }
+
+    pub fn parse(value: &str, mode: ParseMode) -> Result<Self, ParseError> {
+        let invalid = || ParseError::Header(format!("Via: {value}"));
+        // sent-protocol allows whitespace around its slashes.
+        let mut rest = value.trim_start();
+        let mut parts = Vec::with_capacity(3);
+        for i in 0..3 {
+            if i > 0 {
+                rest = rest.strip_prefix('/').ok_or_else(invalid)?.trim_start();
+            }
+            let end = rest
+                .bytes()
+                .position(|b| !is_token_char(b))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            parts.push(&rest[..end]);
+            rest = rest[end..].trim_start();
+        }
+        let (sent_by, params) = match rest.split_once(';') {
+            Some((sent_by, params)) => (sent_by.trim(), params),
+            None => (rest.trim(), ""),
+        };
+        let (host, port) = parse_host_port(sent_by).map_err(|_| invalid())?;
+        let params = if rest.contains(';') {
+            Params::parse(params, mode, "Via")?
+        } else {
+            Params::default()
+        };
+        Ok(Via {
+            protocol: format!("{}/{}", parts[0], parts[1]),
+            transport: parts[2].to_string(),
+            host,
+            port,
+            params,
+        })
+    }
+
+    /// Every hop in a (possibly comma-separated) Via value.
+    pub fn parse_list(value: &str, mode: ParseMode) -> Result<Vec<Self>, ParseError> {
+        parse_list(value, mode, "Via", |v| Via::parse(v, mode))
+    }
+}
+
+impl fmt::Display for Via {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} {}", self.protocol, self.transport, self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        self.params.fmt(f)
+    }
+}
+
+/// An address as used in From, To, Contact, Route and Record-Route:
+/// `"Display Name" <uri>;params` or a bare `uri;params`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameAddr {
+    pub display_name: Option<String>,
+    pub uri: Uri,
+    pub params: Params,
+}
+
+impl NameAddr {
+    pub fn tag(&self) -> Option<&str> {
+        self.params.get("tag")
+    }
+
+    pub fn parse(value: &str, mode: ParseMode) -> Result<Self, ParseError> {
+        let invalid = || ParseError::Address(value.to_string());
+        let value = value.trim();
+        let (display_name, rest) = if let Some(quoted) = value.strip_prefix('"') {
+            let (display, rest) = unquote(quoted).ok_or_else(invalid)?;
+            let rest = rest.trim_start();
+            if !rest.starts_with('<') {
+                return Err(invalid());
+            }
+            (Some(display), rest)
+        } else if let Some(open) = value.find('<') {
+            let display = value[..open].trim();
+            let tokens_ok = display
+                .split_whitespace()
+                .all(|word| word.bytes().all(is_token_char));
+            if mode == ParseMode::Strict && !tokens_ok {
+                return Err(invalid());
+            }
+            (
+                (!display.is_empty()).then(|| display.to_string()),
+                &value[open..],
+            )
+        } else {
+            // addr-spec: the URI runs up to the first `;`, and may then not
+            // contain the characters that would make that ambiguous.
+            let (uri, params) = match value.split_once(';') {
+                Some((uri, params)) => (uri.trim_end(), Some(params)),
+                None => (value, None),
+            };
+            if uri.contains(['?', ',']) {
+                return Err(invalid());
+            }
+            let uri = uri.parse().map_err(|_| invalid())?;
+            let params = match params {
+                Some(params) => Params::parse(params, mode, value)?,
+                None => Params::default(),
+            };
+            return Ok(NameAddr {
+                display_name: None,
+                uri,
+                params,
+            });
+        };
+
+        let inner = rest.strip_prefix('<').ok_or_else(invalid)?;
+        let (uri, after) = inner.split_once('>').ok_or_else(invalid)?;
+        if mode == ParseMode::Strict && uri.trim() != uri {
+            return Err(invalid());
+        }
+        let uri = uri.trim().parse().map_err(|_| invalid())?;
+        let after = after.trim();
+        let params = match after.strip_prefix(';') {
+            Some(params) => Params::parse(params, mode, value)?,
+            None if after.is_empty() => Params::default(),
+            None => return Err(invalid()),
+        };
+        Ok(NameAddr {
+            display_name,
+            uri,
+            params,
+        })
+    }
+
+    /// Every address in a comma-separated value (Route, Record-Route).
+    pub fn parse_list(value: &str, mode: ParseMode) -> Result<Vec<Self>, ParseError> {
+        parse_list(value, mode, "address list", |v| NameAddr::parse(v, mode))
+    }
+}
+
+impl fmt::Display for NameAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(display) = &self.display_name {
+            f.write_str("\"")?;
+            for c in display.chars() {
+                if c == '"' || c == '\\' {
+                    f.write_str("\\")?;
+                }
+                write!(f, "{c}")?;
+            }
+            f.write_str("\" ")?;
+        }
+        write!(f, "<{}>{}", self.uri, self.params)
+    }
+}
+
+/// One value of a Contact header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Contact {
+    /// `*`, only valid in a REGISTER removing every binding.
+    Wildcard,
+    Address(NameAddr),
+}
+
+impl Contact {
+    pub fn parse_list(value: &str, mode: ParseMode) -> Result<Vec<Self>, ParseError> {
+        if value.trim() == "*" {
+            return Ok(vec![Contact::Wildcard]);
+        }
+        parse_list(value, mode, "Contact", |v| {
+            NameAddr::parse(v, mode).map(Contact::Address)
+        })
+    }
+}
+
+impl fmt::Display for Contact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Contact::Wildcard => f.write_str("*"),
+            Contact::Address(addr) => addr.fmt(f),
+        }
+    }
+}
+
+/// `CSeq: 42 INVITE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CSeq {
+    pub seq: u32,
+    pub method: Method,
+}
+
+impl CSeq {
+    pub fn parse(value: &str) -> Result<Self, ParseError> {
+        let invalid = || ParseError::Header(format!("CSeq: {value}"));
+        let mut parts = value.split_whitespace();
+        let (Some(seq), Some(method), None) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        // The sequence number must stay below 2**31 (RFC 3261 section 8.1.1.5).
+        let seq = seq
+            .parse::<u32>()
+            .ok()
+            .filter(|seq| *seq < 1 << 31)
+            .ok_or_else(invalid)?;
+        Ok(CSeq {
+            seq,
+            method: method.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for CSeq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.seq, self.method)
+    }
+}
+
+/// Read a quoted string after its opening quote, returning the unescaped
+/// content and what follows the closing quote.
+fn unquote(s: &str) -> Option<(String, &str)> {
+    let mut out = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &s[i + 1..])),
+            '\\' => out.push(chars.next()?.1),
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+/// Split on `sep` where it is not inside a quoted string or `<...>`.
+fn split_outside_quotes(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut quoted, mut escaped, mut angle) = (false, false, false);
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '<' if !quoted => angle = true,
+            '>' if !quoted => angle = false,
+            c if c == sep && !quoted && !angle => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn parse_list<T>(
+    value: &str,
+    mode: ParseMode,
+    header: &str,
+    parse: impl Fn(&str) -> Result<T, ParseError>,
+) -> Result<Vec<T>, ParseError> {
+    let mut items = Vec::new();
+    for item in split_outside_quotes(value, ',') {
+        // Lenient mode skips stray separators such as `;,,` left behind by
+        // sloppy implementations.
+        if item
+            .trim_matches(|c: char| c.is_whitespace() || c == ';')
+            .is_empty()
+        {
+            if mode == ParseMode::Strict {
+                return Err(ParseError::Header(header.to_string()));
+            }
+            continue;
+        }
+        items.push(parse(item)?);
+    }
+    Ok(items)
+}
+
+/// Value of a `;name=value` parameter in a raw header such as From or Via.
+pub fn param<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    // Parameters after a bracketed URI belong to the header, not the URI.
+    let params = match value.rfind('>') {
+        Some(end) => &value[end + 1..],
+        None => value,
+    };
+    params.split(';').skip(1).find_map(|p| {
+        let (key, val) = p.split_once('=').unwrap_or((p, ""));
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| val.trim().trim_matches('"'))
+    })
+}
+
+/// The URI inside a raw name-addr (`"Alice" <sip:alice@x>;tag=1`) or
+/// addr-spec.
+pub fn uri_of(value: &str) -> &str {
+    match (value.find('<'), value.find('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value.split(';').next().unwrap_or(value).trim(),
+    }
+}
+
+/// The user part of a SIP URI (`sip:user@host` → `user`).
+pub fn user_of(uri: &str) -> Option<&str> {
+    let rest = uri
+        .strip_prefix("sip:")
+        .or_else(|| uri.strip_prefix("sips:"))?;
+    let (user, _) = rest.split_once('@')?;
+    // Drop the password and any user parameters.
+    let user = user.split([':', ';']).next()?;
+    (!user.is_empty()).then_some(user)
+}
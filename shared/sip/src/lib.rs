@@ -0,0 +1,23 @@
+//! SIP message model, parser and serializer (RFC 3261).
+//!
+//! Shared by every SIP transport and by trunking. A [`Message`] keeps its
+//! start line, an ordered header list and the body. Header values are stored
+//! as raw text, so unknown or vendor headers survive when a message is echoed
+//! back, and the headers routing cares about (Via, From/To, Contact, CSeq,
+//! Route, Record-Route) are parsed into typed values on demand.
+//!
+//! Parsing comes in two modes. [`ParseMode::Lenient`] is what transports use:
+//! it accepts bare LF line endings and only checks what is needed to process
+//! the message. [`ParseMode::Strict`] additionally validates the typed headers
+//! and the request URI against the RFC 3261 grammar, which is what the
+//! RFC 4475 torture tests expect of a conforming parser.
+
+mod header;
+mod message;
+mod uri;
+
+pub use header::{param, uri_of, user_of, CSeq, Contact, NameAddr, Params, Via};
+pub use message::{
+    frame_len, Headers, Message, Method, ParseError, ParseMode, Request, Response, VERSION,
+};
+pub use uri::{unescape, SipUri, Uri};
@@ -0,0 +1,616 @@
+//! Messages, start lines and the header list (RFC 3261 section 7).
+
+use crate::header::{CSeq, Contact, NameAddr, Via};
+use crate::uri::Uri;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("message is not valid UTF-8")]
+    Encoding,
+    #[error("missing header terminator")]
+    Incomplete,
+    #[error("malformed start line")]
+    StartLine,
+    #[error("unsupported SIP version {0:?}")]
+    Version(String),
+    #[error("malformed header line {0:?}")]
+    Header(String),
+    #[error("invalid Content-Length")]
+    ContentLength,
+    #[error("body shorter than Content-Length")]
+    TruncatedBody,
+    #[error("missing mandatory header {0}")]
+    MissingHeader(&'static str),
+    #[error("header {0} may appear only once")]
+    Duplicate(&'static str),
+    #[error("bare LF line ending")]
+    LineEnding,
+    #[error("invalid URI {0:?}")]
+    Uri(String),
+    #[error("invalid address {0:?}")]
+    Address(String),
+    #[error("CSeq method does not match the request method")]
+    CSeqMismatch,
+    #[error("invalid Max-Forwards")]
+    MaxForwards,
+}
+
+/// How forgiving [`Message::parse_with`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Accept anything that can be processed unambiguously.
+    #[default]
+    Lenient,
+    /// Enforce the RFC 3261 grammar on the start line and routing headers.
+    Strict,
+}
+
+/// Headers that may appear at most once (RFC 3261 section 7.3.1).
+const SINGLE_VALUED: [&str; 7] = [
+    "Call-ID",
+    "CSeq",
+    "From",
+    "To",
+    "Max-Forwards",
+    "Content-Length",
+    "Content-Type",
+];
+
+/// Headers every request and response carries (RFC 3261 section 8.1.1).
+const MANDATORY: [&str; 5] = ["Via", "From", "To", "Call-ID", "CSeq"];
+
+pub const VERSION: &str = "SIP/2.0";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Register,
+    Invite,
+    Ack,
+    Bye,
+    Cancel,
+    Options,
+    Refer,
+    Notify,
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Register => "REGISTER",
+            Method::Invite => "INVITE",
+            Method::Ack => "ACK",
+            Method::Bye => "BYE",
+            Method::Cancel => "CANCEL",
+            Method::Options => "OPTIONS",
+            Method::Refer => "REFER",
+            Method::Notify => "NOTIFY",
+            Method::Other(other) => other,
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || !s.bytes().all(is_token_char) {
+            return Err(ParseError::StartLine);
+        }
+        // Methods are case-sensitive (RFC 3261 section 7.1).
+        Ok(match s {
+            "REGISTER" => Method::Register,
+            "INVITE" => Method::Invite,
+            "ACK" => Method::Ack,
+            "BYE" => Method::Bye,
+            "CANCEL" => Method::Cancel,
+            "OPTIONS" => Method::Options,
+            "REFER" => Method::Refer,
+            "NOTIFY" => Method::Notify,
+            other => Method::Other(other.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Ordered header list. Names are stored in their full (non-compact) form;
+/// lookups are case-insensitive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// First value of the header.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of the header, in message order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn push(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    /// Replace every occurrence of the header with a single value.
+    pub fn set(&mut self, name: &str, value: impl Into<String>) {
+        self.remove(name);
+        self.push(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// Every hop of every Via header, topmost first.
+    pub fn via(&self) -> Result<Vec<Via>, ParseError> {
+        self.list("Via", |value| Via::parse_list(value, ParseMode::Lenient))
+    }
+
+    pub fn from(&self) -> Result<NameAddr, ParseError> {
+        self.address("From")
+    }
+
+    pub fn to(&self) -> Result<NameAddr, ParseError> {
+        self.address("To")
+    }
+
+    pub fn contact(&self) -> Result<Vec<Contact>, ParseError> {
+        self.list("Contact", |value| {
+            Contact::parse_list(value, ParseMode::Lenient)
+        })
+    }
+
+    pub fn route(&self) -> Result<Vec<NameAddr>, ParseError> {
+        self.list("Route", |value| {
+            NameAddr::parse_list(value, ParseMode::Lenient)
+        })
+    }
+
+    pub fn record_route(&self) -> Result<Vec<NameAddr>, ParseError> {
+        self.list("Record-Route", |value| {
+            NameAddr::parse_list(value, ParseMode::Lenient)
+        })
+    }
+
+    pub fn cseq(&self) -> Result<CSeq, ParseError> {
+        CSeq::parse(self.get("CSeq").ok_or(ParseError::MissingHeader("CSeq"))?)
+    }
+
+    fn address(&self, name: &'static str) -> Result<NameAddr, ParseError> {
+        let value = self.get(name).ok_or(ParseError::MissingHeader(name))?;
+        NameAddr::parse(value, ParseMode::Lenient)
+    }
+
+    fn list<T>(
+        &self,
+        name: &str,
+        parse: impl Fn(&str) -> Result<Vec<T>, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        let mut items = Vec::new();
+        for value in self.get_all(name) {
+            items.extend(parse(value)?);
+        }
+        Ok(items)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    pub uri: String,
+    pub headers: Headers,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Request(Request),
+    Response(Response),
+}
+
+impl Request {
+    pub fn new(method: Method, uri: impl Into<String>) -> Self {
+        Request {
+            method,
+            uri: uri.into(),
+            headers: Headers::default(),
+            body: String::new(),
+        }
+    }
+
+    pub fn call_id(&self) -> Option<&str> {
+        self.headers.get("Call-ID")
+    }
+
+    pub fn cseq(&self) -> Option<CSeq> {
+        self.headers.cseq().ok()
+    }
+
+    /// The Request-URI as a typed value.
+    pub fn request_uri(&self) -> Result<Uri, ParseError> {
+        self.uri.parse()
+    }
+}
+
+impl Response {
+    /// Build a response to `req`, copying the headers RFC 3261 section 8.2.6
+    /// requires (Via, From, To, Call-ID, CSeq).
+    pub fn to(req: &Request, status: u16, reason: impl Into<String>) -> Self {
+        let mut headers = Headers::default();
+        for name in ["Via", "From", "To", "Call-ID", "CSeq"] {
+            for value in req.headers.get_all(name) {
+                headers.push(name, value);
+            }
+        }
+        Response {
+            status,
+            reason: reason.into(),
+            headers,
+            body: String::new(),
+        }
+    }
+
+    pub fn cseq(&self) -> Option<CSeq> {
+        self.headers.cseq().ok()
+    }
+}
+
+/// Expand the single-letter compact header forms (RFC 3261 section 7.3.3).
+fn canonical_name(name: &str) -> &str {
+    match name {
+        "i" | "I" => "Call-ID",
+        "m" | "M" => "Contact",
+        "e" | "E" => "Content-Encoding",
+        "l" | "L" => "Content-Length",
+        "c" | "C" => "Content-Type",
+        "f" | "F" => "From",
+        "s" | "S" => "Subject",
+        "k" | "K" => "Supported",
+        "t" | "T" => "To",
+        "v" | "V" => "Via",
+        "r" | "R" => "Refer-To",
+        "b" | "B" => "Referred-By",
+        "o" | "O" => "Event",
+        other => other,
+    }
+}
+
+pub(crate) fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-.!%*_+`'~".contains(&b)
+}
+
+impl FromStr for Message {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Message::parse(s.as_bytes())
+    }
+}
+
+impl Message {
+    /// Parse one complete message, as carried in a single websocket frame or
+    /// UDP datagram, in [`ParseMode::Lenient`]. Bytes past Content-Length
+    /// are ignored.
+    pub fn parse(raw: &[u8]) -> Result<Self, ParseError> {
+        Message::parse_with(raw, ParseMode::Lenient)
+    }
+
+    pub fn parse_with(raw: &[u8], mode: ParseMode) -> Result<Self, ParseError> {
+        let strict = mode == ParseMode::Strict;
+        // Tolerate leading blank lines (keep-alives) before the start line.
+        let skip = raw
+            .iter()
+            .position(|b| !matches!(b, b'\r' | b'\n'))
+            .unwrap_or(raw.len());
+        let raw = &raw[skip..];
+        let (head_end, body_start) = find_head_end(raw).ok_or(ParseError::Incomplete)?;
+        let head = std::str::from_utf8(&raw[..head_end]).map_err(|_| ParseError::Encoding)?;
+        if strict && (head.replace("\r\n", "").contains('\n') || body_start - head_end != 4) {
+            return Err(ParseError::LineEnding);
+        }
+
+        let mut lines = unfold(head).into_iter();
+        let start = lines.next().ok_or(ParseError::StartLine)?;
+        let mut headers = Headers::default();
+        for line in lines {
+            let parsed = line.split_once(':').and_then(|(name, value)| {
+                let name = name.trim_end_matches([' ', '\t']);
+                (!name.is_empty() && name.bytes().all(is_token_char)).then_some((name, value))
+            });
+            match parsed {
+                Some((name, value)) => headers.push(canonical_name(name), value.trim()),
+                // A line that is not a header cannot be understood, but it
+                // need not stop the rest of the message from being.
+                None if !strict => continue,
+                None => return Err(ParseError::Header(line)),
+            }
+        }
+
+        let rest = &raw[body_start..];
+        let first_length = headers.get("Content-Length");
+        let conflicting = headers
+            .get_all("Content-Length")
+            .any(|other| Some(other) != first_length);
+        if strict && conflicting {
+            return Err(ParseError::Duplicate("Content-Length"));
+        }
+        let body = match first_length {
+            Some(len) => {
+                let len: usize = len.parse().map_err(|_| ParseError::ContentLength)?;
+                rest.get(..len).ok_or(ParseError::TruncatedBody)?
+            }
+            None => rest,
+        };
+        let body = std::str::from_utf8(body)
+            .map_err(|_| ParseError::Encoding)?
+            .to_string();
+
+        let msg = if let Some(rest) = start.strip_prefix("SIP/") {
+            let mut parts = rest.splitn(3, ' ');
+            let version = parts.next().unwrap_or_default();
+            if !version.eq_ignore_ascii_case("2.0") {
+                return Err(ParseError::Version(format!("SIP/{version}")));
+            }
+            let status = parts
+                .next()
+                .filter(|code| code.len() == 3)
+                .and_then(|code| code.parse::<u16>().ok())
+                .filter(|code| (100..700).contains(code))
+                .ok_or(ParseError::StartLine)?;
+            let reason = parts.next().unwrap_or_default().to_string();
+            Message::Response(Response {
+                status,
+                reason,
+                headers,
+                body,
+            })
+        } else {
+            let mut parts = start.split(' ');
+            let (Some(method), Some(uri), Some(version), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(ParseError::StartLine);
+            };
+            if !version.eq_ignore_ascii_case(VERSION) {
+                return Err(ParseError::Version(version.to_string()));
+            }
+            if uri.is_empty() {
+                return Err(ParseError::StartLine);
+            }
+            let req = Request {
+                method: method.parse()?,
+                uri: uri.to_string(),
+                headers,
+                body,
+            };
+            for name in MANDATORY {
+                if req.headers.get(name).is_none() {
+                    return Err(ParseError::MissingHeader(name));
+                }
+            }
+            if req.cseq().is_none() {
+                return Err(ParseError::Header("CSeq".to_string()));
+            }
+            Message::Request(req)
+        };
+
+        if strict {
+            msg.validate()?;
+        }
+        Ok(msg)
+    }
+
+    pub fn headers(&self) -> &Headers {
+        match self {
+            Message::Request(req) => &req.headers,
+            Message::Response(res) => &res.headers,
+        }
+    }
+
+    /// The checks [`ParseMode::Strict`] adds on top of lenient parsing.
+    fn validate(&self) -> Result<(), ParseError> {
+        let headers = self.headers();
+        for name in MANDATORY {
+            if headers.get(name).is_none() {
+                return Err(ParseError::MissingHeader(name));
+            }
+        }
+        for name in SINGLE_VALUED {
+            if headers.get_all(name).nth(1).is_some() {
+                return Err(ParseError::Duplicate(name));
+            }
+        }
+        let strict = ParseMode::Strict;
+        for value in headers.get_all("Via") {
+            Via::parse_list(value, strict)?;
+        }
+        for name in ["From", "To"] {
+            NameAddr::parse(headers.get(name).unwrap_or_default(), strict)?;
+        }
+        for value in headers.get_all("Contact") {
+            Contact::parse_list(value, strict)?;
+        }
+        for name in ["Route", "Record-Route"] {
+            for value in headers.get_all(name) {
+                NameAddr::parse_list(value, strict)?;
+            }
+        }
+        let cseq = headers.cseq()?;
+        if let Some(max_forwards) = headers.get("Max-Forwards") {
+            let valid = !max_forwards.is_empty()
+                && max_forwards.bytes().all(|b| b.is_ascii_digit())
+                && max_forwards.parse::<u8>().is_ok();
+            if !valid {
+                return Err(ParseError::MaxForwards);
+            }
+        }
+        if let Message::Request(req) = self {
+            if cseq.method != req.method {
+                return Err(ParseError::CSeqMismatch);
+            }
+            // Headers belong in the message, not the Request-URI
+            // (RFC 3261 section 19.1.5).
+            if let Uri::Sip(uri) = req.request_uri()? {
+                if !uri.headers.is_empty() {
+                    return Err(ParseError::Uri(req.uri.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Length of the first message in a stream buffer, known once its header
+/// block has arrived; the body may still be on its way. Streams have no
+/// datagram boundaries, so Content-Length is mandatory there (RFC 3261
+/// section 18.3).
+pub fn frame_len(buf: &[u8]) -> Result<Option<usize>, ParseError> {
+    let Some((head_end, body_start)) = find_head_end(buf) else {
+        return Ok(None);
+    };
+    let head = std::str::from_utf8(&buf[..head_end]).map_err(|_| ParseError::Encoding)?;
+    let len = unfold(head)
+        .iter()
+        .skip(1)
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            canonical_name(name.trim_end())
+                .eq_ignore_ascii_case("Content-Length")
+                .then(|| value.trim().parse::<usize>())
+        })
+        .ok_or(ParseError::MissingHeader("Content-Length"))?
+        .map_err(|_| ParseError::ContentLength)?;
+    body_start
+        .checked_add(len)
+        .map(Some)
+        .ok_or(ParseError::ContentLength)
+}
+
+/// Offsets of the end of the header block and the start of the body.
+fn find_head_end(raw: &[u8]) -> Option<(usize, usize)> {
+    let crlf = raw.windows(4).position(|w| w == b"\r\n\r\n");
+    let lf = raw.windows(2).position(|w| w == b"\n\n");
+    match (crlf, lf) {
+        (Some(c), Some(l)) if l < c => Some((l, l + 2)),
+        (Some(c), _) => Some((c, c + 4)),
+        (None, Some(l)) => Some((l, l + 2)),
+        (None, None) => None,
+    }
+}
+
+/// Split the header block into logical lines, joining folded continuation
+/// lines (those starting with whitespace) onto the previous one.
+fn unfold(head: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in head.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.starts_with([' ', '\t']) {
+            if let Some(last) = lines.last_mut() {
+                last.push(' ');
+                last.push_str(line.trim());
+                continue;
+            }
+        }
+        lines.push(line.to_string());
+    }
+    lines
+}
+
+/// Write the header block, with a Content-Length that matches the body: in
+/// place of the first one present, or last if there was none.
+fn write_headers(f: &mut fmt::Formatter<'_>, headers: &Headers, body: &str) -> fmt::Result {
+    let mut length_written = false;
+    for (name, value) in headers.iter() {
+        if !name.eq_ignore_ascii_case("Content-Length") {
+            write!(f, "{name}: {value}\r\n")?;
+        } else if !length_written {
+            write!(f, "{name}: {}\r\n", body.len())?;
+            length_written = true;
+        }
+    }
+    if !length_written {
+        write!(f, "Content-Length: {}\r\n", body.len())?;
+    }
+    write!(f, "\r\n{body}")
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {VERSION}\r\n", self.method, self.uri)?;
+        write_headers(f, &self.headers, &self.body)
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{VERSION} {} {}\r\n", self.status, self.reason)?;
+        write_headers(f, &self.headers, &self.body)
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Request(req) => req.fmt(f),
+            Message::Response(res) => res.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEAD: &str = "OPTIONS sip:a@example.com SIP/2.0\r\nContent-Length: ";
+
+    #[test]
+    fn a_frame_is_sized_once_its_head_arrives() {
+        let raw = format!("{HEAD}4\r\n\r\nbo");
+        assert_eq!(frame_len(raw.as_bytes()), Ok(Some(raw.len() + 2)));
+        assert_eq!(frame_len(HEAD.as_bytes()), Ok(None));
+    }
+
+    #[test]
+    fn a_stream_frame_needs_a_content_length() {
+        let raw = b"OPTIONS sip:a@example.com SIP/2.0\r\n\r\n";
+        assert_eq!(
+            frame_len(raw),
+            Err(ParseError::MissingHeader("Content-Length"))
+        );
+        let raw = format!("{HEAD}-1\r\n\r\n");
+        assert_eq!(frame_len(raw.as_bytes()), Err(ParseError::ContentLength));
+    }
+
+    #[test]
+    fn an_overflowing_content_length_is_refused() {
+        let raw = format!("{HEAD}{}\r\n\r\n", usize::MAX);
+        assert_eq!(frame_len(raw.as_bytes()), Err(ParseError::ContentLength));
+    }
+}
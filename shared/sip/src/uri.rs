@@ -0,0 +1,273 @@
+//! SIP and SIPS URIs (RFC 3261 section 19.1).
+
+use crate::message::ParseError;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Uri {
+    Sip(SipUri),
+    /// Any other absolute URI (`tel:`, `urn:`, ...), kept as written.
+    Other(String),
+}
+
+/// `sip:user:password@host:port;params?headers`. User, password and
+/// parameter values are kept escaped, exactly as they appeared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SipUri {
+    /// `sips:` rather than `sip:`.
+    pub secure: bool,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    pub params: Vec<(String, Option<String>)>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl SipUri {
+    pub fn param(&self, name: &str) -> Option<Option<&str>> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_deref())
+    }
+}
+
+impl Uri {
+    pub fn as_sip(&self) -> Option<&SipUri> {
+        match self {
+            Uri::Sip(uri) => Some(uri),
+            Uri::Other(_) => None,
+        }
+    }
+}
+
+/// RFC 3261 `unreserved`: alphanumerics and `mark`.
+fn is_unreserved(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-_.!~*'()".contains(c)
+}
+
+/// Check that every character is allowed or part of a `%XX` escape.
+fn valid_escaped(s: &str, allowed: impl Fn(char) -> bool) -> bool {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let escape = bytes.get(i + 1..i + 3);
+            if !escape.is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit)) {
+                return false;
+            }
+            i += 3;
+            continue;
+        }
+        let c = s[i..].chars().next().expect("index is on a char boundary");
+        if !allowed(c) {
+            return false;
+        }
+        i += c.len_utf8();
+    }
+    true
+}
+
+fn valid_host(host: &str) -> bool {
+    if let Some(v6) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return !v6.is_empty()
+            && v6
+                .chars()
+                .all(|c| c.is_ascii_hexdigit() || c == ':' || c == '.');
+    }
+    !host.is_empty()
+        && host.split('.').enumerate().all(|(i, label)| {
+            // A trailing dot (fully qualified name) leaves one empty label.
+            (label.is_empty() && i > 0 && host.ends_with('.'))
+                || (!label.is_empty()
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+        })
+}
+
+/// Decode `%XX` escapes, as needed for URI header values such as an
+/// escaped `Replaces`. Malformed escapes are kept as written.
+pub fn unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = (bytes[i] == b'%')
+            .then(|| s.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Split `host[:port]`, validating both.
+pub(crate) fn parse_host_port(s: &str) -> Result<(String, Option<u16>), ParseError> {
+    let invalid = || ParseError::Uri(s.to_string());
+    let (host, port) = if s.starts_with('[') {
+        let end = s.find(']').ok_or_else(invalid)?;
+        let port = s[end + 1..].strip_prefix(':');
+        if port.is_none() && end + 1 != s.len() {
+            return Err(invalid());
+        }
+        (&s[..=end], port)
+    } else {
+        match s.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (s, None),
+        }
+    };
+    if !valid_host(host) {
+        return Err(invalid());
+    }
+    let port = port
+        .map(|port| port.parse::<u16>().map_err(|_| invalid()))
+        .transpose()?;
+    Ok((host.to_string(), port))
+}
+
+impl FromStr for Uri {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::Uri(s.to_string());
+        let (scheme, rest) = s.split_once(':').ok_or_else(invalid)?;
+        let scheme_ok = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+        // URIs never contain whitespace, controls or the characters that
+        // delimit them in headers.
+        let chars_ok = s
+            .chars()
+            .all(|c| c > ' ' && c != '\u{7f}' && !"<>\"".contains(c));
+        if !scheme_ok || !chars_ok || rest.is_empty() {
+            return Err(invalid());
+        }
+        let secure = match scheme.to_ascii_lowercase().as_str() {
+            "sip" => false,
+            "sips" => true,
+            _ => return Ok(Uri::Other(s.to_string())),
+        };
+
+        // The user part may itself contain `?` and `;`, so split it off
+        // first; `@` cannot appear unescaped anywhere after it.
+        let (userinfo, after_user) = match rest.find('@') {
+            Some(at) => (Some(&rest[..at]), &rest[at + 1..]),
+            None => (None, rest),
+        };
+        let (host_part, headers) = match after_user.split_once('?') {
+            Some((host_part, headers)) => (host_part, Some(headers)),
+            None => (after_user, None),
+        };
+        let (user, password) = match userinfo {
+            Some(userinfo) => match userinfo.split_once(':') {
+                Some((user, password)) => (Some(user), Some(password)),
+                None => (Some(userinfo), None),
+            },
+            None => (None, None),
+        };
+        let user_ok = user.is_none_or(|user| {
+            !user.is_empty() && valid_escaped(user, |c| is_unreserved(c) || "&=+$,;?/".contains(c))
+        });
+        let password_ok = password.is_none_or(|password| {
+            valid_escaped(password, |c| is_unreserved(c) || "&=+$,".contains(c))
+        });
+        if !user_ok || !password_ok {
+            return Err(invalid());
+        }
+
+        let mut parts = host_part.split(';');
+        let (host, port) = parse_host_port(parts.next().unwrap_or_default())?;
+        let params = parts
+            .map(|param| {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (param, None),
+                };
+                let param_ok = |s: &str| {
+                    !s.is_empty() && valid_escaped(s, |c| is_unreserved(c) || "[]/:&+$".contains(c))
+                };
+                if !param_ok(name) || !value.is_none_or(param_ok) {
+                    return Err(invalid());
+                }
+                Ok((name.to_string(), value.map(str::to_string)))
+            })
+            .collect::<Result<_, _>>()?;
+        let headers = match headers {
+            Some(headers) => headers
+                .split('&')
+                .map(|header| {
+                    let (name, value) = header.split_once('=').ok_or_else(invalid)?;
+                    let header_ok =
+                        |s: &str| valid_escaped(s, |c| is_unreserved(c) || "[]/?:+$".contains(c));
+                    if name.is_empty() || !header_ok(name) || !header_ok(value) {
+                        return Err(invalid());
+                    }
+                    Ok((name.to_string(), value.to_string()))
+                })
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+
+        Ok(Uri::Sip(SipUri {
+            secure,
+            user: user.map(str::to_string),
+            password: password.map(str::to_string),
+            host,
+            port,
+            params,
+            headers,
+        }))
+    }
+}
+
+impl fmt::Display for SipUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.secure { "sips:" } else { "sip:" })?;
+        if let Some(user) = &self.user {
+            f.write_str(user)?;
+            if let Some(password) = &self.password {
+                write!(f, ":{password}")?;
+            }
+            f.write_str("@")?;
+        }
+        f.write_str(&self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        for (name, value) in &self.params {
+            write!(f, ";{name}")?;
+            if let Some(value) = value {
+                write!(f, "={value}")?;
+            }
+        }
+        for (i, (name, value)) in self.headers.iter().enumerate() {
+            let sep = if i == 0 { '?' } else { '&' };
+            write!(f, "{sep}{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Uri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Uri::Sip(uri) => uri.fmt(f),
+            Uri::Other(uri) => f.write_str(uri),
+        }
+    }
+}
@@ -0,0 +1,947 @@
+//! Torture messages from RFC 4475 ("SIP Torture Test Messages").
+//!
+//! Valid messages must parse strictly and survive a serialize/parse round
+//! trip; invalid ones must be refused in strict mode. Where lenient mode is
+//! meant to cope with a message anyway, that is checked too.
+
+use sip::{Contact, Message, Method, NameAddr, ParseError, ParseMode, Uri, Via};
+
+const SDP: &str = "v=0\r\n\
+                   o=mhandley 29739 7272939 IN IP4 192.0.2.3\r\n\
+                   s=-\r\n\
+                   c=IN IP4 192.0.2.4\r\n\
+                   t=0 0\r\n\
+                   m=audio 49217 RTP/AVP 0 12\r\n\
+                   m=video 3227 RTP/AVP 31\r\n\
+                   a=rtpmap:31 LPC\r\n";
+
+/// Join lines with CRLF, end the header block and append `body`. `{len}`
+/// in a line is replaced with the body's length.
+fn message(lines: &[&str], body: &str) -> Vec<u8> {
+    let head = lines.join("\r\n").replace("{len}", &body.len().to_string());
+    format!("{head}\r\n\r\n{body}").into_bytes()
+}
+
+fn strict(raw: &[u8]) -> Result<Message, ParseError> {
+    Message::parse_with(raw, ParseMode::Strict)
+}
+
+fn lenient(raw: &[u8]) -> Result<Message, ParseError> {
+    Message::parse_with(raw, ParseMode::Lenient)
+}
+
+/// Parse strictly, then check the serialized form parses back to the same
+/// message.
+fn valid(raw: &[u8]) -> Message {
+    let msg = strict(raw).unwrap_or_else(|err| panic!("valid message refused: {err}"));
+    let reparsed = strict(msg.to_string().as_bytes()).expect("serialized message parses");
+    assert_eq!(reparsed, msg);
+    msg
+}
+
+fn request(msg: Message) -> sip::Request {
+    match msg {
+        Message::Request(req) => req,
+        Message::Response(_) => panic!("expected a request"),
+    }
+}
+
+// 3.1.1.1 A Short Tortuous INVITE
+#[test]
+fn short_tortuous_invite() {
+    let raw = message(
+        &[
+            "INVITE sip:vivekg@chair-dnrc.example.com;unknownparam SIP/2.0",
+            "TO :",
+            " sip:vivekg@chair-dnrc.example.com ;   tag    = 1918181833n",
+            r#"from   : "J Rosenberg \\\""       <sip:jdrosen@example.com>"#,
+            "  ;",
+            "  tag = 98asjd8",
+            "MaX-fOrWaRdS: 0068",
+            "Call-ID: wsinv.ndaksdj@192.0.2.1",
+            "Content-Length   : {len}",
+            "cseq: 0009",
+            "  INVITE",
+            "Via  : SIP  /   2.0",
+            " /UDP",
+            "    192.0.2.2;rport;branch=390skdjuw",
+            "s :",
+            "NewFangledHeader:   newfangled value",
+            " continued newfangled value",
+            "UnknownHeaderWithUnusualValue: ;;,,;;,;",
+            "Content-Type: application/sdp",
+            "Route:",
+            " <sip:services.example.com;lr;unknownwith=value;unknown-no-value>",
+            "v:  SIP  / 2.0  / TCP     spindle.example.com   ;",
+            "  branch  =   z9hG4bK9ikj8  ,",
+            " SIP  /    2.0   / UDP  192.168.255.111   ; branch=",
+            " z9hG4bK30239",
+            r#"m:"Quoted string \"\"" <sip:jdrosen@example.com> ; newparam ="#,
+            "      newvalue ;",
+            "  secondparam ; q = 0.33",
+        ],
+        SDP,
+    );
+    let req = request(valid(&raw));
+    assert_eq!(req.method, Method::Invite);
+    assert_eq!(req.body, SDP);
+
+    let cseq = req.cseq().unwrap();
+    assert_eq!((cseq.seq, cseq.method), (9, Method::Invite));
+    assert_eq!(req.headers.to().unwrap().tag(), Some("1918181833n"));
+    let from = req.headers.from().unwrap();
+    assert_eq!(from.display_name.as_deref(), Some(r#"J Rosenberg \""#));
+    assert_eq!(from.tag(), Some("98asjd8"));
+
+    let vias = req.headers.via().unwrap();
+    let hops: Vec<_> = vias
+        .iter()
+        .map(|via| (via.transport.as_str(), via.host.as_str(), via.branch()))
+        .collect();
+    assert_eq!(
+        hops,
+        [
+            ("UDP", "192.0.2.2", Some("390skdjuw")),
+            ("TCP", "spindle.example.com", Some("z9hG4bK9ikj8")),
+            ("UDP", "192.168.255.111", Some("z9hG4bK30239")),
+        ]
+    );
+    assert!(vias[0].params.contains("rport"));
+
+    let Contact::Address(contact) = &req.headers.contact().unwrap()[0] else {
+        panic!("expected an address");
+    };
+    assert_eq!(contact.display_name.as_deref(), Some(r#"Quoted string """#));
+    assert_eq!(contact.params.get("newparam"), Some("newvalue"));
+    assert_eq!(contact.params.get("q"), Some("0.33"));
+
+    let route = req.headers.route().unwrap();
+    let route_uri = route[0].uri.as_sip().unwrap();
+    assert_eq!(route_uri.host, "services.example.com");
+    assert_eq!(route_uri.param("lr"), Some(None));
+}
+
+// 3.1.1.3 Valid Use of the % Escaping Mechanism
+#[test]
+fn escaped_uris() {
+    let raw = message(
+        &[
+            "INVITE sip:sips%3Auser%40example.com@example.net SIP/2.0",
+            "To: sip:%75se%72@example.com",
+            "From: <sip:I%20have%20spaces@example.net>;tag=938",
+            "Max-Forwards: 87",
+            "i: esc01.239409asdfakjkn23onasd0-3234",
+            "CSeq: 234234 INVITE",
+            "Via: SIP/2.0/UDP host5.example.net;branch=z9hG4bKkdjuw",
+            "C: application/sdp",
+            "Contact:",
+            "  <sip:cal%6Cer@host5.example.net;%6C%72;n%61me=v%61lue%25%31>",
+            "Content-Length: {len}",
+        ],
+        SDP,
+    );
+    let req = request(valid(&raw));
+    let Uri::Sip(uri) = req.request_uri().unwrap() else {
+        panic!("expected a sip uri");
+    };
+    assert_eq!(uri.user.as_deref(), Some("sips%3Auser%40example.com"));
+    assert_eq!(uri.host, "example.net");
+    assert_eq!(req.call_id(), Some("esc01.239409asdfakjkn23onasd0-3234"));
+    assert_eq!(req.headers.get("Content-Type"), Some("application/sdp"));
+}
+
+// 3.1.1.4 Escaped Nulls in URIs
+#[test]
+fn escaped_nulls() {
+    let raw = message(
+        &[
+            "REGISTER sip:example.com SIP/2.0",
+            "To: sip:null-%00-null@example.com",
+            "From: sip:null-%00-null@example.com;tag=839923423",
+            "Max-Forwards: 70",
+            "Call-ID: escnull.39203ndfvkjdasfkq3w4otrq0adsfdfnavd",
+            "CSeq: 14398234 REGISTER",
+            "Via: SIP/2.0/UDP host5.example.com;branch=z9hG4bKkdjuw",
+            "Contact: <sip:%00@host5.example.com>",
+            "Contact: <sip:%00%00@host5.example.com>",
+            "L:0",
+        ],
+        "",
+    );
+    let req = request(valid(&raw));
+    assert_eq!(req.headers.contact().unwrap().len(), 2);
+}
+
+// 3.1.1.5 Use of % When It Is Not an Escape
+#[test]
+fn percent_outside_escapes() {
+    let raw = message(
+        &[
+            "RE%47IST%45R sip:registrar.example.com SIP/2.0",
+            r#"To: "%Z%45" <sip:resource@example.com>"#,
+            r#"From: "%Z%45" <sip:resource@example.com>;tag=f232jadfj23"#,
+            "Call-ID: esc02.asdfnqwo34rq23i34jrjasdcnl23nrlknsdf",
+            "Via: SIP/2.0/TCP host.example.com;rport;branch=z9hG4bK209793",
+            "Contact: <sip:alias1@host1.example.com>, <sip:alias2@host2.example.com>,",
+            " <sip:alias3@host3.example.com>",
+            "Max-Forwards: 70",
+            "CSeq: 29344 RE%47IST%45R",
+            "Content-Length: 0",
+        ],
+        "",
+    );
+    let req = request(valid(&raw));
+    // Methods are never unescaped; this is not a REGISTER.
+    assert_eq!(req.method, Method::Other("RE%47IST%45R".to_string()));
+    assert_eq!(req.headers.contact().unwrap().len(), 3);
+    assert_eq!(
+        req.headers.to().unwrap().display_name.as_deref(),
+        Some("%Z%45")
+    );
+}
+
+// 3.1.1.2 Wide Range of Valid Characters
+#[test]
+fn unusual_characters() {
+    let raw = message(
+        &[
+            "!interesting-Method0123456789_*+`.%indeed'~ \
+             sip:1_unusual.URI~(to-be!sure)&isn't+it$/crazy?,/;;*:&it+has=1,weird!*pas$wo~d_too.(doesn't-it)@example.com SIP/2.0",
+            "Via: SIP/2.0/TCP host1.example.com;branch=z9hG4bK-.!%66*_+`'~",
+            "To: \"BEL:\\\u{7} NUL:\\\u{0} DEL:\\\u{7f}\" \
+             <sip:1_unusual.URI~(to-be!sure)&isn't+it$/crazy?,/;;*@example.com>",
+            "From: token1~` token2'+_ token3*%!.- <sip:mundane@example.com>\
+             ;fromParam''~+*_!.-%=\"работающий\";tag=_token~1'+`*%!-.",
+            "Call-ID: intmeth.word%ZK-!.*_+'@word`~)(><:\\}{",
+            "CSeq: 139122385 !interesting-Method0123456789_*+`.%indeed'~",
+            "Max-Forwards: 255",
+            "extensionHeader-!.%*+_`'~: 大停電",
+            "Content-Length: 0",
+        ],
+        "",
+    );
+    let req = request(valid(&raw));
+    let Uri::Sip(uri) = req.request_uri().unwrap() else {
+        panic!("expected a sip uri");
+    };
+    assert_eq!(
+        uri.user.as_deref(),
+        Some("1_unusual.URI~(to-be!sure)&isn't+it$/crazy?,/;;*")
+    );
+    assert_eq!(
+        uri.password.as_deref(),
+        Some("&it+has=1,weird!*pas$wo~d_too.(doesn't-it)")
+    );
+    let to = req.headers.to().unwrap();
+    assert_eq!(
+        to.display_name.as_deref(),
+        Some("BEL:\u{7} NUL:\u{0} DEL:\u{7f}")
+    );
+    let from = req.headers.from().unwrap();
+    assert_eq!(
+        from.display_name.as_deref(),
+        Some("token1~` token2'+_ token3*%!.-")
+    );
+    assert_eq!(from.tag(), Some("_token~1'+`*%!-."));
+    assert_eq!(req.headers.get("extensionHeader-!.%*+_`'~"), Some("大停電"));
+}
+
+// 3.1.1.6 Message with No LWS between Display Name and <
+#[test]
+fn no_space_before_angle_bracket() {
+    let raw = message(
+        &[
+            "OPTIONS sip:user@example.com SIP/2.0",
+            "To: sip:user@example.com",
+            "From: caller<sip:caller@example.com>;tag=323",
+            "Max-Forwards: 70",
+            "Call-ID: lwsdisp.1234abcd@funky.example.com",
+            "CSeq: 60 OPTIONS",
+            "Via: SIP/2.0/UDP funky.example.com;branch=z9hG4bKkdjuw",
+            "l: 0",
+        ],
+        "",
+    );
+    let from = request(valid(&raw)).headers.from().unwrap();
+    assert_eq!(from.display_name.as_deref(), Some("caller"));
+}
+
+// 3.1.1.7 Long Values in Header Fields
+#[test]
+fn long_values() {
+    let long = "very".repeat(64);
+    let to =
+        format!("To: \"I have a user name of {long} proportion\" <sip:{long}user@example.com>");
+    let call_id = format!("Call-ID: longreq.one{long}");
+    let vias: Vec<String> = (0..34)
+        .map(|i| format!("Via: SIP/2.0/TCP sip{i}.example.com;branch=z9hG4bK{i}{long}"))
+        .collect();
+    let mut lines = vec![
+        "INVITE sip:user@example.com SIP/2.0",
+        &to,
+        "From: sip:amazinglylongcallername@example.net;tag=12982982",
+        &call_id,
+        "CSeq: 3882340 INVITE",
+        "Max-Forwards: 70",
+        "Content-Type: application/sdp",
+        "Content-Length: {len}",
+    ];
+    lines.extend(vias.iter().map(String::as_str));
+    let req = request(valid(&message(&lines, SDP)));
+    assert_eq!(req.headers.via().unwrap().len(), 34);
+    assert!(req.headers.to().unwrap().display_name.unwrap().len() > 256);
+}
+
+// 3.1.1.8 Extra Trailing Octets in a UDP Datagram
+#[test]
+fn extra_trailing_octets() {
+    let mut raw = message(
+        &[
+            "REGISTER sip:example.com SIP/2.0",
+            "To: sip:j.user@example.com",
+            "From: sip:j.user@example.com;tag=43251j3j324",
+            "Max-Forwards: 8",
+            "I: dblreq.0ha0isndaksdj99sdfafnl3lk233412",
+            "Contact: sip:j.user@host.example.com",
+            "CSeq: 8 REGISTER",
+            "Via: SIP/2.0/UDP 192.0.2.125;branch=z9hG4bKkdjuw23492",
+            "Content-Length: 0",
+        ],
+        "",
+    );
+    raw.extend(message(
+        &[
+            "INVITE sip:joe@example.com SIP/2.0",
+            "t: sip:joe@example.com",
+            "From: sip:caller@example.net;tag=141334",
+            "Max-Forwards: 8",
+            "Call-ID: dblreq.0ha0isnda977644900765@192.0.2.15",
+            "CSeq: 8 INVITE",
+            "Via: SIP/2.0/UDP 192.0.2.15;branch=z9hG4bKkdjuw380234",
+            "Content-Type: application/sdp",
+            "Content-Length: {len}",
+        ],
+        SDP,
+    ));
+    let req = request(valid(&raw));
+    assert_eq!(req.method, Method::Register);
+    assert!(req.body.is_empty());
+}
+
+// 3.1.1.9 Semicolon-Separated Parameters in URI User Part
+#[test]
+fn semicolon_in_user_part() {
+    let raw = message(
+        &[
+            "OPTIONS sip:user;par=u%40example.net@example.com SIP/2.0",
+            "To: sip:j_user@example.com",
+            "From: sip:caller@example.org;tag=33242",
+            "Max-Forwards: 3",
+            "Call-ID: semiuri.0ha0isndaksdj",
+            "CSeq: 8 OPTIONS",
+            "Accept: application/sdp, application/pkcs7-mime,",
+            "        multipart/mixed, multipart/signed,",
+            "        message/sip, message/sipfrag",
+            "Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKkdjuw",
+            "l: 0",
+        ],
+        "",
+    );
+    let req = request(valid(&raw));
+    let Uri::Sip(uri) = req.request_uri().unwrap() else {
+        panic!("expected a sip uri");
+    };
+    assert_eq!(uri.user.as_deref(), Some("user;par=u%40example.net"));
+    assert_eq!(uri.host, "example.com");
+}
+
+// 3.1.1.10 Varied and Unknown Transport Types
+#[test]
+fn transport_types() {
+    let raw = message(
+        &[
+            "OPTIONS sip:user@example.com SIP/2.0",
+            "To: sip:user@example.com",
+            "From: <sip:caller@example.com>;tag=323",
+            "Max-Forwards: 70",
+            "Call-ID:  transports.kijh4akdnaqjkwendsasfdj",
+            "Accept: application/sdp",
+            "CSeq: 60 OPTIONS",
+            "Via: SIP/2.0/UDP t1.example.com;branch=z9hG4bKkdjuw",
+            "Via: SIP/2.0/SCTP t2.example.com;branch=z9hG4bKklasjdhf",
+            "Via: SIP/2.0/TLS t3.example.com;branch=z9hG4bK2980unddj",
+            "Via: SIP/2.0/UNKNOWN t4.example.com;branch=z9hG4bKasd0f3en",
+            "Via: SIP/2.0/TCP t5.example.com;branch=z9hG4bK0a9idfnee",
+            "l: 0",
+        ],
+        "",
+    );
+    let req = request(valid(&raw));
+    let transports: Vec<_> = req
+        .headers
+        .via()
+        .unwrap()
+        .into_iter()
+        .map(|via| via.transport)
+        .collect();
+    assert_eq!(transports, ["UDP", "SCTP", "TLS", "UNKNOWN", "TCP"]);
+}
+
+// 3.1.1.11 Multipart MIME Message: the body is opaque to the parser, only
+// its length matters.
+#[test]
+fn multipart_body() {
+    let body = "--boundary\r\nContent-Type: application/sdp\r\n\r\nv=0\r\n\
+                --boundary\r\nContent-Type: text/plain\r\n\r\nhello\r\n--boundary--\r\n";
+    let raw = message(
+        &[
+            "MESSAGE sip:kumiko@example.org SIP/2.0",
+            "Via: SIP/2.0/UDP 127.0.0.1:5070;branch=z9hG4bK-d87543-4dade06d0bdb11ee-1--d87543-;rport",
+            "Max-Forwards: 70",
+            "Route: <sip:127.0.0.1:5080>",
+            "Identity: r5mwreLuyDRYBi/0TiPwEsY3rEVsk/G2WxhgTV1PF7hHuLIK0YWVKZhKv9Mj8UeXqkMVbnVq37CD+813gvYjcBUaZngQmXc9WNZSDNGCzA+fWl9MEUHWIZo1CeJebdY/XlgKeTa0Olvq0rt70Q5jiSfbqMJmQFteeivUhkMWYUA=",
+            "Contact: <sip:fluffy@127.0.0.1:5070>",
+            "To: <sip:kumiko@example.org>",
+            "From: <sip:fluffy@example.com>;tag=2fb0dcc9",
+            "Call-ID: 3d9485ad0c49859b@Zmx1ZmZ5LW1hYy0xNi5sb2NhbA..",
+            "CSeq: 1 MESSAGE",
+            "Content-Transfer-Encoding: binary",
+            "Content-Type: multipart/mixed;boundary=boundary",
+            "Content-Length: {len}",
+        ],
+        body,
+    );
+    let req = request(valid(&raw));
+    assert_eq!(req.body, body);
+    assert_eq!(req.headers.via().unwrap()[0].port, Some(5070));
+}
+
+// 3.1.1.12 Unusual Reason Phrase
+#[test]
+fn unusual_reason_phrase() {
+    let raw = message(
+        &[
+            "SIP/2.0 200 = 2**3 * 5**2 но сто девяносто девять - простое",
+            "Via: SIP/2.0/UDP 192.0.2.198;branch=z9hG4bK1324923",
+            "Call-ID: unreason.1234ksdfak3j2erwedfsASdf",
+            "CSeq: 35 INVITE",
+            "From: sip:user@example.com;tag=11141343",
+            "To: sip:user@example.edu;tag=2229",
+            "Content-Length: {len}",
+            "Content-Type: application/sdp",
+            "Contact: <sip:user@host198.example.com>",
+        ],
+        SDP,
+    );
+    let Message::Response(res) = valid(&raw) else {
+        panic!("expected a response");
+    };
+    assert_eq!(res.status, 200);
+    assert_eq!(
+        res.reason,
+        "= 2**3 * 5**2 но сто девяносто девять - простое"
+    );
+}
+
+// 3.1.1.13 Empty Reason Phrase
+#[test]
+fn empty_reason_phrase() {
+    let raw = message(
+        &[
+            "SIP/2.0 100 ",
+            "Via: SIP/2.0/UDP 192.0.2.105;branch=z9hG4bK2398ndaoe",
+            "Call-ID: noreason.asndj203insdf99223ndf",
+            "CSeq: 35 INVITE",
+            "From: <sip:user@example.com>;tag=39ansfi3",
+            "To: <sip:user@example.edu>;tag=902jndnke3",
+            "Content-Length: 0",
+            "Contact: <sip:user@host105.example.com>",
+        ],
+        "",
+    );
+    let Message::Response(res) = valid(&raw) else {
+        panic!("expected a response");
+    };
+    assert_eq!((res.status, res.reason.as_str()), (100, ""));
+}
+
+// 3.3.1 / 3.3.2 Unknown and novel URI schemes in the Request-URI parse; it
+// is up to the application to refuse them with 416.
+#[test]
+fn unknown_uri_schemes() {
+    for uri in [
+        "nobodyKnowsThisScheme:totallyopaquecontent",
+        "soap.beep://192.0.2.103:3002",
+    ] {
+        let start = format!("OPTIONS {uri} SIP/2.0");
+        let raw = message(
+            &[
+                &start,
+                "To: sip:user@example.com",
+                "From: sip:caller@example.net;tag=384",
+                "Max-Forwards: 3",
+                "Call-ID: unkscm.nasdfasser0q239nwsdfasdkl34",
+                "CSeq: 3923423 OPTIONS",
+                "Via: SIP/2.0/TCP host9.example.com;branch=z9hG4bKkdjuw39234",
+                "Content-Length: 0",
+            ],
+            "",
+        );
+        let req = request(valid(&raw));
+        assert_eq!(req.request_uri().unwrap(), Uri::Other(uri.to_string()));
+    }
+}
+
+// 3.3.4 / 3.3.5 Contact parameters, inside and outside angle brackets.
+#[test]
+fn contact_parameters() {
+    for (contact, uri_param, header_param) in [
+        ("sip:+19725552222@gw1.example.net;unknownparam", false, true),
+        (
+            "<sip:+19725552222@gw1.example.net;unknownparam>",
+            true,
+            false,
+        ),
+    ] {
+        let contact = format!("Contact: {contact}");
+        let raw = message(
+            &[
+                "REGISTER sip:example.com SIP/2.0",
+                "Via: SIP/2.0/UDP saturn.example.com:5060;branch=z9hG4bKkdjuw",
+                "Max-Forwards: 70",
+                "From: sip:watson@example.com;tag=DkfVgjkrtMwaerKKpe",
+                "To: sip:watson@example.com",
+                "Call-ID: cparam01.70710@saturn.example.com",
+                "CSeq: 2 REGISTER",
+                &contact,
+                "l: 0",
+            ],
+            "",
+        );
+        let req = request(valid(&raw));
+        let Contact::Address(addr) = &req.headers.contact().unwrap()[0] else {
+            panic!("expected an address");
+        };
+        let uri = addr.uri.as_sip().unwrap();
+        assert_eq!(uri.param("unknownparam").is_some(), uri_param);
+        assert_eq!(addr.params.contains("unknownparam"), header_param);
+    }
+}
+
+// 3.3.3 REGISTER with a URL-Escaped Header in a bracketed Contact
+#[test]
+fn escaped_header_in_contact() {
+    let raw = message(
+        &[
+            "REGISTER sip:example.com SIP/2.0",
+            "To: sip:user@example.com",
+            "From: sip:user@example.com;tag=8",
+            "Max-Forwards: 70",
+            "Call-ID: regescrt.k345asrl3fdbv@192.0.2.1",
+            "CSeq: 14398234 REGISTER",
+            "Via: SIP/2.0/UDP host5.example.com;branch=z9hG4bKkdjuw",
+            "M: <sip:user@example.com?Route=%3Csip:sip.example.com%3E>",
+            "L:0",
+        ],
+        "",
+    );
+    let req = request(valid(&raw));
+    let Contact::Address(addr) = &req.headers.contact().unwrap()[0] else {
+        panic!("expected an address");
+    };
+    let uri = addr.uri.as_sip().unwrap();
+    assert_eq!(
+        uri.headers,
+        [("Route".to_string(), "%3Csip:sip.example.com%3E".to_string())]
+    );
+}
+
+// 3.1.1.x Max-Forwards of zero is valid.
+#[test]
+fn zero_max_forwards() {
+    let raw = message(
+        &[
+            "OPTIONS sip:user@example.com SIP/2.0",
+            "To: sip:user@example.com",
+            "From: sip:caller@example.net;tag=3ghsd41",
+            "Call-ID: zeromf.jfasdlfnm2o2l43r5u0asdfas",
+            "CSeq: 39234321 OPTIONS",
+            "Via: SIP/2.0/UDP host1.example.com;branch=z9hG4bKkdjuw2349i",
+            "Max-Forwards: 0",
+            "Content-Length: 0",
+        ],
+        "",
+    );
+    valid(&raw);
+}
+
+/// A minimal valid OPTIONS with `start` as its request line and `extra`
+/// header lines appended.
+fn options(start: &str, extra: &[&str]) -> Vec<u8> {
+    let mut lines = vec![
+        start,
+        "To: sip:user@example.com",
+        "From: sip:caller@example.net;tag=134161461246",
+        "Max-Forwards: 7",
+        "Call-ID: torture.0ha0isndaksdjasdf3234nas",
+        "CSeq: 8 OPTIONS",
+        "Via: SIP/2.0/UDP 192.0.2.15;branch=z9hG4bKkdjuw",
+    ];
+    lines.extend_from_slice(extra);
+    lines.push("Content-Length: 0");
+    message(&lines, "")
+}
+
+// 3.1.2.1 Extraneous Header Field Separators
+#[test]
+fn extraneous_separators() {
+    let raw = message(
+        &[
+            "INVITE sip:user@example.com SIP/2.0",
+            "To: sip:j.user@example.com",
+            "From: sip:caller@example.net;tag=134161461246",
+            "Max-Forwards: 7",
+            "Call-ID: badinv01.0ha0isndaksdjasdf3234nas",
+            "CSeq: 8 INVITE",
+            "Via: SIP/2.0/UDP 192.0.2.15;;,;,,",
+            "Contact: \"Joe\" <sip:joe@example.org>;;;;",
+            "Content-Length: {len}",
+            "Content-Type: application/sdp",
+        ],
+        SDP,
+    );
+    assert!(strict(&raw).is_err());
+    // Lenient parsing skips the empty elements.
+    let req = request(lenient(&raw).unwrap());
+    let vias = req.headers.via().unwrap();
+    assert_eq!(vias.len(), 1);
+    assert_eq!(vias[0].host, "192.0.2.15");
+}
+
+// 3.1.2.2 Content Length Larger Than Message
+#[test]
+fn content_length_larger_than_message() {
+    let raw = message(
+        &[
+            "INVITE sip:user@example.com SIP/2.0",
+            "Max-Forwards: 80",
+            "To: sip:j.user@example.com",
+            "From: sip:caller@example.net;tag=93942939o2",
+            "Contact: <sip:caller@hungry.example.net>",
+            "Call-ID: clerr.0ha0isndaksdjweiafasdk3",
+            "CSeq: 8 INVITE",
+            "Via: SIP/2.0/UDP host5.example.com;branch=z9hG4bK-39234-23523",
+            "Content-Type: application/sdp",
+            "Content-Length: 9999",
+        ],
+        SDP,
+    );
+    assert_eq!(strict(&raw), Err(ParseError::TruncatedBody));
+    assert_eq!(lenient(&raw), Err(ParseError::TruncatedBody));
+}
+
+// 3.1.2.3 Negative Content-Length
+#[test]
+fn negative_content_length() {
+    let raw = message(
+        &[
+            "INVITE sip:user@example.com SIP/2.0",
+            "Max-Forwards: 254",
+            "To: sip:j.user@example.com",
+            "From: sip:caller@example.net;tag=32394234",
+            "Call-ID: ncl.0ha0isndaksdj2193423r542w35",
+            "CSeq: 0 INVITE",
+            "Via: SIP/2.0/UDP 192.0.2.53;branch=z9hG4bKkdjuw",
+            "Contact: <sip:caller@example53.example.net>",
+            "Content-Type: application/sdp",
+            "Content-Length: -999",
+        ],
+        SDP,
+    );
+    assert_eq!(strict(&raw), Err(ParseError::ContentLength));
+    assert_eq!(lenient(&raw), Err(ParseError::ContentLength));
+}
+
+// 3.1.2.4 Request Scalar Fields with Overlarge Values
+#[test]
+fn overlarge_scalars() {
+    let raw = message(
+        &[
+            "REGISTER sip:example.com SIP/2.0",
+            "Via: SIP/2.0/TCP host129.example.com;branch=z9hG4bK342sdfoi3",
+            "To: <sip:user@example.com>",
+            "From: <sip:user@example.com>;tag=239232jh3",
+            "CSeq: 36893488147419103232 REGISTER",
+            "Call-ID: scalar02.23o0pd9vanlq3wnrlnewofjas9ui32",
+            "Max-Forwards: 300",
+            "Expires: 1000000000000000000000000000000000000000000000000000000",
+            "Contact: <sip:user@host129.example.com>",
+            "  ;expires=280297596632815",
+            "Content-Length: 0",
+        ],
+        "",
+    );
+    assert!(strict(&raw).is_err());
+    assert!(lenient(&raw).is_err());
+
+    let max_forwards_only = options(
+        "OPTIONS sip:user@example.com SIP/2.0",
+        &["Max-Forwards: 300"],
+    );
+    assert!(strict(&max_forwards_only).is_err());
+}
+
+// 3.1.2.5 Response Scalar Fields with Overlarge Values
+#[test]
+fn overlarge_status_code() {
+    let raw = message(
+        &[
+            "SIP/2.0 4294967301 better not break the receiver",
+            "Via: SIP/2.0/UDP 192.0.2.105;branch=z9hG4bK2398ndaoe",
+            "Call-ID: bigcode.asdof3uj203asdnf3429uasdhfas3",
+            "CSeq: 3923239 OPTIONS",
+            "From: <sip:user@example.com>;tag=39ansfi3",
+            "To: <sip:user@example.edu>;tag=902jndnke3",
+            "Content-Length: 0",
+            "Contact: <sip:user@host105.example.com>",
+        ],
+        "",
+    );
+    assert_eq!(strict(&raw), Err(ParseError::StartLine));
+    assert_eq!(lenient(&raw), Err(ParseError::StartLine));
+}
+
+// 3.1.2.6 Unterminated Quoted String in Display Name
+#[test]
+fn unterminated_quoted_string() {
+    let raw = message(
+        &[
+            "INVITE sip:user@example.com SIP/2.0",
+            "To: \"Mr. J. User <sip:j.user@example.com>",
+            "From: sip:caller@example.net;tag=93334",
+            "Max-Forwards: 10",
+            "Call-ID: quotbal.aksdj",
+            "Contact: <sip:caller@host59.example.net>",
+            "CSeq: 8 INVITE",
+            "Via: SIP/2.0/UDP 192.0.2.59:5050;branch=z9hG4bKkdjuw39234",
+            "Content-Type: application/sdp",
+            "Content-Length: {len}",
+        ],
+        SDP,
+    );
+    assert!(matches!(strict(&raw), Err(ParseError::Address(_))));
+    let req = request(lenient(&raw).unwrap());
+    assert!(req.headers.to().is_err());
+}
+
+// 3.1.2.7 <> Enclosing Request-URI
+#[test]
+fn bracketed_request_uri() {
+    let raw = options("OPTIONS <sip:user@example.com> SIP/2.0", &[]);
+    assert!(matches!(strict(&raw), Err(ParseError::Uri(_))));
+    assert!(request(lenient(&raw).unwrap()).request_uri().is_err());
+}
+
+// 3.1.2.8 Malformed SIP Request-URI (embedded LWS)
+#[test]
+fn whitespace_in_request_uri() {
+    let raw = options("INVITE sip:user@example.com; lr SIP/2.0", &[]);
+    assert_eq!(strict(&raw), Err(ParseError::StartLine));
+    assert_eq!(lenient(&raw), Err(ParseError::StartLine));
+}
+
+// 3.1.2.9 Multiple SP Separating Request-Line Elements
+#[test]
+fn multiple_spaces_in_request_line() {
+    let raw = options("INVITE  sip:user@example.com  SIP/2.0", &[]);
+    assert!(strict(&raw).is_err());
+    assert!(lenient(&raw).is_err());
+}
+
+// 3.1.2.10 SP Characters at End of Request-Line
+#[test]
+fn trailing_spaces_in_request_line() {
+    let raw = options("OPTIONS sip:remote-target@example.com SIP/2.0  ", &[]);
+    assert_eq!(strict(&raw), Err(ParseError::StartLine));
+    assert_eq!(lenient(&raw), Err(ParseError::StartLine));
+}
+
+// 3.1.2.11 Escaped Headers in SIP Request-URI
+#[test]
+fn headers_in_request_uri() {
+    let raw = options(
+        "OPTIONS sip:user@example.com?Route=%3Csip:example.com%3E SIP/2.0",
+        &[],
+    );
+    assert!(matches!(strict(&raw), Err(ParseError::Uri(_))));
+}
+
+// 3.1.2.13 Failure to Enclose name-addr URI in <>
+#[test]
+fn unbracketed_uri_with_headers() {
+    let raw = options(
+        "REGISTER sip:example.com SIP/2.0",
+        &["Contact: sip:user@example.com?Route=%3Csip:sip.example.com%3E"],
+    );
+    assert!(matches!(strict(&raw), Err(ParseError::Address(_))));
+}
+
+// 3.1.2.14 Spaces within addr-spec
+#[test]
+fn spaces_within_addr_spec() {
+    let raw = message(
+        &[
+            "OPTIONS sip:user@example.org SIP/2.0",
+            "Via: SIP/2.0/UDP host4.example.com:5060;branch=z9hG4bKkdju43234",
+            "Max-Forwards: 70",
+            "From: \"Bell, Alexander\" <sip:a.g.bell@example.com>;tag=433423",
+            "To: \"Watson, Thomas\" < sip:t.watson@example.org >",
+            "Call-ID: badaspec.sdf0234n2nds0a099u23h3hnnw009cdkne3",
+            "Accept: application/sdp",
+            "CSeq: 3923239 OPTIONS",
+            "l: 0",
+        ],
+        "",
+    );
+    assert!(matches!(strict(&raw), Err(ParseError::Address(_))));
+    let to = request(lenient(&raw).unwrap()).headers.to().unwrap();
+    assert_eq!(to.uri.as_sip().unwrap().user.as_deref(), Some("t.watson"));
+}
+
+// 3.1.2.15 Non-token Characters in Display Name
+#[test]
+fn unquoted_display_name_with_comma() {
+    let raw = message(
+        &[
+            "OPTIONS sip:t.watson@example.org SIP/2.0",
+            "Via: SIP/2.0/UDP c.example.com:5060;branch=z9hG4bKkdjuw",
+            "Max-Forwards: 70",
+            "From: Bell, Alexander <sip:a.g.bell@example.com>;tag=43",
+            "To: Watson, Thomas <sip:t.watson@example.org>",
+            "Call-ID: baddn.31415@c.example.com",
+            "Accept: application/sdp",
+            "CSeq: 3923239 OPTIONS",
+            "l: 0",
+        ],
+        "",
+    );
+    assert!(matches!(strict(&raw), Err(ParseError::Address(_))));
+    let from = request(lenient(&raw).unwrap()).headers.from().unwrap();
+    assert_eq!(from.display_name.as_deref(), Some("Bell, Alexander"));
+}
+
+// 3.1.2.16 Unknown Protocol Version
+#[test]
+fn unknown_protocol_version() {
+    let raw = options("OPTIONS sip:t.watson@example.org SIP/7.0", &[]);
+    assert_eq!(
+        strict(&raw),
+        Err(ParseError::Version("SIP/7.0".to_string()))
+    );
+}
+
+// 3.1.2.17 Start Line and CSeq Method Mismatch
+#[test]
+fn cseq_method_mismatch() {
+    let raw = options("INVITE sip:user@example.com SIP/2.0", &[]);
+    assert_eq!(strict(&raw), Err(ParseError::CSeqMismatch));
+    // Lenient parsing leaves the check to the transaction layer.
+    assert!(lenient(&raw).is_ok());
+}
+
+// 3.1.2.18 Unknown Method with CSeq Method Mismatch
+#[test]
+fn unknown_method_cseq_mismatch() {
+    let raw = options("NEWMETHOD sip:user@example.com SIP/2.0", &[]);
+    assert_eq!(strict(&raw), Err(ParseError::CSeqMismatch));
+}
+
+// 3.1.2.19 Overlarge Response Code, covered above; 3.4.1 Missing Required
+// Header Fields
+#[test]
+fn missing_required_headers() {
+    let raw = message(
+        &[
+            "INVITE sip:user@example.com SIP/2.0",
+            "CSeq: 193942 INVITE",
+            "Via: SIP/2.0/UDP 192.0.2.95;branch=z9hG4bKkdj.insuf",
+            "Content-Type: application/sdp",
+            "l: {len}",
+        ],
+        SDP,
+    );
+    assert!(matches!(strict(&raw), Err(ParseError::MissingHeader(_))));
+    assert!(matches!(lenient(&raw), Err(ParseError::MissingHeader(_))));
+}
+
+// 3.1.2.x Multiple Values in Single-Value Required Fields
+#[test]
+fn duplicated_single_value_headers() {
+    let raw = options(
+        "OPTIONS sip:user@example.com SIP/2.0",
+        &["To: sip:other@example.com", "CSeq: 9 OPTIONS"],
+    );
+    assert!(matches!(strict(&raw), Err(ParseError::Duplicate(_))));
+}
+
+// 3.1.2.x Multiple Content-Length Values
+#[test]
+fn conflicting_content_lengths() {
+    let raw = message(
+        &[
+            "OPTIONS sip:user@example.com SIP/2.0",
+            "Via: SIP/2.0/UDP host5.example.net;branch=z9hG4bK293423",
+            "To: sip:user@example.com",
+            "From: sip:other@example.net;tag=3923942",
+            "Call-ID: mcl01.fhn2323orihawfdoa3o4r52o3irsdf",
+            "CSeq: 15932 OPTIONS",
+            "Content-Length: 13",
+            "Max-Forwards: 60",
+            "Content-Length: 5",
+            "Content-Type: text/plain",
+        ],
+        "There's no way to know how many octets are supposed to be here.",
+    );
+    assert_eq!(strict(&raw), Err(ParseError::Duplicate("Content-Length")));
+}
+
+// Not from RFC 4475: bare LF line endings are only accepted leniently.
+#[test]
+fn bare_line_feeds() {
+    let raw = options("OPTIONS sip:user@example.com SIP/2.0", &[])
+        .into_iter()
+        .filter(|b| *b != b'\r')
+        .collect::<Vec<u8>>();
+    assert_eq!(strict(&raw), Err(ParseError::LineEnding));
+    assert!(lenient(&raw).is_ok());
+}
+
+#[test]
+fn typed_headers_round_trip() {
+    let via = Via::parse(
+        "SIP/2.0/UDP [2001:db8::9:1]:5061;branch=z9hG4bKas;received=192.0.2.1",
+        ParseMode::Strict,
+    )
+    .unwrap();
+    assert_eq!(via.host, "[2001:db8::9:1]");
+    assert_eq!(via.port, Some(5061));
+    assert_eq!(
+        Via::parse(&via.to_string(), ParseMode::Strict).unwrap(),
+        via
+    );
+
+    let addr = NameAddr::parse(
+        r#""Quoted \"name\"" <sips:alice:secret@example.com:5061;transport=tcp>;tag=a1"#,
+        ParseMode::Strict,
+    )
+    .unwrap();
+    let uri = addr.uri.as_sip().unwrap();
+    assert!(uri.secure);
+    assert_eq!(uri.password.as_deref(), Some("secret"));
+    assert_eq!(uri.param("transport"), Some(Some("tcp")));
+    assert_eq!(addr.tag(), Some("a1"));
+    assert_eq!(
+        NameAddr::parse(&addr.to_string(), ParseMode::Strict).unwrap(),
+        addr
+    );
+}